@@ -0,0 +1,42 @@
+#![no_main]
+
+use kstd::hosted::FromStd;
+use kstd::str::StringView;
+use libfuzzer_sys::fuzz_target;
+use phantom_filesystem_drivers::phantomfs::PhantomFS;
+use phantom_filesystem_drivers::traits::{InodeId, ReadDir, ReadFS, Search, StreamId, OBJECT_NULL};
+
+/// Bounds the amount of work done per image, so that large on-disk counts show up as slow inputs rather than timeouts
+const MAX_ENTRIES: usize = 64;
+
+fuzz_target!(|image: &[u8]| {
+    let mut fs = PhantomFS::new(FromStd(std::io::Cursor::new(image)));
+
+    if fs.read_descriptor().is_err() {
+        return;
+    }
+
+    let mut idx = 0;
+    let mut entries = Vec::new();
+    while let Ok(Some((found, entry))) = fs.read_dir_entry(OBJECT_NULL, idx) {
+        entries.push(entry);
+        if entries.len() == MAX_ENTRIES {
+            break;
+        }
+        idx = found + 1;
+    }
+
+    for entry in entries {
+        let _ = fs.get_object_from(
+            InodeId(OBJECT_NULL, StreamId(None)),
+            StringView::new(&entry.name),
+        );
+
+        if let Ok(stream) = fs.get_stream_of_object(entry.obj, StringView::new("FileData")) {
+            let node = InodeId(entry.obj, stream);
+            let _ = fs.stream_len(node);
+            let mut buf = [0u8; 256];
+            let _ = fs.read_exact_from(node, 0, &mut buf);
+        }
+    }
+});
@@ -0,0 +1,29 @@
+#![no_main]
+
+use core::num::NonZeroU64;
+
+use kstd::hosted::FromStd;
+use libfuzzer_sys::fuzz_target;
+use phantom_filesystem_drivers::phantomfs::PhantomFS;
+use phantom_filesystem_drivers::traits::{InodeId, ObjectId, ReadFS, StreamId};
+
+fuzz_target!(|input: &[u8]| {
+    // The first 24 bytes choose the object, stream and offset to read; the rest is the image
+    if input.len() < 24 {
+        return;
+    }
+    let (params, image) = input.split_at(24);
+    let obj = u64::from_le_bytes(params[0..8].try_into().unwrap());
+    let stream = u64::from_le_bytes(params[8..16].try_into().unwrap());
+    let offset = u64::from_le_bytes(params[16..24].try_into().unwrap());
+
+    let mut fs = PhantomFS::new(FromStd(std::io::Cursor::new(image)));
+    let node = InodeId(
+        ObjectId(NonZeroU64::new(obj)),
+        StreamId(NonZeroU64::new(stream)),
+    );
+
+    let mut buf = [0u8; 512];
+    let _ = fs.read_bytes_from(node, offset, &mut buf);
+    let _ = fs.read_exact_from(node, offset, &mut buf[..64]);
+});
@@ -0,0 +1,71 @@
+#![no_main]
+
+use kstd::hosted::FromStd;
+use kstd::str::StringView;
+use libfuzzer_sys::fuzz_target;
+use phantom_filesystem_drivers::phantomfs::PhantomFS;
+use phantom_filesystem_drivers::traits::{
+    InodeId, ObjectId, ReadDir, ReadFS, Search, StreamId, WriteDir, WriteFS, OBJECT_NULL,
+};
+
+/// Bounds the number of operations per input
+const MAX_OPS: usize = 16;
+
+/// Long enough that names are stored in the `Strings` stream of their directory rather than inline
+const LONG_NAME: &str = "a name that is too long to be stored inline in its directory entry";
+
+fuzz_target!(|input: &[u8]| {
+    // The first byte is the number of operations, followed by two bytes for each operation; the rest is the image
+    let (&count, rest) = match input.split_first() {
+        Some(split) => split,
+        None => return,
+    };
+    let count = usize::from(count).min(MAX_OPS);
+    if rest.len() < 2 * count {
+        return;
+    }
+    let (ops, image) = rest.split_at(2 * count);
+
+    let mut fs = PhantomFS::new(FromStd(std::io::Cursor::new(image.to_vec())));
+    if fs.read_descriptor().is_err() {
+        return;
+    }
+
+    let mut dir = OBJECT_NULL;
+    let mut file: Option<ObjectId> = None;
+    for (i, op) in ops.chunks_exact(2).enumerate() {
+        let name = format!("{}{}", if op[0] & 0x80 != 0 { LONG_NAME } else { "n" }, i);
+        match op[0] % 4 {
+            0 => file = fs.create_file(dir, StringView::new(&name)).ok(),
+            1 => {
+                if let Ok(created) = fs.create_dir(dir, StringView::new(&name)) {
+                    dir = created;
+                }
+            }
+            2 => {
+                if let Some(obj) = file {
+                    if let Ok(stream) = fs.get_stream_of_object(obj, StringView::new("FileData")) {
+                        let content = vec![op[1]; usize::from(op[1]) * 8];
+                        let _ = fs.write_stream(InodeId(obj, stream), &content);
+                    }
+                }
+            }
+            _ => {
+                if let Some(obj) = file {
+                    let _ = fs.create_stream(obj, StringView::new("Extra"));
+                }
+            }
+        }
+    }
+
+    // Read back whatever the operations left in the directory they ended in
+    let mut idx = 0;
+    while let Ok(Some((found, entry))) = fs.read_dir_entry(dir, idx) {
+        let _ = fs.get_object_from(InodeId(dir, StreamId(None)), StringView::new(&entry.name));
+        if let Ok(stream) = fs.get_stream_of_object(entry.obj, StringView::new("FileData")) {
+            let mut buf = [0u8; 256];
+            let _ = fs.read_exact_from(InodeId(entry.obj, stream), 0, &mut buf);
+        }
+        idx = found + 1;
+    }
+});
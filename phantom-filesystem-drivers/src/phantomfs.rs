@@ -1,9 +1,13 @@
+use alloc::{string::String, vec::Vec};
 use core::num::{NonZeroU32, NonZeroU64};
 use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::str::StringView;
 
 use bytemuck::{Pod, Zeroable};
 
-use crate::traits::{ReadFS, Search, StreamId};
+use crate::traits::{
+    DirEntry, InodeId, ObjectId, ReadDir, ReadFS, Search, StreamId, WriteDir, WriteFS,
+};
 
 bitflags::bitflags! {
     #[derive(Default,Zeroable,Pod)]
@@ -63,6 +67,89 @@ pub struct StreamListing {
     inline_data: [u8; 48],
 }
 
+/// The location of the content of a stream that is too large to fit in the `inline_data` of its [`StreamListing`].
+///
+/// This is stored in place of the inline data, and is interpreted like the `streams_ref`/`streams_indirection` pair of a [`PhantomFSObject`].
+/// The layout is specified under "Stream content" in `docs/phantomfs.md`.
+#[repr(C, align(16))]
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, Zeroable, Pod)]
+pub struct StreamDataRef {
+    data_ref: u128,
+    indirection: u8,
+    reserved17: [u8; 31],
+}
+
+impl StreamDataRef {
+    /// A reference to content stored contiguously at `pos`
+    pub fn new(pos: u128) -> Self {
+        Self {
+            data_ref: pos,
+            indirection: 1,
+            reserved17: [0; 31],
+        }
+    }
+}
+
+impl PhantomFSObject {
+    /// The number of strong references (directory entries) to this object
+    pub fn strong_ref(&self) -> u32 {
+        self.strong_ref
+    }
+
+    /// The number of weak references to this object, if any
+    pub fn weak_ref(&self) -> Option<NonZeroU32> {
+        self.weak_ref
+    }
+
+    pub fn ty(&self) -> PhantomFSObjectType {
+        self.ty
+    }
+
+    pub fn flags(&self) -> PhantomFSObjectFlags {
+        self.flags
+    }
+
+    /// The number of entries in the `Streams` stream of this object, including the `Streams` stream itself.
+    ///
+    /// This is read from the image unchecked. Use [`PhantomFS::stream_count`] to bound loops over the streams
+    pub fn stream_count(&self) -> u64 {
+        self.streams_size / size_of_u64::<StreamListing>()
+    }
+}
+
+impl StreamListing {
+    /// The size of the content of the stream, in bytes
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn flags(&self) -> PhantomFSStreamFlags {
+        self.flags
+    }
+
+    /// Whether the content of the stream is stored in the listing itself
+    pub fn is_inline(&self) -> bool {
+        self.size <= STREAM_INLINE_MAX
+    }
+
+    /// An empty stream with the inline name `name`, which may be NUL-terminated
+    fn named(name: &[u8]) -> Self {
+        let name = trim_nul(name);
+        let mut listing = Self::zeroed();
+        listing.name[..name.len()].copy_from_slice(name);
+        listing
+    }
+
+    /// The location of the content of the stream, if it isn't inline
+    pub fn data_ref(&self) -> Option<StreamDataRef> {
+        if self.is_inline() {
+            None
+        } else {
+            Some(bytemuck::pod_read_unaligned(&self.inline_data))
+        }
+    }
+}
+
 #[repr(C, align(64))]
 #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, Zeroable, Pod)]
 pub struct DirectoryElement {
@@ -178,6 +265,8 @@ pub struct RootFSDescriptor {
 pub struct PhantomFS<S> {
     stream: S,
     descriptor: Option<RootFSDescriptor>,
+    /// The length of the image, which every on-disk extent must lie within. Set by [`PhantomFS::read_descriptor`]
+    image_len: u128,
 }
 
 impl<S> PhantomFS<S> {
@@ -185,6 +274,7 @@ impl<S> PhantomFS<S> {
         Self {
             stream: inner,
             descriptor: None,
+            image_len: 0,
         }
     }
 
@@ -214,16 +304,53 @@ impl<S> PhantomFS<S> {
 impl<S: Read + Seek> PhantomFS<S> {
     pub fn read_descriptor(&mut self) -> std::io::Result<()> {
         self.stream.seek(SeekFrom::Start(1024))?;
-        let desc = self.descriptor.get_or_insert_with(Zeroable::zeroed);
-        self.stream.read_exact(bytemuck::bytes_of_mut(desc))?;
+        let mut desc = RootFSDescriptor::zeroed();
+        self.stream.read_exact(bytemuck::bytes_of_mut(&mut desc))?;
 
         if desc.magic != consts::PHANTOMFS_MAGIC {
-            return Err(io::Error::InvalidData(Some(alloc::format!(
-                "Invalid Magic {:x?}",
-                desc.magic
-            ))));
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                alloc::format!("Invalid Magic {:x?}", desc.magic),
+            ));
+        }
+
+        if desc.major != consts::MAJOR_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                alloc::format!(
+                    "Unsupported PhantomFS version {}.{}",
+                    desc.major,
+                    desc.minor
+                ),
+            ));
+        }
+
+        if desc.descriptor_size as usize != core::mem::size_of::<RootFSDescriptor>() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                alloc::format!("Invalid descriptor size {}", desc.descriptor_size),
+            ));
         }
 
+        let mut check = desc;
+        check.descriptor_crc = 0;
+        let mut crc = crc_any::CRCu32::crc32();
+        crc.digest(bytemuck::bytes_of(&check));
+        // Fuzzers can't be expected to produce valid checksums, so let them past this check
+        if !cfg!(fuzzing) && crc.get_crc() != desc.descriptor_crc {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                alloc::format!(
+                    "Descriptor checksum mismatch (expected {:#010x}, got {:#010x})",
+                    desc.descriptor_crc,
+                    crc.get_crc()
+                ),
+            ));
+        }
+
+        self.image_len = self.stream.stream_len()?;
+        self.descriptor = Some(desc);
+
         Ok(())
     }
 
@@ -236,103 +363,1148 @@ impl<S: Read + Seek> PhantomFS<S> {
             }
         }
     }
+
+    /// Returns the index of `obj` in the object table. [`OBJECT_NULL`] refers to the root object.
+    pub fn object_index(&mut self, obj: ObjectId) -> std::io::Result<u64> {
+        match obj.0 {
+            Some(idx) => Ok(idx.get()),
+            None => Ok(self.get_or_read_descriptor()?.rootidx),
+        }
+    }
+
+    /// Reads the entry for `obj` from the object table. [`OBJECT_NULL`] refers to the root object.
+    pub fn read_object(&mut self, obj: ObjectId) -> std::io::Result<PhantomFSObject> {
+        let idx = self.object_index(obj)?;
+        let pos = self.object_pos(idx)?;
+
+        self.stream.seek(SeekFrom::StartFar(pos))?;
+        let mut obj = PhantomFSObject::zeroed();
+        self.stream.read_exact(bytemuck::bytes_of_mut(&mut obj))?;
+
+        Ok(obj)
+    }
+
+    /// Returns the position of the entry at `idx` in the object table
+    fn object_pos(&mut self, idx: u64) -> std::io::Result<u128> {
+        let desc = self.get_or_read_descriptor()?;
+        let count = desc.objtabsize / size_of_u64::<PhantomFSObject>();
+
+        // The object table grows downwards from `objtab`, so index 0 would lie past its end
+        if idx == 0 || idx > count {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+
+        let pos = u128::from(idx * size_of_u64::<PhantomFSObject>());
+        desc.objtab.checked_sub(pos).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                alloc::format!(
+                    "Object table at {:#x} extends past the start of the partition",
+                    desc.objtab
+                ),
+            )
+        })
+    }
+
+    /// Returns the extent of the object table, after checking that it lies within the image
+    fn object_table_extent(&mut self) -> std::io::Result<(u128, u64)> {
+        let desc = *self.get_or_read_descriptor()?;
+        let start = desc
+            .objtab
+            .checked_sub(u128::from(desc.objtabsize))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    alloc::format!(
+                        "Object table at {:#x} extends past the start of the partition",
+                        desc.objtab
+                    ),
+                )
+            })?;
+        self.check_extent(start, desc.objtabsize, "Object table")?;
+        Ok((start, desc.objtabsize))
+    }
+
+    /// Returns the number of streams of `obj`, after checking that its `Streams` stream lies within the image.
+    ///
+    /// The count comes from the image, so this must be used to bound any loop over the streams of an object
+    pub fn stream_count(&mut self, obj: &PhantomFSObject) -> std::io::Result<u64> {
+        match obj.streams_indirection {
+            0 => return Ok(0),
+            1 => {}
+            n => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    alloc::format!("Unsupported streams indirection level {}", n),
+                ))
+            }
+        }
+
+        self.check_extent(obj.streams_ref, obj.streams_size, "Streams stream")?;
+        Ok(obj.stream_count())
+    }
+
+    /// Checks that the `size` bytes at `start` lie within the image
+    fn check_extent(&mut self, start: u128, size: u64, what: &str) -> std::io::Result<()> {
+        self.get_or_read_descriptor()?;
+        match start.checked_add(u128::from(size)) {
+            Some(end) if end <= self.image_len => Ok(()),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                alloc::format!(
+                    "{} at {:#x} of {} bytes extends past the end of the image",
+                    what,
+                    start,
+                    size
+                ),
+            )),
+        }
+    }
+
+    /// Checks that the content of the stream described by `listing` lies within the image.
+    ///
+    /// Inline content always does. Loops over the content of a stream must be bounded with this first, since its size comes from the image
+    fn check_stream_extent(&mut self, listing: &StreamListing, what: &str) -> std::io::Result<()> {
+        match listing.data_ref() {
+            Some(data_ref) => self.check_extent(data_ref.data_ref, listing.size, what),
+            None => Ok(()),
+        }
+    }
+
+    /// Reads the listing of the stream at `idx` from the `Streams` stream of `obj`.
+    ///
+    /// Index 0 is the listing of the `Streams` stream itself.
+    pub fn read_stream_listing(
+        &mut self,
+        obj: &PhantomFSObject,
+        idx: u64,
+    ) -> std::io::Result<StreamListing> {
+        if idx >= self.stream_count(obj)? {
+            return Err(io::Error::from(io::ErrorKind::NotFound));
+        }
+
+        // idx is less than streams_size/128, and the `Streams` stream lies within the image, so this cannot overflow
+        let pos = obj.streams_ref + u128::from(idx * size_of_u64::<StreamListing>());
+
+        self.stream.seek(SeekFrom::StartFar(pos))?;
+        let mut listing = StreamListing::zeroed();
+        self.stream
+            .read_exact(bytemuck::bytes_of_mut(&mut listing))?;
+
+        Ok(listing)
+    }
+
+    /// Reads from the content of the stream described by `listing`, starting at `offset`.
+    ///
+    /// Returns `0` at the end of the stream.
+    pub fn read_stream_data(
+        &mut self,
+        listing: &StreamListing,
+        offset: u64,
+        bytes: &mut [u8],
+    ) -> std::io::Result<usize> {
+        if offset >= listing.size {
+            return Ok(0);
+        }
+
+        let len = usize::try_from(listing.size - offset)
+            .unwrap_or(usize::MAX)
+            .min(bytes.len());
+
+        if listing.size <= STREAM_INLINE_MAX {
+            // offset < size <= 48, so neither conversion truncates
+            let offset = offset as usize;
+            bytes[..len].copy_from_slice(&listing.inline_data[offset..][..len]);
+            return Ok(len);
+        }
+
+        let data_ref: StreamDataRef = bytemuck::pod_read_unaligned(&listing.inline_data);
+
+        match data_ref.indirection {
+            1 => {}
+            n => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    alloc::format!("Unsupported stream indirection level {}", n),
+                ))
+            }
+        }
+
+        let pos = data_ref
+            .data_ref
+            .checked_add(u128::from(offset))
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, "Stream data out of range")
+            })?;
+
+        self.stream.seek(SeekFrom::StartFar(pos))?;
+        self.stream.read(&mut bytes[..len])
+    }
+
+    fn read_stream_data_exact(
+        &mut self,
+        listing: &StreamListing,
+        mut offset: u64,
+        mut bytes: &mut [u8],
+    ) -> std::io::Result<()> {
+        while !bytes.is_empty() {
+            match self.read_stream_data(listing, offset, bytes) {
+                Ok(0) => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+                Ok(n) => {
+                    offset += n as u64;
+                    bytes = &mut bytes[n..];
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the NUL-terminated string at `offset` in the `Strings` stream described by `strings`
+    fn read_string(&mut self, strings: &StreamListing, offset: u64) -> std::io::Result<String> {
+        let mut name = Vec::new();
+        let mut buf = [0u8; 64];
+        let mut pos = offset;
+
+        loop {
+            let n = self.read_stream_data(strings, pos, &mut buf)?;
+            if n == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+
+            if let Some(end) = buf[..n].iter().position(|&b| b == 0) {
+                name.extend_from_slice(&buf[..end]);
+                break;
+            }
+
+            name.extend_from_slice(&buf[..n]);
+            pos += n as u64;
+
+            if name.len() > NAME_MAX {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    alloc::format!("Name exceeds {} bytes", NAME_MAX),
+                ));
+            }
+        }
+
+        String::from_utf8(name)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Name is not valid UTF-8"))
+    }
+
+    /// Finds a stream of `obj` by its inline name only, without consulting the `Strings` stream
+    fn find_inline_stream(
+        &mut self,
+        obj: &PhantomFSObject,
+        name: &[u8],
+    ) -> std::io::Result<Option<(u64, StreamListing)>> {
+        for idx in 0..self.stream_count(obj)? {
+            let listing = self.read_stream_listing(obj, idx)?;
+            if listing.name_ref.is_none() && inline_name(&listing.name) == name {
+                return Ok(Some((idx, listing)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Resolves the name of the stream described by `listing`, which belongs to `obj`
+    pub fn stream_name(
+        &mut self,
+        obj: &PhantomFSObject,
+        listing: &StreamListing,
+    ) -> std::io::Result<String> {
+        match listing.name_ref {
+            None => String::from_utf8(inline_name(&listing.name).to_vec())
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Name is not valid UTF-8")),
+            Some(name_ref) => {
+                let strings = self
+                    .find_inline_stream(obj, trim_nul(consts::STREAM_STRINGS))?
+                    .ok_or(io::Error::from(io::ErrorKind::NotFound))?
+                    .1;
+                self.read_string(&strings, name_ref.get())
+            }
+        }
+    }
+
+    /// Finds a stream of `obj` by name, returning its index and listing
+    pub fn find_stream(
+        &mut self,
+        obj: &PhantomFSObject,
+        name: &str,
+    ) -> std::io::Result<Option<(u64, StreamListing)>> {
+        let mut strings = None;
+        for idx in 0..self.stream_count(obj)? {
+            let listing = self.read_stream_listing(obj, idx)?;
+            let matches = match listing.name_ref {
+                None => inline_name(&listing.name) == name.as_bytes(),
+                Some(name_ref) => {
+                    if strings.is_none() {
+                        strings = Some(
+                            self.find_inline_stream(obj, trim_nul(consts::STREAM_STRINGS))?
+                                .ok_or(io::Error::from(io::ErrorKind::NotFound))?
+                                .1,
+                        );
+                    }
+                    self.read_string(strings.as_ref().unwrap(), name_ref.get())? == name
+                }
+            };
+
+            if matches {
+                return Ok(Some((idx, listing)));
+            }
+        }
+        Ok(None)
+    }
 }
 
 impl<S: Read + Seek> Search for PhantomFS<S> {
-    fn get_object_from(
-        &mut self,
-        _pos: crate::traits::InodeId,
-        _pname: std::str::StringView,
-    ) -> std::io::Result<crate::traits::ObjectId> {
-        todo!()
+    fn get_object_from(&mut self, pos: InodeId, pname: StringView) -> std::io::Result<ObjectId> {
+        let mut idx = 0;
+        while let Some((found, entry)) = self.read_dir_entry(pos.0, idx)? {
+            if entry.name == *pname {
+                return Ok(entry.obj);
+            }
+            idx = found + 1;
+        }
+
+        Err(io::Error::from(io::ErrorKind::NotFound))
     }
 
     fn get_stream_of_object(
         &mut self,
-        _obj: crate::traits::ObjectId,
-        _lname: std::str::StringView,
-    ) -> std::io::Result<crate::traits::StreamId> {
-        let _desc = self.get_or_read_descriptor()?;
+        obj: ObjectId,
+        lname: StringView,
+    ) -> std::io::Result<StreamId> {
+        let obj = self.read_object(obj)?;
+        match self.find_stream(&obj, &lname)? {
+            Some((idx, _)) => Ok(StreamId(NonZeroU64::new(idx))),
+            None => Err(io::Error::from(io::ErrorKind::NotFound)),
+        }
+    }
+}
+
+impl<S: Read + Seek> ReadDir for PhantomFS<S> {
+    fn read_dir_entry(
+        &mut self,
+        dir: ObjectId,
+        mut idx: u64,
+    ) -> std::io::Result<Option<(u64, DirEntry)>> {
+        let obj = self.read_object(dir)?;
+        if obj.ty != PhantomFSObjectType::Directory {
+            return Err(io::Error::from(io::ErrorKind::NotADirectory));
+        }
+
+        let content =
+            match self.find_stream(&obj, trim_nul_str(consts::STREAM_DIRECTORY_CONTENT))? {
+                Some((_, content)) => content,
+                None => return Ok(None),
+            };
+
+        self.check_stream_extent(&content, "DirectoryContent stream")?;
+        let count = content.size / size_of_u64::<DirectoryElement>();
+
+        while idx < count {
+            let mut elem = DirectoryElement::zeroed();
+            // idx < size/64, so this cannot overflow
+            self.read_stream_data_exact(
+                &content,
+                idx * size_of_u64::<DirectoryElement>(),
+                bytemuck::bytes_of_mut(&mut elem),
+            )?;
+
+            if let Some(objidx) = elem.objidx {
+                let name = match elem.name_index {
+                    None => String::from_utf8(inline_name(&elem.name).to_vec()).map_err(|_| {
+                        io::Error::new(io::ErrorKind::InvalidData, "Name is not valid UTF-8")
+                    })?,
+                    Some(name_index) => {
+                        let strings = self
+                            .find_inline_stream(&obj, trim_nul(consts::STREAM_STRINGS))?
+                            .ok_or(io::Error::from(io::ErrorKind::NotFound))?
+                            .1;
+                        self.read_string(&strings, name_index.get())?
+                    }
+                };
+
+                return Ok(Some((
+                    idx,
+                    DirEntry {
+                        obj: ObjectId(Some(objidx)),
+                        name,
+                    },
+                )));
+            }
+
+            idx += 1;
+        }
 
-        Ok(StreamId(None))
+        Ok(None)
     }
 }
 
 impl<S: Read + Seek> ReadFS for PhantomFS<S> {
     fn read_bytes_from(
         &mut self,
-        node: crate::traits::InodeId,
-        _offset: u64,
-        _bytes: &mut [u8],
+        node: InodeId,
+        offset: u64,
+        bytes: &mut [u8],
     ) -> std::io::Result<usize> {
+        let obj = self.read_object(node.0)?;
+        let streampos = node
+            .1
+             .0
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?
+            .get();
+        let listing = self.read_stream_listing(&obj, streampos)?;
+
+        self.read_stream_data(&listing, offset, bytes)
+    }
+
+    fn stream_len(&mut self, node: InodeId) -> std::io::Result<u64> {
+        let obj = self.read_object(node.0)?;
+        let streampos = node
+            .1
+             .0
+            .ok_or(io::Error::from(io::ErrorKind::NotFound))?
+            .get();
+
+        Ok(self.read_stream_listing(&obj, streampos)?.size)
+    }
+}
+
+impl<S: Write + Seek> PhantomFS<S> {
+    pub fn write_descriptor(&mut self) -> std::io::Result<()> {
+        if let Some(desc) = &self.descriptor {
+            self.stream.seek(SeekFrom::Start(1024))?;
+            self.stream.write_all(bytemuck::bytes_of(desc))?;
+        }
+        Ok(())
+    }
+}
+
+impl<S: Read + Write + Seek> PhantomFS<S> {
+    /// Recomputes the checksum of the descriptor, and writes it to the image
+    fn commit_descriptor(&mut self) -> std::io::Result<()> {
         let desc = self.get_or_read_descriptor()?;
-        let _objtab = desc.objtab;
-        let obj: u64 = if let Some(obj) = node.0 .0 {
-            obj.get()
+        desc.descriptor_crc = 0;
+        let mut crc = crc_any::CRCu32::crc32();
+        crc.digest(bytemuck::bytes_of(&*desc));
+        desc.descriptor_crc = crc.get_crc();
+        self.write_descriptor()
+    }
+
+    /// Writes `bytes` to the image at `pos`, extending the image if they lie past its end
+    fn write_at(&mut self, pos: u128, bytes: &[u8]) -> std::io::Result<()> {
+        self.stream.seek(SeekFrom::StartFar(pos))?;
+        self.stream.write_all(bytes)?;
+        self.image_len = self.image_len.max(pos + bytes.len() as u128);
+        Ok(())
+    }
+
+    fn write_object(&mut self, idx: u64, obj: &PhantomFSObject) -> std::io::Result<()> {
+        let pos = self.object_pos(idx)?;
+        self.write_at(pos, bytemuck::bytes_of(obj))
+    }
+
+    /// Returns the extents in use by the filesystem as `(start, end)` pairs, sorted by `start`.
+    ///
+    /// PhantomFS has no free space map, so everything that isn't referenced from the descriptor or the object table is free
+    fn used_extents(&mut self) -> std::io::Result<Vec<(u128, u128)>> {
+        let (objtab_start, objtab_size) = self.object_table_extent()?;
+        let mut extents = alloc::vec![
+            (0, 1024 + core::mem::size_of::<RootFSDescriptor>() as u128),
+            (objtab_start, objtab_start + u128::from(objtab_size)),
+        ];
+
+        for idx in 1..=objtab_size / size_of_u64::<PhantomFSObject>() {
+            let obj = self.read_object(ObjectId(NonZeroU64::new(idx)))?;
+            let count = self.stream_count(&obj)?;
+            if count == 0 {
+                continue;
+            }
+            extents.push((
+                obj.streams_ref,
+                obj.streams_ref + u128::from(obj.streams_size),
+            ));
+
+            // Stream 0 is the `Streams` stream, which is already accounted for
+            for sidx in 1..count {
+                let listing = self.read_stream_listing(&obj, sidx)?;
+                if let Some(data_ref) = listing.data_ref() {
+                    self.check_stream_extent(&listing, "Stream")?;
+                    extents.push((
+                        data_ref.data_ref,
+                        data_ref.data_ref + u128::from(listing.size),
+                    ));
+                }
+            }
+        }
+
+        extents.sort_unstable();
+        Ok(extents)
+    }
+
+    /// Finds `size` free bytes, in the first gap between the extents in use that is large enough, or else past all of them.
+    ///
+    /// The returned extent is free until something that refers to it is written, so each allocation must be linked into the filesystem before the next
+    fn allocate(&mut self, size: u64) -> std::io::Result<u128> {
+        let align_up = |pos: u128| (pos + EXTENT_ALIGN - 1) & !(EXTENT_ALIGN - 1);
+
+        let mut pos = 0;
+        for (start, end) in self.used_extents()? {
+            if align_up(pos) + u128::from(size) <= start {
+                break;
+            }
+            pos = pos.max(end);
+        }
+        Ok(align_up(pos))
+    }
+
+    /// Reads the whole content of the stream described by `listing`
+    fn read_stream_content(&mut self, listing: &StreamListing) -> std::io::Result<Vec<u8>> {
+        self.check_stream_extent(listing, "Stream")?;
+        let len = usize::try_from(listing.size)
+            .map_err(|_| io::Error::from(io::ErrorKind::OutOfMemory))?;
+        let mut content = alloc::vec![0; len];
+        self.read_stream_data_exact(listing, 0, &mut content)?;
+        Ok(content)
+    }
+
+    /// Replaces the content of the stream at `idx` of `obj` with `bytes`.
+    ///
+    /// Content that still fits in the extent it was stored in is rewritten in place, and otherwise is moved to a new extent
+    fn write_stream_content(
+        &mut self,
+        obj: &PhantomFSObject,
+        idx: u64,
+        bytes: &[u8],
+    ) -> std::io::Result<()> {
+        let mut listing = self.read_stream_listing(obj, idx)?;
+        // The extent is only rewritten in place if it lies within the image
+        self.check_stream_extent(&listing, "Stream")?;
+        let size = bytes.len() as u64;
+
+        if size <= STREAM_INLINE_MAX {
+            listing.inline_data = [0; 48];
+            listing.inline_data[..bytes.len()].copy_from_slice(bytes);
+        } else {
+            let pos = match listing.data_ref() {
+                Some(data_ref) if data_ref.indirection == 1 && size <= listing.size => {
+                    data_ref.data_ref
+                }
+                _ => self.allocate(size)?,
+            };
+            self.write_at(pos, bytes)?;
+            listing.inline_data = bytemuck::cast(StreamDataRef::new(pos));
+        }
+        listing.size = size;
+
+        // idx is a valid stream, so the listing lies within the `Streams` stream
+        let pos = obj.streams_ref + u128::from(idx * size_of_u64::<StreamListing>());
+        self.write_at(pos, bytemuck::bytes_of(&listing))
+    }
+
+    /// Writes `listings` as the `Streams` stream of `obj`, which is at `objidx` in the object table, and updates its entry.
+    ///
+    /// `listings[0]` is filled in with the listing of the `Streams` stream itself
+    fn write_streams(
+        &mut self,
+        objidx: u64,
+        obj: &mut PhantomFSObject,
+        listings: &mut [StreamListing],
+    ) -> std::io::Result<()> {
+        let size = listings.len() as u64 * size_of_u64::<StreamListing>();
+        let pos = if obj.streams_indirection == 1 && size <= obj.streams_size {
+            obj.streams_ref
         } else {
-            desc.rootidx
+            self.allocate(size)?
         };
 
-        if obj > (desc.objtabsize / u64::try_from(core::mem::size_of::<PhantomFSObject>()).unwrap())
-        {
-            return Err(std::io::Error::NotFound);
+        listings[0] = StreamListing {
+            size,
+            inline_data: bytemuck::cast(StreamDataRef::new(pos)),
+            ..StreamListing::named(consts::STREAM_STREAMS)
+        };
+        self.write_at(pos, bytemuck::cast_slice(listings))?;
+
+        obj.streams_ref = pos;
+        obj.streams_size = size;
+        obj.streams_indirection = 1;
+        self.write_object(objidx, obj)
+    }
+
+    /// Adds an empty stream named `name` to `obj`, which is at `objidx` in the object table, and returns its index
+    fn add_stream(
+        &mut self,
+        objidx: u64,
+        obj: &mut PhantomFSObject,
+        name: &[u8],
+    ) -> std::io::Result<u64> {
+        if name.is_empty() || name.len() > 32 || name.contains(&0) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Stream names must be between 1 and 32 bytes",
+            ));
+        }
+
+        let count = self.stream_count(obj)?;
+        let mut listings = Vec::new();
+        if count == 0 {
+            // Placeholder for the listing of the `Streams` stream
+            listings.push(StreamListing::zeroed());
+        }
+        for idx in 0..count {
+            listings.push(self.read_stream_listing(obj, idx)?);
         }
+        listings.push(StreamListing::named(name));
 
-        let objtab = desc.objtab;
+        self.write_streams(objidx, obj, &mut listings)?;
+        Ok(listings.len() as u64 - 1)
+    }
 
-        #[allow(clippy::drop_ref)]
-        {
-            drop(desc); // ensure we don't use it again
+    /// Appends `name` to the `Strings` stream of `obj`, which is at `objidx` in the object table, creating the stream if needed.
+    ///
+    /// Returns the offset of `name` in the stream
+    fn add_string(
+        &mut self,
+        objidx: u64,
+        obj: &mut PhantomFSObject,
+        name: &[u8],
+    ) -> std::io::Result<NonZeroU64> {
+        let idx = match self.find_inline_stream(obj, trim_nul(consts::STREAM_STRINGS))? {
+            Some((idx, _)) => idx,
+            None => self.add_stream(objidx, obj, trim_nul(consts::STREAM_STRINGS))?,
+        };
+        let listing = self.read_stream_listing(obj, idx)?;
+        let mut content = self.read_stream_content(&listing)?;
+
+        // An offset of 0 means a name is inline, so no string can start there
+        if content.is_empty() {
+            content.push(0);
         }
+        let offset = content.len() as u64;
+        content.extend_from_slice(name);
+        content.push(0);
 
-        self.stream.seek(SeekFrom::StartFar(objtab))?;
-        let objoffset: u64 = obj * u64::try_from(core::mem::size_of::<PhantomFSObject>()).unwrap();
+        self.write_stream_content(obj, idx, &content)?;
+        Ok(NonZeroU64::new(offset).unwrap())
+    }
 
-        let pos = -(objoffset as i64);
-        self.stream.seek(SeekFrom::Current(pos))?;
+    /// Returns a free entry of the object table, growing the table if there is none
+    fn free_object_index(&mut self) -> std::io::Result<u64> {
+        let (_, objtab_size) = self.object_table_extent()?;
+        let count = objtab_size / size_of_u64::<PhantomFSObject>();
+        let rootidx = self.get_or_read_descriptor()?.rootidx;
+
+        for idx in (1..=count).filter(|&idx| idx != rootidx) {
+            if self.read_object(ObjectId(NonZeroU64::new(idx)))? == PhantomFSObject::zeroed() {
+                return Ok(idx);
+            }
+        }
 
-        let mut obj: PhantomFSObject = Zeroable::zeroed();
+        self.grow_object_table()?;
+        Ok(count + 1)
+    }
 
-        self.stream.read_exact(bytemuck::bytes_of_mut(&mut obj))?;
+    /// Moves the object table to a new extent with room for twice as many objects
+    fn grow_object_table(&mut self) -> std::io::Result<()> {
+        let (_, objtab_size) = self.object_table_extent()?;
+        let count = objtab_size / size_of_u64::<PhantomFSObject>();
+        let new_count = (count * 2).max(OBJTAB_MIN_COUNT);
+        let new_size = new_count * size_of_u64::<PhantomFSObject>();
 
-        let streamdisp = obj.streams_ref;
+        let mut table = alloc::vec![PhantomFSObject::zeroed(); new_count as usize];
+        // The table grows downwards, so object `idx` is `idx` entries from its end
+        for idx in 1..=count {
+            table[(new_count - idx) as usize] = self.read_object(ObjectId(NonZeroU64::new(idx)))?;
+        }
 
-        let indirect = obj.streams_indirection;
+        let start = self.allocate(new_size)?;
+        self.write_at(start, bytemuck::cast_slice(&table))?;
 
-        let streamslen = obj.streams_size;
+        let desc = self.get_or_read_descriptor()?;
+        let old = *desc;
+        desc.objtab = start + u128::from(new_size);
+        desc.objtabsize = new_size;
+        // The image still has the old table if the descriptor couldn't be written, so keep using it
+        let res = self.commit_descriptor();
+        if res.is_err() {
+            self.descriptor = Some(old);
+        }
+        res
+    }
 
-        let streampos = node.1 .0.ok_or(std::io::Error::NotFound)?.get();
+    /// Creates an object of type `ty` with an empty stream named `stream`, and returns its index in the object table.
+    ///
+    /// The object has no references until it is linked into a directory
+    fn create_object(&mut self, ty: PhantomFSObjectType, stream: &[u8]) -> std::io::Result<u64> {
+        let idx = self.free_object_index()?;
+        let mut obj = PhantomFSObject {
+            ty,
+            ..PhantomFSObject::zeroed()
+        };
+        let mut listings = [StreamListing::zeroed(), StreamListing::named(stream)];
+        self.write_streams(idx, &mut obj, &mut listings)?;
+        Ok(idx)
+    }
 
-        if streampos >= (streamslen / u64::try_from(core::mem::size_of::<StreamListing>()).unwrap())
+    /// Checks that `name` is a valid name for a new entry of `dir`, and that `dir` has no entry by that name yet
+    fn check_new_entry(&mut self, dir: ObjectId, name: &str) -> std::io::Result<()> {
+        if name.is_empty()
+            || name.len() > NAME_MAX
+            || name == "."
+            || name == ".."
+            || name.contains(['/', '\0'])
         {
-            return Err(std::io::Error::NotFound);
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                alloc::format!("Invalid name {:?}", name),
+            ));
         }
 
-        if indirect == 0 {
-            return Err(std::io::Error::NotFound);
+        match self.get_object_from(InodeId(dir, StreamId(None)), StringView::new(name)) {
+            Ok(_) => Err(io::Error::from(io::ErrorKind::AlreadyExists)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
         }
+    }
 
-        if indirect == 1 {
-            self.stream.seek(SeekFrom::StartFar(streamdisp))?;
-            let disp =
-                (streampos * u64::try_from(core::mem::size_of::<StreamListing>()).unwrap()) as i64;
-            self.stream.seek(SeekFrom::Current(disp))?;
-            let mut stream = StreamListing::zeroed();
-            self.stream
-                .read_exact(bytemuck::bytes_of_mut(&mut stream))?;
+    /// Adds an entry named `name` to `dir` for the object at `target` in the object table, and takes a strong reference to it.
+    ///
+    /// The reference is taken before the entry is written, so a failure never leaves an entry to an object that doesn't count it
+    fn link(&mut self, dir: ObjectId, name: &str, target: u64) -> std::io::Result<()> {
+        let target_id = ObjectId(NonZeroU64::new(target));
+        let mut obj = self.read_object(target_id)?;
+        obj.strong_ref = obj
+            .strong_ref
+            .checked_add(1)
+            .ok_or_else(|| io::Error::from(io::ErrorKind::TooManyLinks))?;
+        self.write_object(target, &obj)?;
+
+        let diridx = self.object_index(dir)?;
+        let mut dirobj = self.read_object(dir)?;
+
+        let mut elem = DirectoryElement {
+            objidx: NonZeroU64::new(target),
+            ..DirectoryElement::zeroed()
+        };
+        if name.len() <= elem.name.len() {
+            elem.name[..name.len()].copy_from_slice(name.as_bytes());
         } else {
+            elem.name_index = Some(self.add_string(diridx, &mut dirobj, name.as_bytes())?);
+        }
+
+        let content_name = trim_nul(consts::STREAM_DIRECTORY_CONTENT);
+        let idx = match self.find_inline_stream(&dirobj, content_name)? {
+            Some((idx, _)) => idx,
+            None => self.add_stream(diridx, &mut dirobj, content_name)?,
+        };
+        let listing = self.read_stream_listing(&dirobj, idx)?;
+        let mut content = self.read_stream_content(&listing)?;
+
+        let elem_size = core::mem::size_of::<DirectoryElement>();
+        content.truncate(content.len() / elem_size * elem_size);
+        let slot = content
+            .chunks_exact(elem_size)
+            .position(|elem| elem[..8] == [0; 8])
+            .unwrap_or(content.len() / elem_size);
+        if slot == content.len() / elem_size {
+            content.resize(content.len() + elem_size, 0);
         }
+        content[slot * elem_size..][..elem_size].copy_from_slice(bytemuck::bytes_of(&elem));
+        self.write_stream_content(&dirobj, idx, &content)
+    }
 
-        Err(std::io::Error::Interrupted)
+    /// Creates an object of type `ty` with an empty stream named `stream`, and links it into `dir` as `name`
+    fn create_entry(
+        &mut self,
+        dir: ObjectId,
+        name: StringView,
+        ty: PhantomFSObjectType,
+        stream: &[u8],
+    ) -> std::io::Result<ObjectId> {
+        self.check_new_entry(dir, &name)?;
+        let idx = self.create_object(ty, trim_nul(stream))?;
+        if let Err(e) = self.link(dir, &name, idx) {
+            // Free the object again, rather than leave it allocated with no entry referring to it
+            let _ = self.write_object(idx, &PhantomFSObject::zeroed());
+            return Err(e);
+        }
+        Ok(ObjectId(NonZeroU64::new(idx)))
     }
 }
 
-impl<S: Write + Seek> PhantomFS<S> {
-    pub fn write_descriptor(&mut self) -> std::io::Result<()> {
-        if let Some(desc) = &self.descriptor {
-            self.stream.seek(SeekFrom::Start(1024))?;
-            self.stream.write_all(bytemuck::bytes_of(desc))?;
+impl<S: Read + Write + Seek> WriteFS for PhantomFS<S> {
+    fn write_stream(&mut self, pos: InodeId, bytes: &[u8]) -> std::io::Result<()> {
+        let obj = self.read_object(pos.0)?;
+        let idx = pos
+            .1
+             .0
+            .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?
+            .get();
+        self.write_stream_content(&obj, idx, bytes)
+    }
+
+    fn create_stream(&mut self, obj: ObjectId, name: StringView) -> std::io::Result<StreamId> {
+        let objidx = self.object_index(obj)?;
+        let mut entry = self.read_object(obj)?;
+        if self.find_stream(&entry, &name)?.is_some() {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+        let idx = self.add_stream(objidx, &mut entry, name.as_bytes())?;
+        Ok(StreamId(NonZeroU64::new(idx)))
+    }
+}
+
+impl<S: Read + Write + Seek> WriteDir for PhantomFS<S> {
+    fn create_file(&mut self, dir: ObjectId, name: StringView) -> std::io::Result<ObjectId> {
+        self.create_entry(
+            dir,
+            name,
+            PhantomFSObjectType::Regular,
+            consts::STREAM_FILE_DATA,
+        )
+    }
+
+    fn create_dir(&mut self, dir: ObjectId, name: StringView) -> std::io::Result<ObjectId> {
+        self.create_entry(
+            dir,
+            name,
+            PhantomFSObjectType::Directory,
+            consts::STREAM_DIRECTORY_CONTENT,
+        )
+    }
+}
+
+/// Streams no larger than this are stored directly in the `inline_data` of their [`StreamListing`]
+pub const STREAM_INLINE_MAX: u64 = 48;
+
+/// The longest name that will be read from a `Strings` stream
+pub const NAME_MAX: usize = 4096;
+
+/// Extents are allocated on this boundary
+const EXTENT_ALIGN: u128 = 128;
+
+/// The number of entries an object table is grown to when it is first grown
+const OBJTAB_MIN_COUNT: u64 = 16;
+
+fn size_of_u64<T>() -> u64 {
+    core::mem::size_of::<T>() as u64
+}
+
+fn trim_nul(name: &[u8]) -> &[u8] {
+    name.strip_suffix(b"\0").unwrap_or(name)
+}
+
+fn trim_nul_str(name: &[u8]) -> &str {
+    core::str::from_utf8(trim_nul(name)).unwrap()
+}
+
+fn inline_name(name: &[u8]) -> &[u8] {
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    &name[..len]
+}
+
+#[cfg(all(test, feature = "std"))]
+mod test {
+    use alloc::collections::BTreeMap;
+    use alloc::vec::Vec;
+    use core::num::NonZeroU64;
+    use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
+    use std::str::StringView;
+
+    use bytemuck::Zeroable;
+
+    use super::{size_of_u64, PhantomFS, PhantomFSObject, PhantomFSObjectType};
+    use crate::traits::{
+        InodeId, ObjectId, ReadDir, ReadFS, Search, StreamId, WriteDir, WriteFS, OBJECT_NULL,
+    };
+
+    /// A stream that fails the write at a chosen index, and performs all the others
+    struct FailingStream {
+        inner: Cursor<Vec<u8>>,
+        writes: usize,
+        fail_at: usize,
+    }
+
+    impl Read for FailingStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+    }
+
+    impl Write for FailingStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.writes += 1;
+            if self.writes - 1 == self.fail_at {
+                return Err(io::Error::new(io::ErrorKind::Other, "injected failure"));
+            }
+            self.inner.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Seek for FailingStream {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u128> {
+            self.inner.seek(pos)
+        }
+    }
+
+    /// Formats an empty filesystem, with the root directory as the only entry of its object table
+    fn format() -> PhantomFS<Cursor<Vec<u8>>> {
+        let mut fs = PhantomFS::new(Cursor::new(alloc::vec![0; 4096]));
+        fs.create_new_fs(0x1234);
+        let desc = fs.descriptor.as_mut().unwrap();
+        desc.objtab = 4096;
+        desc.objtabsize = size_of_u64::<PhantomFSObject>();
+        desc.rootidx = 1;
+        fs.image_len = 4096;
+        fs.commit_descriptor().unwrap();
+
+        let root = PhantomFSObject {
+            strong_ref: 1,
+            ty: PhantomFSObjectType::Directory,
+            ..Zeroable::zeroed()
+        };
+        fs.write_object(1, &root).unwrap();
+        fs
+    }
+
+    fn mount<S: Read + Seek>(stream: S) -> PhantomFS<S> {
+        let mut fs = PhantomFS::new(stream);
+        fs.read_descriptor().unwrap();
+        fs
+    }
+
+    fn lookup<S: Read + Seek>(fs: &mut PhantomFS<S>, path: &str) -> io::Result<ObjectId> {
+        path.split('/').try_fold(OBJECT_NULL, |dir, name| {
+            fs.get_object_from(InodeId(dir, StreamId(None)), StringView::new(name))
+        })
+    }
+
+    fn file_data<S: Read + Seek>(fs: &mut PhantomFS<S>, obj: ObjectId) -> InodeId {
+        let stream = fs
+            .get_stream_of_object(obj, StringView::new("FileData"))
+            .unwrap();
+        InodeId(obj, stream)
+    }
+
+    fn create_file<S: Read + Write + Seek>(
+        fs: &mut PhantomFS<S>,
+        dir: ObjectId,
+        name: &str,
+        content: &[u8],
+    ) -> io::Result<ObjectId> {
+        let obj = fs.create_file(dir, StringView::new(name))?;
+        let node = file_data(fs, obj);
+        fs.write_stream(node, content)?;
+        Ok(obj)
+    }
+
+    fn read_file<S: Read + Seek>(fs: &mut PhantomFS<S>, path: &str) -> Vec<u8> {
+        let obj = lookup(fs, path).unwrap();
+        let node = file_data(fs, obj);
+        let mut content = alloc::vec![0; fs.stream_len(node).unwrap() as usize];
+        fs.read_exact_from(node, 0, &mut content).unwrap();
+        content
+    }
+
+    /// Checks the structure of the filesystem: no two extents in use overlap, every object but the root has as many strong references as
+    ///  directory entries, and every entry of the object table is either referenced or free
+    fn check<S: Read + Write + Seek>(fs: &mut PhantomFS<S>) {
+        let extents = fs.used_extents().unwrap();
+        for pair in extents.windows(2) {
+            assert!(
+                pair[0].1 <= pair[1].0,
+                "{:x?} overlaps {:x?}",
+                pair[0],
+                pair[1]
+            );
+        }
+
+        let rootidx = fs.object_index(OBJECT_NULL).unwrap();
+        let mut links = BTreeMap::<u64, u32>::new();
+        let mut dirs = alloc::vec![OBJECT_NULL];
+        while let Some(dir) = dirs.pop() {
+            let mut idx = 0;
+            while let Some((found, entry)) = fs.read_dir_entry(dir, idx).unwrap() {
+                let objidx = entry.obj.0.unwrap().get();
+                *links.entry(objidx).or_default() += 1;
+                if fs.read_object(entry.obj).unwrap().ty() == PhantomFSObjectType::Directory {
+                    dirs.push(entry.obj);
+                }
+                idx = found + 1;
+            }
+        }
+
+        let count = fs.descriptor.unwrap().objtabsize / size_of_u64::<PhantomFSObject>();
+        for idx in (1..=count).filter(|&idx| idx != rootidx) {
+            let obj = fs.read_object(ObjectId(NonZeroU64::new(idx))).unwrap();
+            match links.get(&idx) {
+                Some(&n) => assert_eq!(obj.strong_ref(), n, "object {}", idx),
+                None => assert_eq!(obj, PhantomFSObject::zeroed(), "object {}", idx),
+            }
+        }
+    }
+
+    const LONG_NAME: &str = "a name that is too long to be stored inline in its directory entry";
+
+    #[test]
+    fn round_trip() {
+        let mut fs = format();
+        let dir = fs.create_dir(OBJECT_NULL, StringView::new("dir")).unwrap();
+        create_file(&mut fs, dir, "small", b"hello").unwrap();
+        let big = (0..1000).map(|i| i as u8).collect::<Vec<_>>();
+        create_file(&mut fs, OBJECT_NULL, LONG_NAME, &big).unwrap();
+        check(&mut fs);
+
+        let mut fs = mount(fs.into_inner());
+        assert_eq!(read_file(&mut fs, "dir/small"), b"hello");
+        assert_eq!(read_file(&mut fs, LONG_NAME), big);
+        assert_eq!(
+            lookup(&mut fs, "dir/missing").unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        check(&mut fs);
+    }
+
+    #[test]
+    fn grows_object_table() {
+        let mut fs = format();
+        for i in 0..40 {
+            let content = alloc::vec![i as u8; i * 10];
+            create_file(&mut fs, OBJECT_NULL, &alloc::format!("file{}", i), &content).unwrap();
+        }
+        assert_eq!(fs.descriptor.unwrap().objtabsize, 64 * 64);
+        check(&mut fs);
+
+        let mut fs = mount(fs.into_inner());
+        for i in 0..40 {
+            assert_eq!(
+                read_file(&mut fs, &alloc::format!("file{}", i)),
+                alloc::vec![i as u8; i * 10]
+            );
+        }
+        check(&mut fs);
+    }
+
+    #[test]
+    fn rewrites_reuse_and_free_extents() {
+        let mut fs = format();
+        let obj = create_file(&mut fs, OBJECT_NULL, "file", &[1; 1000]).unwrap();
+        let node = file_data(&mut fs, obj);
+        let extent = |fs: &mut PhantomFS<_>| {
+            let obj = fs.read_object(obj).unwrap();
+            let listing = fs
+                .read_stream_listing(&obj, node.1 .0.unwrap().get())
+                .unwrap();
+            listing.data_ref().map(|data_ref| data_ref.data_ref)
+        };
+        let first = extent(&mut fs).unwrap();
+
+        // Shrinking rewrites the content in place
+        fs.write_stream(node, &[2; 500]).unwrap();
+        assert_eq!(extent(&mut fs), Some(first));
+
+        // Growing moves it, and the old extent is free again
+        fs.write_stream(node, &[3; 2000]).unwrap();
+        let moved = extent(&mut fs).unwrap();
+        assert_ne!(moved, first);
+        assert!(!fs
+            .used_extents()
+            .unwrap()
+            .iter()
+            .any(|&(start, _)| start == first));
+
+        // Small enough content is stored inline, and frees its extent
+        fs.write_stream(node, b"inline").unwrap();
+        assert_eq!(extent(&mut fs), None);
+        assert!(!fs
+            .used_extents()
+            .unwrap()
+            .iter()
+            .any(|&(start, _)| start == moved));
+
+        let other = create_file(&mut fs, OBJECT_NULL, "other", &[4; 900]).unwrap();
+        check(&mut fs);
+
+        let mut fs = mount(fs.into_inner());
+        assert_eq!(read_file(&mut fs, "file"), b"inline");
+        assert_eq!(read_file(&mut fs, "other"), [4; 900]);
+        assert_eq!(lookup(&mut fs, "other").unwrap(), other);
+        check(&mut fs);
+    }
+
+    #[test]
+    fn rejects_bad_names_without_writing() {
+        let mut fs = format();
+        create_file(&mut fs, OBJECT_NULL, "file", b"content").unwrap();
+        let image = fs.stream.get_ref().clone();
+
+        for name in ["", ".", "..", "a/b", "nul\0", "file"] {
+            let err = fs
+                .create_dir(OBJECT_NULL, StringView::new(name))
+                .unwrap_err();
+            let expected = if name == "file" {
+                io::ErrorKind::AlreadyExists
+            } else {
+                io::ErrorKind::InvalidInput
+            };
+            assert_eq!(err.kind(), expected, "{:?}", name);
+        }
+        assert!(*fs.stream.get_ref() == image);
+
+        let file = lookup(&mut fs, "file").unwrap();
+        assert_eq!(
+            fs.create_file(file, StringView::new("x"))
+                .unwrap_err()
+                .kind(),
+            io::ErrorKind::NotADirectory
+        );
+    }
+
+    #[test]
+    fn failed_writes_leave_image_consistent() {
+        let mut base = format();
+        let dir = base
+            .create_dir(OBJECT_NULL, StringView::new("dir"))
+            .unwrap();
+        create_file(&mut base, dir, "existing", &[1; 300]).unwrap();
+        let base = base.into_inner().into_inner();
+
+        // Fail each write of the operations in turn, until they complete without reaching the failure
+        for fail_at in 0.. {
+            let stream = FailingStream {
+                inner: Cursor::new(base.clone()),
+                writes: 0,
+                fail_at,
+            };
+            let mut fs = mount(stream);
+            let result = (|| {
+                let dir = lookup(&mut fs, "dir")?;
+                create_file(&mut fs, dir, LONG_NAME, &[2; 200])?;
+                fs.create_dir(dir, StringView::new("sub"))?;
+                for i in 0..16 {
+                    fs.create_file(OBJECT_NULL, StringView::new(&alloc::format!("f{}", i)))?;
+                }
+                Ok::<_, io::Error>(())
+            })();
+
+            let mut fs = mount(fs.into_inner().inner);
+            check(&mut fs);
+            assert_eq!(read_file(&mut fs, "dir/existing"), [1; 300]);
+            if result.is_ok() {
+                assert!(fail_at > 0);
+                assert_eq!(
+                    read_file(&mut fs, &alloc::format!("dir/{}", LONG_NAME)),
+                    [2; 200]
+                );
+                break;
+            }
         }
-        Ok(())
     }
 }
@@ -1,3 +1,4 @@
+use alloc::string::String;
 use core::num::NonZeroU64;
 use std::str::StringView;
 
@@ -28,6 +29,21 @@ pub trait Search {
     ) -> std::io::Result<StreamId>;
 }
 
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct DirEntry {
+    pub obj: ObjectId,
+    pub name: String,
+}
+
+pub trait ReadDir {
+    /// Returns the first entry of `dir` at or after `idx`, together with its index, or `None` if there are no more entries.
+    fn read_dir_entry(
+        &mut self,
+        dir: ObjectId,
+        idx: u64,
+    ) -> std::io::Result<Option<(u64, DirEntry)>>;
+}
+
 pub trait ReadFS {
     fn read_bytes_from(
         &mut self,
@@ -36,6 +52,8 @@ pub trait ReadFS {
         bytes: &mut [u8],
     ) -> std::io::Result<usize>;
 
+    fn stream_len(&mut self, pos: InodeId) -> std::io::Result<u64>;
+
     fn read_exact_from(
         &mut self,
         pos: InodeId,
@@ -44,15 +62,31 @@ pub trait ReadFS {
     ) -> std::io::Result<()> {
         while !bytes.is_empty() {
             match self.read_bytes_from(pos, offset, bytes) {
-                Ok(0) => return Err(std::io::Error::UnexpectedEof),
+                Ok(0) => return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof)),
                 Ok(cnt) => {
                     offset += u64::try_from(cnt).unwrap();
                     bytes = &mut bytes[cnt..];
                 }
-                Err(std::io::Error::Interrupted) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
                 Err(e) => return Err(e),
             }
         }
         Ok(())
     }
 }
+
+pub trait WriteFS {
+    /// Replaces the content of the stream `pos` with `bytes`
+    fn write_stream(&mut self, pos: InodeId, bytes: &[u8]) -> std::io::Result<()>;
+
+    /// Adds an empty stream named `name` to `obj`
+    fn create_stream(&mut self, obj: ObjectId, name: StringView) -> std::io::Result<StreamId>;
+}
+
+pub trait WriteDir {
+    /// Creates an empty regular file named `name` in `dir`
+    fn create_file(&mut self, dir: ObjectId, name: StringView) -> std::io::Result<ObjectId>;
+
+    /// Creates an empty directory named `name` in `dir`
+    fn create_dir(&mut self, dir: ObjectId, name: StringView) -> std::io::Result<ObjectId>;
+}
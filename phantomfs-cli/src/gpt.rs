@@ -0,0 +1,252 @@
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::str::FromStr;
+
+const SECTOR_SIZE: u64 = 512;
+
+/// A GUID, in the mixed-endian layout used by GPT
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct Guid([u8; 16]);
+
+#[derive(Debug)]
+pub struct GuidFromStrError;
+
+impl fmt::Display for GuidFromStrError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Invalid GUID, expected the form xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx")
+    }
+}
+
+impl FromStr for Guid {
+    type Err = GuidFromStrError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let groups: Vec<&str> = s.split('-').collect();
+        if groups.len() != 5
+            || groups.iter().map(|g| g.len()).ne([8, 4, 4, 4, 12])
+            || !s.chars().all(|c| c == '-' || c.is_ascii_hexdigit())
+        {
+            return Err(GuidFromStrError);
+        }
+
+        let hex: String = groups.concat();
+        let mut bytes = [0u8; 16];
+        for (i, b) in bytes.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&hex[2 * i..][..2], 16).map_err(|_| GuidFromStrError)?;
+        }
+
+        // The first three groups are stored little-endian
+        bytes[0..4].reverse();
+        bytes[4..6].reverse();
+        bytes[6..8].reverse();
+
+        Ok(Self(bytes))
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:02x}{:02x}{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}-{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+            b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6], b[8], b[9], b[10], b[11], b[12], b[13], b[14], b[15]
+        )
+    }
+}
+
+/// Finds the partition with the unique GUID `guid` in the GPT of `disk`, and returns its byte offset and length
+pub fn find_partition<D: Read + Seek>(disk: &mut D, guid: Guid) -> io::Result<(u64, u64)> {
+    let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, msg.to_string());
+
+    let mut header = [0u8; 92];
+    disk.seek(SeekFrom::Start(SECTOR_SIZE))?;
+    disk.read_exact(&mut header)?;
+
+    if &header[0..8] != b"EFI PART" {
+        return Err(invalid("No GPT header found"));
+    }
+
+    let entries_lba = u64::from_le_bytes(header[72..80].try_into().unwrap());
+    let entry_count = u32::from_le_bytes(header[80..84].try_into().unwrap());
+    let entry_size = u32::from_le_bytes(header[84..88].try_into().unwrap());
+
+    // Entries are at least 128 bytes, and bounding them by a sector keeps the disk from choosing the size of the allocation below
+    if !(128..=SECTOR_SIZE as u32).contains(&entry_size) {
+        return Err(invalid("Invalid GPT partition entry size"));
+    }
+
+    let table = entries_lba
+        .checked_mul(SECTOR_SIZE)
+        .ok_or_else(|| invalid("Invalid GPT partition table location"))?;
+
+    let mut entry = vec![0u8; entry_size as usize];
+    for i in 0..u64::from(entry_count) {
+        let offset = i
+            .checked_mul(u64::from(entry_size))
+            .and_then(|off| off.checked_add(table))
+            .ok_or_else(|| invalid("Invalid GPT partition table location"))?;
+        disk.seek(SeekFrom::Start(offset))?;
+        disk.read_exact(&mut entry)?;
+
+        // Bytes 0..16 are the partition type GUID, and 16..32 the unique partition GUID
+        if entry[16..32] == guid.0 {
+            let first = u64::from_le_bytes(entry[32..40].try_into().unwrap());
+            let last = u64::from_le_bytes(entry[40..48].try_into().unwrap());
+            let bounds = (last >= first)
+                .then(|| {
+                    let start = first.checked_mul(SECTOR_SIZE)?;
+                    let len = (last - first).checked_add(1)?.checked_mul(SECTOR_SIZE)?;
+                    // `Partition` addresses the disk at `start + pos` for any `pos <= len`
+                    start.checked_add(len)?;
+                    Some((start, len))
+                })
+                .flatten();
+            return bounds.ok_or_else(|| invalid("Invalid GPT partition bounds"));
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("No partition with GUID {}", guid),
+    ))
+}
+
+/// A window onto `len` bytes of a stream, beginning at `start`
+pub struct Partition<D> {
+    disk: D,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl<D: Seek> Partition<D> {
+    pub fn new(disk: D, start: u64, len: u64) -> Self {
+        Self {
+            disk,
+            start,
+            len,
+            pos: 0,
+        }
+    }
+}
+
+impl<D: Read + Seek> Read for Partition<D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.len.saturating_sub(self.pos);
+        let len = buf
+            .len()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        self.disk.seek(SeekFrom::Start(self.start + self.pos))?;
+        let n = self.disk.read(&mut buf[..len])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<D: Write + Seek> Write for Partition<D> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // The partition can't grow, so writes past its end are cut short
+        let remaining = self.len.saturating_sub(self.pos);
+        let len = buf
+            .len()
+            .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+        self.disk.seek(SeekFrom::Start(self.start + self.pos))?;
+        let n = self.disk.write(&buf[..len])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.disk.flush()
+    }
+}
+
+impl<D: Seek> Seek for Partition<D> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let npos = match pos {
+            SeekFrom::Start(off) => Some(off),
+            SeekFrom::End(disp) => self.len.checked_add_signed(disp),
+            SeekFrom::Current(disp) => self.pos.checked_add_signed(disp),
+        };
+
+        match npos {
+            Some(npos) if npos <= self.len => {
+                self.pos = npos;
+                Ok(npos)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek outside of partition",
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Cursor, ErrorKind};
+
+    use super::{find_partition, Guid, SECTOR_SIZE};
+
+    const GUID: Guid = Guid([0x5a; 16]);
+
+    /// Builds a disk whose GPT has one partition entry, at `entries_lba`, spanning sectors `first..=last`
+    fn disk(entries_lba: u64, entry_size: u32, first: u64, last: u64) -> Cursor<Vec<u8>> {
+        let mut disk = vec![0u8; 4 * SECTOR_SIZE as usize];
+        let header = &mut disk[SECTOR_SIZE as usize..];
+        header[0..8].copy_from_slice(b"EFI PART");
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&1u32.to_le_bytes());
+        header[84..88].copy_from_slice(&entry_size.to_le_bytes());
+        let entry = &mut disk[2 * SECTOR_SIZE as usize..];
+        entry[16..32].copy_from_slice(&GUID.0);
+        entry[32..40].copy_from_slice(&first.to_le_bytes());
+        entry[40..48].copy_from_slice(&last.to_le_bytes());
+        Cursor::new(disk)
+    }
+
+    fn invalid_data(res: std::io::Result<(u64, u64)>) -> bool {
+        matches!(res, Err(e) if e.kind() == ErrorKind::InvalidData)
+    }
+
+    #[test]
+    fn finds_partition() {
+        assert_eq!(
+            find_partition(&mut disk(2, 128, 34, 99), GUID).unwrap(),
+            (34 * SECTOR_SIZE, 66 * SECTOR_SIZE)
+        );
+        let other = Guid([0xa5; 16]);
+        let err = find_partition(&mut disk(2, 128, 34, 99), other).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn rejects_entry_sizes() {
+        assert!(invalid_data(find_partition(&mut disk(2, 64, 34, 99), GUID)));
+        assert!(invalid_data(find_partition(
+            &mut disk(2, u32::MAX, 34, 99),
+            GUID
+        )));
+    }
+
+    #[test]
+    fn rejects_overflowing_bounds() {
+        assert!(invalid_data(find_partition(
+            &mut disk(2, 128, 99, 34),
+            GUID
+        )));
+        assert!(invalid_data(find_partition(
+            &mut disk(2, 128, 0, u64::MAX),
+            GUID
+        )));
+        assert!(invalid_data(find_partition(
+            &mut disk(2, 128, u64::MAX / SECTOR_SIZE, u64::MAX / SECTOR_SIZE),
+            GUID
+        )));
+        assert!(invalid_data(find_partition(
+            &mut disk(u64::MAX, 128, 34, 99),
+            GUID
+        )));
+    }
+}
@@ -0,0 +1,290 @@
+//! Host-side tool for inspecting and modifying PhantomFS images
+
+mod gpt;
+
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, Write};
+use std::process::ExitCode;
+
+use kstd::hosted::FromStd;
+use kstd::str::StringView;
+use phantom_filesystem_drivers::phantomfs::{consts, PhantomFS, PhantomFSObjectType};
+use phantom_filesystem_drivers::traits::{
+    InodeId, ObjectId, ReadDir, ReadFS, Search, StreamId, WriteDir, WriteFS, OBJECT_NULL,
+};
+
+const USAGE: &str = "\
+usage: phantomfs <image> [--partition <guid>] <command> [args...]
+
+commands:
+    ls [path]                           list the entries of a directory
+    cat <path> [--stream <name>]        write the content of a stream to stdout
+    stat <path>                         show object metadata and streams
+    cp-out <path> <dest> [--stream <name>]
+                                        copy a stream out of the image into <dest>
+    cp-in <src> <path> [--stream <name>]
+                                        copy <src> into a stream of the file at <path>, creating either if needed
+    mkdir <path>                        create a directory in the image
+
+The default stream is FileData. Paths are relative to the root of the filesystem.";
+
+trait Image: Read + Write + Seek {}
+
+impl<T: Read + Write + Seek> Image for T {}
+
+type Fs = PhantomFS<FromStd<Box<dyn Image>>>;
+
+#[derive(Debug)]
+enum Error {
+    Usage(String),
+    Host(std::io::Error),
+    Fs(kstd::io::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Usage(msg) => write!(f, "{}\n\n{}", msg, USAGE),
+            Self::Host(e) => e.fmt(f),
+            Self::Fs(e) => e.fmt(f),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Self::Host(e)
+    }
+}
+
+impl From<kstd::io::Error> for Error {
+    fn from(e: kstd::io::Error) -> Self {
+        Self::Fs(e)
+    }
+}
+
+fn usage(msg: &str) -> Error {
+    Error::Usage(msg.to_string())
+}
+
+fn open_image(path: &str, partition: Option<gpt::Guid>, write: bool) -> Result<Fs, Error> {
+    let mut file = OpenOptions::new().read(true).write(write).open(path)?;
+    let image: Box<dyn Image> = match partition {
+        Some(guid) => {
+            let (start, len) = gpt::find_partition(&mut file, guid)?;
+            Box::new(gpt::Partition::new(file, start, len))
+        }
+        None => Box::new(file),
+    };
+
+    let mut fs = PhantomFS::new(FromStd(image));
+    fs.read_descriptor()?;
+    Ok(fs)
+}
+
+fn resolve(fs: &mut Fs, path: &str) -> Result<ObjectId, Error> {
+    let mut obj = OBJECT_NULL;
+    for component in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+        obj = fs.get_object_from(InodeId(obj, StreamId(None)), StringView::new(component))?;
+    }
+    Ok(obj)
+}
+
+/// Splits `path` into the directory that contains it and its last component
+fn resolve_parent<'a>(fs: &mut Fs, path: &'a str) -> Result<(ObjectId, &'a str), Error> {
+    let path = path.trim_end_matches('/');
+    let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+    if name.is_empty() {
+        return Err(usage("the root directory already exists"));
+    }
+    Ok((resolve(fs, parent)?, name))
+}
+
+fn open_stream(fs: &mut Fs, path: &str, stream: &str) -> Result<InodeId, Error> {
+    let obj = resolve(fs, path)?;
+    let stream = fs.get_stream_of_object(obj, StringView::new(stream))?;
+    Ok(InodeId(obj, stream))
+}
+
+fn copy_stream<W: Write>(fs: &mut Fs, node: InodeId, out: &mut W) -> Result<(), Error> {
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut offset = 0;
+    loop {
+        let n = fs.read_bytes_from(node, offset, &mut buf)?;
+        if n == 0 {
+            break Ok(());
+        }
+        out.write_all(&buf[..n])?;
+        offset += n as u64;
+    }
+}
+
+fn cp_in(fs: &mut Fs, src: &str, path: &str, stream: &str) -> Result<(), Error> {
+    let data = std::fs::read(src)?;
+    let (dir, name) = resolve_parent(fs, path)?;
+
+    let obj = match fs.get_object_from(InodeId(dir, StreamId(None)), StringView::new(name)) {
+        Ok(obj) => obj,
+        Err(e) if e.kind() == kstd::io::ErrorKind::NotFound => {
+            fs.create_file(dir, StringView::new(name))?
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    let stream = match fs.get_stream_of_object(obj, StringView::new(stream)) {
+        Ok(stream) => stream,
+        Err(e) if e.kind() == kstd::io::ErrorKind::NotFound => {
+            fs.create_stream(obj, StringView::new(stream))?
+        }
+        Err(e) => return Err(e.into()),
+    };
+
+    fs.write_stream(InodeId(obj, stream), &data)?;
+    Ok(())
+}
+
+fn mkdir(fs: &mut Fs, path: &str) -> Result<(), Error> {
+    let (dir, name) = resolve_parent(fs, path)?;
+    fs.create_dir(dir, StringView::new(name))?;
+    Ok(())
+}
+
+fn type_name(ty: PhantomFSObjectType) -> String {
+    match ty {
+        PhantomFSObjectType::Regular => "regular".to_string(),
+        PhantomFSObjectType::Directory => "directory".to_string(),
+        PhantomFSObjectType::Symlink => "symlink".to_string(),
+        PhantomFSObjectType::Fifo => "fifo".to_string(),
+        PhantomFSObjectType::Socket => "socket".to_string(),
+        PhantomFSObjectType::BlockDeivce => "block device".to_string(),
+        PhantomFSObjectType::CharDevice => "char device".to_string(),
+        PhantomFSObjectType::CustomType => "custom".to_string(),
+        ty => format!("unknown ({:?})", ty),
+    }
+}
+
+fn ls(fs: &mut Fs, path: &str) -> Result<(), Error> {
+    let dir = resolve(fs, path)?;
+    let mut idx = 0;
+    while let Some((found, entry)) = fs.read_dir_entry(dir, idx)? {
+        let ty = fs.read_object(entry.obj)?.ty();
+        let suffix = if ty == PhantomFSObjectType::Directory {
+            "/"
+        } else {
+            ""
+        };
+        println!("{}{}", entry.name, suffix);
+        idx = found + 1;
+    }
+    Ok(())
+}
+
+fn stat(fs: &mut Fs, path: &str) -> Result<(), Error> {
+    let id = resolve(fs, path)?;
+    let obj = fs.read_object(id)?;
+
+    match id.0 {
+        Some(idx) => println!("object:      {}", idx),
+        None => println!("object:      root"),
+    }
+    println!("type:        {}", type_name(obj.ty()));
+    println!("strong refs: {}", obj.strong_ref());
+    println!(
+        "weak refs:   {}",
+        obj.weak_ref().map_or(0, std::num::NonZeroU32::get)
+    );
+    println!("flags:       {:?}", obj.flags());
+    println!("streams:");
+
+    for idx in 0..fs.stream_count(&obj)? {
+        let listing = fs.read_stream_listing(&obj, idx)?;
+        let name = fs.stream_name(&obj, &listing)?;
+        println!(
+            "    {:>3} {:<24} {:>12} bytes{} {:?}",
+            idx,
+            name,
+            listing.size(),
+            if listing.is_inline() { " (inline)" } else { "" },
+            listing.flags()
+        );
+    }
+    Ok(())
+}
+
+fn default_stream() -> String {
+    String::from_utf8_lossy(consts::STREAM_FILE_DATA)
+        .trim_end_matches('\0')
+        .to_string()
+}
+
+fn run(args: &[String]) -> Result<(), Error> {
+    let mut args = args.iter().map(String::as_str);
+    let image = args.next().ok_or_else(|| usage("missing image"))?;
+
+    let mut partition = None;
+    let mut stream = default_stream();
+    let mut positional = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg {
+            "--partition" => {
+                let guid = args
+                    .next()
+                    .ok_or_else(|| usage("--partition requires a GUID"))?;
+                partition = Some(
+                    guid.parse()
+                        .map_err(|e: gpt::GuidFromStrError| usage(&e.to_string()))?,
+                );
+            }
+            "--stream" => {
+                stream = args
+                    .next()
+                    .ok_or_else(|| usage("--stream requires a name"))?
+                    .to_string();
+            }
+            "-h" | "--help" => return Err(usage("")),
+            arg => positional.push(arg),
+        }
+    }
+
+    let (&command, operands) = positional
+        .split_first()
+        .ok_or_else(|| usage("missing command"))?;
+
+    match (command, operands) {
+        ("ls", []) => ls(&mut open_image(image, partition, false)?, "/"),
+        ("ls", [path]) => ls(&mut open_image(image, partition, false)?, path),
+        ("cat", [path]) => {
+            let mut fs = open_image(image, partition, false)?;
+            let node = open_stream(&mut fs, path, &stream)?;
+            copy_stream(&mut fs, node, &mut std::io::stdout().lock())
+        }
+        ("stat", [path]) => stat(&mut open_image(image, partition, false)?, path),
+        ("cp-out", [path, dest]) => {
+            let mut fs = open_image(image, partition, false)?;
+            let node = open_stream(&mut fs, path, &stream)?;
+            let mut out = File::create(dest)?;
+            copy_stream(&mut fs, node, &mut out)?;
+            Ok(out.flush()?)
+        }
+        ("cp-in", [src, path]) => {
+            cp_in(&mut open_image(image, partition, true)?, src, path, &stream)
+        }
+        ("mkdir", [path]) => mkdir(&mut open_image(image, partition, true)?, path),
+        ("ls" | "cat" | "stat" | "cp-out" | "cp-in" | "mkdir", _) => {
+            Err(usage(&format!("wrong number of arguments to {}", command)))
+        }
+        (command, _) => Err(usage(&format!("unknown command {}", command))),
+    }
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("phantomfs: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -16,7 +16,6 @@ pub mod allocator;
 pub mod devicetree;
 pub mod dynloader;
 pub mod elf;
-pub mod rawmem;
 pub mod util;
 pub mod writer;
 
@@ -28,9 +27,8 @@ extern crate paste;
 use acpi::RsdpDescriptor;
 use core::arch::global_asm;
 use core::fmt::Write;
-use core::mem::MaybeUninit;
 use elf::{Elf64Dyn, Elf64Rela};
-use std::sync::OnceCell;
+use std::sync::{IrqSpinLock, IrqSpinLockGuard, OnceCell};
 use stivale_boot::v2::{StivaleMemoryMapTag, StivaleStruct};
 use uuid::Uuid;
 use writer::TerminalWriter;
@@ -75,13 +73,11 @@ _start:
     "
 );
 
-static mut TERMINAL: MaybeUninit<TerminalWriter> = MaybeUninit::uninit();
+static TERMINAL: OnceCell<IrqSpinLock<TerminalWriter>> = OnceCell::new();
 static MEMORY_MAP: OnceCell<&StivaleMemoryMapTag> = OnceCell::new();
 
-fn term<'a>() -> &'a mut TerminalWriter<'static> {
-    // This is actually unsound wrt interrupts, but that's not a problem yet.
-    // TODO: do something clever
-    unsafe { TERMINAL.assume_init_mut() }
+fn term() -> IrqSpinLockGuard<'static, TerminalWriter<'static>> {
+    TERMINAL.get().unwrap_or_else(|| loop {}).lock()
 }
 
 mod gdt_setup {
@@ -221,8 +217,11 @@ mod gdt_setup {
 
 mod idt_setup {
     use alloc::boxed::Box;
-    use core::arch::asm;
+    use core::arch::{asm, global_asm};
+    use core::num::NonZeroUsize;
     use core::pin::Pin;
+    use core::ptr;
+    use std::sync::atomic::{AtomicPtr, Ordering};
 
     #[repr(C)]
     struct InterruptDescriptor {
@@ -243,14 +242,128 @@ mod idt_setup {
 
     use InterruptBehavior::*;
 
+    extern "C" {
+        fn page_fault_entry();
+    }
+
+    /// Returns the address to resume at after a page fault in kernel mode at the given instruction, if it has a fixup
+    type FixupHook = unsafe extern "C" fn(usize) -> Option<NonZeroUsize>;
+
+    /// The kernel's [`FixupHook`], or null until the kernel registers it with [`__register_fixup_exception`]
+    static FIXUP_HOOK: AtomicPtr<()> = AtomicPtr::new(ptr::null_mut());
+
+    /// Registers the hook that the page fault handler asks for the fixup of a faulting instruction.
+    ///
+    /// The kernel is loaded after init, so init can't import the kernel's exception table. Instead, the kernel calls this once it's loaded.
+    /// Until then, every page fault panics
+    #[no_mangle]
+    pub extern "C" fn __register_fixup_exception(hook: FixupHook) {
+        FIXUP_HOOK.store(hook as *mut (), Ordering::Release);
+    }
+
+    /// Asks the kernel's hook for the fixup of a fault at `ip`. There's none if no hook is registered
+    fn fixup_exception(ip: usize) -> Option<NonZeroUsize> {
+        let hook = FIXUP_HOOK.load(Ordering::Acquire);
+        if hook.is_null() {
+            return None;
+        }
+        // SAFETY: Only `__register_fixup_exception` stores to `FIXUP_HOOK`, and it stores a `FixupHook`
+        let hook = unsafe { core::mem::transmute::<*mut (), FixupHook>(hook) };
+        // SAFETY: The hook only reads the kernel's exception table
+        unsafe { hook(ip) }
+    }
+
+    /// The stack of [`page_fault_entry`] when it calls [`page_fault_handler`]
+    #[repr(C)]
+    struct PageFaultFrame {
+        // Saved by `page_fault_entry`, from r11 down to rax
+        saved: [u64; 9],
+        // Pushed by the processor
+        error_code: u64,
+        rip: u64,
+        cs: u64,
+        rflags: u64,
+        rsp: u64,
+        ss: u64,
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    global_asm!(
+        r"
+.hidden page_fault_handler
+
+.global page_fault_entry
+.hidden page_fault_entry
+page_fault_entry:
+    push rax
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push r8
+    push r9
+    push r10
+    push r11
+    mov rdi, rsp
+    // The processor aligned the stack before pushing its six words, so the nine saved registers leave it 8 bytes off
+    sub rsp, 8
+    cld
+    call page_fault_handler
+    add rsp, 8
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rax
+    // Discard the error code
+    add rsp, 8
+    iretq
+        "
+    );
+
+    #[no_mangle]
+    unsafe extern "C" fn page_fault_handler(frame: &mut PageFaultFrame) {
+        let addr: u64;
+        // SAFETY: reading CR2 has no side effects. It's read first, since a nested page fault would overwrite it
+        unsafe {
+            asm!("mov {}, cr2", out(reg) addr);
+        }
+
+        // A fault in kernel mode at an instruction that accesses user memory is a bad user pointer, which the instruction's fixup reports as an error
+        if frame.cs & 3 == 0 {
+            if let Some(fixup) = fixup_exception(frame.rip as usize) {
+                frame.rip = fixup.get() as u64;
+                return;
+            }
+        }
+
+        panic!(
+            "Page Fault at {:#x} accessing {:#x} (error code {:#x})",
+            frame.rip, addr, frame.error_code
+        );
+    }
+
+    macro_rules! launchpad {
+        ($launchpad:ident) => {{
+            #[allow(non_snake_case)]
+            fn $launchpad() {
+                ::core::todo!("This is a todo!() macro to prevent, uh, doing a bad");
+            }
+            $launchpad as *mut ()
+        }};
+        ($launchpad:ident, $handler:path) => {
+            $handler as *mut ()
+        };
+    }
+
     macro_rules! generate_idt {
-        [$(($name:ident, $type:expr)),*] => ([$({
+        [$(($name:ident, $type:expr $(, $handler:path)?)),*] => ([$({
             paste! {
-                #[allow(non_snake_case)]
-                fn [<launchpad_ $name>] () {
-                    ::core::todo!("This is a todo!() macro to prevent, uh, doing a bad");
-                }
-                let offset: *mut () = [<launchpad_ $name>] as *mut ();
+                let offset: *mut () = launchpad!([<launchpad_ $name>] $(, $handler)?);
                 $crate::idt_setup::InterruptDescriptor {
                     offset1: (offset as usize & 0xFFFF) as u16,
                     offset2: ((offset as usize >> 16) & 0xFFFF) as u16,
@@ -270,7 +383,7 @@ mod idt_setup {
             (DE, Panic("Divide Error")), (DB, Print("Debug Exception")), (NMI, Print("NMI")), (BP, Print("Breakpoint")),
             (OF, Panic("Overflow")), (BR, Panic("BOUND Range Exceeded")), (UD, Panic("Undefined Opcode")), (NM, Panic("No Math Coprocessor")),
             (DF, Panic("Double Fault")), (CSO, Panic("Coprocessor Segment Overrun")), (TS, Panic("Invalid TSS")), (NP, Panic("Segment Not Present")),
-            (SS, Panic("Stack Segment Fault")), (GP, Panic("General Protection")), (PF, Panic("Page Fault")), (RESERVED15, Print("???")),
+            (SS, Panic("Stack Segment Fault")), (GP, Panic("General Protection")), (PF, Panic("Page Fault"), page_fault_entry), (RESERVED15, Print("???")),
             (MF, Panic("Math Fault")), (AC, Panic("Alignment Check")), (MC, Panic("Machine Check")), (XM, Panic("SIMD Exception")),
             (VE, Panic("Virtualization Exception")), (CP, Panic("Control Protection Exception")), (RESERVED16, Print("???")), (RESERVED17, Print("???")),
             (RESERVED18, Panic("???")), (RESERVED19, Panic("???")), (RESERVED1A, Panic("???")), (RESERVED1B, Panic("???")),
@@ -328,9 +441,11 @@ unsafe fn register_idt() {
 #[cfg(target_arch = "x86_64")]
 unsafe extern "C" fn main(stivale_data: *const StivaleStruct) -> ! {
     let stivale_data = &*stivale_data;
-    TERMINAL.write(TerminalWriter::new(
-        stivale_data.terminal().unwrap_or_else(|| loop {}),
-    ));
+    TERMINAL
+        .set(IrqSpinLock::new(TerminalWriter::new(
+            stivale_data.terminal().unwrap_or_else(|| loop {}),
+        )))
+        .unwrap_or_else(|_| loop {});
     writeln!(
         term(),
         "Initializing PhantomOS {}...",
@@ -374,6 +489,15 @@ unsafe extern "C" fn main(stivale_data: *const StivaleStruct) -> ! {
     register_idt();
     writeln!(term(), "Setting up interrupts... done").unwrap();
 
+    #[cfg(feature = "page-fault-smoke-test")]
+    {
+        writeln!(term(), "Triggering a kernel page fault...").unwrap();
+        // SAFETY:
+        // Nothing is mapped this far into the lower half, and this isn't an instruction with a fixup, so the page fault handler panics instead of returning
+        unsafe { core::ptr::read_volatile(0x4000_0000_0000 as *const u8) };
+        writeln!(term(), "Triggering a kernel page fault... returned").unwrap();
+    }
+
     // SAFETY: we are the first people to call set.
     unsafe {
         MEMORY_MAP
@@ -440,6 +564,14 @@ unsafe extern "C" fn main(stivale_data: *const StivaleStruct) -> ! {
 
 #[panic_handler]
 fn handle_panic(info: &core::panic::PanicInfo) -> ! {
+    if let Some(terminal) = TERMINAL.get() {
+        if terminal.is_locked() {
+            // SAFETY:
+            // The panicking code may hold the lock, and it never resumes, so its guard is never used again.
+            // Nothing else runs concurrently this early in boot
+            unsafe { terminal.force_unlock() }
+        }
+    }
     if let Some(s) = info.message() {
         writeln!(term(), "panic @ {}: {:?}", info.location().unwrap(), s).unwrap_or(());
     } else {
@@ -1,5 +1,5 @@
 use core::arch::asm;
-use std::{collection::HashSet, str::StringView, sync::OnceCell};
+use std::{collection::HashSet, str::StringView, sync::Lazy};
 
 use crate::x86_64::features::X86Feature;
 
@@ -256,7 +256,7 @@ pub mod features {
 
 #[no_mangle]
 pub unsafe extern "C" fn __has_x86_feature(f: StringView) -> bool {
-    static FEATURES: OnceCell<HashSet<X86Feature>> = OnceCell::new();
+    static FEATURES: Lazy<HashSet<X86Feature>> = Lazy::new(features::get_x86_features);
 
     let feature = if let Ok(feature) = f.parse::<X86Feature>() {
         feature
@@ -264,7 +264,5 @@ pub unsafe extern "C" fn __has_x86_feature(f: StringView) -> bool {
         return false;
     };
 
-    FEATURES
-        .get_or_init(features::get_x86_features)
-        .contains(&feature)
+    FEATURES.contains(&feature)
 }
@@ -0,0 +1,195 @@
+//! The bucketed `HashMap` that `kstd::collection::HashMap` replaced, reduced to what the benchmarks use.
+//!
+//! Each key hashes to one bucket of up to 16 entries, which are searched linearly. The table doubles when the map
+//! averages 8 entries per bucket, or when a bucket overflows.
+
+use std::alloc::{alloc_zeroed, dealloc, handle_alloc_error, Layout};
+use std::hash::{BuildHasher, BuildHasherDefault, Hash};
+use std::mem::MaybeUninit;
+use std::ptr::NonNull;
+
+use kstd::hash::XLangHasher;
+
+/// The number of entries that fit in a single bucket
+const BUCKET_SIZE: usize = 16;
+
+/// The average number of entries per bucket at which a [`HashMap`] grows
+const BUCKET_LOAD: usize = 8;
+
+/// The number of buckets in the first table allocated by a [`HashMap`]
+const MIN_BUCKETS: usize = 16;
+
+#[repr(C)]
+struct HashMapSlot<K, V> {
+    ecount: usize,
+    entries: [MaybeUninit<(K, V)>; BUCKET_SIZE],
+}
+
+pub struct HashMap<K, V> {
+    htab: NonNull<HashMapSlot<K, V>>,
+    buckets: usize,
+    items: usize,
+    hash: BuildHasherDefault<XLangHasher>,
+}
+
+impl<K, V> Drop for HashMap<K, V> {
+    fn drop(&mut self) {
+        for i in 0..self.buckets {
+            // SAFETY: `i` is in bounds, and only the first `ecount` entries are initialized
+            unsafe {
+                let slot = &mut *self.htab.as_ptr().add(i);
+                for entry in &mut slot.entries[..slot.ecount] {
+                    entry.assume_init_drop();
+                }
+            }
+        }
+        // SAFETY: The table is not used again
+        unsafe { dealloc_table(self.htab, self.buckets) }
+    }
+}
+
+fn alloc_table<K, V>(buckets: usize) -> NonNull<HashMapSlot<K, V>> {
+    let layout = Layout::array::<HashMapSlot<K, V>>(buckets).unwrap();
+    // SAFETY: `buckets` is never 0, so neither is the size of the layout.
+    // Zeroing the table sets the `ecount` of each bucket to 0
+    NonNull::new(unsafe { alloc_zeroed(layout) })
+        .unwrap_or_else(|| handle_alloc_error(layout))
+        .cast()
+}
+
+/// # Safety
+/// `table` must have been returned by [`alloc_table`] with `buckets` buckets, and must not be accessed again
+unsafe fn dealloc_table<K, V>(table: NonNull<HashMapSlot<K, V>>, buckets: usize) {
+    if buckets != 0 {
+        dealloc(
+            table.as_ptr().cast(),
+            Layout::array::<HashMapSlot<K, V>>(buckets).unwrap(),
+        );
+    }
+}
+
+impl<K: Hash + Eq, V> HashMap<K, V> {
+    pub fn new() -> Self {
+        Self {
+            htab: NonNull::dangling(),
+            buckets: 0,
+            items: 0,
+            hash: BuildHasherDefault::default(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items == 0
+    }
+
+    fn bucket_in(&self, key: &K, buckets: usize) -> usize {
+        self.hash.hash_one(key) as usize % buckets
+    }
+
+    fn slot(&self, bucket: usize) -> &HashMapSlot<K, V> {
+        // SAFETY: `bucket_in` returns an index in bounds
+        unsafe { &*self.htab.as_ptr().add(bucket) }
+    }
+
+    fn slot_mut(&mut self, bucket: usize) -> &mut HashMapSlot<K, V> {
+        // SAFETY: `bucket_in` returns an index in bounds
+        unsafe { &mut *self.htab.as_ptr().add(bucket) }
+    }
+
+    /// Returns whether every entry fits in a table of `buckets` buckets
+    fn fits_in(&self, buckets: usize) -> bool {
+        let mut counts = vec![0usize; buckets];
+        (0..self.buckets).all(|i| {
+            let slot = self.slot(i);
+            slot.entries[..slot.ecount].iter().all(|entry| {
+                // SAFETY: Only the first `ecount` entries are read
+                let count =
+                    &mut counts[self.bucket_in(unsafe { &entry.assume_init_ref().0 }, buckets)];
+                *count += 1;
+                *count <= BUCKET_SIZE
+            })
+        })
+    }
+
+    fn grow(&mut self) {
+        let mut buckets = (self.buckets * 2).max(MIN_BUCKETS);
+        while !self.fits_in(buckets) {
+            buckets *= 2;
+        }
+
+        let old = std::mem::replace(&mut self.htab, alloc_table(buckets));
+        let obuckets = std::mem::replace(&mut self.buckets, buckets);
+        for i in 0..obuckets {
+            // SAFETY: `i` is in bounds of the old table, and each entry is moved out exactly once
+            let slot = unsafe { &mut *old.as_ptr().add(i) };
+            for entry in &slot.entries[..slot.ecount] {
+                let entry = unsafe { entry.assume_init_read() };
+                let dst = self.slot_mut(self.bucket_in(&entry.0, buckets));
+                dst.entries[dst.ecount].write(entry);
+                dst.ecount += 1;
+            }
+        }
+        // SAFETY: Every entry of the old table has been moved out
+        unsafe { dealloc_table(old, obuckets) }
+    }
+
+    fn find(&self, key: &K) -> Option<(usize, usize)> {
+        if self.buckets == 0 {
+            return None;
+        }
+        let bucket = self.bucket_in(key, self.buckets);
+        let slot = self.slot(bucket);
+        slot.entries[..slot.ecount]
+            .iter()
+            // SAFETY: Only the first `ecount` entries are read
+            .position(|entry| unsafe { entry.assume_init_ref() }.0 == *key)
+            .map(|idx| (bucket, idx))
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        if let Some((bucket, idx)) = self.find(&key) {
+            // SAFETY: `find` returns the position of an entry
+            let entry = unsafe { self.slot_mut(bucket).entries[idx].assume_init_mut() };
+            return Some(std::mem::replace(&mut entry.1, value));
+        }
+        if self.items >= self.buckets * BUCKET_LOAD {
+            self.grow();
+        }
+        loop {
+            let bucket = self.bucket_in(&key, self.buckets);
+            let slot = self.slot_mut(bucket);
+            if slot.ecount < BUCKET_SIZE {
+                slot.entries[slot.ecount].write((key, value));
+                slot.ecount += 1;
+                self.items += 1;
+                return None;
+            }
+            self.grow();
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<&V> {
+        let (bucket, idx) = self.find(key)?;
+        // SAFETY: `find` returns the position of an entry
+        Some(&unsafe { self.slot(bucket).entries[idx].assume_init_ref() }.1)
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<(K, V)> {
+        let (bucket, idx) = self.find(key)?;
+        let slot = self.slot_mut(bucket);
+        // SAFETY: `find` returns the position of an entry, and the following entries of the bucket are shifted down over it
+        let val = unsafe {
+            let entries = slot.entries.as_mut_ptr();
+            let val = entries.add(idx).read().assume_init();
+            std::ptr::copy(
+                entries.add(idx + 1),
+                entries.add(idx),
+                slot.ecount - idx - 1,
+            );
+            val
+        };
+        slot.ecount -= 1;
+        self.items -= 1;
+        Some(val)
+    }
+}
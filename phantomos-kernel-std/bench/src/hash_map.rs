@@ -0,0 +1,157 @@
+//! Compares `kstd::collection::HashMap` with the bucketed table it replaced, and with the host's `HashMap`.
+//!
+//! All three use `XLangHasher`, so only the table layout differs. Run with `cargo run --release --bin hash_map [entries]`.
+//! Each result is the mean time per operation over `entries` `u64` keys.
+
+mod bucketed;
+
+use std::hash::BuildHasherDefault;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use kstd::hash::XLangHasher;
+
+/// The operations of a map that are measured
+trait Map: Sized {
+    const NAME: &'static str;
+
+    fn new() -> Self;
+    fn insert(&mut self, key: u64, value: u64);
+    fn get(&self, key: u64) -> Option<u64>;
+    fn remove(&mut self, key: u64) -> bool;
+    fn is_empty(&self) -> bool;
+}
+
+impl Map for kstd::collection::HashMap<u64, u64> {
+    const NAME: &'static str = "kstd";
+
+    fn new() -> Self {
+        Self::new()
+    }
+
+    fn insert(&mut self, key: u64, value: u64) {
+        self.insert(key, value);
+    }
+
+    fn get(&self, key: u64) -> Option<u64> {
+        self.get(&key).copied()
+    }
+
+    fn remove(&mut self, key: u64) -> bool {
+        self.remove(&key).is_some()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.is_empty()
+    }
+}
+
+impl Map for bucketed::HashMap<u64, u64> {
+    const NAME: &'static str = "bucketed";
+
+    fn new() -> Self {
+        Self::new()
+    }
+
+    fn insert(&mut self, key: u64, value: u64) {
+        self.insert(key, value);
+    }
+
+    fn get(&self, key: u64) -> Option<u64> {
+        self.get(&key).copied()
+    }
+
+    fn remove(&mut self, key: u64) -> bool {
+        self.remove(&key).is_some()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.is_empty()
+    }
+}
+
+impl Map for std::collections::HashMap<u64, u64, BuildHasherDefault<XLangHasher>> {
+    const NAME: &'static str = "host";
+
+    fn new() -> Self {
+        Self::default()
+    }
+
+    fn insert(&mut self, key: u64, value: u64) {
+        self.insert(key, value);
+    }
+
+    fn get(&self, key: u64) -> Option<u64> {
+        self.get(&key).copied()
+    }
+
+    fn remove(&mut self, key: u64) -> bool {
+        self.remove(&key).is_some()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.is_empty()
+    }
+}
+
+/// Runs `f` over every key and returns the mean time per key
+fn time_per_key(keys: &[u64], mut f: impl FnMut(u64)) -> Duration {
+    let start = Instant::now();
+    for &key in keys {
+        f(key);
+    }
+    start.elapsed() / keys.len() as u32
+}
+
+fn bench<M: Map>(keys: &[u64]) {
+    let mut map = M::new();
+    let insert = time_per_key(keys, |key| map.insert(key, key));
+    let hit = time_per_key(keys, |key| assert_eq!(black_box(map.get(key)), Some(key)));
+    // The keys have no bits set in common with this, so flipping them gives keys that aren't in the map
+    let miss = time_per_key(keys, |key| {
+        assert_eq!(black_box(map.get(!key)), None);
+    });
+    let remove = time_per_key(keys, |key| assert!(map.remove(key)));
+    assert!(map.is_empty());
+
+    println!(
+        "    {:<10} insert {:>7.1?}  hit {:>7.1?}  miss {:>7.1?}  remove {:>7.1?}",
+        M::NAME,
+        insert,
+        hit,
+        miss,
+        remove
+    );
+}
+
+fn main() {
+    let entries = std::env::args().nth(1).map_or(1_000_000, |arg| {
+        arg.parse().expect("entries must be a number")
+    });
+
+    // xorshift64, limited to the low 62 bits so that no key is the complement of another
+    let mut state = 0x1234_5678_u64;
+    let random = (0..entries)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state >> 2
+        })
+        .collect::<std::collections::HashSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    let sequential = (0..entries).collect::<Vec<_>>();
+    let strided = (0..entries).map(|i| i * 128).collect::<Vec<_>>();
+
+    for (name, keys) in [
+        ("random", &random),
+        ("sequential", &sequential),
+        ("stride 128", &strided),
+    ] {
+        println!("{} keys, {}:", keys.len(), name);
+        bench::<kstd::collection::HashMap<u64, u64>>(keys);
+        bench::<bucketed::HashMap<u64, u64>>(keys);
+        bench::<std::collections::HashMap<u64, u64, BuildHasherDefault<XLangHasher>>>(keys);
+    }
+}
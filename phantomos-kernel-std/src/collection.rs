@@ -3,26 +3,286 @@ use core::fmt::Debug;
 use core::hash::{BuildHasher, Hash, Hasher};
 use core::iter::{FromIterator, FusedIterator};
 use core::marker::PhantomData;
-use core::mem::MaybeUninit;
-use core::ops::Index;
-use core::sync::atomic::{AtomicUsize, Ordering};
+use core::ops::{Index, Range};
 
 use crate::hash::XLangHasher;
+use crate::sync::prim::{spin_loop, AtomicUsize, Ordering};
+use crate::sync::WaitQueue;
 use alloc::alloc::{Allocator, Global, Layout};
+use alloc::collections::btree_map::{self, BTreeMap};
 use core::hash::BuildHasherDefault;
 use core::ptr::NonNull;
 
-#[repr(C)]
-struct HashMapSlot<K, V> {
-    ecount: usize,
-    entries: [MaybeUninit<(K, V)>; 16],
+pub mod intrusive;
+
+/// The number of control bytes examined at once while probing
+const GROUP_WIDTH: usize = 16;
+
+/// The control byte of an empty slot.
+///
+/// A full slot stores the top 7 bits of the hash of its key instead, so control bytes of full slots never have the high bit set.
+const EMPTY: u8 = 0x80;
+
+/// The number of slots in the first table allocated by a [`HashMap`].
+///
+/// This is at least [`GROUP_WIDTH`], so that the mirrored group at the end of the control bytes covers each slot at most once.
+const MIN_BUCKETS: usize = GROUP_WIDTH;
+
+/// Operations on a group of [`GROUP_WIDTH`] control bytes.
+///
+/// SSE2 is used when the target enables it, with a portable fallback that works 8 bytes at a time.
+/// This is decided at compile time rather than with `has_x86_feature!`, since init answers that from a `HashSet` built on first use,
+/// and probing the set while it is being built would re-enter its initialization.
+mod group {
+    /// A set of positions within a group, one bit per control byte
+    #[derive(Copy, Clone)]
+    pub struct BitMask(u16);
+
+    impl BitMask {
+        pub fn any(self) -> bool {
+            self.0 != 0
+        }
+
+        pub fn lowest(self) -> Option<usize> {
+            if self.0 == 0 {
+                None
+            } else {
+                Some(self.0.trailing_zeros() as usize)
+            }
+        }
+    }
+
+    impl Iterator for BitMask {
+        type Item = usize;
+
+        fn next(&mut self) -> Option<usize> {
+            let bit = self.lowest()?;
+            self.0 &= self.0 - 1;
+            Some(bit)
+        }
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+    use self::sse2 as imp;
+
+    #[cfg(not(all(target_arch = "x86_64", target_feature = "sse2")))]
+    use self::portable as imp;
+
+    /// Returns the positions in the group at `ctrl` whose control byte is `byte`
+    ///
+    /// # Safety
+    /// `ctrl` must be valid for reads of [`GROUP_WIDTH`](super::GROUP_WIDTH) bytes
+    pub unsafe fn match_byte(ctrl: *const u8, byte: u8) -> BitMask {
+        imp::match_byte(ctrl, byte)
+    }
+
+    /// Returns the positions in the group at `ctrl` that are empty
+    ///
+    /// # Safety
+    /// `ctrl` must be valid for reads of [`GROUP_WIDTH`](super::GROUP_WIDTH) bytes
+    pub unsafe fn match_empty(ctrl: *const u8) -> BitMask {
+        imp::match_empty(ctrl)
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_feature = "sse2"))]
+    mod sse2 {
+        use super::BitMask;
+        use core::arch::x86_64::{
+            _mm_cmpeq_epi8, _mm_loadu_si128, _mm_movemask_epi8, _mm_set1_epi8,
+        };
+
+        #[allow(clippy::cast_possible_truncation, clippy::cast_possible_wrap)]
+        pub unsafe fn match_byte(ctrl: *const u8, byte: u8) -> BitMask {
+            let group = _mm_loadu_si128(ctrl.cast());
+            let eq = _mm_cmpeq_epi8(group, _mm_set1_epi8(byte as i8));
+            BitMask(_mm_movemask_epi8(eq) as u16)
+        }
+
+        /// Empty control bytes are the only ones with the high bit set, so the mask of high bits is the mask of empty slots
+        #[allow(clippy::cast_possible_truncation)]
+        pub unsafe fn match_empty(ctrl: *const u8) -> BitMask {
+            BitMask(_mm_movemask_epi8(_mm_loadu_si128(ctrl.cast())) as u16)
+        }
+    }
+
+    // Also built for tests on SSE2 targets, which check it against the SSE2 version
+    #[cfg(any(test, not(all(target_arch = "x86_64", target_feature = "sse2"))))]
+    mod portable {
+        use super::BitMask;
+        use crate::collection::GROUP_WIDTH;
+
+        const LO: u64 = 0x0101_0101_0101_0101;
+        const HI: u64 = 0x8080_8080_8080_8080;
+
+        /// Gathers the high bit of each byte of `x` into the low 8 bits of the result
+        #[allow(clippy::cast_possible_truncation)]
+        fn high_bits(x: u64) -> u16 {
+            (((x & HI) >> 7).wrapping_mul(0x0102_0408_1020_4080) >> 56) as u16
+        }
+
+        /// Sets the high bit of exactly the bytes of `x` that are zero
+        fn zero_bytes(x: u64) -> u64 {
+            !(((x & !HI) + !HI) | x | !HI)
+        }
+
+        unsafe fn load(ctrl: *const u8) -> (u64, u64) {
+            let lo = u64::from_le(ctrl.cast::<u64>().read_unaligned());
+            let hi = u64::from_le(ctrl.add(GROUP_WIDTH / 2).cast::<u64>().read_unaligned());
+            (lo, hi)
+        }
+
+        pub unsafe fn match_byte(ctrl: *const u8, byte: u8) -> BitMask {
+            let (lo, hi) = load(ctrl);
+            let pattern = LO * u64::from(byte);
+            BitMask(high_bits(zero_bytes(lo ^ pattern)) | high_bits(zero_bytes(hi ^ pattern)) << 8)
+        }
+
+        pub unsafe fn match_empty(ctrl: *const u8) -> BitMask {
+            let (lo, hi) = load(ctrl);
+            BitMask(high_bits(lo) | high_bits(hi) << 8)
+        }
+    }
+
+    #[cfg(all(
+        test,
+        feature = "std",
+        not(loom),
+        target_arch = "x86_64",
+        target_feature = "sse2"
+    ))]
+    mod test {
+        use super::{portable, sse2};
+        use crate::collection::{EMPTY, GROUP_WIDTH};
+
+        #[test]
+        fn portable_matches_sse2() {
+            let mut seed = 0x2545_f491_4f6c_dd1du64;
+            for _ in 0..1000 {
+                let mut ctrl = [0u8; GROUP_WIDTH];
+                for byte in &mut ctrl {
+                    seed ^= seed << 13;
+                    seed ^= seed >> 7;
+                    seed ^= seed << 17;
+                    // Mostly a few distinct values, so that most groups have several matches
+                    *byte = [0x00, 0x7f, EMPTY, seed as u8][(seed >> 32) as usize % 4];
+                }
+                let ctrl = ctrl.as_ptr();
+                unsafe {
+                    assert_eq!(portable::match_empty(ctrl).0, sse2::match_empty(ctrl).0);
+                    for byte in [0x00, 0x7f, 0x80, 0xff, (seed >> 8) as u8] {
+                        assert_eq!(
+                            portable::match_byte(ctrl, byte).0,
+                            sse2::match_byte(ctrl, byte).0
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The error returned by fallible allocation methods of the collections in this module
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TryReserveError {
+    /// The requested capacity exceeds the maximum size of an allocation
+    CapacityOverflow,
+    /// The allocator failed to provide memory
+    AllocError {
+        /// The layout of the allocation that failed
+        layout: Layout,
+    },
+}
+
+impl core::fmt::Display for TryReserveError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match self {
+            Self::CapacityOverflow => f.write_str("capacity overflow"),
+            Self::AllocError { layout } => write!(
+                f,
+                "memory allocation of {} bytes (align {}) failed",
+                layout.size(),
+                layout.align()
+            ),
+        }
+    }
+}
+
+impl From<TryReserveError> for crate::io::Error {
+    fn from(e: TryReserveError) -> Self {
+        let kind = match e {
+            TryReserveError::CapacityOverflow => crate::io::ErrorKind::InvalidInput,
+            TryReserveError::AllocError { .. } => crate::io::ErrorKind::OutOfMemory,
+        };
+        crate::io::Error::new(kind, alloc::string::ToString::to_string(&e))
+    }
+}
+
+/// Unwraps the result of a fallible allocation for the infallible APIs.
+///
+/// Allocation failures are reported through [`handle_alloc_error`](alloc::alloc::handle_alloc_error), never by unwrapping.
+fn handle_reserve<T>(res: Result<T, TryReserveError>) -> T {
+    match res {
+        Ok(val) => val,
+        Err(TryReserveError::CapacityOverflow) => panic!("capacity overflow"),
+        Err(TryReserveError::AllocError { layout }) => alloc::alloc::handle_alloc_error(layout),
+    }
+}
+
+/// Returns the number of entries a table of `buckets` slots holds before growing, which is a load factor of 7/8
+const fn bucket_capacity(buckets: usize) -> usize {
+    buckets / 8 * 7
+}
+
+/// Returns the number of slots needed to hold `cap` entries without growing
+fn buckets_for(cap: usize) -> Result<usize, TryReserveError> {
+    if cap == 0 {
+        return Ok(0);
+    }
+    cap.checked_mul(8)
+        .ok_or(TryReserveError::CapacityOverflow)?
+        .div_ceil(7)
+        .max(MIN_BUCKETS)
+        .checked_next_power_of_two()
+        .ok_or(TryReserveError::CapacityOverflow)
+}
+
+/// Returns the layout of a table of `buckets` slots, and the offset of its control bytes.
+///
+/// The slots come first, followed by one control byte per slot and a copy of the first [`GROUP_WIDTH`] control bytes,
+/// so that a group can be loaded from any slot without wrapping.
+fn table_layout<K, V>(buckets: usize) -> Result<(Layout, usize), TryReserveError> {
+    let slots = Layout::array::<(K, V)>(buckets).map_err(|_| TryReserveError::CapacityOverflow)?;
+    let ctrl = buckets
+        .checked_add(GROUP_WIDTH)
+        .and_then(|len| Layout::array::<u8>(len).ok())
+        .ok_or(TryReserveError::CapacityOverflow)?;
+    slots
+        .extend(ctrl)
+        .map_err(|_| TryReserveError::CapacityOverflow)
+}
+
+/// Sets the control byte of slot `idx` in a table of `buckets` slots, along with its mirror
+///
+/// # Safety
+/// `ctrl` must point to the control bytes of a table of `buckets` slots, and `idx` must be less than `buckets`
+unsafe fn set_ctrl(ctrl: NonNull<u8>, buckets: usize, idx: usize, byte: u8) {
+    *ctrl.as_ptr().add(idx) = byte;
+    if idx < GROUP_WIDTH {
+        *ctrl.as_ptr().add(buckets + idx) = byte;
+    }
 }
 
-/// An ABI Safe [`HashMap`] that uses an Allocator to obtain the memory to store the slots
+/// An ABI Safe [`HashMap`] that uses an Allocator to obtain the memory to store the slots.
+///
+/// Entries are stored in an open-addressed table with linear probing. Each slot has a control byte, which is either [`EMPTY`]
+/// or holds 7 bits of the hash of its key, and lookups compare a whole group of control bytes at once before comparing any keys.
+/// Removal shifts the following entries back into the freed slot, so the table never contains tombstones.
 #[repr(C)]
 pub struct HashMap<K, V, H: BuildHasher = BuildHasherDefault<XLangHasher>, A: Allocator = Global> {
-    htab: NonNull<HashMapSlot<K, V>>,
+    slots: NonNull<(K, V)>,
+    ctrl: NonNull<u8>,
     buckets: usize,
+    items: usize,
     hash: H,
     alloc: A,
 }
@@ -38,20 +298,11 @@ unsafe impl<K: Sync, V: Sync, H: BuildHasher + Sync, A: Allocator + Sync> Sync
 
 impl<K, V, H: BuildHasher, A: Allocator> Drop for HashMap<K, V, H, A> {
     fn drop(&mut self) {
-        for i in 0..self.buckets {
-            let bucket = unsafe { &mut *self.htab.as_ptr().add(i) };
-            for i in 0..bucket.ecount {
-                unsafe { core::ptr::drop_in_place(bucket.entries[i].as_mut_ptr()) }
-            }
-        }
-
-        if self.buckets != 0 {
-            unsafe {
-                self.alloc.deallocate(
-                    self.htab.cast(),
-                    Layout::array::<HashMapSlot<K, V>>(self.buckets).unwrap(),
-                );
-            }
+        self.clear();
+        // SAFETY:
+        // The table is not used again
+        unsafe {
+            self.dealloc_detached(self.slots, self.buckets);
         }
     }
 }
@@ -61,12 +312,7 @@ impl<K, V, H: BuildHasher + Default, A: Allocator + Default> HashMap<K, V, H, A>
     /// Returns a new [`HashMap`] with defaults for both the hasher and the allocator, containing no entries
     #[must_use]
     pub fn new() -> Self {
-        Self {
-            htab: NonNull::dangling(),
-            buckets: 0,
-            hash: Default::default(),
-            alloc: Default::default(),
-        }
+        Self::with_hasher_in(Default::default(), Default::default())
     }
 }
 
@@ -79,24 +325,38 @@ impl<K, V, H: BuildHasher + Default, A: Allocator + Default> Default for HashMap
 impl<K, V, H: BuildHasher + Default, A: Allocator> HashMap<K, V, H, A> {
     /// Returns a new [`HashMap`] with the given allocator and a default hasher, containing no entries.
     pub fn new_in(alloc: A) -> Self {
-        Self {
-            htab: NonNull::dangling(),
-            buckets: 0,
-            hash: Default::default(),
-            alloc,
-        }
+        Self::with_hasher_in(Default::default(), alloc)
+    }
+
+    /// Returns a new [`HashMap`] with the given allocator and a default hasher, which can hold at least `cap` entries without reallocating
+    pub fn with_capacity_in(cap: usize, alloc: A) -> Self {
+        Self::with_capacity_and_hasher_in(cap, Default::default(), alloc)
+    }
+
+    /// Returns a new [`HashMap`] with the given allocator and a default hasher, which can hold at least `cap` entries without reallocating,
+    /// or an error if the memory can't be allocated
+    pub fn try_with_capacity_in(cap: usize, alloc: A) -> Result<Self, TryReserveError> {
+        Self::try_with_capacity_and_hasher_in(cap, Default::default(), alloc)
+    }
+}
+
+impl<K, V, H: BuildHasher + Default, A: Allocator + Default> HashMap<K, V, H, A> {
+    /// Returns a new [`HashMap`] with defaults for both the hasher and the allocator, which can hold at least `cap` entries without reallocating
+    #[must_use]
+    pub fn with_capacity(cap: usize) -> Self {
+        Self::with_capacity_and_hasher_in(cap, Default::default(), Default::default())
     }
 }
 
 impl<K, V, H: BuildHasher, A: Allocator + Default> HashMap<K, V, H, A> {
     /// Returns a new [`HashMap`] with the given hasher and a default alocator, containing no entries.
     pub fn with_hasher(hash: H) -> Self {
-        Self {
-            htab: NonNull::dangling(),
-            buckets: 0,
-            hash,
-            alloc: Default::default(),
-        }
+        Self::with_hasher_in(hash, Default::default())
+    }
+
+    /// Returns a new [`HashMap`] with the given hasher and a default alocator, which can hold at least `cap` entries without reallocating
+    pub fn with_capacity_and_hasher(cap: usize, hash: H) -> Self {
+        Self::with_capacity_and_hasher_in(cap, hash, Default::default())
     }
 }
 
@@ -104,30 +364,153 @@ impl<K, V, H: BuildHasher, A: Allocator> HashMap<K, V, H, A> {
     /// Returns a new [`HashMap`] with the given hasher and allocator, containing no entries
     pub fn with_hasher_in(hash: H, alloc: A) -> Self {
         Self {
-            htab: NonNull::dangling(),
+            slots: NonNull::dangling(),
+            ctrl: NonNull::dangling(),
             buckets: 0,
+            items: 0,
             hash,
             alloc,
         }
     }
 
+    /// Returns a new [`HashMap`] with the given hasher and allocator, which can hold at least `cap` entries without reallocating
+    pub fn with_capacity_and_hasher_in(cap: usize, hash: H, alloc: A) -> Self {
+        handle_reserve(Self::try_with_capacity_and_hasher_in(cap, hash, alloc))
+    }
+
+    /// Returns a new [`HashMap`] with the given hasher and allocator, which can hold at least `cap` entries without reallocating,
+    /// or an error if the memory can't be allocated
+    pub fn try_with_capacity_and_hasher_in(
+        cap: usize,
+        hash: H,
+        alloc: A,
+    ) -> Result<Self, TryReserveError> {
+        let mut ret = Self::with_hasher_in(hash, alloc);
+        let buckets = buckets_for(cap)?;
+        if buckets != 0 {
+            (ret.slots, ret.ctrl) = ret.try_alloc_table(buckets)?;
+            ret.buckets = buckets;
+        }
+        Ok(ret)
+    }
+
+    /// Allocates a table of `buckets` slots, all of which are empty, and returns its slots and control bytes
+    #[allow(clippy::type_complexity)]
+    fn try_alloc_table(
+        &self,
+        buckets: usize,
+    ) -> Result<(NonNull<(K, V)>, NonNull<u8>), TryReserveError> {
+        let (layout, ctrl_offset) = table_layout::<K, V>(buckets)?;
+        let base = self
+            .alloc
+            .allocate(layout)
+            .map_err(|_| TryReserveError::AllocError { layout })?
+            .cast::<u8>();
+        // SAFETY:
+        // The control bytes lie within the allocation, at `ctrl_offset`
+        unsafe {
+            let ctrl = NonNull::new_unchecked(base.as_ptr().add(ctrl_offset));
+            core::ptr::write_bytes(ctrl.as_ptr(), EMPTY, buckets + GROUP_WIDTH);
+            Ok((base.cast(), ctrl))
+        }
+    }
+
+    /// Deallocates a table of `buckets` slots, without dropping any entries
+    ///
+    /// # Safety
+    /// `slots` must have been returned by [`HashMap::try_alloc_table`] with `buckets`, or `buckets` must be 0, and the table must not be accessed again
+    unsafe fn dealloc_detached(&self, slots: NonNull<(K, V)>, buckets: usize) {
+        if buckets != 0 {
+            let (layout, _) = table_layout::<K, V>(buckets).unwrap();
+            self.alloc.deallocate(slots.cast(), layout);
+        }
+    }
+
+    /// # Safety
+    /// `idx` must be less than `self.buckets`
+    unsafe fn is_full(&self, idx: usize) -> bool {
+        *self.ctrl.as_ptr().add(idx) & EMPTY == 0
+    }
+
+    /// # Safety
+    /// `idx` must be less than `self.buckets`, and the slot must be full
+    unsafe fn entry_at(&self, idx: usize) -> &(K, V) {
+        &*self.slots.as_ptr().add(idx)
+    }
+
+    /// # Safety
+    /// `idx` must be less than `self.buckets`, and the slot must be full
+    unsafe fn entry_at_mut(&mut self, idx: usize) -> &mut (K, V) {
+        &mut *self.slots.as_ptr().add(idx)
+    }
+
+    /// Returns the number of entries in the map
+    pub fn len(&self) -> usize {
+        self.items
+    }
+
+    /// Returns `true` if the map contains no entries
+    pub fn is_empty(&self) -> bool {
+        self.items == 0
+    }
+
+    /// Returns the number of entries the map can hold before it grows
+    pub fn capacity(&self) -> usize {
+        bucket_capacity(self.buckets)
+    }
+
+    /// Removes every entry from the map, keeping the allocated memory
+    pub fn clear(&mut self) {
+        if self.items == 0 {
+            return;
+        }
+        for idx in 0..self.buckets {
+            // SAFETY:
+            // `idx` is in bounds, and the slot is marked empty before its entry is dropped, so a panicking destructor leaks the rest instead of double-dropping
+            unsafe {
+                if self.is_full(idx) {
+                    set_ctrl(self.ctrl, self.buckets, idx, EMPTY);
+                    self.items -= 1;
+                    core::ptr::drop_in_place(self.slots.as_ptr().add(idx));
+                }
+            }
+        }
+    }
+
+    /// Removes every entry from the map, returning them in an [`Iterator`]. The allocated memory is kept.
+    ///
+    /// Entries that are not yielded are dropped when the iterator is dropped.
+    pub fn drain(&mut self) -> Drain<'_, K, V, H, A> {
+        // The table is moved out of the map while draining, so that leaking the `Drain` leaks the entries rather than leaving them in the map
+        let raw = RawIntoIter {
+            slots: core::mem::replace(&mut self.slots, NonNull::dangling()),
+            ctrl: core::mem::replace(&mut self.ctrl, NonNull::dangling()),
+            buckets: core::mem::replace(&mut self.buckets, 0),
+            idx: 0,
+        };
+        self.items = 0;
+        Drain { raw, map: self }
+    }
+
     /// Produces an [`Iterator`] over pairs of keys and values in this [`HashMap`]
     pub fn iter(&self) -> Iter<'_, K, V> {
-        Iter(
-            unsafe { core::slice::from_raw_parts(self.htab.as_ptr(), self.buckets) }.iter(),
-            None,
-        )
+        Iter {
+            slots: self.slots.as_ptr(),
+            ctrl: self.ctrl.as_ptr(),
+            buckets: self.buckets,
+            idx: 0,
+            phantom: PhantomData,
+        }
     }
 
     /// Produces an [`Iterator`] over pairs of keys and mutable values in this [`HashMap`]
     /// Note that they keys are not mutable, to avoid mutation that would affect the implementation of [`Eq`] and [`Hash`]
     pub fn iter_mut(&mut self) -> IterMut<'_, K, V> {
-        let begin = self.htab.as_ptr();
-        let end = unsafe { begin.add(self.buckets) };
         IterMut {
-            buckets: begin,
-            buckets_end: end,
-            bucket_position: 0,
+            slots: self.slots.as_ptr(),
+            ctrl: self.ctrl.as_ptr(),
+            buckets: self.buckets,
+            idx: 0,
             phantom: PhantomData,
         }
     }
@@ -137,355 +520,659 @@ impl<K, V, H: BuildHasher, A: Allocator> HashMap<K, V, H, A> {
         Values(self.iter())
     }
 
+    /// Produces an [`Iterator`] over the mutable values in this [`HashMap`]
+    pub fn values_mut(&mut self) -> ValuesMut<'_, K, V> {
+        ValuesMut(self.iter_mut())
+    }
+
     /// Produces an [`Iterator`] over the keys in this [`HashMap`]
     pub fn keys(&self) -> Keys<'_, K, V> {
         Keys(self.iter())
     }
 }
 
-impl<K: Eq + Hash, V, H: BuildHasher, A: Allocator> HashMap<K, V, H, A> {
-    #[allow(clippy::similar_names)]
-    fn rehash(&mut self) {
-        let ocount = self.buckets;
-        let ncount = self.buckets * 2;
-        assert!(
-            ncount <= (core::isize::MAX as usize),
-            "We have too many buckets, bail before we maybe cause UB"
-        );
+/// Splits `hash` into the slot at which probing starts in a table with the given `mask`, and the control byte stored for the key
+#[allow(clippy::cast_possible_truncation)]
+fn split_hash(hash: u64, mask: usize) -> (usize, u8) {
+    // FNV-1a, which `XLangHasher` uses, mixes poorly into the low bits, so fold the high half down before masking
+    let h1 = (hash ^ (hash >> 32)) as usize & mask;
+    let h2 = (hash >> 57) as u8;
+    (h1, h2)
+}
 
-        let ptr = self
-            .alloc
-            .allocate_zeroed(Layout::array::<HashMapSlot<K, V>>(ncount).unwrap())
-            .unwrap_or_else(|_| {
-                alloc::alloc::handle_alloc_error(Layout::array::<HashMapSlot<K, V>>(16).unwrap())
-            });
-        let ptr = core::mem::replace(&mut self.htab, ptr.cast());
-        self.buckets = ncount;
+impl<K: Hash, V, H: BuildHasher, A: Allocator> HashMap<K, V, H, A> {
+    fn hash_of<Q: ?Sized + Hash>(&self, key: &Q) -> u64 {
+        self.hash.hash_one(key)
+    }
 
-        for i in 0..self.buckets {
-            let bucket = unsafe { &mut *ptr.as_ptr().add(i) };
-            for i in 0..bucket.ecount {
-                let (key, value) = unsafe { bucket.entries[i].as_ptr().read() };
-                self.insert(key, value).unwrap();
-            }
-        }
-        unsafe {
-            self.alloc.deallocate(
-                ptr.cast(),
-                Layout::array::<HashMapSlot<K, V>>(ocount).unwrap(),
-            );
-        }
+    /// Returns the slot where probing for `hash` starts
+    fn home_of(&self, hash: u64) -> usize {
+        split_hash(hash, self.buckets - 1).0
     }
 
+    /// Returns the first empty slot probing from the home of `hash`
     ///
-    /// Inserts `value` into the map with `key`, returning the existing value in the slot if present
-    #[allow(clippy::cast_possible_truncation, clippy::missing_panics_doc)]
-    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
-        if self.buckets == 0 {
-            let ptr = self
-                .alloc
-                .allocate_zeroed(Layout::array::<HashMapSlot<K, V>>(16).unwrap())
-                .unwrap_or_else(|_| {
-                    alloc::alloc::handle_alloc_error(
-                        Layout::array::<HashMapSlot<K, V>>(16).unwrap(),
-                    )
-                });
-            self.htab = ptr.cast();
-            self.buckets = 16;
-        }
+    /// # Safety
+    /// The table must have at least one empty slot
+    unsafe fn find_empty(&self, hash: u64) -> usize {
+        let mask = self.buckets - 1;
+        let mut pos = self.home_of(hash);
         loop {
-            let mut hasher = self.hash.build_hasher();
-            key.hash(&mut hasher);
-            let hash = hasher.finish() as usize % self.buckets;
-            // SAFETY:
-            // htab is as long as the number of available buckets, thus this offset is guaranteed to be valid
-            let bucket = unsafe { &mut *self.htab.as_ptr().add(hash) };
-            for i in 0..bucket.ecount {
-                // SAFETY:
-                // bucket.ecount never exceeds 16, by the check following this loop
-                let key_val = unsafe { &mut *bucket.entries.get_unchecked_mut(i).as_mut_ptr() };
-
-                if key_val.0.eq(&key) {
-                    return Some(core::mem::replace(&mut key_val.1, value));
-                }
+            if let Some(bit) = group::match_empty(self.ctrl.as_ptr().add(pos)).lowest() {
+                return (pos + bit) & mask;
             }
-
-            if bucket.ecount == 16 {
-                self.rehash();
-                continue;
-            }
-            // SAFETY:
-            // This is literally MaybeUninit::write, but valid in 1.39
-            // TODO: Change this to MaybeUninit::write, since we are no longer in 1.39
-            unsafe {
-                bucket.entries[bucket.ecount]
-                    .as_mut_ptr()
-                    .write((key, value));
-            }
-            bucket.ecount += 1;
-            return None;
+            pos = (pos + GROUP_WIDTH) & mask;
         }
     }
 
-    /// Returns a mutable reference to the value with the given key, inserting `value` in that slot if necessary
-    #[allow(clippy::cast_possible_truncation, clippy::missing_panics_doc)]
-    pub fn get_or_insert_mut(&mut self, key: K, value: V) -> &mut V {
-        if self.buckets == 0 {
-            let ptr = self
-                .alloc
-                .allocate_zeroed(Layout::array::<HashMapSlot<K, V>>(16).unwrap())
-                .unwrap_or_else(|_| {
-                    alloc::alloc::handle_alloc_error(
-                        Layout::array::<HashMapSlot<K, V>>(16).unwrap(),
-                    )
-                });
-            self.htab = ptr.cast();
-            self.buckets = 16;
-        }
-        loop {
-            let mut hasher = self.hash.build_hasher();
-            key.hash(&mut hasher);
-            let hash = hasher.finish() as usize % self.buckets;
-            // SAFETY:
-            // htab is as long as the number of available buckets, thus this offset is guaranteed to be valid
-            let bucket = unsafe { &mut *self.htab.as_ptr().add(hash) };
-            for i in 0..bucket.ecount {
-                // SAFETY:
-                // bucket.ecount never exceeds 16, by the check following this loop
-                let key_val = unsafe { &mut *bucket.entries.get_unchecked_mut(i).as_mut_ptr() };
-
-                if key_val.0.eq(&key) {
-                    return &mut key_val.1;
-                }
-            }
-
-            if bucket.ecount == 16 {
-                self.rehash();
-                continue;
-            }
-            // SAFETY:
-            // This is literally MaybeUninit::write, but valid in 1.39
-            // TODO: Change this to MaybeUninit::write, since we're no longer bound to 1.39
-            unsafe {
-                bucket.entries[bucket.ecount]
-                    .as_mut_ptr()
-                    .write((key, value));
-            }
-            bucket.ecount += 1;
-            return &mut unsafe { &mut *bucket.entries[bucket.ecount - 1].as_mut_ptr() }.1;
-        }
+    /// Writes an entry into an empty slot without checking for an existing key or updating `items`, and returns its slot
+    ///
+    /// # Safety
+    /// The table must have at least one empty slot
+    unsafe fn place(&mut self, hash: u64, entry: (K, V)) -> usize {
+        let idx = self.find_empty(hash);
+        self.slots.as_ptr().add(idx).write(entry);
+        set_ctrl(
+            self.ctrl,
+            self.buckets,
+            idx,
+            split_hash(hash, self.buckets - 1).1,
+        );
+        idx
     }
 
-    /// Returns a mutable reference to the value with the given key, inserting the value produced by `value`
-    #[allow(clippy::cast_possible_truncation, clippy::missing_panics_doc)]
-    pub fn get_or_insert_with_mut<F: FnOnce(&K) -> V>(&mut self, key: K, value: F) -> &mut V {
-        if self.buckets == 0 {
-            let ptr = self
-                .alloc
-                .allocate_zeroed(Layout::array::<HashMapSlot<K, V>>(16).unwrap()) //
-                .unwrap_or_else(|_| {
-                    alloc::alloc::handle_alloc_error(
-                        Layout::array::<HashMapSlot<K, V>>(16).unwrap(),
-                    )
-                });
-            self.htab = ptr.cast();
-            self.buckets = 16;
-        }
-        loop {
-            let mut hasher = self.hash.build_hasher();
-            key.hash(&mut hasher);
-            let hash = hasher.finish() as usize % self.buckets;
-            // SAFETY:
-            // htab is as long as the number of available buckets, thus this offset is guaranteed to be valid
-            let bucket = unsafe { &mut *self.htab.as_ptr().add(hash) };
-            for i in 0..bucket.ecount {
-                // SAFETY:
-                // bucket.ecount never exceeds 16, by the check following this loop
-                let key_val = unsafe { &mut *bucket.entries.get_unchecked_mut(i).as_mut_ptr() };
-
-                if key_val.0.eq(&key) {
-                    return &mut key_val.1;
-                }
-            }
+    /// Moves every entry into a new table of `buckets` slots.
+    ///
+    /// The new table is allocated before any entry is moved, so on error the map is left unchanged.
+    fn try_resize(&mut self, buckets: usize) -> Result<(), TryReserveError> {
+        debug_assert!(bucket_capacity(buckets) >= self.items);
+        let (slots, ctrl) = if buckets == 0 {
+            (NonNull::dangling(), NonNull::dangling())
+        } else {
+            self.try_alloc_table(buckets)?
+        };
 
-            if bucket.ecount == 16 {
-                self.rehash();
-                continue;
-            }
+        let old_slots = core::mem::replace(&mut self.slots, slots);
+        let old_ctrl = core::mem::replace(&mut self.ctrl, ctrl);
+        let old_buckets = core::mem::replace(&mut self.buckets, buckets);
+
+        for idx in 0..old_buckets {
             // SAFETY:
-            // This is literally MaybeUninit::write, but valid in 1.39
-            // TODO: Change this to MaybeUninit::write, since we're no longer bound to 1.39
-            let value = value(&key);
+            // `idx` is in bounds of the old table, which no longer belongs to `self`, and each full slot is moved out exactly once.
+            // The new table has room for every entry, so it always has an empty slot
             unsafe {
-                bucket.entries[bucket.ecount]
-                    .as_mut_ptr()
-                    .write((key, value));
+                if *old_ctrl.as_ptr().add(idx) & EMPTY == 0 {
+                    let entry = old_slots.as_ptr().add(idx).read();
+                    let hash = self.hash_of(&entry.0);
+                    self.place(hash, entry);
+                }
             }
-            bucket.ecount += 1;
-            return &mut unsafe { &mut *bucket.entries[bucket.ecount - 1].as_mut_ptr() }.1;
         }
-    }
 
-    /// Gets an immutable reference to the value given by `key`, if present, otherwise returns `None`.
-    #[allow(clippy::cast_possible_truncation)]
-    pub fn get<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> Option<&V>
-    where
-        K: Borrow<Q>,
-    {
-        if self.buckets == 0 {
-            return None;
+        // SAFETY:
+        // All entries of the old table have been moved out
+        unsafe {
+            self.dealloc_detached(old_slots, old_buckets);
         }
-        let mut hasher = self.hash.build_hasher();
-        key.hash(&mut hasher);
-        let hash = hasher.finish() as usize % self.buckets;
+        Ok(())
+    }
 
-        let bucket = unsafe { &mut *self.htab.as_ptr().add(hash) };
-        for i in 0..bucket.ecount {
-            // SAFETY:
-            // bucket.ecount never exceeds 16, by the check following this loop
-            let key_val = unsafe { &*bucket.entries.get_unchecked_mut(i).as_mut_ptr() };
+    fn try_grow(&mut self) -> Result<(), TryReserveError> {
+        let buckets = if self.buckets == 0 {
+            MIN_BUCKETS
+        } else {
+            self.buckets
+                .checked_mul(2)
+                .ok_or(TryReserveError::CapacityOverflow)?
+        };
+        self.try_resize(buckets)
+    }
 
-            if key_val.0.borrow().eq(key) {
-                return Some(&key_val.1);
-            }
+    /// Inserts an entry whose key is known not to be in the map, and returns its slot.
+    ///
+    /// On error, the map is unchanged and `key` and `value` are dropped.
+    fn try_insert_unique(&mut self, key: K, value: V) -> Result<usize, TryReserveError> {
+        if self.items >= self.capacity() {
+            self.try_grow()?;
         }
-
-        None
+        let hash = self.hash_of(&key);
+        // SAFETY:
+        // `items` is below the capacity, which is less than the number of slots
+        let idx = unsafe { self.place(hash, (key, value)) };
+        self.items += 1;
+        Ok(idx)
     }
 
-    /// Gets a mutable reference to the value given by `key`, if present, otherwise returns `None`.
-    #[allow(clippy::cast_possible_truncation)]
-    pub fn get_mut<Q: ?Sized + Hash + Eq>(&mut self, key: &Q) -> Option<&mut V>
+    /// Returns the slot of the entry for `key`, if present
+    fn find<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> Option<usize>
     where
         K: Borrow<Q>,
     {
         if self.buckets == 0 {
             return None;
         }
-        let mut hasher = self.hash.build_hasher();
-        key.hash(&mut hasher);
-        let hash = hasher.finish() as usize % self.buckets;
-
-        let bucket = unsafe { &mut *self.htab.as_ptr().add(hash) };
-        for i in 0..bucket.ecount {
+        let mask = self.buckets - 1;
+        let (mut pos, h2) = split_hash(self.hash_of(key), mask);
+        loop {
             // SAFETY:
-            // bucket.ecount never exceeds 16, by the check following this loop
-            let key_val = unsafe { &mut *bucket.entries.get_unchecked_mut(i).as_mut_ptr() };
-
-            if key_val.0.borrow().eq(key) {
-                return Some(&mut key_val.1);
+            // The control bytes are readable for a whole group past any slot, thanks to the mirrored group at the end.
+            // Matching slots are full, and the table always has an empty slot, so the loop ends.
+            unsafe {
+                let group = self.ctrl.as_ptr().add(pos);
+                for bit in group::match_byte(group, h2) {
+                    let idx = (pos + bit) & mask;
+                    if self.entry_at(idx).0.borrow() == key {
+                        return Some(idx);
+                    }
+                }
+                // Linear probing never leaves an empty slot between an entry and its home
+                if group::match_empty(group).any() {
+                    return None;
+                }
             }
+            pos = (pos + GROUP_WIDTH) & mask;
         }
-
-        None
     }
 
-    /// Removes an entry by Key from this map, and returns the Key and Value
-    #[allow(clippy::cast_possible_truncation)]
-    pub fn remove<Q: ?Sized + Hash + Eq>(&mut self, key: &Q) -> Option<(K, V)>
-    where
-        K: Borrow<Q>,
-    {
-        if self.buckets == 0 {
-            return None;
+    /// Moves the entry in slot `idx` out of the map, then shifts back the following entries that probed past it, so that no tombstone is needed
+    ///
+    /// # Safety
+    /// `idx` must be less than `self.buckets`, and the slot must be full
+    unsafe fn remove_at(&mut self, idx: usize) -> (K, V) {
+        let mask = self.buckets - 1;
+        let val = self.slots.as_ptr().add(idx).read();
+        set_ctrl(self.ctrl, self.buckets, idx, EMPTY);
+        self.items -= 1;
+
+        // The hole is always marked empty, so if hashing panics the map only loses track of some entries, which are then leaked
+        let mut hole = idx;
+        let mut next = (idx + 1) & mask;
+        while self.is_full(next) {
+            let home = self.home_of(self.hash_of(&self.entry_at(next).0));
+            // The entry can move into the hole if the hole lies between its home and its current slot
+            if next.wrapping_sub(home) & mask >= next.wrapping_sub(hole) & mask {
+                core::ptr::copy_nonoverlapping(
+                    self.slots.as_ptr().add(next),
+                    self.slots.as_ptr().add(hole),
+                    1,
+                );
+                set_ctrl(self.ctrl, self.buckets, hole, *self.ctrl.as_ptr().add(next));
+                set_ctrl(self.ctrl, self.buckets, next, EMPTY);
+                hole = next;
+            }
+            next = (next + 1) & mask;
         }
+        val
+    }
 
-        let mut hasher = self.hash.build_hasher();
-        key.hash(&mut hasher);
-        let hash = hasher.finish() as usize % self.buckets;
-
-        let bucket = unsafe { &mut *self.htab.as_ptr().add(hash) };
-
-        for i in 0..bucket.ecount {
+    /// Keeps only the entries for which `f` returns `true`, visiting each entry once
+    pub fn retain<F: FnMut(&K, &mut V) -> bool>(&mut self, mut f: F) {
+        if self.items == 0 {
+            return;
+        }
+        let mask = self.buckets - 1;
+        // Removing an entry only shifts back entries between it and the next empty slot.
+        // Visiting the slots in order, starting after an empty one, means that entries are never shifted into a slot that was already visited.
+        // SAFETY:
+        // The load factor guarantees that an empty slot exists
+        let start = (0..self.buckets)
+            .find(|&idx| unsafe { !self.is_full(idx) })
+            .unwrap();
+        let mut offset = 1;
+        while offset < self.buckets {
+            let idx = (start + offset) & mask;
             // SAFETY:
-            // bucket.ecount never exceeds 16, by the check following this loop
-            let key_val = unsafe { &mut *bucket.entries.get_unchecked_mut(i).as_mut_ptr() };
-
-            if key_val.0.borrow().eq(key) {
-                let val = unsafe { core::ptr::read(key_val) };
-                for j in i..(bucket.ecount - 1) {
-                    unsafe {
-                        core::ptr::write(
-                            bucket.entries.as_mut_ptr().add(j),
-                            core::ptr::read(bucket.entries.as_mut_ptr().add(j + 1)),
-                        );
+            // `idx` is in bounds, and the slot is only accessed if it's full
+            unsafe {
+                if self.is_full(idx) {
+                    let (k, v) = self.entry_at_mut(idx);
+                    if !f(k, v) {
+                        drop(self.remove_at(idx));
+                        // An entry may have been shifted into `idx`
+                        continue;
                     }
                 }
-                return Some(val);
             }
+            offset += 1;
         }
-        None
     }
-}
 
-impl<K: Clone, V: Clone, H: BuildHasher + Clone, A: Allocator + Clone> Clone
-    for HashMap<K, V, H, A>
-{
-    #[allow(clippy::similar_names)]
-    fn clone(&self) -> Self {
-        let buckets = self.buckets;
-        let hash = self.hash.clone();
-        let alloc = self.alloc.clone();
-        let htab = alloc
-            .allocate_zeroed(Layout::array::<HashMapSlot<K, V>>(buckets).unwrap())
-            .unwrap()
-            .cast::<HashMapSlot<K, V>>();
-        let ptr = htab.as_ptr();
-        for i in 0..buckets {
-            let bucket = unsafe { &mut *ptr.add(i) };
-            let obucket = unsafe { &*self.htab.as_ptr().add(i) };
-            for j in 0..obucket.ecount {
-                unsafe {
-                    bucket.entries[j]
-                        .as_mut_ptr()
-                        .write((&*obucket.entries[j].as_ptr()).clone());
+    /// Ensures that at least `additional` more entries can be inserted without the map growing
+    ///
+    /// ## Panics
+    /// Panics if the new capacity overflows `usize`
+    pub fn reserve(&mut self, additional: usize) {
+        handle_reserve(self.try_reserve(additional));
+    }
+
+    /// Ensures that at least `additional` more entries can be inserted without the map growing,
+    /// or returns an error and leaves the map unchanged if the memory can't be allocated
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        let cap = self
+            .items
+            .checked_add(additional)
+            .ok_or(TryReserveError::CapacityOverflow)?;
+        if cap > self.capacity() {
+            self.try_resize(buckets_for(cap)?)?;
+        }
+        Ok(())
+    }
+
+    /// Shrinks the table to the smallest size that holds the current entries, freeing it entirely if the map is empty.
+    ///
+    /// If the smaller table can't be allocated, the map is left unchanged.
+    pub fn shrink_to_fit(&mut self) {
+        // `buckets_for` can't fail for a number of entries that is already stored
+        let buckets = buckets_for(self.items).unwrap_or(self.buckets);
+        if buckets < self.buckets {
+            let _ = self.try_resize(buckets);
+        }
+    }
+}
+
+impl<K: Eq + Hash, V, H: BuildHasher, A: Allocator> HashMap<K, V, H, A> {
+    /// Returns the [`Entry`] for `key`, which allows inspecting and modifying the map in place with a single lookup
+    pub fn entry(&mut self, key: K) -> Entry<'_, K, V, H, A> {
+        match self.find(&key) {
+            Some(idx) => Entry::Occupied(OccupiedEntry { map: self, idx }),
+            None => Entry::Vacant(VacantEntry { map: self, key }),
+        }
+    }
+
+    ///
+    /// Inserts `value` into the map with `key`, returning the existing value in the slot if present
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        handle_reserve(self.try_insert(key, value))
+    }
+
+    /// Inserts `value` into the map with `key`, returning the existing value in the slot if present,
+    /// or an error if the map needs to grow and the memory can't be allocated.
+    ///
+    /// On error, the map is unchanged and `key` and `value` are dropped.
+    pub fn try_insert(&mut self, key: K, value: V) -> Result<Option<V>, TryReserveError> {
+        match self.entry(key) {
+            Entry::Occupied(mut entry) => Ok(Some(entry.insert(value))),
+            Entry::Vacant(entry) => {
+                entry.try_insert(value)?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Returns a mutable reference to the value with the given key, inserting `value` in that slot if necessary
+    pub fn get_or_insert_mut(&mut self, key: K, value: V) -> &mut V {
+        self.entry(key).or_insert(value)
+    }
+
+    /// Returns a mutable reference to the value with the given key, inserting the value produced by `value`
+    pub fn get_or_insert_with_mut<F: FnOnce(&K) -> V>(&mut self, key: K, value: F) -> &mut V {
+        self.entry(key).or_insert_with_key(value)
+    }
+
+    /// Gets an immutable reference to the value given by `key`, if present, otherwise returns `None`.
+    pub fn get<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+    {
+        let idx = self.find(key)?;
+        // SAFETY:
+        // `find` returns a full slot
+        Some(&unsafe { self.entry_at(idx) }.1)
+    }
+
+    /// Gets a mutable reference to the value given by `key`, if present, otherwise returns `None`.
+    pub fn get_mut<Q: ?Sized + Hash + Eq>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+    {
+        let idx = self.find(key)?;
+        // SAFETY:
+        // `find` returns a full slot
+        Some(&mut unsafe { self.entry_at_mut(idx) }.1)
+    }
+
+    /// Checks if the map contains an entry for `key`
+    pub fn contains_key<Q: ?Sized + Hash + Eq>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+    {
+        self.find(key).is_some()
+    }
+
+    /// Removes an entry by Key from this map, and returns the Key and Value
+    pub fn remove<Q: ?Sized + Hash + Eq>(&mut self, key: &Q) -> Option<(K, V)>
+    where
+        K: Borrow<Q>,
+    {
+        let idx = self.find(key)?;
+        // SAFETY:
+        // `find` returns a full slot
+        Some(unsafe { self.remove_at(idx) })
+    }
+}
+
+/// A view into a single entry of a [`HashMap`], which may be vacant or occupied. Returned by [`HashMap::entry`].
+pub enum Entry<'a, K, V, H: BuildHasher, A: Allocator> {
+    Occupied(OccupiedEntry<'a, K, V, H, A>),
+    Vacant(VacantEntry<'a, K, V, H, A>),
+}
+
+/// An entry of a [`HashMap`] that contains a value
+pub struct OccupiedEntry<'a, K, V, H: BuildHasher, A: Allocator> {
+    map: &'a mut HashMap<K, V, H, A>,
+    idx: usize,
+}
+
+/// An entry of a [`HashMap`] that contains no value
+pub struct VacantEntry<'a, K, V, H: BuildHasher, A: Allocator> {
+    map: &'a mut HashMap<K, V, H, A>,
+    key: K,
+}
+
+impl<'a, K: Hash, V, H: BuildHasher, A: Allocator> Entry<'a, K, V, H, A> {
+    /// Returns the key of the entry
+    pub fn key(&self) -> &K {
+        match self {
+            Self::Occupied(entry) => entry.key(),
+            Self::Vacant(entry) => entry.key(),
+        }
+    }
+
+    /// Returns a mutable reference to the value of the entry, inserting `default` if it is vacant
+    pub fn or_insert(self, default: V) -> &'a mut V {
+        match self {
+            Self::Occupied(entry) => entry.into_mut(),
+            Self::Vacant(entry) => entry.insert(default),
+        }
+    }
+
+    /// Returns a mutable reference to the value of the entry, inserting the result of `default` if it is vacant
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Self::Occupied(entry) => entry.into_mut(),
+            Self::Vacant(entry) => entry.insert(default()),
+        }
+    }
+
+    /// Returns a mutable reference to the value of the entry, inserting the result of `default` if it is vacant.
+    ///
+    /// `default` is passed the key of the entry.
+    pub fn or_insert_with_key<F: FnOnce(&K) -> V>(self, default: F) -> &'a mut V {
+        match self {
+            Self::Occupied(entry) => entry.into_mut(),
+            Self::Vacant(entry) => {
+                let value = default(entry.key());
+                entry.insert(value)
+            }
+        }
+    }
+
+    /// Returns a mutable reference to the value of the entry, inserting [`V::default()`](Default::default) if it is vacant
+    pub fn or_default(self) -> &'a mut V
+    where
+        V: Default,
+    {
+        self.or_insert_with(V::default)
+    }
+
+    /// Calls `f` with the value of the entry if it is occupied, and returns the entry
+    pub fn and_modify<F: FnOnce(&mut V)>(mut self, f: F) -> Self {
+        if let Self::Occupied(entry) = &mut self {
+            f(entry.get_mut());
+        }
+        self
+    }
+}
+
+impl<'a, K, V, H: BuildHasher, A: Allocator> OccupiedEntry<'a, K, V, H, A> {
+    fn kv(&self) -> &(K, V) {
+        // SAFETY:
+        // An `OccupiedEntry` always refers to a full slot of the map, which it borrows exclusively
+        unsafe { self.map.entry_at(self.idx) }
+    }
+
+    fn kv_mut(&mut self) -> &mut (K, V) {
+        // SAFETY:
+        // An `OccupiedEntry` always refers to a full slot of the map, which it borrows exclusively
+        unsafe { self.map.entry_at_mut(self.idx) }
+    }
+
+    /// Returns the key of the entry
+    pub fn key(&self) -> &K {
+        &self.kv().0
+    }
+
+    pub fn get(&self) -> &V {
+        &self.kv().1
+    }
+
+    pub fn get_mut(&mut self) -> &mut V {
+        &mut self.kv_mut().1
+    }
+
+    /// Converts the entry into a mutable reference to its value, with the lifetime of the map
+    pub fn into_mut(self) -> &'a mut V {
+        // SAFETY:
+        // An `OccupiedEntry` always refers to a full slot of the map, which it borrows exclusively
+        &mut unsafe { self.map.entry_at_mut(self.idx) }.1
+    }
+
+    /// Replaces the value of the entry, and returns the old value
+    pub fn insert(&mut self, value: V) -> V {
+        core::mem::replace(self.get_mut(), value)
+    }
+}
+
+impl<'a, K: Hash, V, H: BuildHasher, A: Allocator> OccupiedEntry<'a, K, V, H, A> {
+    /// Removes the entry from the map, and returns its value
+    pub fn remove(self) -> V {
+        self.remove_entry().1
+    }
+
+    /// Removes the entry from the map, and returns its key and value
+    pub fn remove_entry(self) -> (K, V) {
+        // SAFETY:
+        // An `OccupiedEntry` always refers to a full slot of the map, which it borrows exclusively
+        unsafe { self.map.remove_at(self.idx) }
+    }
+}
+
+impl<'a, K: Hash, V, H: BuildHasher, A: Allocator> VacantEntry<'a, K, V, H, A> {
+    /// Returns the key that would be used when inserting through this entry
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Returns the key of the entry, without inserting anything
+    pub fn into_key(self) -> K {
+        self.key
+    }
+
+    /// Inserts `value` into the entry, and returns a mutable reference to it
+    pub fn insert(self, value: V) -> &'a mut V {
+        handle_reserve(self.try_insert(value))
+    }
+
+    /// Inserts `value` into the entry, and returns a mutable reference to it,
+    /// or an error if the map needs to grow and the memory can't be allocated
+    pub fn try_insert(self, value: V) -> Result<&'a mut V, TryReserveError> {
+        let idx = self.map.try_insert_unique(self.key, value)?;
+        // SAFETY:
+        // `try_insert_unique` returns the slot it filled
+        Ok(&mut unsafe { self.map.entry_at_mut(idx) }.1)
+    }
+}
+
+/// Iterates over the entries of a table by value, marking each slot empty as its entry is moved out, without deallocating the table
+struct RawIntoIter<K, V> {
+    slots: NonNull<(K, V)>,
+    ctrl: NonNull<u8>,
+    buckets: usize,
+    idx: usize,
+}
+
+impl<K, V> RawIntoIter<K, V> {
+    fn next(&mut self) -> Option<(K, V)> {
+        while self.idx < self.buckets {
+            let idx = self.idx;
+            self.idx += 1;
+            // SAFETY:
+            // `idx` is in bounds, and a full slot is marked empty before its entry is moved out, so each entry is read exactly once
+            unsafe {
+                if *self.ctrl.as_ptr().add(idx) & EMPTY == 0 {
+                    set_ctrl(self.ctrl, self.buckets, idx, EMPTY);
+                    return Some(self.slots.as_ptr().add(idx).read());
                 }
             }
         }
-        Self {
-            htab,
-            buckets,
-            hash,
-            alloc,
+        None
+    }
+}
+
+impl<K, V> Drop for RawIntoIter<K, V> {
+    fn drop(&mut self) {
+        while self.next().is_some() {}
+    }
+}
+
+/// A draining [`Iterator`] over the entries of a [`HashMap`], returned by [`HashMap::drain`]
+pub struct Drain<'a, K, V, H: BuildHasher, A: Allocator> {
+    raw: RawIntoIter<K, V>,
+    map: &'a mut HashMap<K, V, H, A>,
+}
+
+impl<K, V, H: BuildHasher, A: Allocator> Iterator for Drain<'_, K, V, H, A> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        self.raw.next()
+    }
+}
+
+impl<K, V, H: BuildHasher, A: Allocator> FusedIterator for Drain<'_, K, V, H, A> {}
+
+impl<K, V, H: BuildHasher, A: Allocator> Drop for Drain<'_, K, V, H, A> {
+    fn drop(&mut self) {
+        while self.raw.next().is_some() {}
+        // Every slot has been emptied, so the table can be handed back
+        self.map.slots = self.raw.slots;
+        self.map.ctrl = self.raw.ctrl;
+        self.map.buckets = self.raw.buckets;
+        self.raw.buckets = 0;
+    }
+}
+
+/// An owning [`Iterator`] over the entries of a [`HashMap`]
+pub struct IntoIter<K, V, A: Allocator = Global> {
+    raw: RawIntoIter<K, V>,
+    alloc: A,
+}
+
+impl<K, V, A: Allocator> Iterator for IntoIter<K, V, A> {
+    type Item = (K, V);
+
+    fn next(&mut self) -> Option<(K, V)> {
+        self.raw.next()
+    }
+}
+
+impl<K, V, A: Allocator> FusedIterator for IntoIter<K, V, A> {}
+
+impl<K, V, A: Allocator> Drop for IntoIter<K, V, A> {
+    fn drop(&mut self) {
+        while self.raw.next().is_some() {}
+        if self.raw.buckets != 0 {
+            // SAFETY:
+            // The table was allocated from `alloc` with `buckets` slots, and has been emptied
+            unsafe {
+                let (layout, _) = table_layout::<K, V>(self.raw.buckets).unwrap();
+                self.alloc.deallocate(self.raw.slots.cast(), layout);
+            }
         }
     }
 }
 
-/// An [`Iterator`] over the keys and values of a [`HashMap`]
-pub struct Iter<'a, K, V>(
-    core::slice::Iter<'a, HashMapSlot<K, V>>,
-    Option<(&'a HashMapSlot<K, V>, usize)>,
-);
-
-impl<'a, K, V> Iter<'a, K, V> {
-    fn current_slot(&mut self) -> core::option::Option<(&'a HashMapSlot<K, V>, usize)> {
-        if let Some((slot, offset)) = self.1 {
-            Some((slot, offset))
-        } else {
-            let x = self.0.next()?;
-            self.1 = Some((x, 0));
-            Some((x, 0))
+impl<K, V, H: BuildHasher, A: Allocator> IntoIterator for HashMap<K, V, H, A> {
+    type Item = (K, V);
+
+    type IntoIter = IntoIter<K, V, A>;
+
+    fn into_iter(self) -> IntoIter<K, V, A> {
+        let this = core::mem::ManuallyDrop::new(self);
+        // SAFETY:
+        // `this` is never used or dropped again, so the hasher and allocator are moved out exactly once, and the table is owned by the iterator
+        unsafe {
+            drop(core::ptr::read(&this.hash));
+            IntoIter {
+                raw: RawIntoIter {
+                    slots: this.slots,
+                    ctrl: this.ctrl,
+                    buckets: this.buckets,
+                    idx: 0,
+                },
+                alloc: core::ptr::read(&this.alloc),
+            }
+        }
+    }
+}
+
+impl<K: Clone, V: Clone, H: BuildHasher + Clone, A: Allocator + Clone> Clone
+    for HashMap<K, V, H, A>
+{
+    fn clone(&self) -> Self {
+        let mut ret = Self::with_hasher_in(self.hash.clone(), self.alloc.clone());
+        if self.buckets != 0 {
+            (ret.slots, ret.ctrl) = handle_reserve(ret.try_alloc_table(self.buckets));
+            ret.buckets = self.buckets;
+        }
+        // Entries are cloned into the same slots, with the same control bytes.
+        // Each slot is marked full only once its entry is written, so a panicking `clone` drops exactly the entries cloned so far.
+        for idx in 0..self.buckets {
+            // SAFETY:
+            // Both tables have `self.buckets` slots, and only full slots of `self` are read
+            unsafe {
+                if self.is_full(idx) {
+                    let entry = self.entry_at(idx).clone();
+                    ret.slots.as_ptr().add(idx).write(entry);
+                    set_ctrl(ret.ctrl, ret.buckets, idx, *self.ctrl.as_ptr().add(idx));
+                    ret.items += 1;
+                }
+            }
         }
+        ret
     }
 }
 
-#[allow(clippy::borrow_as_ptr)]
+/// An [`Iterator`] over the keys and values of a [`HashMap`]
+pub struct Iter<'a, K, V> {
+    slots: *const (K, V),
+    ctrl: *const u8,
+    buckets: usize,
+    idx: usize,
+    phantom: PhantomData<&'a (K, V)>,
+}
+
 impl<'a, K, V> Iterator for Iter<'a, K, V> {
     type Item = &'a (K, V);
 
     fn next(&mut self) -> Option<Self::Item> {
-        let (mut slot, mut offset) = self.current_slot()?;
-
-        while offset == slot.ecount {
-            slot = self.0.next()?;
-            offset = 0;
-            self.1 = Some((slot, 0));
+        while self.idx < self.buckets {
+            let idx = self.idx;
+            self.idx += 1;
+            // SAFETY:
+            // `idx` is in bounds, and only full slots are read
+            unsafe {
+                if *self.ctrl.add(idx) & EMPTY == 0 {
+                    return Some(&*self.slots.add(idx));
+                }
+            }
         }
-        self.1.as_mut().unwrap().1 += 1;
-        Some(unsafe { &*(&slot.entries[offset] as *const MaybeUninit<(K, V)>).cast::<(K, V)>() })
+        None
     }
 }
 
+impl<'a, K, V> FusedIterator for Iter<'a, K, V> {}
+
 /// An [`Iterator`] over the values of a [`HashMap`]
 pub struct Values<'a, K, V>(Iter<'a, K, V>);
 
@@ -566,18 +1253,7 @@ impl<K: Eq + Hash, V: PartialEq, H: BuildHasher, A: Allocator> PartialEq for Has
         if core::ptr::eq(self, other) {
             return true;
         }
-        for (k, v) in self {
-            if other.get(k).map_or(false, |v2| v.eq(v2)) {
-                return false;
-            }
-        }
-        for (k, v) in other {
-            if self.get(k).map_or(false, |v2| v.eq(v2)) {
-                return false;
-            }
-        }
-
-        true
+        self.len() == other.len() && self.iter().all(|(k, v)| other.get(k) == Some(v))
     }
 }
 
@@ -603,6 +1279,8 @@ impl<K: Eq + Hash, V, H: BuildHasher, A: Allocator> Extend<(K, V)> for HashMap<K
     where
         I: IntoIterator<Item = (K, V)>,
     {
+        let it = it.into_iter();
+        self.reserve(it.size_hint().0);
         for (k, v) in it {
             self.insert(k, v);
         }
@@ -611,45 +1289,30 @@ impl<K: Eq + Hash, V, H: BuildHasher, A: Allocator> Extend<(K, V)> for HashMap<K
 
 /// An [`Iterator`] over the keys and mutable values of a [`HashMap`].
 pub struct IterMut<'a, K, V> {
-    buckets: *mut HashMapSlot<K, V>,
-    buckets_end: *mut HashMapSlot<K, V>,
-    bucket_position: usize,
-    phantom: PhantomData<&'a mut [HashMapSlot<K, V>]>,
-}
-
-impl<'a, K, V> IterMut<'a, K, V> {
-    fn next_item(&mut self) -> Option<&'a mut (K, V)> {
-        if self.buckets == self.buckets_end {
-            None
-        } else {
-            let mut ecount = unsafe { (*self.buckets).ecount };
-            let mut entries =
-                unsafe { core::ptr::addr_of_mut!((*self.buckets).entries) }.cast::<(K, V)>();
-
-            while self.bucket_position >= ecount {
-                self.bucket_position = 0;
-                self.buckets = unsafe { self.buckets.offset(1) };
-                if self.buckets == self.buckets_end {
-                    return None;
-                }
-                ecount = unsafe { (*self.buckets).ecount };
-                entries =
-                    unsafe { core::ptr::addr_of_mut!((*self.buckets).entries) }.cast::<(K, V)>();
-            }
-
-            let ret = unsafe { entries.add(self.bucket_position) };
-            self.bucket_position += 1;
-
-            Some(unsafe { &mut *ret })
-        }
-    }
+    slots: *mut (K, V),
+    ctrl: *const u8,
+    buckets: usize,
+    idx: usize,
+    phantom: PhantomData<&'a mut (K, V)>,
 }
 
 impl<'a, K, V> Iterator for IterMut<'a, K, V> {
     type Item = (&'a K, &'a mut V);
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.next_item().map(|(k, v)| (k as &K, v))
+        while self.idx < self.buckets {
+            let idx = self.idx;
+            self.idx += 1;
+            // SAFETY:
+            // `idx` is in bounds, only full slots are read, and each slot is yielded at most once
+            unsafe {
+                if *self.ctrl.add(idx) & EMPTY == 0 {
+                    let (k, v) = &mut *self.slots.add(idx);
+                    return Some((k, v));
+                }
+            }
+        }
+        None
     }
 }
 
@@ -727,6 +1390,14 @@ impl<K, H: BuildHasher + Default, A: Allocator> HashSet<K, H, A> {
             inner: HashMap::new_in(alloc),
         }
     }
+
+    /// Constructs a new [`HashSet`] with the given allocator and a [`Default`] hasher, which can hold at least `cap` elements without reallocating,
+    /// or returns an error if the memory can't be allocated
+    pub fn try_with_capacity_in(cap: usize, alloc: A) -> Result<Self, TryReserveError> {
+        Ok(Self {
+            inner: HashMap::try_with_capacity_in(cap, alloc)?,
+        })
+    }
 }
 
 impl<K, H: BuildHasher, A: Allocator> HashSet<K, H, A> {
@@ -742,6 +1413,21 @@ impl<K, H: BuildHasher, A: Allocator> HashSet<K, H, A> {
     pub fn iter(&self) -> SetIter<'_, K> {
         SetIter(self.inner.keys())
     }
+
+    /// Returns the number of elements in the set
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Returns `true` if the set contains no elements
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// Removes every element from the set, keeping the allocated memory
+    pub fn clear(&mut self) {
+        self.inner.clear();
+    }
 }
 
 impl<K: Eq + Hash, H: BuildHasher, A: Allocator> HashSet<K, H, A> {
@@ -758,14 +1444,32 @@ impl<K: Eq + Hash, H: BuildHasher, A: Allocator> HashSet<K, H, A> {
     /// Inserts `val` into the set if it is not already present (according to the [`Eq`] implementation), or returns it (as an Err) instead.
     #[allow(clippy::missing_errors_doc)] // Not an Error to return the `K`
     pub fn insert(&mut self, val: K) -> Result<(), K> {
-        if self.inner.get(&val).is_some() {
-            Err(val)
+        handle_reserve(self.try_insert(val))
+    }
+
+    /// Inserts `val` into the set if it is not already present, or returns it (as an inner Err) instead.
+    ///
+    /// Returns an error if the set needs to grow and the memory can't be allocated, in which case `val` is dropped.
+    pub fn try_insert(&mut self, val: K) -> Result<Result<(), K>, TryReserveError> {
+        if self.inner.find(&val).is_some() {
+            Ok(Err(val))
         } else {
-            self.inner.insert(val, ());
-            Ok(())
+            self.inner.try_insert_unique(val, ())?;
+            Ok(Ok(()))
         }
     }
 
+    /// Ensures that at least `additional` more elements can be inserted without the set growing,
+    /// or returns an error and leaves the set unchanged if the memory can't be allocated
+    pub fn try_reserve(&mut self, additional: usize) -> Result<(), TryReserveError> {
+        self.inner.try_reserve(additional)
+    }
+
+    /// Ensures that at least `additional` more elements can be inserted without the set growing
+    pub fn reserve(&mut self, additional: usize) {
+        self.inner.reserve(additional);
+    }
+
     /// Removes a value from the set and returns it if present
     pub fn remove<Q: ?Sized + Hash + Eq>(&mut self, val: &Q) -> Option<K>
     where
@@ -831,9 +1535,21 @@ impl<'a, K: 'a, H: BuildHasher, A: Allocator> IntoIterator for &'a HashSet<K, H,
     }
 }
 
+/// A bounded queue that any number of threads can push to and pop from concurrently, without locking.
+///
+/// Producers reserve slots by advancing `reserved_head` and publish them in order by advancing `write_head`.
+/// Consumers claim slots by advancing `read_head` and release them in order by advancing `tail`.
+/// The positions count every slot ever used rather than wrapping at the capacity, so that a thread that stalls
+/// while the buffer goes around in full can't mistake a new position for the one it read.
+/// The number of slots is the capacity rounded up to a power of two, so that positions still map to consecutive slots when they wrap around `usize::MAX`.
+///
+/// Slots are published and released in the order they were reserved, so a thread that is preempted between the two
+/// stalls every other producer or consumer behind it. Callers should not be preempted while pushing or popping.
 pub struct RingBuffer<T, A: Allocator = Global> {
     base: NonNull<T>,
     capacity: usize,
+    // The number of slots, minus one
+    mask: usize,
     write_head: AtomicUsize,
     reserved_head: AtomicUsize,
     read_head: AtomicUsize,
@@ -847,108 +1563,1048 @@ impl<T> RingBuffer<T> {
     }
 }
 
+impl<T> RingBuffer<T> {
+    pub fn try_with_capacity(cap: usize) -> Result<Self, TryReserveError> {
+        Self::try_with_capacity_in(cap, Global)
+    }
+}
+
 impl<T, A: Allocator> RingBuffer<T, A> {
     pub fn with_capacity_in(cap: usize, alloc: A) -> Self {
+        handle_reserve(Self::try_with_capacity_in(cap, alloc))
+    }
+
+    /// Creates a ring buffer that holds up to `cap` elements allocated from `alloc`, or returns an error if the memory can't be allocated
+    pub fn try_with_capacity_in(cap: usize, alloc: A) -> Result<Self, TryReserveError> {
+        let slots = cap
+            .checked_next_power_of_two()
+            .ok_or(TryReserveError::CapacityOverflow)?;
+        let layout = Layout::array::<T>(slots).map_err(|_| TryReserveError::CapacityOverflow)?;
         let base = alloc
-            .allocate_zeroed(
-                Layout::from_size_align(
-                    cap * core::mem::size_of::<T>(),
-                    core::mem::align_of::<T>(),
-                )
-                .unwrap(),
-            )
-            .unwrap()
+            .allocate(layout)
+            .map_err(|_| TryReserveError::AllocError { layout })?
             .cast();
 
-        Self {
+        Ok(Self {
             base,
             capacity: cap,
+            mask: slots - 1,
             alloc,
             write_head: AtomicUsize::new(0),
             reserved_head: AtomicUsize::new(0),
             read_head: AtomicUsize::new(0),
             tail: AtomicUsize::new(0),
-        }
+        })
     }
-}
 
-impl<T, A: Allocator> Drop for RingBuffer<T, A> {
-    fn drop(&mut self) {
-        let layout = Layout::from_size_align(
-            self.capacity * core::mem::size_of::<T>(),
-            core::mem::align_of::<T>(),
-        )
-        .unwrap();
-        unsafe { self.alloc.deallocate(self.base.cast(), layout) }
+    /// Returns the maximum number of elements the buffer can hold
+    pub fn capacity(&self) -> usize {
+        self.capacity
     }
-}
 
-impl<T: Copy, A: Allocator> RingBuffer<T, A> {
-    pub fn push(&self, val: T) -> Result<(), T> {
-        let mut write = self.reserved_head.load(Ordering::Acquire);
-        loop {
-            let next = (write + 1) % self.capacity;
+    /// Returns the number of elements in the buffer.
+    ///
+    /// Elements that are being pushed or popped concurrently may or may not be counted.
+    pub fn len(&self) -> usize {
+        // `tail` never passes `write_head`, so loading it first keeps the difference from going negative
+        let tail = self.tail.load(Ordering::Acquire);
+        let write = self.write_head.load(Ordering::Acquire);
+        write.wrapping_sub(tail)
+    }
 
-            if next == self.tail.load(Ordering::Acquire) {
-                return Err(val); // push failed
-            }
+    /// Returns `true` if there are no elements to pop
+    pub fn is_empty(&self) -> bool {
+        let read = self.read_head.load(Ordering::Acquire);
+        read == self.write_head.load(Ordering::Acquire)
+    }
 
-            match self.reserved_head.compare_exchange_weak(
-                write,
-                next,
-                Ordering::Acquire,
-                Ordering::Relaxed,
-            ) {
-                Ok(pos) => {
-                    unsafe {
-                        self.base.as_ptr().add(pos).write(val);
-                    }
-                    while self.write_head.load(Ordering::Acquire) != write {
-                        core::hint::spin_loop()
-                    }
-                    self.write_head.swap(next, Ordering::Release); //  We swap here, instead of store, to carry the release chain forward.
-                    return Ok(());
-                }
-                Err(real) => {
-                    write = real;
-                }
+    /// Returns `true` if there is no room to push an element
+    pub fn is_full(&self) -> bool {
+        let tail = self.tail.load(Ordering::Acquire);
+        self.reserved_head
+            .load(Ordering::Acquire)
+            .wrapping_sub(tail)
+            >= self.capacity
+    }
+
+    /// Returns a pointer to the slot at `pos`
+    fn slot(&self, pos: usize) -> *mut T {
+        // SAFETY:
+        // The index is less than the number of slots
+        unsafe { self.base.as_ptr().add(pos & self.mask) }
+    }
+
+    /// Returns how many of `n` slots starting at `pos` come before the end of the buffer
+    fn contiguous(&self, pos: usize, n: usize) -> usize {
+        n.min(self.mask + 1 - (pos & self.mask))
+    }
+
+    /// Reserves up to `max` slots for writing, and returns the position of the first one and the number reserved
+    fn reserve_write(&self, max: usize) -> (usize, usize) {
+        let mut write = self.reserved_head.load(Ordering::Acquire);
+        loop {
+            let used = write.wrapping_sub(self.tail.load(Ordering::Acquire));
+            if used > self.capacity {
+                // `write` is stale, and consumers have since released slots past it
+                write = self.reserved_head.load(Ordering::Acquire);
+                continue;
+            }
+            let n = (self.capacity - used).min(max);
+            if n == 0 {
+                return (write, 0);
+            }
+
+            match self.reserved_head.compare_exchange_weak(
+                write,
+                write.wrapping_add(n),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return (write, n),
+                Err(real) => write = real,
             }
         }
     }
 
-    pub fn pop(&self) -> Result<T, ()> {
+    /// Publishes `n` slots starting at `write`, once every slot reserved before them has been published
+    fn commit_write(&self, write: usize, n: usize) {
+        while self.write_head.load(Ordering::Acquire) != write {
+            spin_loop()
+        }
+        self.write_head
+            .store(write.wrapping_add(n), Ordering::Release);
+    }
+
+    /// Claims up to `max` published slots for reading, and returns the position of the first one and the number claimed
+    fn reserve_read(&self, max: usize) -> (usize, usize) {
         let mut read = self.read_head.load(Ordering::Acquire);
         loop {
-            let next = (read + 1) % self.capacity;
-
-            let write = self.write_head.load(Ordering::Relaxed);
-
-            if read == write {
-                return Err(());
+            let avail = self.write_head.load(Ordering::Acquire).wrapping_sub(read);
+            let n = avail.min(max);
+            if n == 0 {
+                return (read, 0);
             }
 
             match self.read_head.compare_exchange_weak(
                 read,
-                next,
+                read.wrapping_add(n),
                 Ordering::AcqRel,
-                Ordering::Relaxed,
+                Ordering::Acquire,
             ) {
-                Ok(pos) => {
-                    let val = unsafe { self.base.as_ptr().add(pos).read() };
-                    while self.tail.load(Ordering::Acquire) != write {
-                        core::hint::spin_loop()
-                    }
-                    self.read_head.swap(next, Ordering::Release);
-                    return Ok(val);
-                }
-                Err(real) => {
-                    read = real;
-                }
+                Ok(_) => return (read, n),
+                Err(real) => read = real,
             }
         }
     }
+
+    /// Releases `n` slots starting at `read` to producers, once every slot claimed before them has been released
+    fn commit_read(&self, read: usize, n: usize) {
+        while self.tail.load(Ordering::Acquire) != read {
+            spin_loop()
+        }
+        self.tail.store(read.wrapping_add(n), Ordering::Release);
+    }
+
+    /// Pushes `val` to the back of the buffer, or returns it if the buffer is full
+    pub fn push(&self, val: T) -> Result<(), T> {
+        let (write, n) = self.reserve_write(1);
+        if n == 0 {
+            return Err(val); // push failed
+        }
+        // SAFETY:
+        // The slot was reserved by this call, and consumers can't read it until it is committed
+        unsafe {
+            self.slot(write).write(val);
+        }
+        self.commit_write(write, 1);
+        Ok(())
+    }
+
+    /// Pops the element at the front of the buffer, or returns `None` if the buffer is empty
+    pub fn pop(&self) -> Option<T> {
+        let (read, n) = self.reserve_read(1);
+        if n == 0 {
+            return None;
+        }
+        // SAFETY:
+        // The slot was published by a producer and claimed by this call, and producers can't reuse it until it is released
+        let val = unsafe { self.slot(read).read() };
+        self.commit_read(read, 1);
+        Some(val)
+    }
+
+    /// Removes every element from the buffer, returning them in an [`Iterator`].
+    ///
+    /// Elements that are not yielded are dropped when the iterator is dropped.
+    pub fn drain(&mut self) -> RingDrain<'_, T, A> {
+        RingDrain(self)
+    }
+}
+
+impl<T: Copy, A: Allocator> RingBuffer<T, A> {
+    /// Pushes as many elements from the front of `vals` as fit in the buffer, and returns how many were pushed.
+    ///
+    /// The elements are reserved and published together, so they are contiguous in the buffer even with concurrent producers.
+    /// `T: Copy` ensures that nothing can panic while the slots are reserved.
+    pub fn push_slice(&self, vals: &[T]) -> usize {
+        let (write, n) = self.reserve_write(vals.len());
+        if n == 0 {
+            return 0;
+        }
+        let first = self.contiguous(write, n);
+        // SAFETY:
+        // The `n` slots starting at `write`, wrapping around the end of the buffer, were reserved by this call
+        unsafe {
+            core::ptr::copy_nonoverlapping(vals.as_ptr(), self.slot(write), first);
+            core::ptr::copy_nonoverlapping(vals.as_ptr().add(first), self.base.as_ptr(), n - first);
+        }
+        self.commit_write(write, n);
+        n
+    }
+
+    /// Pops as many elements as are available into the front of `out`, and returns how many were popped.
+    ///
+    /// `T: Copy` ensures that nothing can panic while the slots are claimed.
+    pub fn pop_into(&self, out: &mut [T]) -> usize {
+        let (read, n) = self.reserve_read(out.len());
+        if n == 0 {
+            return 0;
+        }
+        let first = self.contiguous(read, n);
+        // SAFETY:
+        // The `n` slots starting at `read`, wrapping around the end of the buffer, were published and then claimed by this call
+        unsafe {
+            core::ptr::copy_nonoverlapping(self.slot(read), out.as_mut_ptr(), first);
+            core::ptr::copy_nonoverlapping(
+                self.base.as_ptr(),
+                out.as_mut_ptr().add(first),
+                n - first,
+            );
+        }
+        self.commit_read(read, n);
+        n
+    }
+}
+
+impl<T, A: Allocator> Drop for RingBuffer<T, A> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+        // This layout was already checked in `try_with_capacity_in`
+        let layout = Layout::array::<T>(self.mask + 1).unwrap();
+        unsafe { self.alloc.deallocate(self.base.cast(), layout) }
+    }
+}
+
+impl<T: Debug, A: Allocator> Debug for RingBuffer<T, A> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("RingBuffer")
+            .field("len", &self.len())
+            .field("capacity", &self.capacity())
+            .finish_non_exhaustive()
+    }
 }
 
 unsafe impl<T: Send, A: Allocator> Sync for RingBuffer<T, A> {}
 
 unsafe impl<T: Send, A: Allocator + Send> Send for RingBuffer<T, A> {}
+
+/// A draining [`Iterator`] over the elements of a [`RingBuffer`], returned by [`RingBuffer::drain`]
+pub struct RingDrain<'a, T, A: Allocator>(&'a mut RingBuffer<T, A>);
+
+impl<T, A: Allocator> Iterator for RingDrain<'_, T, A> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.0.pop()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.0.len();
+        (len, Some(len))
+    }
+}
+
+impl<T, A: Allocator> ExactSizeIterator for RingDrain<'_, T, A> {}
+
+impl<T, A: Allocator> FusedIterator for RingDrain<'_, T, A> {}
+
+impl<T, A: Allocator> Drop for RingDrain<'_, T, A> {
+    fn drop(&mut self) {
+        while self.0.pop().is_some() {}
+    }
+}
+
+/// A [`RingBuffer`] that blocks callers on a [`WaitQueue`] while it is full or empty, instead of failing.
+pub struct BlockingRingBuffer<T, W: WaitQueue, A: Allocator = Global> {
+    buf: RingBuffer<T, A>,
+    not_full: W,
+    not_empty: W,
+}
+
+impl<T, W: WaitQueue + Default> BlockingRingBuffer<T, W> {
+    pub fn with_capacity(cap: usize) -> Self {
+        Self::with_capacity_in(cap, Global)
+    }
+
+    pub fn try_with_capacity(cap: usize) -> Result<Self, TryReserveError> {
+        Self::try_with_capacity_in(cap, Global)
+    }
+}
+
+impl<T, W: WaitQueue + Default, A: Allocator> BlockingRingBuffer<T, W, A> {
+    pub fn with_capacity_in(cap: usize, alloc: A) -> Self {
+        Self::from_parts(
+            RingBuffer::with_capacity_in(cap, alloc),
+            W::default(),
+            W::default(),
+        )
+    }
+
+    /// Creates a blocking ring buffer that holds up to `cap` elements allocated from `alloc`, or returns an error if the memory can't be allocated
+    pub fn try_with_capacity_in(cap: usize, alloc: A) -> Result<Self, TryReserveError> {
+        Ok(Self::from_parts(
+            RingBuffer::try_with_capacity_in(cap, alloc)?,
+            W::default(),
+            W::default(),
+        ))
+    }
+}
+
+impl<T, W: WaitQueue, A: Allocator> BlockingRingBuffer<T, W, A> {
+    /// Wraps `buf`, blocking producers on `not_full` and consumers on `not_empty`
+    pub fn from_parts(buf: RingBuffer<T, A>, not_full: W, not_empty: W) -> Self {
+        Self {
+            buf,
+            not_full,
+            not_empty,
+        }
+    }
+
+    /// Returns the maximum number of elements the buffer can hold
+    pub fn capacity(&self) -> usize {
+        self.buf.capacity()
+    }
+
+    /// Returns the number of elements in the buffer.
+    ///
+    /// Elements that are being pushed or popped concurrently may or may not be counted.
+    pub fn len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Returns `true` if there are no elements to pop
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Returns `true` if there is no room to push an element
+    pub fn is_full(&self) -> bool {
+        self.buf.is_full()
+    }
+
+    /// Pushes `val` to the back of the buffer, waiting on the `not_full` queue while the buffer is full
+    pub fn push(&self, mut val: T) {
+        loop {
+            match self.buf.push(val) {
+                Ok(()) => {
+                    self.not_empty.wake_one();
+                    return;
+                }
+                Err(v) => {
+                    val = v;
+                    self.not_full.wait_while(&mut || self.buf.is_full());
+                }
+            }
+        }
+    }
+
+    /// Pops the element at the front of the buffer, waiting on the `not_empty` queue while the buffer is empty
+    pub fn pop(&self) -> T {
+        loop {
+            if let Some(val) = self.buf.pop() {
+                self.not_full.wake_one();
+                return val;
+            }
+            self.not_empty.wait_while(&mut || self.buf.is_empty());
+        }
+    }
+
+    /// Pushes `val` to the back of the buffer, or returns it if the buffer is full
+    pub fn try_push(&self, val: T) -> Result<(), T> {
+        self.buf.push(val)?;
+        self.not_empty.wake_one();
+        Ok(())
+    }
+
+    /// Pops the element at the front of the buffer, or returns `None` if the buffer is empty
+    pub fn try_pop(&self) -> Option<T> {
+        let val = self.buf.pop()?;
+        self.not_full.wake_one();
+        Some(val)
+    }
+
+    /// Removes every element from the buffer, returning them in an [`Iterator`].
+    ///
+    /// Nobody can be waiting on the buffer while it is mutably borrowed, so no waiters are woken.
+    pub fn drain(&mut self) -> RingDrain<'_, T, A> {
+        self.buf.drain()
+    }
+}
+
+impl<T: Copy, W: WaitQueue, A: Allocator> BlockingRingBuffer<T, W, A> {
+    /// Pushes every element of `vals`, waiting on the `not_full` queue whenever the buffer is full
+    pub fn push_slice(&self, mut vals: &[T]) {
+        while !vals.is_empty() {
+            let n = self.buf.push_slice(vals);
+            if n == 0 {
+                self.not_full.wait_while(&mut || self.buf.is_full());
+            } else {
+                vals = &vals[n..];
+                self.not_empty.wake_all();
+            }
+        }
+    }
+
+    /// Pops at least one element into the front of `out`, waiting on the `not_empty` queue while the buffer is empty,
+    /// and returns how many were popped. Returns 0 without waiting if `out` is empty.
+    pub fn pop_into(&self, out: &mut [T]) -> usize {
+        if out.is_empty() {
+            return 0;
+        }
+        loop {
+            let n = self.buf.pop_into(out);
+            if n != 0 {
+                self.not_full.wake_all();
+                return n;
+            }
+            self.not_empty.wait_while(&mut || self.buf.is_empty());
+        }
+    }
+}
+
+impl<T: Debug, W: WaitQueue, A: Allocator> Debug for BlockingRingBuffer<T, W, A> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        self.buf.fmt(f)
+    }
+}
+
+/// A key of an [`IntervalMap`] that can be offset and aligned, which is needed to search for free gaps between intervals
+pub trait IntervalKey: Ord + Copy {
+    /// Returns `self` advanced by `len`, or `None` if that overflows
+    fn checked_add(self, len: usize) -> Option<Self>;
+
+    /// Returns `self` rounded up to a multiple of `align`, which is a power of two, or `None` if that overflows
+    fn align_up(self, align: usize) -> Option<Self>;
+}
+
+macro_rules! impl_interval_key_for_int {
+    ($($ty:ty),* $(,)?) => {
+        $(
+            impl IntervalKey for $ty {
+                fn checked_add(self, len: usize) -> Option<Self> {
+                    <$ty>::checked_add(self, <$ty>::try_from(len).ok()?)
+                }
+
+                fn align_up(self, align: usize) -> Option<Self> {
+                    debug_assert!(align.is_power_of_two());
+                    let mask = <$ty>::try_from(align).ok()? - 1;
+                    Some(<$ty>::checked_add(self, mask)? & !mask)
+                }
+            }
+        )*
+    };
+}
+
+impl_interval_key_for_int!(u32, u64, u128, usize);
+
+/// A map from disjoint half-open ranges of keys to values, backed by a [`BTreeMap`] that obtains memory from an [`Allocator`].
+///
+/// Inserting a range overwrites the parts of any existing intervals it overlaps, splitting them as needed.
+/// Adjacent intervals are kept separate until [`IntervalMap::merge_adjacent`] is called.
+pub struct IntervalMap<K, V, A: Allocator + Clone = Global> {
+    /// Each interval is keyed by its start, and stores its end alongside its value
+    inner: BTreeMap<K, (K, V), A>,
+}
+
+impl<K, V> IntervalMap<K, V> {
+    /// Returns a new [`IntervalMap`] with the global allocator, containing no intervals
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            inner: BTreeMap::new(),
+        }
+    }
+}
+
+impl<K, V> Default for IntervalMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Clone, V: Clone, A: Allocator + Clone> Clone for IntervalMap<K, V, A> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<K, V, A: Allocator + Clone> IntervalMap<K, V, A> {
+    /// Returns a new [`IntervalMap`] with the given allocator, containing no intervals
+    pub const fn new_in(alloc: A) -> Self {
+        Self {
+            inner: BTreeMap::new_in(alloc),
+        }
+    }
+
+    /// Returns the number of intervals in the map
+    pub fn len(&self) -> usize {
+        self.inner.len()
+    }
+
+    /// Returns `true` if the map contains no intervals
+    pub fn is_empty(&self) -> bool {
+        self.inner.is_empty()
+    }
+
+    /// Removes every interval from the map
+    pub fn clear(&mut self) {
+        self.inner.clear()
+    }
+}
+
+impl<K: Ord + Clone, V, A: Allocator + Clone> IntervalMap<K, V, A> {
+    /// Returns the start of the interval containing `key`, if any
+    fn start_of(&self, key: &K) -> Option<&K> {
+        let (start, (end, _)) = self.inner.range(..=key).next_back()?;
+        (key < end).then_some(start)
+    }
+
+    /// Returns the interval that contains `key` and its value, if present
+    pub fn get(&self, key: &K) -> Option<(Range<K>, &V)> {
+        let (start, (end, val)) = self.inner.range(..=key).next_back()?;
+        (key < end).then(|| (start.clone()..end.clone(), val))
+    }
+
+    /// Returns the interval that contains `key` and a mutable reference to its value, if present
+    pub fn get_mut(&mut self, key: &K) -> Option<(Range<K>, &mut V)> {
+        let (start, (end, val)) = self.inner.range_mut(..=key).next_back()?;
+        (*key < *end).then(|| (start.clone()..end.clone(), val))
+    }
+
+    /// Checks if any interval contains `key`
+    pub fn contains(&self, key: &K) -> bool {
+        self.start_of(key).is_some()
+    }
+
+    /// Produces an [`Iterator`] over the intervals of the map and their values, in ascending order
+    pub fn iter(&self) -> IntervalIter<'_, K, V> {
+        IntervalIter {
+            inner: self.inner.range(..),
+        }
+    }
+
+    /// Produces an [`Iterator`] over the intervals that overlap `range` and their values, in ascending order.
+    ///
+    /// The intervals are yielded whole, so the first and last may extend past `range`.
+    pub fn overlapping(&self, range: Range<K>) -> IntervalIter<'_, K, V> {
+        if range.start >= range.end {
+            return IntervalIter {
+                inner: self.inner.range(range.start.clone()..range.start),
+            };
+        }
+        let start = self.start_of(&range.start).cloned().unwrap_or(range.start);
+        IntervalIter {
+            inner: self.inner.range(start..range.end),
+        }
+    }
+
+    /// Checks if any interval overlaps `range`
+    pub fn overlaps(&self, range: Range<K>) -> bool {
+        self.overlapping(range).next().is_some()
+    }
+}
+
+impl<K: Ord + Clone, V: Clone, A: Allocator + Clone> IntervalMap<K, V, A> {
+    /// Removes `range` from every interval it overlaps, splitting an interval that extends past both ends of `range` in two.
+    ///
+    /// Returns `true` if any interval overlapped `range`.
+    pub fn remove(&mut self, range: Range<K>) -> bool {
+        if range.start >= range.end {
+            return false;
+        }
+        let mut removed = false;
+
+        // An interval starting before `range` keeps its head, and its tail if it ends after `range`
+        if let Some((_, (end, val))) = self.inner.range_mut(..&range.start).next_back() {
+            if *end > range.start {
+                removed = true;
+                let old_end = core::mem::replace(end, range.start.clone());
+                if old_end > range.end {
+                    let tail = val.clone();
+                    self.inner.insert(range.end.clone(), (old_end, tail));
+                    return true;
+                }
+            }
+        }
+
+        // Intervals starting within `range` are removed, keeping the tail of the last one if it ends after `range`
+        while let Some(start) = self
+            .inner
+            .range(&range.start..&range.end)
+            .next()
+            .map(|(start, _)| start.clone())
+        {
+            removed = true;
+            let (end, val) = self.inner.remove(&start).unwrap();
+            if end > range.end {
+                self.inner.insert(range.end.clone(), (end, val));
+                break;
+            }
+        }
+
+        removed
+    }
+
+    /// Maps every key in `range` to `value`, overwriting the parts of existing intervals that overlap it.
+    ///
+    /// Inserting an empty range does nothing.
+    pub fn insert(&mut self, range: Range<K>, value: V) {
+        if range.start >= range.end {
+            return;
+        }
+        self.remove(range.clone());
+        self.inner.insert(range.start, (range.end, value));
+    }
+}
+
+impl<K: Ord + Clone, V: PartialEq, A: Allocator + Clone> IntervalMap<K, V, A> {
+    /// Merges each interval into the previous one if the previous one ends where it starts and both have equal values
+    pub fn merge_adjacent(&mut self) {
+        let mut cursor = self.inner.range(..).next().map(|(start, _)| start.clone());
+        while let Some(start) = cursor {
+            let end = self.inner[&start].0.clone();
+            match self.inner.get(&end) {
+                Some((_, next)) if *next == self.inner[&start].1 => {
+                    let (next_end, _) = self.inner.remove(&end).unwrap();
+                    self.inner.get_mut(&start).unwrap().0 = next_end;
+                    cursor = Some(start);
+                }
+                _ => {
+                    cursor = self
+                        .inner
+                        .range(end..)
+                        .next()
+                        .map(|(start, _)| start.clone())
+                }
+            }
+        }
+    }
+}
+
+impl<K: IntervalKey, V, A: Allocator + Clone> IntervalMap<K, V, A> {
+    /// Returns the lowest key `k` in `within` that is a multiple of `align` such that no interval overlaps `k..k+size`,
+    /// and `k + size` does not exceed `within.end`.
+    ///
+    /// `align` must be a power of two.
+    pub fn first_fit(&self, within: Range<K>, size: usize, align: usize) -> Option<K> {
+        let mut candidate = within.start.align_up(align)?;
+        loop {
+            // Aligning up past the end of an interval can land inside the next one, so this is checked after every move
+            if let Some(start) = self.start_of(&candidate) {
+                candidate = self.inner[start].0.align_up(align)?;
+                continue;
+            }
+            let fit_end = candidate.checked_add(size)?;
+            if fit_end > within.end {
+                return None;
+            }
+            match self.inner.range(candidate..fit_end).next() {
+                Some((_, &(end, _))) => candidate = end.align_up(align)?,
+                None => return Some(candidate),
+            }
+        }
+    }
+}
+
+impl<K: Debug, V: Debug, A: Allocator + Clone> Debug for IntervalMap<K, V, A> {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_map()
+            .entries(
+                self.inner
+                    .iter()
+                    .map(|(start, (end, val))| (start..end, val)),
+            )
+            .finish()
+    }
+}
+
+impl<K: PartialEq, V: PartialEq, A: Allocator + Clone> PartialEq for IntervalMap<K, V, A> {
+    fn eq(&self, other: &Self) -> bool {
+        self.inner == other.inner
+    }
+}
+
+impl<K: Eq, V: Eq, A: Allocator + Clone> Eq for IntervalMap<K, V, A> {}
+
+impl<K: Ord + Clone, V: Clone> FromIterator<(Range<K>, V)> for IntervalMap<K, V> {
+    fn from_iter<I: IntoIterator<Item = (Range<K>, V)>>(iter: I) -> Self {
+        let mut ret = Self::new();
+        ret.extend(iter);
+        ret
+    }
+}
+
+impl<K: Ord + Clone, V: Clone, A: Allocator + Clone> Extend<(Range<K>, V)>
+    for IntervalMap<K, V, A>
+{
+    fn extend<I: IntoIterator<Item = (Range<K>, V)>>(&mut self, iter: I) {
+        for (range, val) in iter {
+            self.insert(range, val);
+        }
+    }
+}
+
+impl<'a, K: Ord + Clone, V, A: Allocator + Clone> IntoIterator for &'a IntervalMap<K, V, A> {
+    type Item = (Range<K>, &'a V);
+
+    type IntoIter = IntervalIter<'a, K, V>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// An [`Iterator`] over the intervals of an [`IntervalMap`] and their values
+pub struct IntervalIter<'a, K, V> {
+    inner: btree_map::Range<'a, K, (K, V)>,
+}
+
+impl<'a, K: Clone, V> Iterator for IntervalIter<'a, K, V> {
+    type Item = (Range<K>, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner
+            .next()
+            .map(|(start, (end, val))| (start.clone()..end.clone(), val))
+    }
+}
+
+impl<'a, K: Clone, V> DoubleEndedIterator for IntervalIter<'a, K, V> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner
+            .next_back()
+            .map(|(start, (end, val))| (start.clone()..end.clone(), val))
+    }
+}
+
+impl<'a, K: Clone, V> FusedIterator for IntervalIter<'a, K, V> {}
+
+#[cfg(all(test, feature = "std", not(loom)))]
+mod test {
+    extern crate std as host;
+
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use core::ops::Range;
+
+    use super::{BlockingRingBuffer, HashMap, IntervalMap, RingBuffer};
+    use crate::hosted::HostWaitQueue;
+
+    fn intervals<V: Clone>(map: &IntervalMap<u64, V>) -> Vec<(Range<u64>, V)> {
+        map.iter()
+            .map(|(range, val)| (range, val.clone()))
+            .collect()
+    }
+
+    #[test]
+    fn hash_map_insert_get_remove() {
+        let mut map = HashMap::<u32, u64>::new();
+        for i in 0..1000 {
+            assert_eq!(map.insert(i, u64::from(i) * 3), None);
+        }
+        assert_eq!(map.len(), 1000);
+        assert_eq!(map.insert(7, 0), Some(21));
+        for i in (0..1000).step_by(2) {
+            assert!(map.remove(&i).is_some());
+        }
+        assert_eq!(map.len(), 500);
+        assert_eq!(map.get(&7), Some(&0));
+        assert_eq!(map.get(&8), None);
+        assert_eq!(map.get(&999), Some(&2997));
+    }
+
+    #[test]
+    fn ring_buffer_wraps_around() {
+        let buf = RingBuffer::with_capacity(4);
+        for round in 0..10 {
+            for i in 0..4 {
+                assert_eq!(buf.push(round * 4 + i), Ok(()));
+            }
+            assert_eq!(buf.push(-1), Err(-1));
+            for i in 0..4 {
+                assert_eq!(buf.pop(), Some(round * 4 + i));
+            }
+            assert_eq!(buf.pop(), None);
+        }
+    }
+
+    #[test]
+    fn blocking_ring_buffer_across_threads() {
+        let buf = Arc::new(BlockingRingBuffer::<u32, HostWaitQueue>::with_capacity(8));
+        let producer = {
+            let buf = buf.clone();
+            host::thread::spawn(move || {
+                for i in 0..10_000 {
+                    buf.push(i);
+                }
+            })
+        };
+        for i in 0..10_000 {
+            assert_eq!(buf.pop(), i);
+        }
+        producer.join().unwrap();
+    }
+
+    #[test]
+    fn interval_map_insert_splits() {
+        let mut map = IntervalMap::new();
+        map.insert(0..100, 'a');
+        map.insert(40..60, 'b');
+        assert_eq!(
+            intervals(&map),
+            [(0..40, 'a'), (40..60, 'b'), (60..100, 'a')]
+        );
+
+        // Overwrites the parts of every interval it overlaps
+        map.insert(30..70, 'c');
+        assert_eq!(
+            intervals(&map),
+            [(0..30, 'a'), (30..70, 'c'), (70..100, 'a')]
+        );
+        map.insert(50..50, 'd');
+        assert_eq!(map.len(), 3);
+
+        assert_eq!(map.get(&29), Some((0..30, &'a')));
+        assert_eq!(map.get(&30), Some((30..70, &'c')));
+        assert_eq!(map.get(&100), None);
+        *map.get_mut(&99).unwrap().1 = 'e';
+        assert_eq!(map.get(&70), Some((70..100, &'e')));
+
+        let overlapping: Vec<_> = map.overlapping(29..31).map(|(range, _)| range).collect();
+        assert_eq!(overlapping, [0..30, 30..70]);
+        assert!(!map.overlaps(100..200));
+        assert!(!map.overlaps(50..50));
+    }
+
+    #[test]
+    fn interval_map_remove() {
+        let mut map: IntervalMap<u64, u8> = [(0..10, 1), (20..30, 2), (30..40, 3), (50..60, 4)]
+            .into_iter()
+            .collect();
+
+        // Splits an interval that extends past both ends
+        assert!(map.remove(4..6));
+        assert_eq!(
+            intervals(&map),
+            [(0..4, 1), (6..10, 1), (20..30, 2), (30..40, 3), (50..60, 4)]
+        );
+
+        // Trims the intervals at either end, and drops those in between
+        assert!(map.remove(8..35));
+        assert_eq!(
+            intervals(&map),
+            [(0..4, 1), (6..8, 1), (35..40, 3), (50..60, 4)]
+        );
+
+        assert!(!map.remove(40..50));
+        assert!(!map.remove(55..55));
+        assert!(map.remove(0..100));
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn interval_map_merge_adjacent() {
+        let mut map = IntervalMap::new();
+        map.insert(0..100, 'a');
+        map.insert(40..60, 'b');
+        map.insert(40..60, 'a');
+        map.insert(100..110, 'a');
+        map.insert(110..120, 'b');
+        map.insert(130..140, 'b');
+        assert_eq!(map.len(), 6);
+
+        map.merge_adjacent();
+        // Intervals are only merged if they touch and have equal values
+        assert_eq!(
+            intervals(&map),
+            [(0..110, 'a'), (110..120, 'b'), (130..140, 'b')]
+        );
+    }
+
+    #[test]
+    fn interval_map_first_fit() {
+        let map: IntervalMap<u64, ()> = [(1..13, ()), (13..49, ()), (49..55, ())]
+            .into_iter()
+            .collect();
+        // Aligning up past the end of 13..49 lands inside 49..55
+        assert_eq!(map.first_fit(35..64, 2, 4), Some(56));
+        assert_eq!(map.first_fit(35..64, 8, 4), Some(56));
+        assert_eq!(map.first_fit(35..64, 9, 4), None);
+        assert_eq!(map.first_fit(0..64, 1, 1), Some(0));
+        assert_eq!(map.first_fit(0..64, 2, 1), Some(55));
+
+        let empty = IntervalMap::<u64, ()>::new();
+        assert_eq!(empty.first_fit(3..20, 4, 8), Some(8));
+        assert_eq!(empty.first_fit(3..20, 13, 8), None);
+        assert_eq!(empty.first_fit(u64::MAX - 2..u64::MAX, 1, 16), None);
+
+        let high: IntervalMap<u64, ()> = [(u64::MAX - 16..u64::MAX, ())].into_iter().collect();
+        assert_eq!(
+            high.first_fit(u64::MAX - 32..u64::MAX, 8, 8),
+            Some(u64::MAX - 31)
+        );
+        assert_eq!(high.first_fit(u64::MAX - 16..u64::MAX, 1, 1), None);
+    }
+
+    #[test]
+    fn interval_map_first_fit_matches_brute_force() {
+        let mut seed = 0x9e37_79b9_7f4a_7c15u64;
+        let mut next = move |bound: u64| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed % bound
+        };
+        for _ in 0..2000 {
+            let mut map = IntervalMap::new();
+            for _ in 0..next(8) {
+                let start = next(200);
+                map.insert(start..start + 1 + next(30), ());
+            }
+            let start = next(200);
+            let within = start..start + next(100);
+            let size = 1 + next(20) as usize;
+            let align = 1 << next(5);
+
+            let expected = (within.start..within.end)
+                .filter(|k| k % align == 0)
+                .find(|&k| k + size as u64 <= within.end && !map.overlaps(k..k + size as u64));
+            assert_eq!(
+                map.first_fit(within.clone(), size, align as usize),
+                expected,
+                "{:?} in {:?}, size {} align {}",
+                map,
+                within,
+                size,
+                align
+            );
+        }
+    }
+}
+
+#[cfg(all(loom, test))]
+mod loom_test {
+    use alloc::vec::Vec;
+    use loom::model;
+    use loom::sync::Arc;
+    use loom::thread;
+
+    use super::RingBuffer;
+    use crate::sync::prim::Ordering;
+
+    /// Starts every position of `buf` at `pos`, as if `pos` elements had already been pushed and popped
+    fn start_at<T>(buf: &RingBuffer<T>, pos: usize) {
+        for head in [
+            &buf.write_head,
+            &buf.reserved_head,
+            &buf.read_head,
+            &buf.tail,
+        ] {
+            head.store(pos, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn loom_spsc_keeps_order() {
+        model(|| {
+            let buf = Arc::new(RingBuffer::with_capacity(2));
+            let producer = {
+                let buf = buf.clone();
+                thread::spawn(move || {
+                    buf.push(1).unwrap();
+                    buf.push(2).unwrap();
+                })
+            };
+            let first = buf.pop();
+            producer.join().unwrap();
+            let rest = Arc::try_unwrap(buf).unwrap().drain().collect::<Vec<_>>();
+            match first {
+                Some(1) => assert_eq!(rest, [2]),
+                None => assert_eq!(rest, [1, 2]),
+                other => panic!("popped {other:?} first"),
+            }
+        });
+    }
+
+    #[test]
+    fn loom_mpmc_conserves_elements() {
+        model(|| {
+            let buf = Arc::new(RingBuffer::with_capacity(1));
+            let producers = (0..2)
+                .map(|i| {
+                    let buf = buf.clone();
+                    thread::spawn(move || buf.push(i).is_ok())
+                })
+                .collect::<Vec<_>>();
+            let consumer = {
+                let buf = buf.clone();
+                thread::spawn(move || buf.pop())
+            };
+            let popped = buf.pop();
+            let pushed = producers
+                .into_iter()
+                .map(|producer| producer.join().unwrap())
+                .filter(|&ok| ok)
+                .count();
+            let mut got = [popped, consumer.join().unwrap()]
+                .into_iter()
+                .flatten()
+                .chain(Arc::try_unwrap(buf).unwrap().drain())
+                .collect::<Vec<_>>();
+            got.sort_unstable();
+            assert_eq!(got.len(), pushed);
+            got.dedup();
+            assert_eq!(got.len(), pushed);
+        });
+    }
+
+    #[test]
+    fn loom_positions_wrap_around() {
+        model(|| {
+            let buf = Arc::new(RingBuffer::with_capacity(3));
+            start_at(&buf, usize::MAX - 1);
+            buf.push(0).unwrap();
+            let producer = {
+                let buf = buf.clone();
+                thread::spawn(move || {
+                    buf.push(1).unwrap();
+                    buf.push(2).unwrap();
+                })
+            };
+            let consumer = {
+                let buf = buf.clone();
+                thread::spawn(move || [buf.pop(), buf.pop()])
+            };
+            producer.join().unwrap();
+            let popped = consumer.join().unwrap();
+            let got = popped
+                .into_iter()
+                .flatten()
+                .chain(Arc::try_unwrap(buf).unwrap().drain())
+                .collect::<Vec<_>>();
+            assert_eq!(got, [0, 1, 2]);
+        });
+    }
+}
@@ -0,0 +1,1807 @@
+//! Intrusive collections, which store their links inside the values they contain.
+//!
+//! Inserting into an intrusive collection never allocates, so they can be used while holding locks that forbid allocation,
+//! such as those protecting scheduler run queues and wait queues.
+//!
+//! A value that can be stored in an intrusive collection embeds a link ([`ListLink`] or [`RbLink`]) for each collection it can be in.
+//! An [`Adapter`], usually declared with [`intrusive_adapter!`](crate::intrusive_adapter), tells the collection where the link is,
+//! and which [`IntrusivePointer`] owns the value while it is in the collection.
+//!
+//! A link can only be in one collection at a time. Inserting a value whose link is already in use panics.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use core::cell::UnsafeCell;
+use core::fmt::Debug;
+use core::iter::FusedIterator;
+use core::marker::{PhantomData, PhantomPinned};
+use core::pin::Pin;
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+/// A pointer that can own a value stored in an intrusive collection.
+///
+/// # Safety
+/// Between a call to [`IntrusivePointer::into_raw`] and the matching call to [`IntrusivePointer::from_raw`],
+/// the value must remain valid for shared access at the same address.
+/// `from_raw(into_raw(ptr))` must be equivalent to `ptr`.
+pub unsafe trait IntrusivePointer {
+    /// The type of the value that is pointed to
+    type Target;
+
+    /// Converts the pointer into a raw pointer to its value, which the collection keeps until the value is removed
+    fn into_raw(this: Self) -> NonNull<Self::Target>;
+
+    /// Converts a raw pointer obtained from [`IntrusivePointer::into_raw`] back into the pointer
+    ///
+    /// # Safety
+    /// `ptr` must have been returned by [`IntrusivePointer::into_raw`] and not converted back since
+    unsafe fn from_raw(ptr: NonNull<Self::Target>) -> Self;
+}
+
+unsafe impl<T> IntrusivePointer for Pin<&T> {
+    type Target = T;
+
+    fn into_raw(this: Self) -> NonNull<T> {
+        NonNull::from(Pin::get_ref(this))
+    }
+
+    unsafe fn from_raw(ptr: NonNull<T>) -> Self {
+        Pin::new_unchecked(&*ptr.as_ptr())
+    }
+}
+
+unsafe impl<T> IntrusivePointer for Pin<Box<T>> {
+    type Target = T;
+
+    fn into_raw(this: Self) -> NonNull<T> {
+        // SAFETY:
+        // The value is not moved out of the box, and `from_raw` pins it again
+        let ptr = Box::into_raw(unsafe { Pin::into_inner_unchecked(this) });
+        // SAFETY:
+        // `Box::into_raw` never returns null
+        unsafe { NonNull::new_unchecked(ptr) }
+    }
+
+    unsafe fn from_raw(ptr: NonNull<T>) -> Self {
+        Box::into_pin(Box::from_raw(ptr.as_ptr()))
+    }
+}
+
+unsafe impl<T> IntrusivePointer for Pin<Arc<T>> {
+    type Target = T;
+
+    fn into_raw(this: Self) -> NonNull<T> {
+        // SAFETY:
+        // The value is not moved out of the `Arc`, and `from_raw` pins it again
+        let ptr = Arc::into_raw(unsafe { Pin::into_inner_unchecked(this) });
+        // SAFETY:
+        // `Arc::into_raw` never returns null
+        unsafe { NonNull::new_unchecked(ptr.cast_mut()) }
+    }
+
+    unsafe fn from_raw(ptr: NonNull<T>) -> Self {
+        Pin::new_unchecked(Arc::from_raw(ptr.as_ptr()))
+    }
+}
+
+/// Describes how a value is stored in an intrusive collection: which link of the value the collection uses, and which pointer owns it.
+///
+/// # Safety
+/// `LINK_OFFSET` must be the offset of a field of type `Link` within `Value`.
+/// Use [`intrusive_adapter!`](crate::intrusive_adapter) to implement this trait safely.
+pub unsafe trait Adapter {
+    /// The type of the link used by the collection, such as [`ListLink`] or [`RbLink`]
+    type Link;
+    /// The type of the values stored in the collection
+    type Value;
+    /// The pointer that owns a value while it's in the collection
+    type Pointer: IntrusivePointer<Target = Self::Value>;
+
+    /// The offset of the link within the value
+    const LINK_OFFSET: usize;
+}
+
+/// Declares a type implementing [`Adapter`] for a field of a struct.
+///
+/// ```ignore
+/// intrusive_adapter!(pub RunQueueAdapter = Pin<Box<Thread>>: Thread { run_link: ListLink });
+/// intrusive_adapter!(BorrowedAdapter<'a> = Pin<&'a Thread>: Thread { run_link: ListLink });
+/// ```
+#[macro_export]
+macro_rules! intrusive_adapter {
+    ($(#[$meta:meta])* $vis:vis $name:ident $(<$lt:lifetime>)? = $ptr:ty : $value:ty { $field:ident : $link:ty } $(;)?) => {
+        $(#[$meta])*
+        $vis struct $name $(<$lt>)? ($(::core::marker::PhantomData<&$lt ()>)?);
+
+        unsafe impl $(<$lt>)? $crate::collection::intrusive::Adapter for $name $(<$lt>)? {
+            type Link = $link;
+            type Value = $value;
+            type Pointer = $ptr;
+
+            const LINK_OFFSET: usize = {
+                // Checks that the field has the type of the link
+                fn _check(value: &$value) -> &$link {
+                    &value.$field
+                }
+                ::core::mem::offset_of!($value, $field)
+            };
+        }
+    };
+}
+
+/// Returns the link of `A` embedded in `value`
+fn link_of<A: Adapter>(value: NonNull<A::Value>) -> NonNull<A::Link> {
+    // SAFETY:
+    // The link is a field of the value, per the contract of `Adapter`
+    unsafe {
+        NonNull::new_unchecked(
+            value
+                .as_ptr()
+                .cast::<u8>()
+                .add(A::LINK_OFFSET)
+                .cast::<A::Link>(),
+        )
+    }
+}
+
+/// Returns the value that embeds the link of `A` at `link`
+///
+/// # Safety
+/// `link` must be the link of `A` within a value
+unsafe fn value_of<A: Adapter>(link: NonNull<A::Link>) -> NonNull<A::Value> {
+    NonNull::new_unchecked(
+        link.as_ptr()
+            .cast::<u8>()
+            .sub(A::LINK_OFFSET)
+            .cast::<A::Value>(),
+    )
+}
+
+/// A link that records whether it's in a collection
+trait Link {
+    fn linked(&self) -> &AtomicBool;
+}
+
+/// Converts `ptr` into the link of the value it owns, claiming the link.
+///
+/// ## Panics
+/// Panics if the link is already in a collection, in which case `ptr` is dropped
+fn claim<A: Adapter>(ptr: A::Pointer) -> NonNull<A::Link>
+where
+    A::Link: Link,
+{
+    let link = link_of::<A>(IntrusivePointer::into_raw(ptr));
+    // SAFETY:
+    // The value was just converted from a valid pointer, so its link is valid
+    let linked = unsafe { link.as_ref() }.linked();
+    if linked
+        .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+        .is_err()
+    {
+        // SAFETY:
+        // The pointer was converted by `into_raw` above, and no collection took ownership of it
+        drop(unsafe { A::Pointer::from_raw(value_of::<A>(link)) });
+        panic!("value is already linked into an intrusive collection");
+    }
+    link
+}
+
+///
+/// A link embedded in a value that can be stored in a [`LinkedList`]
+pub struct ListLink {
+    linked: AtomicBool,
+    next: UnsafeCell<Option<NonNull<ListLink>>>,
+    prev: UnsafeCell<Option<NonNull<ListLink>>>,
+    _pin: PhantomPinned,
+}
+
+// SAFETY:
+// The pointers are only accessed by the list that claimed the link through `linked`, which it does with exclusive access
+unsafe impl Send for ListLink {}
+unsafe impl Sync for ListLink {}
+
+impl ListLink {
+    /// Returns a new link that is not in any list
+    pub const fn new() -> Self {
+        Self {
+            linked: AtomicBool::new(false),
+            next: UnsafeCell::new(None),
+            prev: UnsafeCell::new(None),
+            _pin: PhantomPinned,
+        }
+    }
+
+    /// Checks if the link is currently in a list
+    pub fn is_linked(&self) -> bool {
+        self.linked.load(Ordering::Relaxed)
+    }
+}
+
+impl Link for ListLink {
+    fn linked(&self) -> &AtomicBool {
+        &self.linked
+    }
+}
+
+impl Default for ListLink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debug for ListLink {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("ListLink")
+            .field("linked", &self.is_linked())
+            .finish_non_exhaustive()
+    }
+}
+
+// These accessors must only be used by the list that owns the link
+unsafe fn list_next(link: NonNull<ListLink>) -> Option<NonNull<ListLink>> {
+    *(*link.as_ptr()).next.get()
+}
+
+unsafe fn list_prev(link: NonNull<ListLink>) -> Option<NonNull<ListLink>> {
+    *(*link.as_ptr()).prev.get()
+}
+
+unsafe fn set_list_next(link: NonNull<ListLink>, next: Option<NonNull<ListLink>>) {
+    *(*link.as_ptr()).next.get() = next;
+}
+
+unsafe fn set_list_prev(link: NonNull<ListLink>, prev: Option<NonNull<ListLink>>) {
+    *(*link.as_ptr()).prev.get() = prev;
+}
+
+///
+/// An intrusive doubly linked list, which stores values through a [`ListLink`] embedded in each of them.
+///
+/// Pushing to and removing from the list never allocates.
+pub struct LinkedList<A: Adapter<Link = ListLink>> {
+    head: Option<NonNull<ListLink>>,
+    tail: Option<NonNull<ListLink>>,
+    len: usize,
+    phantom: PhantomData<A::Pointer>,
+}
+
+unsafe impl<A: Adapter<Link = ListLink>> Send for LinkedList<A> where A::Pointer: Send {}
+unsafe impl<A: Adapter<Link = ListLink>> Sync for LinkedList<A> where A::Pointer: Sync {}
+
+impl<A: Adapter<Link = ListLink>> Default for LinkedList<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A: Adapter<Link = ListLink>> Drop for LinkedList<A> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl<A: Adapter<Link = ListLink>> LinkedList<A> {
+    /// Returns a new list containing no values
+    pub const fn new() -> Self {
+        Self {
+            head: None,
+            tail: None,
+            len: 0,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Returns the number of values in the list
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the list contains no values
+    pub fn is_empty(&self) -> bool {
+        self.head.is_none()
+    }
+
+    /// Returns the first value of the list, if any
+    pub fn front(&self) -> Option<&A::Value> {
+        // SAFETY:
+        // Every link in the list belongs to a value owned by the list
+        self.head
+            .map(|link| unsafe { value_of::<A>(link).as_ref() })
+    }
+
+    /// Returns the last value of the list, if any
+    pub fn back(&self) -> Option<&A::Value> {
+        // SAFETY:
+        // Every link in the list belongs to a value owned by the list
+        self.tail
+            .map(|link| unsafe { value_of::<A>(link).as_ref() })
+    }
+
+    /// Inserts `link` between `prev` and `next`, which must be adjacent in the list (or the ends of the list if `None`)
+    unsafe fn link_between(
+        &mut self,
+        link: NonNull<ListLink>,
+        prev: Option<NonNull<ListLink>>,
+        next: Option<NonNull<ListLink>>,
+    ) {
+        match prev {
+            Some(prev) => {
+                debug_assert_eq!(list_next(prev), next, "corrupted list links");
+                set_list_next(prev, Some(link));
+            }
+            None => {
+                debug_assert_eq!(self.head, next, "corrupted list links");
+                self.head = Some(link);
+            }
+        }
+        match next {
+            Some(next) => {
+                debug_assert_eq!(list_prev(next), prev, "corrupted list links");
+                set_list_prev(next, Some(link));
+            }
+            None => {
+                debug_assert_eq!(self.tail, prev, "corrupted list links");
+                self.tail = Some(link);
+            }
+        }
+        set_list_prev(link, prev);
+        set_list_next(link, next);
+        self.len += 1;
+    }
+
+    /// Removes `link` from the list, and returns the pointer to its value
+    unsafe fn unlink(&mut self, link: NonNull<ListLink>) -> A::Pointer {
+        let prev = list_prev(link);
+        let next = list_next(link);
+        match prev {
+            Some(prev) => {
+                debug_assert_eq!(list_next(prev), Some(link), "corrupted list links");
+                set_list_next(prev, next);
+            }
+            None => {
+                debug_assert_eq!(self.head, Some(link), "corrupted list links");
+                self.head = next;
+            }
+        }
+        match next {
+            Some(next) => {
+                debug_assert_eq!(list_prev(next), Some(link), "corrupted list links");
+                set_list_prev(next, prev);
+            }
+            None => {
+                debug_assert_eq!(self.tail, Some(link), "corrupted list links");
+                self.tail = prev;
+            }
+        }
+        set_list_prev(link, None);
+        set_list_next(link, None);
+        self.len -= 1;
+        (*link.as_ptr()).linked.store(false, Ordering::Release);
+        A::Pointer::from_raw(value_of::<A>(link))
+    }
+
+    /// Inserts `ptr` at the front of the list
+    ///
+    /// ## Panics
+    /// Panics if the value is already in a list through the same link
+    pub fn push_front(&mut self, ptr: A::Pointer) {
+        let link = claim::<A>(ptr);
+        // SAFETY:
+        // The link was just claimed, and `None` and the head are adjacent
+        unsafe { self.link_between(link, None, self.head) }
+    }
+
+    /// Inserts `ptr` at the back of the list
+    ///
+    /// ## Panics
+    /// Panics if the value is already in a list through the same link
+    pub fn push_back(&mut self, ptr: A::Pointer) {
+        let link = claim::<A>(ptr);
+        // SAFETY:
+        // The link was just claimed, and the tail and `None` are adjacent
+        unsafe { self.link_between(link, self.tail, None) }
+    }
+
+    /// Removes the first value of the list, if any
+    pub fn pop_front(&mut self) -> Option<A::Pointer> {
+        // SAFETY:
+        // The head is in the list
+        self.head.map(|link| unsafe { self.unlink(link) })
+    }
+
+    /// Removes the last value of the list, if any
+    pub fn pop_back(&mut self) -> Option<A::Pointer> {
+        // SAFETY:
+        // The tail is in the list
+        self.tail.map(|link| unsafe { self.unlink(link) })
+    }
+
+    /// Removes every value from the list, dropping the pointers to them
+    pub fn clear(&mut self) {
+        while self.pop_front().is_some() {}
+    }
+
+    /// Produces an [`Iterator`] over the values of the list, from front to back
+    pub fn iter(&self) -> ListIter<'_, A> {
+        ListIter {
+            front: self.head,
+            back: self.tail,
+            remaining: self.len,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Returns a cursor pointing to the first value of the list, or to the ghost position if the list is empty
+    pub fn cursor_front(&self) -> ListCursor<'_, A> {
+        ListCursor {
+            current: self.head,
+            list: self,
+        }
+    }
+
+    /// Returns a cursor pointing to the last value of the list, or to the ghost position if the list is empty
+    pub fn cursor_back(&self) -> ListCursor<'_, A> {
+        ListCursor {
+            current: self.tail,
+            list: self,
+        }
+    }
+
+    /// Returns a cursor that can modify the list, pointing to the first value, or to the ghost position if the list is empty
+    pub fn cursor_front_mut(&mut self) -> ListCursorMut<'_, A> {
+        ListCursorMut {
+            current: self.head,
+            list: self,
+        }
+    }
+
+    /// Returns a cursor that can modify the list, pointing to the last value, or to the ghost position if the list is empty
+    pub fn cursor_back_mut(&mut self) -> ListCursorMut<'_, A> {
+        ListCursorMut {
+            current: self.tail,
+            list: self,
+        }
+    }
+
+    /// Returns a cursor that can modify the list, pointing to `value`
+    ///
+    /// # Safety
+    /// `value` must be in this list
+    pub unsafe fn cursor_mut_from_value(&mut self, value: &A::Value) -> ListCursorMut<'_, A> {
+        let link = link_of::<A>(NonNull::from(value));
+        ListCursorMut {
+            current: Some(link),
+            list: self,
+        }
+    }
+
+    /// Checks every link of the list, panicking if any is inconsistent
+    pub fn validate(&self) {
+        let mut prev = None;
+        let mut cur = self.head;
+        let mut len = 0;
+        while let Some(link) = cur {
+            // SAFETY:
+            // Every link reachable from the head is in the list
+            unsafe {
+                assert!(
+                    (*link.as_ptr()).linked.load(Ordering::Relaxed),
+                    "list contains an unclaimed link"
+                );
+                assert_eq!(list_prev(link), prev, "corrupted list links");
+                prev = cur;
+                cur = list_next(link);
+            }
+            len += 1;
+            assert!(len <= self.len, "list is longer than its length");
+        }
+        assert_eq!(self.tail, prev, "list tail is not its last link");
+        assert_eq!(len, self.len, "list is shorter than its length");
+    }
+}
+
+impl<A: Adapter<Link = ListLink>> Debug for LinkedList<A>
+where
+    A::Value: Debug,
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<'a, A: Adapter<Link = ListLink>> IntoIterator for &'a LinkedList<A> {
+    type Item = &'a A::Value;
+
+    type IntoIter = ListIter<'a, A>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<A: Adapter<Link = ListLink>> Extend<A::Pointer> for LinkedList<A> {
+    fn extend<I: IntoIterator<Item = A::Pointer>>(&mut self, iter: I) {
+        for ptr in iter {
+            self.push_back(ptr);
+        }
+    }
+}
+
+/// An [`Iterator`] over the values of a [`LinkedList`]
+pub struct ListIter<'a, A: Adapter<Link = ListLink>> {
+    front: Option<NonNull<ListLink>>,
+    back: Option<NonNull<ListLink>>,
+    remaining: usize,
+    phantom: PhantomData<&'a LinkedList<A>>,
+}
+
+impl<'a, A: Adapter<Link = ListLink>> Iterator for ListIter<'a, A> {
+    type Item = &'a A::Value;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let link = self.front?;
+        self.remaining -= 1;
+        // SAFETY:
+        // The list is borrowed for `'a`, so its links are valid and can't change
+        unsafe {
+            self.front = list_next(link);
+            Some(value_of::<A>(link).as_ref())
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, A: Adapter<Link = ListLink>> DoubleEndedIterator for ListIter<'a, A> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let link = self.back?;
+        self.remaining -= 1;
+        // SAFETY:
+        // The list is borrowed for `'a`, so its links are valid and can't change
+        unsafe {
+            self.back = list_prev(link);
+            Some(value_of::<A>(link).as_ref())
+        }
+    }
+}
+
+impl<'a, A: Adapter<Link = ListLink>> ExactSizeIterator for ListIter<'a, A> {}
+
+impl<'a, A: Adapter<Link = ListLink>> FusedIterator for ListIter<'a, A> {}
+
+/// A cursor over a [`LinkedList`].
+///
+/// The cursor points either to a value of the list, or to a ghost position between the back and the front of the list.
+pub struct ListCursor<'a, A: Adapter<Link = ListLink>> {
+    current: Option<NonNull<ListLink>>,
+    list: &'a LinkedList<A>,
+}
+
+impl<'a, A: Adapter<Link = ListLink>> Clone for ListCursor<'a, A> {
+    fn clone(&self) -> Self {
+        Self {
+            current: self.current,
+            list: self.list,
+        }
+    }
+}
+
+impl<'a, A: Adapter<Link = ListLink>> ListCursor<'a, A> {
+    /// Returns the value the cursor points to, or `None` at the ghost position
+    pub fn get(&self) -> Option<&'a A::Value> {
+        // SAFETY:
+        // The current link is in the list, which is borrowed for `'a`
+        self.current
+            .map(|link| unsafe { value_of::<A>(link).as_ref() })
+    }
+
+    /// Moves the cursor to the next value, or from the last value to the ghost position, or from the ghost position to the first value
+    pub fn move_next(&mut self) {
+        self.current = match self.current {
+            // SAFETY:
+            // The current link is in the list
+            Some(link) => unsafe { list_next(link) },
+            None => self.list.head,
+        };
+    }
+
+    /// Moves the cursor to the previous value, or from the first value to the ghost position, or from the ghost position to the last value
+    pub fn move_prev(&mut self) {
+        self.current = match self.current {
+            // SAFETY:
+            // The current link is in the list
+            Some(link) => unsafe { list_prev(link) },
+            None => self.list.tail,
+        };
+    }
+
+    /// Returns the value after the cursor, without moving it
+    pub fn peek_next(&self) -> Option<&'a A::Value> {
+        let mut next = self.clone();
+        next.move_next();
+        next.get()
+    }
+
+    /// Returns the value before the cursor, without moving it
+    pub fn peek_prev(&self) -> Option<&'a A::Value> {
+        let mut prev = self.clone();
+        prev.move_prev();
+        prev.get()
+    }
+}
+
+/// A cursor over a [`LinkedList`] that can insert and remove values.
+///
+/// The cursor points either to a value of the list, or to a ghost position between the back and the front of the list.
+pub struct ListCursorMut<'a, A: Adapter<Link = ListLink>> {
+    current: Option<NonNull<ListLink>>,
+    list: &'a mut LinkedList<A>,
+}
+
+impl<'a, A: Adapter<Link = ListLink>> ListCursorMut<'a, A> {
+    /// Returns a read-only cursor at the same position, borrowing this one
+    pub fn as_cursor(&self) -> ListCursor<'_, A> {
+        ListCursor {
+            current: self.current,
+            list: self.list,
+        }
+    }
+
+    /// Returns the value the cursor points to, or `None` at the ghost position
+    pub fn get(&self) -> Option<&A::Value> {
+        // SAFETY:
+        // The current link is in the list
+        self.current
+            .map(|link| unsafe { value_of::<A>(link).as_ref() })
+    }
+
+    /// Moves the cursor to the next value, or from the last value to the ghost position, or from the ghost position to the first value
+    pub fn move_next(&mut self) {
+        self.current = match self.current {
+            // SAFETY:
+            // The current link is in the list
+            Some(link) => unsafe { list_next(link) },
+            None => self.list.head,
+        };
+    }
+
+    /// Moves the cursor to the previous value, or from the first value to the ghost position, or from the ghost position to the last value
+    pub fn move_prev(&mut self) {
+        self.current = match self.current {
+            // SAFETY:
+            // The current link is in the list
+            Some(link) => unsafe { list_prev(link) },
+            None => self.list.tail,
+        };
+    }
+
+    /// Returns the value after the cursor, without moving it
+    pub fn peek_next(&self) -> Option<&A::Value> {
+        self.as_cursor().peek_next()
+    }
+
+    /// Returns the value before the cursor, without moving it
+    pub fn peek_prev(&self) -> Option<&A::Value> {
+        self.as_cursor().peek_prev()
+    }
+
+    /// Removes the value the cursor points to and moves the cursor to the next value.
+    ///
+    /// Returns `None`, and does nothing, at the ghost position
+    pub fn remove_current(&mut self) -> Option<A::Pointer> {
+        let link = self.current?;
+        // SAFETY:
+        // The current link is in the list
+        unsafe {
+            self.current = list_next(link);
+            Some(self.list.unlink(link))
+        }
+    }
+
+    /// Inserts `ptr` after the cursor, or at the front of the list at the ghost position
+    ///
+    /// ## Panics
+    /// Panics if the value is already in a list through the same link
+    pub fn insert_after(&mut self, ptr: A::Pointer) {
+        let link = claim::<A>(ptr);
+        // SAFETY:
+        // The link was just claimed, and the current link is adjacent to the one after it
+        unsafe {
+            let next = match self.current {
+                Some(cur) => list_next(cur),
+                None => self.list.head,
+            };
+            self.list.link_between(link, self.current, next);
+        }
+    }
+
+    /// Inserts `ptr` before the cursor, or at the back of the list at the ghost position
+    ///
+    /// ## Panics
+    /// Panics if the value is already in a list through the same link
+    pub fn insert_before(&mut self, ptr: A::Pointer) {
+        let link = claim::<A>(ptr);
+        // SAFETY:
+        // The link was just claimed, and the current link is adjacent to the one before it
+        unsafe {
+            let prev = match self.current {
+                Some(cur) => list_prev(cur),
+                None => self.list.tail,
+            };
+            self.list.link_between(link, prev, self.current);
+        }
+    }
+}
+
+///
+/// A link embedded in a value that can be stored in an [`RbTree`]
+pub struct RbLink {
+    linked: AtomicBool,
+    parent: UnsafeCell<Option<NonNull<RbLink>>>,
+    left: UnsafeCell<Option<NonNull<RbLink>>>,
+    right: UnsafeCell<Option<NonNull<RbLink>>>,
+    red: UnsafeCell<bool>,
+    _pin: PhantomPinned,
+}
+
+// SAFETY:
+// The pointers are only accessed by the tree that claimed the link through `linked`, which it does with exclusive access
+unsafe impl Send for RbLink {}
+unsafe impl Sync for RbLink {}
+
+impl RbLink {
+    /// Returns a new link that is not in any tree
+    pub const fn new() -> Self {
+        Self {
+            linked: AtomicBool::new(false),
+            parent: UnsafeCell::new(None),
+            left: UnsafeCell::new(None),
+            right: UnsafeCell::new(None),
+            red: UnsafeCell::new(false),
+            _pin: PhantomPinned,
+        }
+    }
+
+    /// Checks if the link is currently in a tree
+    pub fn is_linked(&self) -> bool {
+        self.linked.load(Ordering::Relaxed)
+    }
+}
+
+impl Link for RbLink {
+    fn linked(&self) -> &AtomicBool {
+        &self.linked
+    }
+}
+
+impl Default for RbLink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debug for RbLink {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_struct("RbLink")
+            .field("linked", &self.is_linked())
+            .finish_non_exhaustive()
+    }
+}
+
+type RbPtr = Option<NonNull<RbLink>>;
+
+// These accessors must only be used by the tree that owns the link
+unsafe fn parent(link: NonNull<RbLink>) -> RbPtr {
+    *(*link.as_ptr()).parent.get()
+}
+
+unsafe fn left(link: NonNull<RbLink>) -> RbPtr {
+    *(*link.as_ptr()).left.get()
+}
+
+unsafe fn right(link: NonNull<RbLink>) -> RbPtr {
+    *(*link.as_ptr()).right.get()
+}
+
+unsafe fn set_parent(link: NonNull<RbLink>, val: RbPtr) {
+    *(*link.as_ptr()).parent.get() = val;
+}
+
+unsafe fn set_left(link: NonNull<RbLink>, val: RbPtr) {
+    *(*link.as_ptr()).left.get() = val;
+}
+
+unsafe fn set_right(link: NonNull<RbLink>, val: RbPtr) {
+    *(*link.as_ptr()).right.get() = val;
+}
+
+/// Returns whether `link` is red. Missing children are black
+unsafe fn is_red(link: RbPtr) -> bool {
+    link.is_some_and(|link| *(*link.as_ptr()).red.get())
+}
+
+unsafe fn set_red(link: NonNull<RbLink>, red: bool) {
+    *(*link.as_ptr()).red.get() = red;
+}
+
+unsafe fn minimum(mut link: NonNull<RbLink>) -> NonNull<RbLink> {
+    while let Some(l) = left(link) {
+        link = l;
+    }
+    link
+}
+
+unsafe fn maximum(mut link: NonNull<RbLink>) -> NonNull<RbLink> {
+    while let Some(r) = right(link) {
+        link = r;
+    }
+    link
+}
+
+unsafe fn successor(link: NonNull<RbLink>) -> RbPtr {
+    if let Some(r) = right(link) {
+        return Some(minimum(r));
+    }
+    let mut child = link;
+    let mut up = parent(link);
+    while let Some(p) = up {
+        if left(p) == Some(child) {
+            break;
+        }
+        child = p;
+        up = parent(p);
+    }
+    up
+}
+
+unsafe fn predecessor(link: NonNull<RbLink>) -> RbPtr {
+    if let Some(l) = left(link) {
+        return Some(maximum(l));
+    }
+    let mut child = link;
+    let mut up = parent(link);
+    while let Some(p) = up {
+        if right(p) == Some(child) {
+            break;
+        }
+        child = p;
+        up = parent(p);
+    }
+    up
+}
+
+/// An [`Adapter`] for an [`RbTree`], which also gives the key that orders values in the tree
+pub trait KeyAdapter: Adapter<Link = RbLink> {
+    /// The type of the key, which values are ordered by
+    type Key: Ord;
+
+    /// Returns the key of `value`.
+    ///
+    /// The key of a value must not change while it's in a tree.
+    fn key(value: &Self::Value) -> Self::Key;
+}
+
+///
+/// An intrusive red-black tree, which stores values through an [`RbLink`] embedded in each of them, ordered by [`KeyAdapter::key`].
+///
+/// Several values may have equal keys, in which case they're kept in insertion order. Inserting and removing values never allocates.
+pub struct RbTree<A: KeyAdapter> {
+    root: RbPtr,
+    len: usize,
+    phantom: PhantomData<A::Pointer>,
+}
+
+unsafe impl<A: KeyAdapter> Send for RbTree<A> where A::Pointer: Send {}
+unsafe impl<A: KeyAdapter> Sync for RbTree<A> where A::Pointer: Sync {}
+
+impl<A: KeyAdapter> Default for RbTree<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A: KeyAdapter> Drop for RbTree<A> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl<A: KeyAdapter> RbTree<A> {
+    /// Returns a new tree containing no values
+    pub const fn new() -> Self {
+        Self {
+            root: None,
+            len: 0,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Returns the number of values in the tree
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns `true` if the tree contains no values
+    pub fn is_empty(&self) -> bool {
+        self.root.is_none()
+    }
+
+    unsafe fn key_of(link: NonNull<RbLink>) -> A::Key {
+        A::key(value_of::<A>(link).as_ref())
+    }
+
+    /// Replaces `old` with `new` in the child pointer of the parent of `old`, or as the root
+    unsafe fn replace_child(&mut self, parent: RbPtr, old: NonNull<RbLink>, new: RbPtr) {
+        match parent {
+            None => self.root = new,
+            Some(p) if left(p) == Some(old) => set_left(p, new),
+            Some(p) => {
+                debug_assert_eq!(right(p), Some(old), "corrupted tree links");
+                set_right(p, new)
+            }
+        }
+    }
+
+    unsafe fn rotate_left(&mut self, x: NonNull<RbLink>) {
+        let y = right(x).expect("rotating without a right child");
+        let b = left(y);
+        set_right(x, b);
+        if let Some(b) = b {
+            set_parent(b, Some(x));
+        }
+        let p = parent(x);
+        set_parent(y, p);
+        self.replace_child(p, x, Some(y));
+        set_left(y, Some(x));
+        set_parent(x, Some(y));
+    }
+
+    unsafe fn rotate_right(&mut self, x: NonNull<RbLink>) {
+        let y = left(x).expect("rotating without a left child");
+        let b = right(y);
+        set_left(x, b);
+        if let Some(b) = b {
+            set_parent(b, Some(x));
+        }
+        let p = parent(x);
+        set_parent(y, p);
+        self.replace_child(p, x, Some(y));
+        set_right(y, Some(x));
+        set_parent(x, Some(y));
+    }
+
+    /// Inserts `ptr` into the tree, after any values with an equal key
+    ///
+    /// ## Panics
+    /// Panics if the value is already in a tree through the same link
+    pub fn insert(&mut self, ptr: A::Pointer) {
+        let link = claim::<A>(ptr);
+        // SAFETY:
+        // The link was just claimed, and every other link reachable from the root is in the tree
+        unsafe {
+            let key = Self::key_of(link);
+            let mut up = None;
+            let mut go_left = false;
+            let mut cur = self.root;
+            while let Some(node) = cur {
+                up = Some(node);
+                go_left = key < Self::key_of(node);
+                cur = if go_left { left(node) } else { right(node) };
+            }
+            set_parent(link, up);
+            set_left(link, None);
+            set_right(link, None);
+            set_red(link, true);
+            match up {
+                None => self.root = Some(link),
+                Some(p) if go_left => set_left(p, Some(link)),
+                Some(p) => set_right(p, Some(link)),
+            }
+            self.len += 1;
+            self.insert_fixup(link);
+        }
+    }
+
+    unsafe fn insert_fixup(&mut self, mut z: NonNull<RbLink>) {
+        while let Some(p) = parent(z).filter(|&p| is_red(Some(p))) {
+            // A red node is never the root, so `p` has a parent
+            let g = parent(p).expect("red root in tree");
+            if left(g) == Some(p) {
+                let uncle = right(g);
+                if is_red(uncle) {
+                    set_red(p, false);
+                    set_red(uncle.unwrap(), false);
+                    set_red(g, true);
+                    z = g;
+                } else {
+                    if right(p) == Some(z) {
+                        z = p;
+                        self.rotate_left(z);
+                    }
+                    let p = parent(z).unwrap();
+                    set_red(p, false);
+                    set_red(g, true);
+                    self.rotate_right(g);
+                }
+            } else {
+                let uncle = left(g);
+                if is_red(uncle) {
+                    set_red(p, false);
+                    set_red(uncle.unwrap(), false);
+                    set_red(g, true);
+                    z = g;
+                } else {
+                    if left(p) == Some(z) {
+                        z = p;
+                        self.rotate_right(z);
+                    }
+                    let p = parent(z).unwrap();
+                    set_red(p, false);
+                    set_red(g, true);
+                    self.rotate_left(g);
+                }
+            }
+        }
+        set_red(self.root.unwrap(), false);
+    }
+
+    /// Removes `z` from the tree, and returns the pointer to its value
+    unsafe fn unlink(&mut self, z: NonNull<RbLink>) -> A::Pointer {
+        let removed_red;
+        let x;
+        let x_parent;
+        match (left(z), right(z)) {
+            (None, child) | (child, None) => {
+                removed_red = is_red(Some(z));
+                x = child;
+                x_parent = parent(z);
+                self.replace_child(x_parent, z, x);
+                if let Some(x) = x {
+                    set_parent(x, x_parent);
+                }
+            }
+            (Some(zl), Some(zr)) => {
+                // Replace `z` with its successor, which has no left child
+                let y = minimum(zr);
+                removed_red = is_red(Some(y));
+                x = right(y);
+                if y == zr {
+                    x_parent = Some(y);
+                } else {
+                    x_parent = parent(y);
+                    self.replace_child(x_parent, y, x);
+                    if let Some(x) = x {
+                        set_parent(x, x_parent);
+                    }
+                    set_right(y, Some(zr));
+                    set_parent(zr, Some(y));
+                }
+                let zp = parent(z);
+                self.replace_child(zp, z, Some(y));
+                set_parent(y, zp);
+                set_left(y, Some(zl));
+                set_parent(zl, Some(y));
+                set_red(y, is_red(Some(z)));
+            }
+        }
+        if !removed_red {
+            self.remove_fixup(x, x_parent);
+        }
+
+        set_parent(z, None);
+        set_left(z, None);
+        set_right(z, None);
+        self.len -= 1;
+        (*z.as_ptr()).linked.store(false, Ordering::Release);
+        A::Pointer::from_raw(value_of::<A>(z))
+    }
+
+    /// Restores the red-black properties after a black node was removed above `x`, which is `None` if it's a missing child of `x_parent`
+    unsafe fn remove_fixup(&mut self, mut x: RbPtr, mut x_parent: RbPtr) {
+        while x != self.root && !is_red(x) {
+            // `x` is not the root, so it has a parent, and it has a sibling since it is missing a black node
+            let p = x_parent.unwrap();
+            if left(p) == x {
+                let mut w = right(p).expect("missing sibling in tree");
+                if is_red(Some(w)) {
+                    set_red(w, false);
+                    set_red(p, true);
+                    self.rotate_left(p);
+                    w = right(p).unwrap();
+                }
+                if !is_red(left(w)) && !is_red(right(w)) {
+                    set_red(w, true);
+                    x = Some(p);
+                    x_parent = parent(p);
+                } else {
+                    if !is_red(right(w)) {
+                        set_red(left(w).unwrap(), false);
+                        set_red(w, true);
+                        self.rotate_right(w);
+                        w = right(p).unwrap();
+                    }
+                    set_red(w, is_red(Some(p)));
+                    set_red(p, false);
+                    set_red(right(w).unwrap(), false);
+                    self.rotate_left(p);
+                    x = self.root;
+                    x_parent = None;
+                }
+            } else {
+                let mut w = left(p).expect("missing sibling in tree");
+                if is_red(Some(w)) {
+                    set_red(w, false);
+                    set_red(p, true);
+                    self.rotate_right(p);
+                    w = left(p).unwrap();
+                }
+                if !is_red(left(w)) && !is_red(right(w)) {
+                    set_red(w, true);
+                    x = Some(p);
+                    x_parent = parent(p);
+                } else {
+                    if !is_red(left(w)) {
+                        set_red(right(w).unwrap(), false);
+                        set_red(w, true);
+                        self.rotate_left(w);
+                        w = left(p).unwrap();
+                    }
+                    set_red(w, is_red(Some(p)));
+                    set_red(p, false);
+                    set_red(left(w).unwrap(), false);
+                    self.rotate_right(p);
+                    x = self.root;
+                    x_parent = None;
+                }
+            }
+        }
+        if let Some(x) = x {
+            set_red(x, false);
+        }
+    }
+
+    fn first_link(&self) -> RbPtr {
+        // SAFETY:
+        // The root is in the tree
+        self.root.map(|root| unsafe { minimum(root) })
+    }
+
+    fn last_link(&self) -> RbPtr {
+        // SAFETY:
+        // The root is in the tree
+        self.root.map(|root| unsafe { maximum(root) })
+    }
+
+    /// Returns the value with the smallest key, if any
+    pub fn first(&self) -> Option<&A::Value> {
+        // SAFETY:
+        // Every link in the tree belongs to a value owned by the tree
+        self.first_link()
+            .map(|link| unsafe { value_of::<A>(link).as_ref() })
+    }
+
+    /// Returns the value with the largest key, if any
+    pub fn last(&self) -> Option<&A::Value> {
+        // SAFETY:
+        // Every link in the tree belongs to a value owned by the tree
+        self.last_link()
+            .map(|link| unsafe { value_of::<A>(link).as_ref() })
+    }
+
+    /// Removes the value with the smallest key, if any
+    pub fn pop_first(&mut self) -> Option<A::Pointer> {
+        // SAFETY:
+        // The link is in the tree
+        self.first_link().map(|link| unsafe { self.unlink(link) })
+    }
+
+    /// Removes the value with the largest key, if any
+    pub fn pop_last(&mut self) -> Option<A::Pointer> {
+        // SAFETY:
+        // The link is in the tree
+        self.last_link().map(|link| unsafe { self.unlink(link) })
+    }
+
+    /// Returns the first link whose key is not less than `key`
+    fn lower_bound_link(&self, key: &A::Key) -> RbPtr {
+        let mut found = None;
+        let mut cur = self.root;
+        while let Some(node) = cur {
+            // SAFETY:
+            // Every link reachable from the root is in the tree
+            unsafe {
+                if Self::key_of(node) < *key {
+                    cur = right(node);
+                } else {
+                    found = Some(node);
+                    cur = left(node);
+                }
+            }
+        }
+        found
+    }
+
+    /// Returns the first value whose key is equal to `key`, if any
+    pub fn find(&self, key: &A::Key) -> Option<&A::Value> {
+        let link = self.lower_bound_link(key)?;
+        // SAFETY:
+        // The link is in the tree
+        unsafe {
+            let value = value_of::<A>(link).as_ref();
+            (A::key(value) == *key).then_some(value)
+        }
+    }
+
+    /// Returns a cursor pointing to the first value whose key is not less than `key`, or to the ghost position if there is none
+    pub fn lower_bound(&self, key: &A::Key) -> RbCursor<'_, A> {
+        RbCursor {
+            current: self.lower_bound_link(key),
+            tree: self,
+        }
+    }
+
+    /// Returns a cursor that can remove values, pointing to the first value whose key is not less than `key`, or to the ghost position if there is none
+    pub fn lower_bound_mut(&mut self, key: &A::Key) -> RbCursorMut<'_, A> {
+        RbCursorMut {
+            current: self.lower_bound_link(key),
+            tree: self,
+        }
+    }
+
+    /// Removes every value from the tree, dropping the pointers to them
+    pub fn clear(&mut self) {
+        while self.pop_first().is_some() {}
+    }
+
+    /// Produces an [`Iterator`] over the values of the tree, in ascending order of key
+    pub fn iter(&self) -> RbIter<'_, A> {
+        RbIter {
+            front: self.first_link(),
+            back: self.last_link(),
+            remaining: self.len,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Returns a cursor pointing to the value with the smallest key, or to the ghost position if the tree is empty
+    pub fn cursor_front(&self) -> RbCursor<'_, A> {
+        RbCursor {
+            current: self.first_link(),
+            tree: self,
+        }
+    }
+
+    /// Returns a cursor pointing to the value with the largest key, or to the ghost position if the tree is empty
+    pub fn cursor_back(&self) -> RbCursor<'_, A> {
+        RbCursor {
+            current: self.last_link(),
+            tree: self,
+        }
+    }
+
+    /// Returns a cursor that can remove values, pointing to the value with the smallest key, or to the ghost position if the tree is empty
+    pub fn cursor_front_mut(&mut self) -> RbCursorMut<'_, A> {
+        RbCursorMut {
+            current: self.first_link(),
+            tree: self,
+        }
+    }
+
+    /// Returns a cursor that can remove values, pointing to the value with the largest key, or to the ghost position if the tree is empty
+    pub fn cursor_back_mut(&mut self) -> RbCursorMut<'_, A> {
+        RbCursorMut {
+            current: self.last_link(),
+            tree: self,
+        }
+    }
+
+    /// Returns a cursor that can remove values, pointing to `value`
+    ///
+    /// # Safety
+    /// `value` must be in this tree
+    pub unsafe fn cursor_mut_from_value(&mut self, value: &A::Value) -> RbCursorMut<'_, A> {
+        let link = link_of::<A>(NonNull::from(value));
+        RbCursorMut {
+            current: Some(link),
+            tree: self,
+        }
+    }
+
+    /// Checks the links, colors and ordering of the whole tree, panicking if any is inconsistent
+    pub fn validate(&self) {
+        // Returns the black height of the subtree at `link`
+        unsafe fn check<A: KeyAdapter>(link: RbPtr, up: RbPtr, count: &mut usize) -> usize {
+            let Some(node) = link else {
+                return 1;
+            };
+            assert!(
+                (*node.as_ptr()).linked.load(Ordering::Relaxed),
+                "tree contains an unclaimed link"
+            );
+            assert_eq!(parent(node), up, "corrupted tree links");
+            if is_red(link) {
+                assert!(
+                    !is_red(left(node)) && !is_red(right(node)),
+                    "red node with a red child"
+                );
+            }
+            let key = RbTree::<A>::key_of(node);
+            if let Some(l) = left(node) {
+                assert!(RbTree::<A>::key_of(l) <= key, "tree is out of order");
+            }
+            if let Some(r) = right(node) {
+                assert!(RbTree::<A>::key_of(r) >= key, "tree is out of order");
+            }
+            *count += 1;
+            let lh = check::<A>(left(node), link, count);
+            let rh = check::<A>(right(node), link, count);
+            assert_eq!(lh, rh, "unequal black heights");
+            lh + usize::from(!is_red(link))
+        }
+
+        let mut count = 0;
+        // SAFETY:
+        // Every link reachable from the root is in the tree
+        unsafe {
+            assert!(!is_red(self.root), "red root");
+            check::<A>(self.root, None, &mut count);
+        }
+        assert_eq!(count, self.len, "tree size does not match its length");
+        assert!(
+            self.iter()
+                .zip(self.iter().skip(1))
+                .all(|(a, b)| A::key(a) <= A::key(b)),
+            "tree is out of order"
+        );
+    }
+}
+
+impl<A: KeyAdapter> Debug for RbTree<A>
+where
+    A::Value: Debug,
+{
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<'a, A: KeyAdapter> IntoIterator for &'a RbTree<A> {
+    type Item = &'a A::Value;
+
+    type IntoIter = RbIter<'a, A>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<A: KeyAdapter> Extend<A::Pointer> for RbTree<A> {
+    fn extend<I: IntoIterator<Item = A::Pointer>>(&mut self, iter: I) {
+        for ptr in iter {
+            self.insert(ptr);
+        }
+    }
+}
+
+/// An [`Iterator`] over the values of an [`RbTree`], in ascending order of key
+pub struct RbIter<'a, A: KeyAdapter> {
+    front: RbPtr,
+    back: RbPtr,
+    remaining: usize,
+    phantom: PhantomData<&'a RbTree<A>>,
+}
+
+impl<'a, A: KeyAdapter> Iterator for RbIter<'a, A> {
+    type Item = &'a A::Value;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let link = self.front?;
+        self.remaining -= 1;
+        // SAFETY:
+        // The tree is borrowed for `'a`, so its links are valid and can't change
+        unsafe {
+            self.front = successor(link);
+            Some(value_of::<A>(link).as_ref())
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, A: KeyAdapter> DoubleEndedIterator for RbIter<'a, A> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let link = self.back?;
+        self.remaining -= 1;
+        // SAFETY:
+        // The tree is borrowed for `'a`, so its links are valid and can't change
+        unsafe {
+            self.back = predecessor(link);
+            Some(value_of::<A>(link).as_ref())
+        }
+    }
+}
+
+impl<'a, A: KeyAdapter> ExactSizeIterator for RbIter<'a, A> {}
+
+impl<'a, A: KeyAdapter> FusedIterator for RbIter<'a, A> {}
+
+/// A cursor over an [`RbTree`], moving in order of key.
+///
+/// The cursor points either to a value of the tree, or to a ghost position between the last and the first value.
+pub struct RbCursor<'a, A: KeyAdapter> {
+    current: RbPtr,
+    tree: &'a RbTree<A>,
+}
+
+impl<'a, A: KeyAdapter> Clone for RbCursor<'a, A> {
+    fn clone(&self) -> Self {
+        Self {
+            current: self.current,
+            tree: self.tree,
+        }
+    }
+}
+
+impl<'a, A: KeyAdapter> RbCursor<'a, A> {
+    /// Returns the value the cursor points to, or `None` at the ghost position
+    pub fn get(&self) -> Option<&'a A::Value> {
+        // SAFETY:
+        // The current link is in the tree, which is borrowed for `'a`
+        self.current
+            .map(|link| unsafe { value_of::<A>(link).as_ref() })
+    }
+
+    /// Moves the cursor to the next value, or from the last value to the ghost position, or from the ghost position to the first value
+    pub fn move_next(&mut self) {
+        self.current = match self.current {
+            // SAFETY:
+            // The current link is in the tree
+            Some(link) => unsafe { successor(link) },
+            None => self.tree.first_link(),
+        };
+    }
+
+    /// Moves the cursor to the previous value, or from the first value to the ghost position, or from the ghost position to the last value
+    pub fn move_prev(&mut self) {
+        self.current = match self.current {
+            // SAFETY:
+            // The current link is in the tree
+            Some(link) => unsafe { predecessor(link) },
+            None => self.tree.last_link(),
+        };
+    }
+
+    /// Returns the value after the cursor, without moving it
+    pub fn peek_next(&self) -> Option<&'a A::Value> {
+        let mut next = self.clone();
+        next.move_next();
+        next.get()
+    }
+
+    /// Returns the value before the cursor, without moving it
+    pub fn peek_prev(&self) -> Option<&'a A::Value> {
+        let mut prev = self.clone();
+        prev.move_prev();
+        prev.get()
+    }
+}
+
+/// A cursor over an [`RbTree`] that can remove values, moving in order of key.
+///
+/// The cursor points either to a value of the tree, or to a ghost position between the last and the first value.
+/// Values are inserted through [`RbTree::insert`] rather than the cursor, since their position is given by their key.
+pub struct RbCursorMut<'a, A: KeyAdapter> {
+    current: RbPtr,
+    tree: &'a mut RbTree<A>,
+}
+
+impl<'a, A: KeyAdapter> RbCursorMut<'a, A> {
+    /// Returns a read-only cursor at the same position, borrowing this one
+    pub fn as_cursor(&self) -> RbCursor<'_, A> {
+        RbCursor {
+            current: self.current,
+            tree: self.tree,
+        }
+    }
+
+    /// Returns the value the cursor points to, or `None` at the ghost position
+    pub fn get(&self) -> Option<&A::Value> {
+        // SAFETY:
+        // The current link is in the tree
+        self.current
+            .map(|link| unsafe { value_of::<A>(link).as_ref() })
+    }
+
+    /// Moves the cursor to the next value, or from the last value to the ghost position, or from the ghost position to the first value
+    pub fn move_next(&mut self) {
+        self.current = match self.current {
+            // SAFETY:
+            // The current link is in the tree
+            Some(link) => unsafe { successor(link) },
+            None => self.tree.first_link(),
+        };
+    }
+
+    /// Moves the cursor to the previous value, or from the first value to the ghost position, or from the ghost position to the last value
+    pub fn move_prev(&mut self) {
+        self.current = match self.current {
+            // SAFETY:
+            // The current link is in the tree
+            Some(link) => unsafe { predecessor(link) },
+            None => self.tree.last_link(),
+        };
+    }
+
+    /// Returns the value after the cursor, without moving it
+    pub fn peek_next(&self) -> Option<&A::Value> {
+        self.as_cursor().peek_next()
+    }
+
+    /// Returns the value before the cursor, without moving it
+    pub fn peek_prev(&self) -> Option<&A::Value> {
+        self.as_cursor().peek_prev()
+    }
+
+    /// Removes the value the cursor points to and moves the cursor to the next value.
+    ///
+    /// Returns `None`, and does nothing, at the ghost position
+    pub fn remove_current(&mut self) -> Option<A::Pointer> {
+        let link = self.current?;
+        // SAFETY:
+        // The current link is in the tree. Removing it relinks the other nodes without moving them, so its successor stays valid
+        unsafe {
+            self.current = successor(link);
+            Some(self.tree.unlink(link))
+        }
+    }
+}
+
+#[cfg(all(test, feature = "std", not(loom)))]
+mod test {
+    extern crate std as host;
+
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use core::pin::Pin;
+    use host::panic::{self, AssertUnwindSafe};
+
+    use super::{KeyAdapter, LinkedList, ListLink, RbLink, RbTree};
+
+    struct Node {
+        key: u32,
+        id: usize,
+        list: ListLink,
+        tree: RbLink,
+    }
+
+    fn node(key: u32, id: usize) -> Pin<Arc<Node>> {
+        Arc::pin(Node {
+            key,
+            id,
+            list: ListLink::new(),
+            tree: RbLink::new(),
+        })
+    }
+
+    crate::intrusive_adapter!(ListAdapter = Pin<Arc<Node>>: Node { list: ListLink });
+    crate::intrusive_adapter!(TreeAdapter = Pin<Arc<Node>>: Node { tree: RbLink });
+
+    impl KeyAdapter for TreeAdapter {
+        type Key = u32;
+
+        fn key(value: &Node) -> u32 {
+            value.key
+        }
+    }
+
+    /// Returns the number of references to the node behind `n`, including `n`
+    fn refs(n: &Pin<Arc<Node>>) -> usize {
+        // SAFETY: the clone is dropped without moving the node out of it
+        let n = unsafe { Pin::into_inner_unchecked(n.clone()) };
+        Arc::strong_count(&n) - 1
+    }
+
+    fn ids(list: &LinkedList<ListAdapter>) -> Vec<usize> {
+        list.iter().map(|n| n.id).collect()
+    }
+
+    #[test]
+    fn list_push_pop() {
+        let nodes: Vec<_> = (0..6).map(|i| node(0, i)).collect();
+        let mut list = LinkedList::<ListAdapter>::new();
+        list.validate();
+        for n in &nodes[..3] {
+            list.push_back(n.clone());
+            list.validate();
+        }
+        for n in &nodes[3..] {
+            list.push_front(n.clone());
+            list.validate();
+        }
+        assert_eq!(ids(&list), [5, 4, 3, 0, 1, 2]);
+        assert_eq!(
+            list.iter().rev().map(|n| n.id).collect::<Vec<_>>(),
+            [2, 1, 0, 3, 4, 5]
+        );
+        assert!(nodes.iter().all(|n| n.list.is_linked()));
+
+        assert_eq!(list.pop_front().unwrap().id, 5);
+        list.validate();
+        assert_eq!(list.pop_back().unwrap().id, 2);
+        list.validate();
+        assert!(!nodes[5].list.is_linked());
+        assert_eq!(list.len(), 4);
+
+        // Clearing the list releases its references
+        list.clear();
+        list.validate();
+        assert!(list.is_empty());
+        assert!(nodes.iter().all(|n| !n.list.is_linked() && refs(n) == 1));
+    }
+
+    #[test]
+    fn list_cursor() {
+        let nodes: Vec<_> = (0..8).map(|i| node(0, i)).collect();
+        let mut list = LinkedList::<ListAdapter>::new();
+
+        // At the ghost position, insert_after pushes to the front and insert_before to the back
+        let mut cursor = list.cursor_front_mut();
+        assert!(cursor.get().is_none());
+        cursor.insert_after(nodes[1].clone());
+        cursor.insert_before(nodes[3].clone());
+        cursor.move_next();
+        assert_eq!(cursor.get().unwrap().id, 1);
+        cursor.insert_after(nodes[2].clone());
+        cursor.insert_before(nodes[0].clone());
+        list.validate();
+        assert_eq!(ids(&list), [0, 1, 2, 3]);
+
+        let mut cursor = list.cursor_back_mut();
+        assert_eq!(cursor.peek_prev().unwrap().id, 2);
+        assert!(cursor.peek_next().is_none());
+        cursor.insert_after(nodes[4].clone());
+        cursor.move_next();
+        cursor.move_next();
+        assert!(cursor.get().is_none());
+        cursor.move_next();
+        assert_eq!(cursor.get().unwrap().id, 0);
+        list.validate();
+        assert_eq!(ids(&list), [0, 1, 2, 3, 4]);
+
+        // Removing moves the cursor to the next value, at the head, in the middle and at the tail
+        let mut cursor = list.cursor_front_mut();
+        assert_eq!(cursor.remove_current().unwrap().id, 0);
+        assert_eq!(cursor.get().unwrap().id, 1);
+        cursor.move_next();
+        assert_eq!(cursor.remove_current().unwrap().id, 2);
+        assert_eq!(cursor.get().unwrap().id, 3);
+        cursor.move_next();
+        assert_eq!(cursor.remove_current().unwrap().id, 4);
+        assert!(cursor.get().is_none());
+        assert!(cursor.remove_current().is_none());
+        list.validate();
+        assert_eq!(ids(&list), [1, 3]);
+
+        // SAFETY: the node is in the list
+        let mut cursor = unsafe { list.cursor_mut_from_value(&nodes[3]) };
+        assert_eq!(cursor.remove_current().unwrap().id, 3);
+        cursor.insert_before(nodes[5].clone());
+        list.validate();
+        assert_eq!(ids(&list), [1, 5]);
+    }
+
+    #[test]
+    fn tree_insert_remove() {
+        let mut seed = 0x853c_49e6_748f_ea9bu64;
+        let mut next = move |bound: u64| {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            (seed % bound) as u32
+        };
+        let nodes: Vec<_> = (0..200).map(|i| node(next(50), i)).collect();
+        let mut tree = RbTree::<TreeAdapter>::new();
+        for n in &nodes {
+            tree.insert(n.clone());
+            tree.validate();
+        }
+        assert_eq!(tree.len(), 200);
+
+        // Values with equal keys are kept in insertion order
+        let order: Vec<_> = tree.iter().map(|n| (n.key, n.id)).collect();
+        let mut expected: Vec<_> = nodes.iter().map(|n| (n.key, n.id)).collect();
+        expected.sort();
+        assert_eq!(order, expected);
+
+        let key = nodes[17].key;
+        assert_eq!(tree.find(&key).unwrap().key, key);
+        let mut cursor = tree.lower_bound_mut(&key);
+        assert_eq!(cursor.get().unwrap().key, key);
+        while cursor.get().is_some_and(|n| n.key == key) {
+            assert_eq!(cursor.remove_current().unwrap().key, key);
+        }
+        tree.validate();
+        assert!(tree.find(&key).is_none());
+
+        // Removes from the middle, the ends, and through the cursor, validating after each
+        let mut removed = 0;
+        for (i, n) in nodes.iter().enumerate() {
+            if !n.tree.is_linked() || i % 3 == 0 {
+                continue;
+            }
+            // SAFETY: the node is in the tree
+            let mut cursor = unsafe { tree.cursor_mut_from_value(n) };
+            assert_eq!(cursor.remove_current().unwrap().id, n.id);
+            tree.validate();
+            removed += 1;
+        }
+        assert!(removed > 0);
+        let first = tree.pop_first().unwrap();
+        let last = tree.pop_last().unwrap();
+        tree.validate();
+        assert!(tree.iter().all(|n| first.key <= n.key && n.key <= last.key));
+
+        let mut cursor = tree.cursor_back_mut();
+        while cursor.remove_current().is_some() {
+            cursor.move_prev();
+            cursor.move_prev();
+        }
+        tree.validate();
+        tree.clear();
+        tree.validate();
+        assert!(tree.is_empty());
+        assert!(nodes.iter().all(|n| !n.tree.is_linked()));
+    }
+
+    #[test]
+    fn double_link_panics() {
+        let n = node(1, 0);
+        let mut list = LinkedList::<ListAdapter>::new();
+        let mut other = LinkedList::<ListAdapter>::new();
+        list.push_back(n.clone());
+
+        // The same value can be in a tree through another link
+        let mut tree = RbTree::<TreeAdapter>::new();
+        tree.insert(n.clone());
+        tree.validate();
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| other.push_back(n.clone())));
+        let msg = res.unwrap_err();
+        assert_eq!(
+            msg.downcast_ref::<&str>(),
+            Some(&"value is already linked into an intrusive collection")
+        );
+        // The pointer that failed to be inserted was dropped, and neither collection was changed
+        assert_eq!(refs(&n), 3);
+        list.validate();
+        other.validate();
+        assert!(other.is_empty());
+        assert_eq!(list.len(), 1);
+
+        let res = panic::catch_unwind(AssertUnwindSafe(|| tree.insert(n.clone())));
+        assert!(res.is_err());
+        tree.validate();
+        assert_eq!(tree.len(), 1);
+
+        list.clear();
+        other.push_back(n.clone());
+        other.validate();
+    }
+}
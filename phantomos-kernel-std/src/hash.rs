@@ -2,8 +2,6 @@ use core::{hash::Hasher, num::Wrapping};
 
 use alloc::slice;
 
-use crate::sync::OnceCell;
-
 ///
 /// A hasher with a consistent ABI and hash algorithm.
 /// the [`XLangHasher`] hashes bytes using the `FNV-1a` 64-bit hash
@@ -15,9 +13,10 @@ const PRIME: u64 = 1_099_511_628_211;
 
 pub fn xlang_hash_bytes(x: &[u8]) -> u64 {
     // When running tests, we don't want to bring in lazy_static to xlang_abi, but since hash seeds will be randomized at runtime, we want to choose a random seed when running miri
-    static SEED: OnceCell<u64> = OnceCell::new();
+    // The seed is the address of a static, which needs no synchronization (and so no `OnceCell`, which isn't const-constructible under loom)
+    static SEED: u8 = 0;
 
-    let mut hash = *SEED.get_or_init(|| &SEED as *const _ as usize as u64);
+    let mut hash = &SEED as *const u8 as usize as u64;
 
     for b in x {
         hash ^= (*b) as u64;
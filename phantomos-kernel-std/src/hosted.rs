@@ -0,0 +1,339 @@
+//! Hosted backend for the kernel standard library, enabled by the `std` feature.
+//!
+//! This provides the symbols that are normally supplied by `phantomos-init` or the kernel, implemented on top of the host's standard library,
+//!  so that code built on this crate can be tested with `cargo test` on a development machine.
+
+extern crate std as host;
+
+use core::ptr::NonNull;
+
+use host::alloc::{GlobalAlloc, Layout, System};
+
+use crate::io;
+
+/// Hosted stand-in for the kernel allocator entry point, backed by the host's system allocator.
+///
+/// # Safety
+/// `align` must be a power of two, and `size` rounded up to `align` must not overflow `isize`
+#[no_mangle]
+pub unsafe extern "C" fn kalloc(
+    size: usize,
+    align: usize,
+    _vaddr_hint: Option<NonNull<u8>>,
+) -> *mut u8 {
+    System.alloc(Layout::from_size_align_unchecked(size, align))
+}
+
+/// Hosted stand-in for the kernel deallocation entry point.
+///
+/// # Safety
+/// `ptr` must have been returned from [`kalloc`] with the same `size` and `align`, and not yet freed
+#[no_mangle]
+pub unsafe extern "C" fn kfree(ptr: *mut u8, size: usize, align: usize) {
+    System.dealloc(ptr, Layout::from_size_align_unchecked(size, align))
+}
+
+/// Hosted stand-in for the spin hint that the locks of this crate wait with, which yields the host thread.
+///
+/// Host threads are freely preempted while holding a lock, and spinning through the holder's timeslice instead of yielding to it
+/// makes the lock tests take minutes on a machine with a single CPU.
+pub(crate) fn spin_loop() {
+    host::thread::yield_now();
+}
+
+#[cfg(target_arch = "x86_64")]
+macro_rules! host_x86_features {
+    ($($feature:literal => $host_feature:tt),* $(,)?) => {
+        /// Hosted stand-in for the kernel's x86 feature detection, answering from the host's `cpuid`.
+        ///
+        /// Features that the host standard library cannot detect are reported as absent.
+        #[no_mangle]
+        pub extern "C" fn __has_x86_feature(feature: crate::str::StringView) -> bool {
+            match &*feature {
+                $($feature => host::is_x86_feature_detected!($host_feature),)*
+                _ => false,
+            }
+        }
+    };
+}
+
+#[cfg(target_arch = "x86_64")]
+host_x86_features! {
+    "mmx" => "mmx",
+    "sse" => "sse",
+    "sse2" => "sse2",
+    "sse3" => "sse3",
+    "ssse3" => "ssse3",
+    "sse4.1" => "sse4.1",
+    "sse4.2" => "sse4.2",
+    "avx" => "avx",
+    "avx2" => "avx2",
+    "avx512f" => "avx512f",
+    "avx512cd" => "avx512cd",
+    "avx512vl" => "avx512vl",
+    "avx512bw" => "avx512bw",
+    "avx512dq" => "avx512dq",
+    "sha" => "sha",
+    "aes" => "aes",
+    "pclmul" => "pclmulqdq",
+    "rdrand" => "rdrand",
+    "rdseed" => "rdseed",
+    "f16c" => "f16c",
+    "fma" => "fma",
+    "popcnt" => "popcnt",
+    "abm" => "abm",
+    "adx" => "adx",
+    "bmi" => "bmi1",
+    "bmi2" => "bmi2",
+    "lzcnt" => "lzcnt",
+    "fxsr" => "fxsr",
+    "xsave" => "xsave",
+    "xsaveopt" => "xsaveopt",
+    "xsavec" => "xsavec",
+    "xsaves" => "xsaves",
+    "rtm" => "rtm",
+    "tsc" => "tsc",
+    "cx16" => "cmpxchg16b",
+}
+
+macro_rules! map_error_kinds {
+    ($($kind:ident => $host_kind:ident),* $(,)?) => {
+        impl From<io::ErrorKind> for host::io::ErrorKind {
+            fn from(kind: io::ErrorKind) -> Self {
+                match kind {
+                    $(io::ErrorKind::$kind => Self::$host_kind,)*
+                    _ => Self::Other,
+                }
+            }
+        }
+
+        impl From<host::io::ErrorKind> for io::ErrorKind {
+            fn from(kind: host::io::ErrorKind) -> Self {
+                match kind {
+                    $(host::io::ErrorKind::$host_kind => Self::$kind,)*
+                    _ => Self::Other,
+                }
+            }
+        }
+    };
+}
+
+map_error_kinds! {
+    UnexpectedEof => UnexpectedEof,
+    Interrupted => Interrupted,
+    InvalidData => InvalidData,
+    NotADirectory => NotADirectory,
+    NotFound => NotFound,
+    PermissionDenied => PermissionDenied,
+    AlreadyExists => AlreadyExists,
+    ReadOnlyFilesystem => ReadOnlyFilesystem,
+    NoSpace => StorageFull,
+    InvalidInput => InvalidInput,
+    Unsupported => Unsupported,
+    WouldBlock => WouldBlock,
+    TimedOut => TimedOut,
+    IsADirectory => IsADirectory,
+    TooManyLinks => TooManyLinks,
+    OutOfMemory => OutOfMemory,
+    ResourceBusy => ResourceBusy,
+    DirectoryNotEmpty => DirectoryNotEmpty,
+    WriteZero => WriteZero,
+}
+
+impl From<host::io::Error> for io::Error {
+    fn from(err: host::io::Error) -> Self {
+        Self::new(err.kind().into(), host::string::ToString::to_string(&err))
+    }
+}
+
+impl From<io::Error> for host::io::Error {
+    fn from(err: io::Error) -> Self {
+        Self::new(err.kind().into(), host::string::ToString::to_string(&err))
+    }
+}
+
+/// Adapts a host [`Read`](host::io::Read), [`Write`](host::io::Write), or [`Seek`](host::io::Seek) implementation to the kernel [`io`] traits.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FromStd<T>(pub T);
+
+impl<T> FromStd<T> {
+    /// Returns the wrapped host stream
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: host::io::Read> io::Read for FromStd<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Ok(self.0.read(buf)?)
+    }
+}
+
+impl<T: host::io::Write> io::Write for FromStd<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(self.0.write(buf)?)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(self.0.flush()?)
+    }
+}
+
+impl<T: host::io::Seek> io::Seek for FromStd<T> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u128> {
+        let far = || io::Error::new(io::ErrorKind::InvalidInput, "seek offset out of range");
+        let pos = match pos {
+            io::SeekFrom::Start(off) => host::io::SeekFrom::Start(off),
+            io::SeekFrom::End(disp) => host::io::SeekFrom::End(disp),
+            io::SeekFrom::Current(disp) => host::io::SeekFrom::Current(disp),
+            io::SeekFrom::StartFar(off) => {
+                host::io::SeekFrom::Start(u64::try_from(off).map_err(|_| far())?)
+            }
+            io::SeekFrom::EndFar(disp) => {
+                host::io::SeekFrom::End(i64::try_from(disp).map_err(|_| far())?)
+            }
+            io::SeekFrom::CurrentFar(disp) => {
+                host::io::SeekFrom::Current(i64::try_from(disp).map_err(|_| far())?)
+            }
+        };
+
+        Ok(u128::from(self.0.seek(pos)?))
+    }
+}
+
+/// Adapts a kernel [`io`] stream to the host [`Read`](host::io::Read), [`Write`](host::io::Write), and [`Seek`](host::io::Seek) traits.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IntoStd<T>(pub T);
+
+impl<T> IntoStd<T> {
+    /// Returns the wrapped kernel stream
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T: io::Read> host::io::Read for IntoStd<T> {
+    fn read(&mut self, buf: &mut [u8]) -> host::io::Result<usize> {
+        Ok(self.0.read(buf)?)
+    }
+}
+
+impl<T: io::Write> host::io::Write for IntoStd<T> {
+    fn write(&mut self, buf: &[u8]) -> host::io::Result<usize> {
+        Ok(self.0.write(buf)?)
+    }
+
+    fn flush(&mut self) -> host::io::Result<()> {
+        Ok(self.0.flush()?)
+    }
+}
+
+impl<T: io::Seek> host::io::Seek for IntoStd<T> {
+    fn seek(&mut self, pos: host::io::SeekFrom) -> host::io::Result<u64> {
+        let pos = match pos {
+            host::io::SeekFrom::Start(off) => io::SeekFrom::Start(off),
+            host::io::SeekFrom::End(disp) => io::SeekFrom::End(disp),
+            host::io::SeekFrom::Current(disp) => io::SeekFrom::Current(disp),
+        };
+
+        u64::try_from(self.0.seek(pos)?).map_err(|_| {
+            host::io::Error::new(
+                host::io::ErrorKind::InvalidInput,
+                "stream position does not fit in a u64",
+            )
+        })
+    }
+}
+
+host::thread_local! {
+    static INTERRUPTS_ENABLED: core::cell::Cell<bool> = const { core::cell::Cell::new(true) };
+}
+
+/// Hosted stand-in for disabling interrupts, returning whether they were enabled beforehand.
+///
+/// User mode can't mask interrupts, so each host thread has a simulated interrupt flag instead, which starts out enabled.
+/// This lets [`IrqSave`](crate::sync::IrqSave) save and restore it as it would on the kernel
+pub(crate) fn disable_interrupts() -> bool {
+    INTERRUPTS_ENABLED.with(|enabled| enabled.replace(false))
+}
+
+/// Hosted stand-in for enabling interrupts. See [`disable_interrupts`]
+pub(crate) unsafe fn enable_interrupts() {
+    INTERRUPTS_ENABLED.with(|enabled| enabled.set(true));
+}
+
+/// Returns whether the simulated interrupt flag of the current thread is set. See [`disable_interrupts`]
+#[cfg(test)]
+pub(crate) fn interrupts_enabled() -> bool {
+    INTERRUPTS_ENABLED.with(|enabled| enabled.get())
+}
+
+/// A [`Park`](crate::task::Park) implementation that parks a host thread
+#[derive(Debug, Clone)]
+pub struct HostParker(host::thread::Thread);
+
+impl HostParker {
+    /// Returns a parker for the calling thread
+    pub fn current() -> Self {
+        Self(host::thread::current())
+    }
+}
+
+impl crate::task::Park for HostParker {
+    type Error = core::convert::Infallible;
+
+    fn park(&self) -> Result<(), Self::Error> {
+        host::thread::park();
+        Ok(())
+    }
+
+    fn unpark(&self) {
+        self.0.unpark();
+    }
+}
+
+/// A [`WaitQueue`](crate::sync::WaitQueue) implementation that blocks host threads on a condition variable
+#[derive(Debug, Default)]
+pub struct HostWaitQueue {
+    lock: host::sync::Mutex<()>,
+    cond: host::sync::Condvar,
+}
+
+impl crate::sync::WaitQueue for HostWaitQueue {
+    fn wait_while(&self, cond: &mut dyn FnMut() -> bool) {
+        // The condition is checked with the lock held, and wakers take the lock before notifying, so no wakeup is lost
+        let guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        drop(
+            self.cond
+                .wait_while(guard, |()| cond())
+                .unwrap_or_else(|e| e.into_inner()),
+        );
+    }
+
+    fn wake_one(&self) {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.cond.notify_one();
+    }
+
+    fn wake_all(&self) {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        self.cond.notify_all();
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod test {
+    use super::{kalloc, kfree};
+
+    #[test]
+    fn kalloc_round_trip() {
+        unsafe {
+            let ptr = kalloc(4096, 64, None);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % 64, 0);
+            ptr.write_bytes(0xa5, 4096);
+            assert_eq!(*ptr.add(4095), 0xa5);
+            kfree(ptr, 4096, 64);
+        }
+    }
+}
@@ -1,5 +1,17 @@
+use alloc::alloc::{Allocator, Global};
+use alloc::boxed::Box;
 use alloc::string::String;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::mem::ManuallyDrop;
+use core::pin::Pin;
+use core::task::{Context, Poll};
 
+mod rawmem;
+
+pub use self::rawmem::{RawMemReader, RawMemWriter};
+
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
 pub enum SeekFrom {
     Start(u64),
     End(i64),
@@ -9,93 +21,404 @@ pub enum SeekFrom {
     CurrentFar(i128),
 }
 
+macro_rules! define_error_kinds {
+    {
+        $($(#[$meta:meta])* $kind:ident = $code:literal => $desc:literal),* $(,)?
+    } => {
+        /// The general category of an [`Error`].
+        ///
+        /// Each kind has a stable numeric code, given by [`ErrorKind::code`], which is used to encode errors across the syscall ABI.
+        /// Codes are never reused or renumbered.
+        #[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+        #[non_exhaustive]
+        #[repr(u32)]
+        pub enum ErrorKind {
+            $($(#[$meta])* $kind = $code,)*
+        }
+
+        impl ErrorKind {
+            /// Returns the stable numeric code of this kind
+            pub const fn code(self) -> u32 {
+                self as u32
+            }
+
+            /// Returns the kind with the given numeric code, or `None` if no kind has that code
+            pub const fn from_code(code: u32) -> Option<Self> {
+                match code {
+                    $($code => Some(Self::$kind),)*
+                    _ => None,
+                }
+            }
+
+            /// Returns a short, human readable, description of this kind
+            pub const fn description(self) -> &'static str {
+                match self {
+                    $(Self::$kind => $desc,)*
+                }
+            }
+        }
+    };
+}
+
+define_error_kinds! {
+    /// An error that does not fit any other kind
+    Other = 0 => "Unknown error",
+    /// A read reached the end of a stream before it was complete
+    UnexpectedEof = 1 => "Unexpected End of File",
+    /// The operation was interrupted, and may be retried
+    Interrupted = 2 => "Operation Interrupted",
+    /// The data on a stream or filesystem was malformed
+    InvalidData = 3 => "Invalid data on stream",
+    /// A path component that must be a directory was not
+    NotADirectory = 4 => "Not a directory",
+    /// The requested object does not exist
+    NotFound = 5 => "No such file or directory",
+    /// The caller does not have permission for the operation
+    PermissionDenied = 6 => "Permission denied",
+    /// The object to be created already exists
+    AlreadyExists = 7 => "Already exists",
+    /// The filesystem is mounted read-only
+    ReadOnlyFilesystem = 8 => "Read-only filesystem",
+    /// The device or filesystem has no space remaining
+    NoSpace = 9 => "No space left on device",
+    /// A parameter to the operation was invalid
+    InvalidInput = 10 => "Invalid argument",
+    /// The operation is not supported by the object or driver
+    Unsupported = 11 => "Operation not supported",
+    /// The operation would need to block, but the object is non-blocking
+    WouldBlock = 12 => "Operation would block",
+    /// The operation did not complete before its deadline
+    TimedOut = 13 => "Operation timed out",
+    /// The object is a directory, but the operation requires a non-directory
+    IsADirectory = 14 => "Is a directory",
+    /// Too many links were encountered, or would be created
+    TooManyLinks = 15 => "Too many links",
+    /// An address passed to the operation was not valid for it
+    BadAddress = 16 => "Bad address",
+    /// Memory could not be allocated to complete the operation
+    OutOfMemory = 17 => "Out of memory",
+    /// The object is in use
+    ResourceBusy = 18 => "Resource busy",
+    /// The directory to be removed is not empty
+    DirectoryNotEmpty = 19 => "Directory not empty",
+    /// A write was attempted on a stream that could not accept any more data
+    WriteZero = 20 => "Write returned zero bytes",
+    /// A handle number did not refer to an open handle
+    InvalidHandle = 21 => "Invalid handle",
+    /// A handle referred to an object of the wrong type for the operation
+    WrongHandleType = 22 => "Wrong handle type",
+}
+
+impl core::fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        f.write_str(self.description())
+    }
+}
+
+/// The error type for I/O operations, consisting of an [`ErrorKind`] and an optional message giving more context.
 #[derive(Debug, Clone, Hash, PartialEq, Eq)]
-pub enum Error {
-    UnexpectedEof,
-    Interrupted,
-    InvalidData(Option<String>),
-    NotADirectory,
-    NotFound,
+pub struct Error {
+    kind: ErrorKind,
+    message: Option<String>,
+}
+
+impl Error {
+    /// Returns a new error of the given kind, with a message
+    pub fn new<M: Into<String>>(kind: ErrorKind, message: M) -> Self {
+        Self {
+            kind,
+            message: Some(message.into()),
+        }
+    }
+
+    /// Returns a new error of the given kind, with no message
+    pub const fn from_kind(kind: ErrorKind) -> Self {
+        Self {
+            kind,
+            message: None,
+        }
+    }
+
+    /// Decodes an error from its numeric code. Unknown codes are decoded as [`ErrorKind::Other`]
+    pub const fn from_code(code: u32) -> Self {
+        match ErrorKind::from_code(code) {
+            Some(kind) => Self::from_kind(kind),
+            None => Self::from_kind(ErrorKind::Other),
+        }
+    }
+
+    /// Returns the kind of this error
+    pub const fn kind(&self) -> ErrorKind {
+        self.kind
+    }
+
+    /// Returns the numeric code of the kind of this error
+    pub const fn code(&self) -> u32 {
+        self.kind.code()
+    }
+
+    /// Returns the message attached to this error, if any
+    pub fn message(&self) -> Option<&str> {
+        self.message.as_deref()
+    }
+
+    /// Prefixes the message of this error with `context`, describing what was being done when the error occurred
+    #[must_use]
+    pub fn context<C: core::fmt::Display>(self, context: C) -> Self {
+        let message = match self.message {
+            Some(message) => crate::format!("{}: {}", context, message),
+            None => crate::format!("{}", context),
+        };
+
+        Self {
+            kind: self.kind,
+            message: Some(message),
+        }
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Self::from_kind(kind)
+    }
 }
 
 impl core::fmt::Display for Error {
     fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
-        match self {
-            Self::UnexpectedEof => f.write_str("Unexpected End of File"),
-            Self::Interrupted => f.write_str("Operation Interrupted"),
-            Self::InvalidData(Some(info)) => {
-                f.write_str("Invalid data on stream:")?;
-                f.write_str(info)
-            }
-            Self::InvalidData(None) => f.write_str("Invalid data on stream"),
-            Self::NotADirectory => f.write_str("Not a directory"),
-            Self::NotFound => f.write_str("No such file or directory"),
+        match &self.message {
+            Some(message) => write!(f, "{}: {}", self.kind, message),
+            None => self.kind.fmt(f),
         }
     }
 }
 
+impl From<core::convert::Infallible> for Error {
+    fn from(x: core::convert::Infallible) -> Self {
+        match x {}
+    }
+}
+
 pub type Result<T> = core::result::Result<T, Error>;
 
 pub trait Read {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize>;
 
+    /// Reads into each of `bufs` in turn, and returns the total number of bytes read.
+    ///
+    /// The default implementation reads only into the first non-empty buffer.
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> Result<usize> {
+        match bufs.iter_mut().find(|b| !b.is_empty()) {
+            Some(buf) => self.read(buf),
+            None => Ok(0),
+        }
+    }
+
     fn read_exact(&mut self, mut buf: &mut [u8]) -> Result<()> {
         loop {
             match self.read(buf) {
-                Ok(0) => break Err(Error::UnexpectedEof),
+                Ok(0) => break Err(Error::from(ErrorKind::UnexpectedEof)),
                 Ok(n) if n == buf.len() => {
                     break Ok(());
                 }
                 Ok(n) => {
                     buf = &mut buf[n..];
                 }
-                Err(Error::Interrupted) => continue,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
                 Err(e) => break Err(e),
             }
         }
     }
 
+    /// Reads all bytes until end of file, appends them to `buf`, and returns the number of bytes read
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> Result<usize> {
+        let start = buf.len();
+        loop {
+            if buf.len() == buf.capacity() {
+                buf.reserve(32);
+            }
+            let len = buf.len();
+            buf.resize(buf.capacity(), 0);
+            let res = self.read(&mut buf[len..]);
+            match res {
+                Ok(0) => {
+                    buf.truncate(len);
+                    break Ok(len - start);
+                }
+                Ok(n) => buf.truncate(len + n),
+                Err(e) if e.kind() == ErrorKind::Interrupted => buf.truncate(len),
+                Err(e) => {
+                    buf.truncate(len);
+                    break Err(e);
+                }
+            }
+        }
+    }
+
+    /// Reads all bytes until end of file, and appends them to `buf`.
+    ///
+    /// Returns an [`ErrorKind::InvalidData`] error, and leaves `buf` unchanged, if the data is not valid UTF-8.
+    fn read_to_string(&mut self, buf: &mut String) -> Result<usize> {
+        // SAFETY:
+        // The appended bytes are validated below, and removed again if they are not UTF-8
+        let bytes = unsafe { buf.as_mut_vec() };
+        let start = bytes.len();
+        let res = self.read_to_end(bytes);
+        if core::str::from_utf8(&bytes[start..]).is_err() {
+            bytes.truncate(start);
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "stream did not contain valid UTF-8",
+            ));
+        }
+        res
+    }
+
     fn by_ref(&mut self) -> &mut Self
     where
         Self: Sized,
     {
         self
     }
+
+    /// Returns an iterator over the bytes of `self`.
+    ///
+    /// Each byte is read with a separate call to [`Read::read`], so `self` should usually be buffered.
+    fn bytes(self) -> Bytes<Self>
+    where
+        Self: Sized,
+    {
+        Bytes { inner: self }
+    }
+
+    /// Returns an adapter that reads at most `limit` bytes from `self`
+    fn take(self, limit: u64) -> Take<Self>
+    where
+        Self: Sized,
+    {
+        Take { inner: self, limit }
+    }
+
+    /// Returns an adapter that reads from `self` until it reaches end of file, and then from `next`
+    fn chain<R: Read>(self, next: R) -> Chain<Self, R>
+    where
+        Self: Sized,
+    {
+        Chain {
+            first: self,
+            second: next,
+            done_first: false,
+        }
+    }
 }
 
 impl<R: Read> Read for &mut R {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         <R as Read>::read(self, buf)
     }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut]) -> Result<usize> {
+        <R as Read>::read_vectored(self, bufs)
+    }
 }
 
 pub trait Seek {
-    fn seek(&mut self, pos: SeekFrom) -> Result<usize>;
+    /// Moves the cursor to `pos`, and returns the new position from the start of the stream
+    fn seek(&mut self, pos: SeekFrom) -> Result<u128>;
+
+    /// Returns the current position from the start of the stream
+    fn stream_position(&mut self) -> Result<u128> {
+        self.seek(SeekFrom::Current(0))
+    }
+
+    /// Moves the cursor to the start of the stream
+    fn rewind(&mut self) -> Result<()> {
+        self.seek(SeekFrom::Start(0))?;
+        Ok(())
+    }
+
+    /// Returns the length of the stream, leaving the cursor where it was
+    fn stream_len(&mut self) -> Result<u128> {
+        let pos = self.stream_position()?;
+        let len = self.seek(SeekFrom::End(0))?;
+        if pos != len {
+            self.seek(SeekFrom::StartFar(pos))?;
+        }
+        Ok(len)
+    }
 }
 
 impl<S: Seek> Seek for &mut S {
-    fn seek(&mut self, pos: SeekFrom) -> Result<usize> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u128> {
         <S as Seek>::seek(self, pos)
     }
+
+    fn stream_position(&mut self) -> Result<u128> {
+        <S as Seek>::stream_position(self)
+    }
+
+    fn stream_len(&mut self) -> Result<u128> {
+        <S as Seek>::stream_len(self)
+    }
 }
 
 pub trait Write {
     fn write(&mut self, buf: &[u8]) -> Result<usize>;
     fn flush(&mut self) -> Result<()>;
 
+    /// Writes from each of `bufs` in turn, and returns the total number of bytes written.
+    ///
+    /// The default implementation writes only from the first non-empty buffer.
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> Result<usize> {
+        match bufs.iter().find(|b| !b.is_empty()) {
+            Some(buf) => self.write(buf),
+            None => Ok(0),
+        }
+    }
+
     fn write_all(&mut self, mut buf: &[u8]) -> Result<()> {
         while !buf.is_empty() {
             match self.write(buf) {
-                Ok(0) => return Err(Error::UnexpectedEof),
+                Ok(0) => return Err(Error::from(ErrorKind::WriteZero)),
                 Ok(n) => {
                     buf = &buf[n..];
                 }
-                Err(Error::Interrupted) => {}
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
                 Err(e) => return Err(e),
             }
         }
         Ok(())
     }
+
+    /// Writes formatted output, for use with the [`write!`] macro.
+    ///
+    /// If formatting fails without an underlying I/O error, an [`ErrorKind::Other`] error is returned.
+    fn write_fmt(&mut self, args: core::fmt::Arguments) -> Result<()> {
+        struct Adapter<'a, W: ?Sized> {
+            inner: &'a mut W,
+            error: Result<()>,
+        }
+
+        impl<W: Write + ?Sized> core::fmt::Write for Adapter<'_, W> {
+            fn write_str(&mut self, s: &str) -> core::fmt::Result {
+                self.inner.write_all(s.as_bytes()).map_err(|e| {
+                    self.error = Err(e);
+                    core::fmt::Error
+                })
+            }
+        }
+
+        let mut adapter = Adapter {
+            inner: self,
+            error: Ok(()),
+        };
+        match core::fmt::write(&mut adapter, args) {
+            Ok(()) => Ok(()),
+            Err(_) => match adapter.error {
+                Err(e) => Err(e),
+                Ok(()) => Err(Error::new(ErrorKind::Other, "formatter error")),
+            },
+        }
+    }
 }
 
 impl<W: Write> Write for &mut W {
@@ -103,7 +426,1921 @@ impl<W: Write> Write for &mut W {
         <W as Write>::write(self, buf)
     }
 
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> Result<usize> {
+        <W as Write>::write_vectored(self, bufs)
+    }
+
     fn flush(&mut self) -> Result<()> {
         <W as Write>::flush(self)
     }
 }
+
+impl<A: Allocator> Write for Vec<u8, A> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        self.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> Result<usize> {
+        let len = self.len();
+        bufs.iter().for_each(|b| self.extend_from_slice(b));
+        Ok(self.len() - len)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Writes into the slice, advancing it past the bytes written
+impl Write for &mut [u8] {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let n = self.len().min(buf.len());
+        let (head, tail) = core::mem::take(self).split_at_mut(n);
+        head.copy_from_slice(&buf[..n]);
+        *self = tail;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// An iterator over the bytes of a reader, returned by [`Read::bytes`]
+#[derive(Debug)]
+pub struct Bytes<R> {
+    inner: R,
+}
+
+impl<R: Read> Iterator for Bytes<R> {
+    type Item = Result<u8>;
+
+    fn next(&mut self) -> Option<Result<u8>> {
+        let mut byte = 0;
+        loop {
+            break match self.inner.read(core::slice::from_mut(&mut byte)) {
+                Ok(0) => None,
+                Ok(_) => Some(Ok(byte)),
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => Some(Err(e)),
+            };
+        }
+    }
+}
+
+/// A buffer to write from, for use with [`Write::write_vectored`]
+#[derive(Copy, Clone, Debug)]
+#[repr(transparent)]
+pub struct IoSlice<'a>(&'a [u8]);
+
+impl<'a> IoSlice<'a> {
+    pub const fn new(buf: &'a [u8]) -> Self {
+        Self(buf)
+    }
+
+    /// Skips the first `n` bytes of the buffer
+    ///
+    /// ## Panics
+    /// Panics if `n` is greater than the length of the buffer
+    pub fn advance(&mut self, n: usize) {
+        self.0 = &self.0[n..];
+    }
+
+    /// Skips the first `n` bytes of `bufs`, removing the buffers that are fully consumed
+    ///
+    /// ## Panics
+    /// Panics if `n` is greater than the total length of `bufs`
+    pub fn advance_slices(bufs: &mut &mut [IoSlice<'a>], mut n: usize) {
+        let skip = bufs
+            .iter()
+            .take_while(|b| {
+                let consumed = b.len() <= n;
+                if consumed {
+                    n -= b.len();
+                }
+                consumed
+            })
+            .count();
+        *bufs = &mut core::mem::take(bufs)[skip..];
+        match bufs.first_mut() {
+            Some(first) => first.advance(n),
+            None => assert!(n == 0, "advancing past the end of the buffers"),
+        }
+    }
+}
+
+impl core::ops::Deref for IoSlice<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.0
+    }
+}
+
+/// A buffer to read into, for use with [`Read::read_vectored`]
+#[derive(Debug)]
+#[repr(transparent)]
+pub struct IoSliceMut<'a>(&'a mut [u8]);
+
+impl<'a> IoSliceMut<'a> {
+    pub fn new(buf: &'a mut [u8]) -> Self {
+        Self(buf)
+    }
+
+    /// Skips the first `n` bytes of the buffer
+    ///
+    /// ## Panics
+    /// Panics if `n` is greater than the length of the buffer
+    pub fn advance(&mut self, n: usize) {
+        self.0 = &mut core::mem::take(&mut self.0)[n..];
+    }
+
+    /// Skips the first `n` bytes of `bufs`, removing the buffers that are fully consumed
+    ///
+    /// ## Panics
+    /// Panics if `n` is greater than the total length of `bufs`
+    pub fn advance_slices(bufs: &mut &mut [IoSliceMut<'a>], mut n: usize) {
+        let skip = bufs
+            .iter()
+            .take_while(|b| {
+                let consumed = b.len() <= n;
+                if consumed {
+                    n -= b.len();
+                }
+                consumed
+            })
+            .count();
+        *bufs = &mut core::mem::take(bufs)[skip..];
+        match bufs.first_mut() {
+            Some(first) => first.advance(n),
+            None => assert!(n == 0, "advancing past the end of the buffers"),
+        }
+    }
+}
+
+impl core::ops::Deref for IoSliceMut<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.0
+    }
+}
+
+impl core::ops::DerefMut for IoSliceMut<'_> {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        self.0
+    }
+}
+
+/// A source of bytes that can be read at arbitrary offsets, without a cursor.
+///
+/// Unlike [`Read`] and [`Seek`], reads take `&self`, so a shared device can serve concurrent reads
+/// without them racing on a shared position.
+pub trait ReadAt {
+    /// Reads bytes starting at `offset` into `buf`, and returns the number of bytes read.
+    ///
+    /// Returns `Ok(0)` if `offset` is at or past the end of the source.
+    fn read_at(&self, buf: &mut [u8], offset: u128) -> Result<usize>;
+
+    /// Reads into each of `bufs` in turn starting at `offset`, and returns the total number of bytes read.
+    ///
+    /// The default implementation reads only into the first non-empty buffer.
+    fn read_vectored_at(&self, bufs: &mut [IoSliceMut], offset: u128) -> Result<usize> {
+        match bufs.iter_mut().find(|b| !b.is_empty()) {
+            Some(buf) => self.read_at(buf, offset),
+            None => Ok(0),
+        }
+    }
+
+    /// Fills `buf` with the bytes starting at `offset`
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u128) -> Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset) {
+                Ok(0) => return Err(Error::from(ErrorKind::UnexpectedEof)),
+                Ok(n) => {
+                    buf = &mut buf[n..];
+                    offset += n as u128;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: ReadAt + ?Sized> ReadAt for &R {
+    fn read_at(&self, buf: &mut [u8], offset: u128) -> Result<usize> {
+        <R as ReadAt>::read_at(self, buf, offset)
+    }
+
+    fn read_vectored_at(&self, bufs: &mut [IoSliceMut], offset: u128) -> Result<usize> {
+        <R as ReadAt>::read_vectored_at(self, bufs, offset)
+    }
+}
+
+impl ReadAt for [u8] {
+    fn read_at(&self, buf: &mut [u8], offset: u128) -> Result<usize> {
+        let src = match usize::try_from(offset) {
+            Ok(offset) if offset < self.len() => &self[offset..],
+            _ => return Ok(0),
+        };
+        let n = src.len().min(buf.len());
+        buf[..n].copy_from_slice(&src[..n]);
+        Ok(n)
+    }
+}
+
+/// A sink of bytes that can be written at arbitrary offsets, without a cursor.
+///
+/// See [`ReadAt`].
+pub trait WriteAt {
+    /// Writes bytes from `buf` starting at `offset`, and returns the number of bytes written
+    fn write_at(&self, buf: &[u8], offset: u128) -> Result<usize>;
+
+    /// Writes from each of `bufs` in turn starting at `offset`, and returns the total number of bytes written.
+    ///
+    /// The default implementation writes only from the first non-empty buffer.
+    fn write_vectored_at(&self, bufs: &[IoSlice], offset: u128) -> Result<usize> {
+        match bufs.iter().find(|b| !b.is_empty()) {
+            Some(buf) => self.write_at(buf, offset),
+            None => Ok(0),
+        }
+    }
+
+    /// Writes the entirety of `buf` starting at `offset`
+    fn write_all_at(&self, mut buf: &[u8], mut offset: u128) -> Result<()> {
+        while !buf.is_empty() {
+            match self.write_at(buf, offset) {
+                Ok(0) => return Err(Error::from(ErrorKind::WriteZero)),
+                Ok(n) => {
+                    buf = &buf[n..];
+                    offset += n as u128;
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Ensures that all written data has reached the underlying device
+    fn flush(&self) -> Result<()>;
+}
+
+impl<W: WriteAt + ?Sized> WriteAt for &W {
+    fn write_at(&self, buf: &[u8], offset: u128) -> Result<usize> {
+        <W as WriteAt>::write_at(self, buf, offset)
+    }
+
+    fn write_vectored_at(&self, bufs: &[IoSlice], offset: u128) -> Result<usize> {
+        <W as WriteAt>::write_vectored_at(self, bufs, offset)
+    }
+
+    fn flush(&self) -> Result<()> {
+        <W as WriteAt>::flush(self)
+    }
+}
+
+/// The default capacity of the buffer of a [`BufReader`] or [`BufWriter`]
+pub const DEFAULT_BUF_SIZE: usize = 4096;
+
+fn alloc_buffer<A: Allocator>(cap: usize, alloc: A) -> Box<[u8], A> {
+    let mut buf = Vec::with_capacity_in(cap, alloc);
+    buf.resize(cap, 0);
+    buf.into_boxed_slice()
+}
+
+/// A [`Read`]er with an internal buffer, which allows for efficient small reads and for reading lines.
+pub trait BufRead: Read {
+    /// Returns the content of the internal buffer, filling it from the underlying reader if it is empty.
+    ///
+    /// An empty slice indicates end of file.
+    fn fill_buf(&mut self) -> Result<&[u8]>;
+
+    /// Marks `amt` bytes of the internal buffer as read, so they are not returned by [`BufRead::fill_buf`] again.
+    ///
+    /// `amt` must not exceed the length of the slice last returned by [`BufRead::fill_buf`].
+    fn consume(&mut self, amt: usize);
+
+    /// Reads bytes into `buf` until `byte` or end of file is reached, and returns the number of bytes read.
+    ///
+    /// The delimiter, if found, is appended to `buf`.
+    fn read_until(&mut self, byte: u8, buf: &mut Vec<u8>) -> Result<usize> {
+        let mut read = 0;
+        loop {
+            let (done, used) = {
+                let available = match self.fill_buf() {
+                    Ok(available) => available,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                };
+                match available.iter().position(|&b| b == byte) {
+                    Some(i) => {
+                        buf.extend_from_slice(&available[..=i]);
+                        (true, i + 1)
+                    }
+                    None => {
+                        buf.extend_from_slice(available);
+                        (available.is_empty(), available.len())
+                    }
+                }
+            };
+            self.consume(used);
+            read += used;
+            if done {
+                break Ok(read);
+            }
+        }
+    }
+
+    /// Reads a line, including the trailing newline if any, and appends it to `buf`.
+    ///
+    /// Returns an [`ErrorKind::InvalidData`] error, and leaves `buf` unchanged, if the line is not valid UTF-8.
+    fn read_line(&mut self, buf: &mut String) -> Result<usize> {
+        // SAFETY:
+        // The appended bytes are validated below, and removed again if they are not UTF-8
+        let bytes = unsafe { buf.as_mut_vec() };
+        let start = bytes.len();
+        let res = self.read_until(b'\n', bytes);
+        if core::str::from_utf8(&bytes[start..]).is_err() {
+            bytes.truncate(start);
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "stream did not contain valid UTF-8",
+            ));
+        }
+        res
+    }
+}
+
+impl<R: BufRead> BufRead for &mut R {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        <R as BufRead>::fill_buf(self)
+    }
+
+    fn consume(&mut self, amt: usize) {
+        <R as BufRead>::consume(self, amt)
+    }
+}
+
+/// Adds buffering to a [`Read`]er.
+///
+/// Reads that are at least as large as the buffer bypass it.
+pub struct BufReader<R, A: Allocator = Global> {
+    inner: R,
+    buf: Box<[u8], A>,
+    pos: usize,
+    filled: usize,
+}
+
+impl<R> BufReader<R> {
+    /// Creates a new reader with a buffer of [`DEFAULT_BUF_SIZE`] bytes
+    pub fn new(inner: R) -> Self {
+        Self::with_capacity_in(DEFAULT_BUF_SIZE, inner, Global)
+    }
+
+    /// Creates a new reader with a buffer of `cap` bytes
+    pub fn with_capacity(cap: usize, inner: R) -> Self {
+        Self::with_capacity_in(cap, inner, Global)
+    }
+}
+
+impl<R, A: Allocator> BufReader<R, A> {
+    /// Creates a new reader with a buffer of `cap` bytes, allocated from `alloc`
+    pub fn with_capacity_in(cap: usize, inner: R, alloc: A) -> Self {
+        Self {
+            inner,
+            buf: alloc_buffer(cap, alloc),
+            pos: 0,
+            filled: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the underlying reader.
+    ///
+    /// Reading directly from the underlying reader will skip over any buffered data.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Returns the data that has been read from the underlying reader but not yet consumed
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf[self.pos..self.filled]
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Unwraps the underlying reader. Any buffered data is lost.
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn discard_buffer(&mut self) {
+        self.pos = 0;
+        self.filled = 0;
+    }
+}
+
+impl<R: Read, A: Allocator> Read for BufReader<R, A> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.pos == self.filled && buf.len() >= self.buf.len() {
+            self.discard_buffer();
+            return self.inner.read(buf);
+        }
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: Read, A: Allocator> BufRead for BufReader<R, A> {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        if self.pos == self.filled {
+            self.filled = self.inner.read(&mut self.buf)?;
+            self.pos = 0;
+        }
+        Ok(&self.buf[self.pos..self.filled])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.filled);
+    }
+}
+
+impl<R: Seek, A: Allocator> Seek for BufReader<R, A> {
+    /// Seeks the underlying reader, discarding the buffer.
+    ///
+    /// [`SeekFrom::Current`] and [`SeekFrom::CurrentFar`] are relative to the position of the `BufReader`,
+    /// not to that of the underlying reader.
+    fn seek(&mut self, pos: SeekFrom) -> Result<u128> {
+        let remainder = (self.filled - self.pos) as i128;
+        let pos = match pos {
+            SeekFrom::Current(disp) => SeekFrom::CurrentFar(i128::from(disp) - remainder),
+            SeekFrom::CurrentFar(disp) => SeekFrom::CurrentFar(
+                disp.checked_sub(remainder)
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "seek offset overflowed"))?,
+            ),
+            pos => pos,
+        };
+        let res = self.inner.seek(pos)?;
+        self.discard_buffer();
+        Ok(res)
+    }
+
+    /// Returns the position of the `BufReader`, without discarding the buffer
+    fn stream_position(&mut self) -> Result<u128> {
+        let remainder = (self.filled - self.pos) as u128;
+        self.inner
+            .stream_position()?
+            .checked_sub(remainder)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "buffered more data than read"))
+    }
+}
+
+/// Adds buffering to a [`Write`]r.
+///
+/// The buffer is flushed when it fills up, when [`Write::flush`] is called, and when the `BufWriter` is dropped.
+/// Errors that occur while flushing on drop are ignored, so callers that care about them should flush explicitly.
+pub struct BufWriter<W: Write, A: Allocator = Global> {
+    inner: W,
+    buf: Vec<u8, A>,
+    panicked: bool,
+}
+
+impl<W: Write> BufWriter<W> {
+    /// Creates a new writer with a buffer of [`DEFAULT_BUF_SIZE`] bytes
+    pub fn new(inner: W) -> Self {
+        Self::with_capacity_in(DEFAULT_BUF_SIZE, inner, Global)
+    }
+
+    /// Creates a new writer with a buffer of `cap` bytes
+    pub fn with_capacity(cap: usize, inner: W) -> Self {
+        Self::with_capacity_in(cap, inner, Global)
+    }
+}
+
+impl<W: Write, A: Allocator> BufWriter<W, A> {
+    /// Creates a new writer with a buffer of `cap` bytes, allocated from `alloc`
+    pub fn with_capacity_in(cap: usize, inner: W, alloc: A) -> Self {
+        Self {
+            inner,
+            buf: Vec::with_capacity_in(cap, alloc),
+            panicked: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the underlying writer.
+    ///
+    /// Writing directly to the underlying writer will bypass any buffered data.
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Returns the data that has been written but not yet flushed to the underlying writer
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buf.capacity()
+    }
+
+    /// Flushes the buffer and unwraps the underlying writer.
+    ///
+    /// If flushing fails, the error is returned and the `BufWriter` is dropped, which retries the flush once.
+    pub fn into_inner(mut self) -> Result<W> {
+        self.flush_buf()?;
+        let mut this = ManuallyDrop::new(self);
+        // SAFETY:
+        // `this` is never used or dropped again, so `inner` is moved out exactly once and `buf` is dropped exactly once
+        unsafe {
+            core::ptr::drop_in_place(&mut this.buf);
+            Ok(core::ptr::read(&this.inner))
+        }
+    }
+
+    fn flush_buf(&mut self) -> Result<()> {
+        let mut written = 0;
+        let mut res = Ok(());
+        while written < self.buf.len() {
+            self.panicked = true;
+            let r = self.inner.write(&self.buf[written..]);
+            self.panicked = false;
+
+            match r {
+                Ok(0) => {
+                    res = Err(Error::new(
+                        ErrorKind::WriteZero,
+                        "failed to write the buffered data",
+                    ));
+                    break;
+                }
+                Ok(n) => written += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => {
+                    res = Err(e);
+                    break;
+                }
+            }
+        }
+        self.buf.drain(..written);
+        res
+    }
+}
+
+impl<W: Write, A: Allocator> Write for BufWriter<W, A> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.buf.len() + buf.len() > self.buf.capacity() {
+            self.flush_buf()?;
+        }
+        if buf.len() >= self.buf.capacity() {
+            self.panicked = true;
+            let r = self.inner.write(buf);
+            self.panicked = false;
+            r
+        } else {
+            self.buf.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice]) -> Result<usize> {
+        let total = bufs.iter().fold(0usize, |n, b| n.saturating_add(b.len()));
+        if self.buf.len() + total > self.buf.capacity() {
+            self.flush_buf()?;
+        }
+        if total >= self.buf.capacity() {
+            self.panicked = true;
+            let r = self.inner.write_vectored(bufs);
+            self.panicked = false;
+            r
+        } else {
+            bufs.iter().for_each(|b| self.buf.extend_from_slice(b));
+            Ok(total)
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.flush_buf()?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write + Seek, A: Allocator> Seek for BufWriter<W, A> {
+    /// Flushes the buffer, then seeks the underlying writer
+    fn seek(&mut self, pos: SeekFrom) -> Result<u128> {
+        self.flush_buf()?;
+        self.inner.seek(pos)
+    }
+}
+
+impl<W: Write, A: Allocator> Drop for BufWriter<W, A> {
+    fn drop(&mut self) {
+        // Don't write to a writer that panicked mid-write, as it may be in an inconsistent state
+        if !self.panicked {
+            let _ = self.flush_buf();
+        }
+    }
+}
+
+/// Wraps an in-memory buffer to provide it with a position, implementing [`Read`], [`Write`] and [`Seek`].
+///
+/// The position may be past the end of the buffer. Reads from there return end of file,
+/// and writes to growable buffers fill the gap with zeroes.
+#[derive(Clone, Debug, Default)]
+pub struct Cursor<T> {
+    inner: T,
+    pos: usize,
+}
+
+impl<T> Cursor<T> {
+    /// Creates a cursor at the start of `inner`
+    pub const fn new(inner: T) -> Self {
+        Self { inner, pos: 0 }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    pub const fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub const fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn set_position(&mut self, pos: usize) {
+        self.pos = pos;
+    }
+}
+
+impl<T: AsRef<[u8]>> Cursor<T> {
+    /// Returns the part of the buffer after the current position
+    pub fn remaining_slice(&self) -> &[u8] {
+        let inner = self.inner.as_ref();
+        &inner[self.pos.min(inner.len())..]
+    }
+}
+
+impl<T: AsRef<[u8]>> Read for Cursor<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let remaining = self.remaining_slice();
+        let n = remaining.len().min(buf.len());
+        buf[..n].copy_from_slice(&remaining[..n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl<T: AsRef<[u8]>> BufRead for Cursor<T> {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        Ok(self.remaining_slice())
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+impl<T: AsRef<[u8]>> Seek for Cursor<T> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u128> {
+        let len = self.inner.as_ref().len() as i128;
+        let npos = match pos {
+            SeekFrom::Start(off) => Some(i128::from(off)),
+            SeekFrom::StartFar(off) => i128::try_from(off).ok(),
+            SeekFrom::End(disp) => len.checked_add(i128::from(disp)),
+            SeekFrom::EndFar(disp) => len.checked_add(disp),
+            SeekFrom::Current(disp) => (self.pos as i128).checked_add(i128::from(disp)),
+            SeekFrom::CurrentFar(disp) => (self.pos as i128).checked_add(disp),
+        };
+
+        match npos.and_then(|n| usize::try_from(n).ok()) {
+            Some(npos) => {
+                self.pos = npos;
+                Ok(npos as u128)
+            }
+            None => Err(Error::new(
+                ErrorKind::InvalidInput,
+                "seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+fn slice_write(pos: &mut usize, slice: &mut [u8], buf: &[u8]) -> usize {
+    let start = (*pos).min(slice.len());
+    let n = (slice.len() - start).min(buf.len());
+    slice[start..][..n].copy_from_slice(&buf[..n]);
+    *pos += n;
+    n
+}
+
+fn vec_write<A: Allocator>(pos: &mut usize, vec: &mut Vec<u8, A>, buf: &[u8]) -> Result<usize> {
+    let end = pos
+        .checked_add(buf.len())
+        .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "cursor position overflowed"))?;
+    if vec.len() < end {
+        vec.resize(end, 0);
+    }
+    vec[*pos..end].copy_from_slice(buf);
+    *pos = end;
+    Ok(buf.len())
+}
+
+impl Write for Cursor<&mut [u8]> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        Ok(slice_write(&mut self.pos, self.inner, buf))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<A: Allocator> Write for Cursor<Box<[u8], A>> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        Ok(slice_write(&mut self.pos, &mut self.inner, buf))
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<A: Allocator> Write for Cursor<Vec<u8, A>> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        vec_write(&mut self.pos, &mut self.inner, buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl<A: Allocator> Write for Cursor<&mut Vec<u8, A>> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        vec_write(&mut self.pos, self.inner, buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Reader adapter which limits the bytes read from an underlying reader, returned by [`Read::take`]
+#[derive(Debug)]
+pub struct Take<R> {
+    inner: R,
+    limit: u64,
+}
+
+impl<R> Take<R> {
+    /// Returns the number of bytes that can still be read before this adapter returns end of file
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    pub fn set_limit(&mut self, limit: u64) {
+        self.limit = limit;
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for Take<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let max = usize::try_from(self.limit)
+            .unwrap_or(usize::MAX)
+            .min(buf.len());
+        let n = self.inner.read(&mut buf[..max])?;
+        self.limit -= n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for Take<R> {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        if self.limit == 0 {
+            return Ok(&[]);
+        }
+        let limit = usize::try_from(self.limit).unwrap_or(usize::MAX);
+        let buf = self.inner.fill_buf()?;
+        Ok(&buf[..buf.len().min(limit)])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        let amt = (amt as u64).min(self.limit);
+        self.limit -= amt;
+        self.inner.consume(amt as usize);
+    }
+}
+
+/// Reader adapter which reads from one reader and then another, returned by [`Read::chain`]
+#[derive(Debug)]
+pub struct Chain<R1, R2> {
+    first: R1,
+    second: R2,
+    done_first: bool,
+}
+
+impl<R1, R2> Chain<R1, R2> {
+    pub fn get_ref(&self) -> (&R1, &R2) {
+        (&self.first, &self.second)
+    }
+
+    pub fn get_mut(&mut self) -> (&mut R1, &mut R2) {
+        (&mut self.first, &mut self.second)
+    }
+
+    pub fn into_inner(self) -> (R1, R2) {
+        (self.first, self.second)
+    }
+}
+
+impl<R1: Read, R2: Read> Read for Chain<R1, R2> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if !self.done_first {
+            match self.first.read(buf)? {
+                0 if !buf.is_empty() => self.done_first = true,
+                n => return Ok(n),
+            }
+        }
+        self.second.read(buf)
+    }
+}
+
+impl<R1: BufRead, R2: BufRead> BufRead for Chain<R1, R2> {
+    fn fill_buf(&mut self) -> Result<&[u8]> {
+        if !self.done_first {
+            match self.first.fill_buf()? {
+                [] => self.done_first = true,
+                buf => return Ok(buf),
+            }
+        }
+        self.second.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        if !self.done_first {
+            self.first.consume(amt)
+        } else {
+            self.second.consume(amt)
+        }
+    }
+}
+
+/// Copies the entire content of `reader` into `writer`, and returns the number of bytes copied.
+///
+/// Reads that fail with [`ErrorKind::Interrupted`] are retried.
+pub fn copy<R: Read + ?Sized, W: Write + ?Sized>(reader: &mut R, writer: &mut W) -> Result<u64> {
+    // Kept small, as this may run on a kernel stack
+    let mut buf = [0u8; 512];
+    let mut copied = 0;
+    loop {
+        let n = match reader.read(&mut buf) {
+            Ok(0) => break Ok(copied),
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => break Err(e),
+        };
+        writer.write_all(&buf[..n])?;
+        copied += n as u64;
+    }
+}
+
+/// The asynchronous counterpart of [`Read`]
+pub trait AsyncRead {
+    /// Attempts to read into `buf`, returning [`Poll::Pending`] and arranging for `cx` to be woken if no data is available yet
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<Result<usize>>;
+}
+
+impl<R: AsyncRead + Unpin + ?Sized> AsyncRead for &mut R {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize>> {
+        Pin::new(&mut **self).poll_read(cx, buf)
+    }
+}
+
+impl<R: AsyncRead + ?Sized, A: Allocator> AsyncRead for Pin<Box<R, A>> {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<Result<usize>> {
+        self.get_mut().as_mut().poll_read(cx, buf)
+    }
+}
+
+/// The asynchronous counterpart of [`Write`]
+pub trait AsyncWrite {
+    /// Attempts to write from `buf`, returning [`Poll::Pending`] and arranging for `cx` to be woken if the writer is not ready yet
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<Result<usize>>;
+
+    /// Attempts to ensure that all written data has reached the underlying device
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>>;
+}
+
+impl<W: AsyncWrite + Unpin + ?Sized> AsyncWrite for &mut W {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<Result<usize>> {
+        Pin::new(&mut **self).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        Pin::new(&mut **self).poll_flush(cx)
+    }
+}
+
+impl<W: AsyncWrite + ?Sized, A: Allocator> AsyncWrite for Pin<Box<W, A>> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<Result<usize>> {
+        self.get_mut().as_mut().poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        self.get_mut().as_mut().poll_flush(cx)
+    }
+}
+
+/// The asynchronous counterpart of [`Seek`]
+pub trait AsyncSeek {
+    /// Attempts to move the cursor to `pos`, returning the new position from the start of the stream once done.
+    ///
+    /// If this returns [`Poll::Pending`], the next call must pass the same `pos`.
+    fn poll_seek(self: Pin<&mut Self>, cx: &mut Context, pos: SeekFrom) -> Poll<Result<u128>>;
+}
+
+impl<S: AsyncSeek + Unpin + ?Sized> AsyncSeek for &mut S {
+    fn poll_seek(mut self: Pin<&mut Self>, cx: &mut Context, pos: SeekFrom) -> Poll<Result<u128>> {
+        Pin::new(&mut **self).poll_seek(cx, pos)
+    }
+}
+
+impl<S: AsyncSeek + ?Sized, A: Allocator> AsyncSeek for Pin<Box<S, A>> {
+    fn poll_seek(self: Pin<&mut Self>, cx: &mut Context, pos: SeekFrom) -> Poll<Result<u128>> {
+        self.get_mut().as_mut().poll_seek(cx, pos)
+    }
+}
+
+/// Future-returning helpers for [`AsyncRead`]
+pub trait AsyncReadExt: AsyncRead {
+    /// Reads into `buf`, and resolves to the number of bytes read
+    fn read<'a>(&'a mut self, buf: &'a mut [u8]) -> ReadFuture<'a, Self>
+    where
+        Self: Unpin,
+    {
+        ReadFuture { reader: self, buf }
+    }
+
+    /// Fills `buf`, resolving to an [`ErrorKind::UnexpectedEof`] error if the reader ends first
+    fn read_exact<'a>(&'a mut self, buf: &'a mut [u8]) -> ReadExactFuture<'a, Self>
+    where
+        Self: Unpin,
+    {
+        ReadExactFuture { reader: self, buf }
+    }
+}
+
+impl<R: AsyncRead + ?Sized> AsyncReadExt for R {}
+
+/// Future-returning helpers for [`AsyncWrite`]
+pub trait AsyncWriteExt: AsyncWrite {
+    /// Writes from `buf`, and resolves to the number of bytes written
+    fn write<'a>(&'a mut self, buf: &'a [u8]) -> WriteFuture<'a, Self>
+    where
+        Self: Unpin,
+    {
+        WriteFuture { writer: self, buf }
+    }
+
+    /// Writes the entirety of `buf`
+    fn write_all<'a>(&'a mut self, buf: &'a [u8]) -> WriteAllFuture<'a, Self>
+    where
+        Self: Unpin,
+    {
+        WriteAllFuture { writer: self, buf }
+    }
+
+    fn flush(&mut self) -> FlushFuture<'_, Self>
+    where
+        Self: Unpin,
+    {
+        FlushFuture { writer: self }
+    }
+}
+
+impl<W: AsyncWrite + ?Sized> AsyncWriteExt for W {}
+
+/// Future-returning helpers for [`AsyncSeek`]
+pub trait AsyncSeekExt: AsyncSeek {
+    /// Moves the cursor to `pos`, and resolves to the new position from the start of the stream
+    fn seek(&mut self, pos: SeekFrom) -> SeekFuture<'_, Self>
+    where
+        Self: Unpin,
+    {
+        SeekFuture { seeker: self, pos }
+    }
+}
+
+impl<S: AsyncSeek + ?Sized> AsyncSeekExt for S {}
+
+/// Future returned by [`AsyncReadExt::read`]
+pub struct ReadFuture<'a, R: ?Sized> {
+    reader: &'a mut R,
+    buf: &'a mut [u8],
+}
+
+impl<R: AsyncRead + Unpin + ?Sized> Future for ReadFuture<'_, R> {
+    type Output = Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        Pin::new(&mut *this.reader).poll_read(cx, this.buf)
+    }
+}
+
+/// Future returned by [`AsyncReadExt::read_exact`]
+pub struct ReadExactFuture<'a, R: ?Sized> {
+    reader: &'a mut R,
+    buf: &'a mut [u8],
+}
+
+impl<R: AsyncRead + Unpin + ?Sized> Future for ReadExactFuture<'_, R> {
+    type Output = Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        while !this.buf.is_empty() {
+            match Pin::new(&mut *this.reader).poll_read(cx, this.buf) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(Err(Error::from(ErrorKind::UnexpectedEof)))
+                }
+                Poll::Ready(Ok(n)) => this.buf = &mut core::mem::take(&mut this.buf)[n..],
+                Poll::Ready(Err(e)) if e.kind() == ErrorKind::Interrupted => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Future returned by [`AsyncWriteExt::write`]
+pub struct WriteFuture<'a, W: ?Sized> {
+    writer: &'a mut W,
+    buf: &'a [u8],
+}
+
+impl<W: AsyncWrite + Unpin + ?Sized> Future for WriteFuture<'_, W> {
+    type Output = Result<usize>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        Pin::new(&mut *this.writer).poll_write(cx, this.buf)
+    }
+}
+
+/// Future returned by [`AsyncWriteExt::write_all`]
+pub struct WriteAllFuture<'a, W: ?Sized> {
+    writer: &'a mut W,
+    buf: &'a [u8],
+}
+
+impl<W: AsyncWrite + Unpin + ?Sized> Future for WriteAllFuture<'_, W> {
+    type Output = Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        while !this.buf.is_empty() {
+            match Pin::new(&mut *this.writer).poll_write(cx, this.buf) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(Error::from(ErrorKind::WriteZero))),
+                Poll::Ready(Ok(n)) => this.buf = &this.buf[n..],
+                Poll::Ready(Err(e)) if e.kind() == ErrorKind::Interrupted => {}
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Future returned by [`AsyncWriteExt::flush`]
+pub struct FlushFuture<'a, W: ?Sized> {
+    writer: &'a mut W,
+}
+
+impl<W: AsyncWrite + Unpin + ?Sized> Future for FlushFuture<'_, W> {
+    type Output = Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<()>> {
+        Pin::new(&mut *self.get_mut().writer).poll_flush(cx)
+    }
+}
+
+/// Future returned by [`AsyncSeekExt::seek`]
+pub struct SeekFuture<'a, S: ?Sized> {
+    seeker: &'a mut S,
+    pos: SeekFrom,
+}
+
+impl<S: AsyncSeek + Unpin + ?Sized> Future for SeekFuture<'_, S> {
+    type Output = Result<u128>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<u128>> {
+        let this = self.get_mut();
+        Pin::new(&mut *this.seeker).poll_seek(cx, this.pos)
+    }
+}
+
+#[cfg(all(test, feature = "std", not(loom)))]
+mod test {
+    use alloc::string::String;
+    use alloc::vec::Vec;
+    use core::cell::{Cell, RefCell};
+
+    use super::{
+        copy, BufRead, BufReader, BufWriter, Cursor, Error, ErrorKind, IoSlice, IoSliceMut, Read,
+        ReadAt, Result, Seek, SeekFrom, Write, WriteAt,
+    };
+
+    /// Reads at most `chunk` bytes at a time from `data`, and counts the reads.
+    ///
+    /// Every other read fails with `Interrupted` while `interrupts` is non-zero.
+    struct ShortReader<'a> {
+        data: Cursor<&'a [u8]>,
+        chunk: usize,
+        reads: usize,
+        interrupts: usize,
+    }
+
+    impl<'a> ShortReader<'a> {
+        fn new(data: &'a [u8], chunk: usize) -> Self {
+            Self {
+                data: Cursor::new(data),
+                chunk,
+                reads: 0,
+                interrupts: 0,
+            }
+        }
+    }
+
+    impl Read for ShortReader<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            self.reads += 1;
+            if self.interrupts > 0 && self.reads % 2 == 1 {
+                self.interrupts -= 1;
+                return Err(Error::from(ErrorKind::Interrupted));
+            }
+            let len = buf.len().min(self.chunk);
+            self.data.read(&mut buf[..len])
+        }
+    }
+
+    /// Fails every read with `kind`
+    struct FailingReader(ErrorKind);
+
+    impl Read for FailingReader {
+        fn read(&mut self, _: &mut [u8]) -> Result<usize> {
+            Err(Error::from(self.0))
+        }
+    }
+
+    impl Seek for ShortReader<'_> {
+        fn seek(&mut self, pos: SeekFrom) -> Result<u128> {
+            self.data.seek(pos)
+        }
+    }
+
+    /// Accepts at most `limit` more bytes, then fails every write with `error`
+    struct LimitedWriter {
+        data: Vec<u8>,
+        limit: usize,
+        error: ErrorKind,
+        flushes: usize,
+    }
+
+    impl LimitedWriter {
+        fn new(limit: usize, error: ErrorKind) -> Self {
+            Self {
+                data: Vec::new(),
+                limit,
+                error,
+                flushes: 0,
+            }
+        }
+    }
+
+    impl Write for LimitedWriter {
+        fn write(&mut self, buf: &[u8]) -> Result<usize> {
+            if self.limit == 0 {
+                return Err(Error::from(self.error));
+            }
+            let n = buf.len().min(self.limit);
+            self.limit -= n;
+            self.data.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn error_kind_codes() {
+        // These codes cross the syscall ABI, so they must never change
+        let codes = [
+            (ErrorKind::Other, 0),
+            (ErrorKind::UnexpectedEof, 1),
+            (ErrorKind::Interrupted, 2),
+            (ErrorKind::InvalidData, 3),
+            (ErrorKind::NotADirectory, 4),
+            (ErrorKind::NotFound, 5),
+            (ErrorKind::PermissionDenied, 6),
+            (ErrorKind::AlreadyExists, 7),
+            (ErrorKind::ReadOnlyFilesystem, 8),
+            (ErrorKind::NoSpace, 9),
+            (ErrorKind::InvalidInput, 10),
+            (ErrorKind::Unsupported, 11),
+            (ErrorKind::WouldBlock, 12),
+            (ErrorKind::TimedOut, 13),
+            (ErrorKind::IsADirectory, 14),
+            (ErrorKind::TooManyLinks, 15),
+            (ErrorKind::BadAddress, 16),
+            (ErrorKind::OutOfMemory, 17),
+            (ErrorKind::ResourceBusy, 18),
+            (ErrorKind::DirectoryNotEmpty, 19),
+            (ErrorKind::WriteZero, 20),
+            (ErrorKind::InvalidHandle, 21),
+            (ErrorKind::WrongHandleType, 22),
+        ];
+        for (kind, code) in codes {
+            assert_eq!(kind.code(), code, "{:?}", kind);
+            assert_eq!(ErrorKind::from_code(code), Some(kind));
+            assert_eq!(Error::from_code(code).kind(), kind);
+        }
+        assert_eq!(ErrorKind::from_code(23), None);
+        assert_eq!(ErrorKind::from_code(u32::MAX), None);
+        assert_eq!(Error::from_code(u32::MAX).kind(), ErrorKind::Other);
+    }
+
+    #[test]
+    fn error_messages() {
+        let err = Error::from(ErrorKind::NotFound);
+        assert_eq!(err.message(), None);
+        assert_eq!(err.code(), 5);
+        assert_eq!(crate::format!("{}", err), "No such file or directory");
+
+        let err = err.context("opening /etc");
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(
+            crate::format!("{}", err.context("loading config")),
+            "No such file or directory: loading config: opening /etc"
+        );
+    }
+
+    /// A device that transfers at most `chunk` bytes per access, after failing the first `interrupts` accesses with `Interrupted`
+    struct Device {
+        data: RefCell<Vec<u8>>,
+        chunk: usize,
+        interrupts: Cell<usize>,
+    }
+
+    impl Device {
+        fn new(len: usize, chunk: usize, interrupts: usize) -> Self {
+            Self {
+                data: RefCell::new((0..len).map(|i| i as u8).collect()),
+                chunk,
+                interrupts: Cell::new(interrupts),
+            }
+        }
+
+        fn interrupt(&self) -> Result<()> {
+            match self.interrupts.get() {
+                0 => Ok(()),
+                n => {
+                    self.interrupts.set(n - 1);
+                    Err(Error::from(ErrorKind::Interrupted))
+                }
+            }
+        }
+    }
+
+    impl ReadAt for Device {
+        fn read_at(&self, buf: &mut [u8], offset: u128) -> Result<usize> {
+            self.interrupt()?;
+            let len = buf.len().min(self.chunk);
+            self.data.borrow().read_at(&mut buf[..len], offset)
+        }
+    }
+
+    impl WriteAt for Device {
+        fn write_at(&self, buf: &[u8], offset: u128) -> Result<usize> {
+            self.interrupt()?;
+            let mut data = self.data.borrow_mut();
+            let offset = match usize::try_from(offset) {
+                Ok(offset) if offset < data.len() => offset,
+                _ => return Ok(0),
+            };
+            let n = buf.len().min(self.chunk).min(data.len() - offset);
+            data[offset..][..n].copy_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn flush(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn buf_reader_short_reads() {
+        let data: Vec<u8> = (0..=255).collect();
+        let mut reader = BufReader::with_capacity(16, ShortReader::new(&data, 5));
+
+        // A short read from the underlying reader is returned as is, rather than retried
+        let mut buf = [0; 8];
+        assert_eq!(reader.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], &data[..5]);
+        assert_eq!(reader.get_ref().reads, 1);
+
+        // Small reads are served from the buffer once it has been filled
+        assert_eq!(reader.fill_buf().unwrap(), &data[5..10]);
+        reader.consume(2);
+        assert_eq!(reader.buffer(), &data[7..10]);
+        assert_eq!(reader.read(&mut buf).unwrap(), 3);
+        assert_eq!(reader.get_ref().reads, 2);
+
+        // Reads at least as large as the buffer bypass it while it is empty
+        let mut big = [0; 32];
+        assert_eq!(reader.read(&mut big).unwrap(), 5);
+        assert_eq!(&big[..5], &data[10..15]);
+        assert!(reader.buffer().is_empty());
+
+        let mut rest = Vec::new();
+        assert_eq!(reader.read_to_end(&mut rest).unwrap(), 256 - 15);
+        assert_eq!(rest, &data[15..]);
+    }
+
+    #[test]
+    fn buf_reader_seek_discards_buffer() {
+        let data: Vec<u8> = (0..64).collect();
+        let mut reader = BufReader::with_capacity(16, ShortReader::new(&data, 64));
+
+        let mut buf = [0; 4];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(reader.buffer(), &data[4..16]);
+        // The position accounts for the data buffered but not yet read
+        assert_eq!(reader.stream_position().unwrap(), 4);
+        assert_eq!(reader.buffer().len(), 12);
+
+        // Relative seeks are from the position of the BufReader, not that of the underlying reader
+        assert_eq!(reader.seek(SeekFrom::Current(2)).unwrap(), 6);
+        assert!(reader.buffer().is_empty());
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [6, 7, 8, 9]);
+
+        assert_eq!(reader.seek(SeekFrom::CurrentFar(-10)).unwrap(), 0);
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0, 1, 2, 3]);
+
+        assert_eq!(reader.seek(SeekFrom::End(-1)).unwrap(), 63);
+        assert!(reader.buffer().is_empty());
+        assert_eq!(reader.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 63);
+
+        // A failed seek leaves the buffer alone
+        reader.seek(SeekFrom::Start(0)).unwrap();
+        reader.read_exact(&mut buf).unwrap();
+        assert!(reader.seek(SeekFrom::Current(-5)).is_err());
+        assert_eq!(reader.buffer(), &data[4..16]);
+    }
+
+    #[test]
+    fn buf_writer_flushes_on_drop() {
+        let mut inner = LimitedWriter::new(usize::MAX, ErrorKind::Other);
+        {
+            let mut writer = BufWriter::with_capacity(8, &mut inner);
+            writer.write_all(b"abc").unwrap();
+            assert_eq!(writer.buffer(), b"abc");
+            assert!(writer.get_ref().data.is_empty());
+
+            // Filling the buffer flushes what was there before
+            writer.write_all(b"defgh").unwrap();
+            writer.write_all(b"ij").unwrap();
+            assert_eq!(writer.get_ref().data, b"abcdefgh");
+            assert_eq!(writer.buffer(), b"ij");
+
+            // Writes at least as large as the buffer bypass it
+            writer.write_all(b"0123456789").unwrap();
+            assert!(writer.buffer().is_empty());
+            writer.write_all(b"kl").unwrap();
+        }
+        assert_eq!(inner.data, b"abcdefghij0123456789kl");
+        // Dropping writes out the buffer, but doesn't flush the underlying writer
+        assert_eq!(inner.flushes, 0);
+
+        let mut writer =
+            BufWriter::with_capacity(8, LimitedWriter::new(usize::MAX, ErrorKind::Other));
+        writer.write_all(b"xyz").unwrap();
+        writer.flush().unwrap();
+        assert_eq!(writer.get_ref().flushes, 1);
+        assert_eq!(writer.into_inner().unwrap().data, b"xyz");
+    }
+
+    #[test]
+    fn buf_writer_keeps_data_on_error() {
+        let mut writer = BufWriter::with_capacity(8, LimitedWriter::new(3, ErrorKind::NoSpace));
+        writer.write_all(b"abcdef").unwrap();
+        assert_eq!(writer.flush().unwrap_err().kind(), ErrorKind::NoSpace);
+        // The data that was written is removed from the buffer, and the rest is kept
+        assert_eq!(writer.get_ref().data, b"abc");
+        assert_eq!(writer.buffer(), b"def");
+        assert_eq!(writer.get_ref().flushes, 0);
+
+        // A write that needs the buffer flushed fails without buffering anything
+        assert_eq!(
+            writer.write(b"ghijkl").unwrap_err().kind(),
+            ErrorKind::NoSpace
+        );
+        assert_eq!(writer.buffer(), b"def");
+
+        // The data is written once the underlying writer recovers
+        writer.get_mut().limit = usize::MAX;
+        let inner = writer.into_inner().unwrap();
+        assert_eq!(inner.data, b"abcdef");
+
+        // If flushing on drop fails, the error is ignored
+        let mut inner = LimitedWriter::new(2, ErrorKind::NoSpace);
+        BufWriter::with_capacity(8, &mut inner)
+            .write_all(b"abcd")
+            .unwrap();
+        assert_eq!(inner.data, b"ab");
+
+        // A writer that accepts nothing fails the flush with WriteZero
+        let mut buf = [0u8; 2];
+        let mut writer = BufWriter::with_capacity(8, Cursor::new(&mut buf[..]));
+        writer.write_all(b"abcd").unwrap();
+        assert_eq!(writer.flush().unwrap_err().kind(), ErrorKind::WriteZero);
+        assert_eq!(writer.buffer(), b"cd");
+    }
+
+    #[test]
+    fn cursor_read_write_seek() {
+        let mut cursor = Cursor::new(Vec::new());
+        cursor.write_all(b"hello").unwrap();
+        assert_eq!(cursor.seek(SeekFrom::Current(2)).unwrap(), 7);
+        // Writing past the end of a growable buffer fills the gap with zeroes
+        cursor.write_all(b"world").unwrap();
+        assert_eq!(cursor.get_ref(), b"hello\0\0world");
+
+        cursor.set_position(100);
+        assert_eq!(cursor.read(&mut [0; 4]).unwrap(), 0);
+        assert!(cursor.seek(SeekFrom::Current(-101)).is_err());
+        assert_eq!(cursor.position(), 100);
+        assert_eq!(cursor.seek(SeekFrom::End(-5)).unwrap(), 7);
+        assert_eq!(cursor.remaining_slice(), b"world");
+
+        // Fixed size buffers truncate writes at their end
+        let mut buf = [0u8; 4];
+        let mut cursor = Cursor::new(&mut buf[..]);
+        assert_eq!(cursor.write(b"abcdef").unwrap(), 4);
+        assert_eq!(cursor.write(b"g").unwrap(), 0);
+        assert_eq!(
+            cursor.write_all(b"g").unwrap_err().kind(),
+            ErrorKind::WriteZero
+        );
+        assert_eq!(&buf, b"abcd");
+    }
+
+    #[test]
+    fn take_limits_reads() {
+        let data: Vec<u8> = (0..32).collect();
+        let mut take = ShortReader::new(&data, 4).take(10);
+        let mut buf = [0; 16];
+        assert_eq!(take.read(&mut buf).unwrap(), 4);
+        assert_eq!(take.limit(), 6);
+
+        let mut rest = Vec::new();
+        assert_eq!(take.read_to_end(&mut rest).unwrap(), 6);
+        assert_eq!(rest, &data[4..10]);
+        assert_eq!(take.read(&mut buf).unwrap(), 0);
+        // The underlying reader is left just past the limit
+        assert_eq!(take.get_ref().data.position(), 10);
+
+        take.set_limit(3);
+        assert_eq!(take.read(&mut buf).unwrap(), 3);
+        assert_eq!(&buf[..3], &data[10..13]);
+
+        // The buffered interface respects the limit too
+        let mut take = Cursor::new(&data[..]).take(5);
+        assert_eq!(take.fill_buf().unwrap(), &data[..5]);
+        take.consume(10);
+        assert_eq!(take.limit(), 0);
+        assert!(take.fill_buf().unwrap().is_empty());
+        assert_eq!(take.into_inner().position(), 5);
+    }
+
+    #[test]
+    fn chain_reads_both() {
+        let mut chain = ShortReader::new(b"abc", 2).chain(Cursor::new(&b"defg"[..]));
+        let mut buf = [0; 8];
+        assert_eq!(chain.read(&mut buf).unwrap(), 2);
+        assert_eq!(chain.read(&mut buf).unwrap(), 1);
+        // Reads don't span the two readers
+        assert_eq!(chain.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"defg");
+        assert_eq!(chain.read(&mut buf).unwrap(), 0);
+
+        // An empty read doesn't end the first reader
+        let mut chain = Cursor::new(&b"ab"[..]).chain(Cursor::new(&b"cd"[..]));
+        assert_eq!(chain.read(&mut []).unwrap(), 0);
+        let mut line = Vec::new();
+        chain.read_until(b'c', &mut line).unwrap();
+        assert_eq!(line, b"abc");
+        assert_eq!(chain.fill_buf().unwrap(), b"d");
+
+        let mut limited = Cursor::new(&b"abc"[..])
+            .chain(Cursor::new(&b"def"[..]))
+            .take(4);
+        let mut out = Vec::new();
+        limited.read_to_end(&mut out).unwrap();
+        assert_eq!(out, b"abcd");
+    }
+
+    #[test]
+    fn copy_all() {
+        let data: Vec<u8> = (0..2000).map(|i| i as u8).collect();
+        let mut out = Vec::new();
+        assert_eq!(
+            copy(&mut ShortReader::new(&data, 300), &mut out).unwrap(),
+            2000
+        );
+        assert_eq!(out, data);
+
+        // Write errors are returned, with the data copied so far written
+        let mut writer = LimitedWriter::new(700, ErrorKind::NoSpace);
+        assert_eq!(
+            copy(&mut Cursor::new(&data[..]), &mut writer)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::NoSpace
+        );
+        assert_eq!(writer.data, &data[..700]);
+    }
+
+    #[test]
+    fn default_vectored_io() {
+        // The defaults only transfer the first non-empty buffer
+        let mut reader = ShortReader::new(b"abcdefgh", 8);
+        let (mut a, mut b) = ([0; 3], [0; 3]);
+        let mut bufs = [
+            IoSliceMut::new(&mut []),
+            IoSliceMut::new(&mut a),
+            IoSliceMut::new(&mut b),
+        ];
+        assert_eq!(reader.read_vectored(&mut bufs).unwrap(), 3);
+        assert_eq!(a, *b"abc");
+        assert_eq!(b, [0; 3]);
+        assert_eq!(
+            reader
+                .read_vectored(&mut [IoSliceMut::new(&mut [])])
+                .unwrap(),
+            0
+        );
+        assert_eq!(reader.reads, 1);
+
+        let mut writer = LimitedWriter::new(usize::MAX, ErrorKind::Other);
+        let bufs = [IoSlice::new(b""), IoSlice::new(b"ab"), IoSlice::new(b"cd")];
+        assert_eq!(writer.write_vectored(&bufs).unwrap(), 2);
+        assert_eq!(writer.data, b"ab");
+        assert_eq!(writer.write_vectored(&[]).unwrap(), 0);
+
+        // Vec and BufWriter take all the buffers at once
+        let mut vec = Vec::new();
+        assert_eq!(vec.write_vectored(&bufs).unwrap(), 4);
+        assert_eq!(vec, b"abcd");
+        let mut writer =
+            BufWriter::with_capacity(8, LimitedWriter::new(usize::MAX, ErrorKind::Other));
+        assert_eq!(writer.write_vectored(&bufs).unwrap(), 4);
+        assert_eq!(writer.buffer(), b"abcd");
+        let big = [
+            IoSlice::new(b"0123"),
+            IoSlice::new(b"4567"),
+            IoSlice::new(b"89"),
+        ];
+        assert_eq!(writer.write_vectored(&big).unwrap(), 4);
+        assert_eq!(writer.get_ref().data, b"abcd0123");
+        assert!(writer.buffer().is_empty());
+    }
+
+    #[test]
+    fn io_slice_advance() {
+        let mut bufs = [
+            IoSlice::new(b"ab"),
+            IoSlice::new(b"cde"),
+            IoSlice::new(b"f"),
+        ];
+        let mut bufs = &mut bufs[..];
+        IoSlice::advance_slices(&mut bufs, 3);
+        assert_eq!(bufs.len(), 2);
+        assert_eq!(&*bufs[0], b"de");
+        IoSlice::advance_slices(&mut bufs, 2);
+        assert_eq!(bufs.len(), 1);
+        assert_eq!(&*bufs[0], b"f");
+        IoSlice::advance_slices(&mut bufs, 1);
+        assert!(bufs.is_empty());
+        IoSlice::advance_slices(&mut bufs, 0);
+
+        let (mut a, mut b) = ([0; 2], [0; 2]);
+        let mut bufs = [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)];
+        let mut bufs = &mut bufs[..];
+        IoSliceMut::advance_slices(&mut bufs, 1);
+        bufs[0][0] = 1;
+        bufs[1][1] = 2;
+        assert_eq!((a, b), ([0, 1], [0, 2]));
+    }
+
+    #[test]
+    #[should_panic(expected = "advancing past the end of the buffers")]
+    fn io_slice_advance_past_end() {
+        let mut bufs = [IoSlice::new(b"ab"), IoSlice::new(b"c")];
+        IoSlice::advance_slices(&mut &mut bufs[..], 4);
+    }
+
+    #[test]
+    fn read_at() {
+        let data = [1u8, 2, 3, 4];
+        let mut buf = [0; 8];
+        assert_eq!(data[..].read_at(&mut buf, 1).unwrap(), 3);
+        assert_eq!(buf[..3], [2, 3, 4]);
+        assert_eq!(data[..].read_at(&mut buf, 4).unwrap(), 0);
+        assert_eq!(data[..].read_at(&mut buf, u128::MAX).unwrap(), 0);
+
+        // Short and interrupted reads are retried until the buffer is full
+        let device = Device::new(64, 3, 2);
+        let mut buf = [0; 10];
+        device.read_exact_at(&mut buf, 50).unwrap();
+        assert!(buf.iter().copied().eq(50..60));
+        assert_eq!(device.interrupts.get(), 0);
+        assert_eq!(
+            device.read_exact_at(&mut buf, 60).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
+
+        let (mut a, mut b) = ([0; 2], [0; 2]);
+        let mut bufs = [IoSliceMut::new(&mut a), IoSliceMut::new(&mut b)];
+        assert_eq!(device.read_vectored_at(&mut bufs, 7).unwrap(), 2);
+        assert_eq!((a, b), ([7, 8], [0, 0]));
+    }
+
+    #[test]
+    fn write_at() {
+        // Short and interrupted writes are retried until everything is written
+        let device = Device::new(16, 3, 2);
+        device.write_all_at(b"abcdefg", 4).unwrap();
+        assert_eq!(&device.data.borrow()[2..13], b"\x02\x03abcdefg\x0b\x0c");
+        assert_eq!(device.interrupts.get(), 0);
+
+        assert_eq!(
+            device.write_all_at(b"xyz", 15).unwrap_err().kind(),
+            ErrorKind::WriteZero
+        );
+        assert_eq!(device.data.borrow()[15], b'x');
+
+        let bufs = [IoSlice::new(b""), IoSlice::new(b"AB"), IoSlice::new(b"CD")];
+        assert_eq!(device.write_vectored_at(&bufs, 0).unwrap(), 2);
+        assert_eq!(&device.data.borrow()[..3], b"AB\x02");
+    }
+
+    #[test]
+    fn read_to_end_retries_interrupted() {
+        let data: Vec<u8> = (0..100).collect();
+        let mut reader = ShortReader::new(&data, 7);
+        reader.interrupts = 5;
+        let mut buf = b"xy".to_vec();
+        assert_eq!(reader.read_to_end(&mut buf).unwrap(), 100);
+        assert_eq!(&buf[..2], b"xy");
+        assert_eq!(&buf[2..], &data[..]);
+        assert_eq!(reader.interrupts, 0);
+
+        // Data read before an error is kept, without any of the scratch space past it
+        let mut reader = Cursor::new(&b"abc"[..]).chain(FailingReader(ErrorKind::TimedOut));
+        let mut buf = Vec::new();
+        assert_eq!(
+            reader.read_to_end(&mut buf).unwrap_err().kind(),
+            ErrorKind::TimedOut
+        );
+        assert_eq!(buf, b"abc");
+    }
+
+    #[test]
+    fn read_to_string_checks_utf8() {
+        // Characters split across reads are put back together
+        let mut reader = ShortReader::new("héllo wörld".as_bytes(), 1);
+        reader.interrupts = 3;
+        let mut buf = String::from("> ");
+        assert_eq!(reader.read_to_string(&mut buf).unwrap(), 13);
+        assert_eq!(buf, "> héllo wörld");
+
+        let mut reader = Cursor::new(&b"ok \xff\xfe"[..]);
+        assert_eq!(
+            reader.read_to_string(&mut buf).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+        assert_eq!(buf, "> héllo wörld");
+
+        // A truncated character is invalid too
+        let mut reader = Cursor::new(&"é".as_bytes()[..1]);
+        assert!(reader.read_to_string(&mut buf).is_err());
+        assert_eq!(buf, "> héllo wörld");
+
+        let mut reader = Cursor::new(&b"line\n\xc3\n"[..]);
+        let mut line = String::new();
+        assert_eq!(reader.read_line(&mut line).unwrap(), 5);
+        assert_eq!(
+            reader.read_line(&mut line).unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+        assert_eq!(line, "line\n");
+    }
+
+    #[test]
+    fn bytes_retries_interrupted() {
+        let mut reader = ShortReader::new(b"abc", 2);
+        reader.interrupts = 2;
+        let bytes = reader.bytes().collect::<Result<Vec<u8>>>().unwrap();
+        assert_eq!(bytes, b"abc");
+
+        let mut bytes = Cursor::new(&b"a"[..])
+            .chain(FailingReader(ErrorKind::InvalidData))
+            .bytes();
+        assert_eq!(bytes.next().unwrap().unwrap(), b'a');
+        assert_eq!(
+            bytes.next().unwrap().unwrap_err().kind(),
+            ErrorKind::InvalidData
+        );
+        assert!(Cursor::new(&b""[..]).bytes().next().is_none());
+    }
+
+    #[test]
+    fn write_fmt_errors() {
+        struct BadDisplay;
+
+        impl core::fmt::Display for BadDisplay {
+            fn fmt(&self, _: &mut core::fmt::Formatter) -> core::fmt::Result {
+                Err(core::fmt::Error)
+            }
+        }
+
+        let (name, n) = ("abc", 10);
+        let mut out = Vec::new();
+        write!(out, "{}-{:02x}", name, n).unwrap();
+        assert_eq!(out, b"abc-0a");
+
+        // The error of the underlying writer is returned, rather than a generic formatting error
+        let mut writer = LimitedWriter::new(4, ErrorKind::NoSpace);
+        assert_eq!(
+            write!(writer, "{}, {}", name, n).unwrap_err().kind(),
+            ErrorKind::NoSpace
+        );
+        assert_eq!(writer.data, b"abc,");
+
+        let err = write!(out, "{}", BadDisplay).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::Other);
+        assert_eq!(out, b"abc-0a");
+    }
+
+    #[test]
+    fn seek_far() {
+        /// A stream of `len` bytes that only keeps track of its position
+        struct Sparse {
+            len: u128,
+            pos: u128,
+            seeks: usize,
+        }
+
+        impl Seek for Sparse {
+            fn seek(&mut self, pos: SeekFrom) -> Result<u128> {
+                self.seeks += 1;
+                let npos = match pos {
+                    SeekFrom::Start(off) => Some(u128::from(off)),
+                    SeekFrom::StartFar(off) => Some(off),
+                    SeekFrom::End(disp) => self.len.checked_add_signed(i128::from(disp)),
+                    SeekFrom::EndFar(disp) => self.len.checked_add_signed(disp),
+                    SeekFrom::Current(disp) => self.pos.checked_add_signed(i128::from(disp)),
+                    SeekFrom::CurrentFar(disp) => self.pos.checked_add_signed(disp),
+                };
+                self.pos = npos.ok_or_else(|| Error::from(ErrorKind::InvalidInput))?;
+                Ok(self.pos)
+            }
+        }
+
+        let far = u128::from(u64::MAX) + 10;
+        let mut stream = Sparse {
+            len: far * 2,
+            pos: 0,
+            seeks: 0,
+        };
+        assert_eq!(stream.seek(SeekFrom::StartFar(far)).unwrap(), far);
+        assert_eq!(stream.stream_position().unwrap(), far);
+        assert_eq!(
+            stream
+                .seek(SeekFrom::CurrentFar(i128::from(u64::MAX)))
+                .unwrap(),
+            far + u128::from(u64::MAX)
+        );
+        assert_eq!(stream.seek(SeekFrom::EndFar(-5)).unwrap(), far * 2 - 5);
+
+        // stream_len puts the position back where it was, even past u64::MAX
+        stream.seek(SeekFrom::StartFar(far)).unwrap();
+        assert_eq!(stream.stream_len().unwrap(), far * 2);
+        assert_eq!(stream.pos, far);
+        // ...and doesn't seek back when it was already at the end
+        stream.seek(SeekFrom::End(0)).unwrap();
+        stream.seeks = 0;
+        assert_eq!(stream.stream_len().unwrap(), far * 2);
+        assert_eq!(stream.seeks, 2);
+
+        stream.rewind().unwrap();
+        assert_eq!(stream.pos, 0);
+        assert!(stream.seek(SeekFrom::Current(-1)).is_err());
+    }
+
+    #[test]
+    fn cursor_seek_far() {
+        let mut cursor = Cursor::new(&b"0123456789"[..]);
+        assert_eq!(cursor.seek(SeekFrom::StartFar(4)).unwrap(), 4);
+        assert_eq!(cursor.seek(SeekFrom::CurrentFar(-2)).unwrap(), 2);
+        assert_eq!(cursor.seek(SeekFrom::EndFar(-1)).unwrap(), 9);
+        assert_eq!(cursor.stream_len().unwrap(), 10);
+        assert_eq!(cursor.stream_position().unwrap(), 9);
+
+        // Positions that don't fit in a usize are rejected, and leave the position alone
+        for pos in [
+            SeekFrom::StartFar(u128::MAX),
+            SeekFrom::StartFar(usize::MAX as u128 + 1),
+            SeekFrom::EndFar(i128::MAX),
+            SeekFrom::CurrentFar(i128::MIN),
+            SeekFrom::End(-11),
+        ] {
+            assert_eq!(
+                cursor.seek(pos).unwrap_err().kind(),
+                ErrorKind::InvalidInput,
+                "{:?}",
+                pos
+            );
+            assert_eq!(cursor.position(), 9);
+        }
+
+        cursor.rewind().unwrap();
+        assert_eq!(cursor.position(), 0);
+    }
+
+    #[test]
+    fn buffered_seek() {
+        let data: Vec<u8> = (0..32).collect();
+        let mut reader = BufReader::with_capacity(8, Cursor::new(&data[..]));
+        reader.read_exact(&mut [0; 3]).unwrap();
+        // The length of the underlying stream, with the position of the BufReader restored
+        assert_eq!(reader.stream_len().unwrap(), 32);
+        assert_eq!(reader.stream_position().unwrap(), 3);
+        let mut buf = [0; 2];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [3, 4]);
+
+        // BufWriter writes out its buffer before seeking
+        let mut writer = BufWriter::with_capacity(8, Cursor::new(Vec::new()));
+        writer.write_all(b"abcdef").unwrap();
+        assert_eq!(writer.seek(SeekFrom::CurrentFar(-4)).unwrap(), 2);
+        writer.write_all(b"XY").unwrap();
+        assert_eq!(writer.into_inner().unwrap().into_inner(), b"abXYef");
+    }
+}
@@ -0,0 +1,267 @@
+use crate::io::{self, Read, Seek, SeekFrom, Write};
+
+/// Resolves `from` against a cursor at `pos` in a region of `size` bytes.
+///
+/// Fails if the resulting position would be negative, or would lie past the end of the region.
+fn resolve_seek(pos: usize, size: usize, from: SeekFrom) -> io::Result<usize> {
+    fn offset_by(base: usize, disp: i128) -> Option<usize> {
+        i128::try_from(base)
+            .ok()?
+            .checked_add(disp)
+            .and_then(|n| usize::try_from(n).ok())
+    }
+
+    let npos = match from {
+        SeekFrom::Start(off) => usize::try_from(off).ok(),
+        SeekFrom::StartFar(off) => usize::try_from(off).ok(),
+        SeekFrom::End(disp) => offset_by(size, i128::from(disp)),
+        SeekFrom::EndFar(disp) => offset_by(size, disp),
+        SeekFrom::Current(disp) => offset_by(pos, i128::from(disp)),
+        SeekFrom::CurrentFar(disp) => offset_by(pos, disp),
+    };
+
+    match npos {
+        Some(npos) if npos <= size => Ok(npos),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            alloc::format!("seek outside of memory region of {} bytes", size),
+        )),
+    }
+}
+
+/// Reads from `*pos` in the region of `size` bytes at `start` into `buf`, and advances `*pos` past the bytes read
+///
+/// # Safety
+/// The range `[start, start+size)` must be valid for reads, and `*pos` must be at most `size`
+unsafe fn read_region(start: *const u8, size: usize, pos: &mut usize, buf: &mut [u8]) -> usize {
+    let len = buf.len().min(size - *pos);
+
+    // SAFETY:
+    // `pos + len <= size`, so the source range is in bounds
+    unsafe {
+        core::ptr::copy_nonoverlapping(start.add(*pos), buf.as_mut_ptr(), len);
+    }
+    *pos += len;
+    len
+}
+
+/// A read-only cursor over a raw region of memory, such as a bootloader module or a firmware table.
+///
+/// Reads and seeks are bounded by the size of the region.
+pub struct RawMemReader {
+    start: *const u8,
+    size: usize,
+    pos: usize,
+}
+
+impl RawMemReader {
+    /// Creates a reader over the `size` bytes beginning at `start`
+    ///
+    /// # Safety
+    /// The range `[start, start+size)` must be valid for reads for as long as the reader is used,
+    /// and must not be modified for that duration.
+    pub unsafe fn new(start: *const u8, size: usize) -> Self {
+        Self {
+            start,
+            size,
+            pos: 0,
+        }
+    }
+
+    /// Returns the total size of the region
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the current offset from the start of the region
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+}
+
+impl Read for RawMemReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // SAFETY: by the contract of `new`
+        Ok(unsafe { read_region(self.start, self.size, &mut self.pos, buf) })
+    }
+}
+
+impl Seek for RawMemReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u128> {
+        self.pos = resolve_seek(self.pos, self.size, pos)?;
+        Ok(self.pos as u128)
+    }
+}
+
+/// A read-write cursor over a raw region of memory.
+///
+/// Reads, writes, and seeks are bounded by the size of the region. Writes that reach the end of the region are truncated.
+pub struct RawMemWriter {
+    start: *mut u8,
+    size: usize,
+    pos: usize,
+}
+
+impl RawMemWriter {
+    /// Creates a cursor over the `size` bytes beginning at `start`
+    ///
+    /// # Safety
+    /// The range `[start, start+size)` must be valid for reads and writes for as long as the cursor is used,
+    /// and must not be accessed through any other pointer for that duration.
+    pub unsafe fn new(start: *mut u8, size: usize) -> Self {
+        Self {
+            start,
+            size,
+            pos: 0,
+        }
+    }
+
+    /// Returns the total size of the region
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the current offset from the start of the region
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+}
+
+impl Read for RawMemWriter {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // SAFETY: by the contract of `new`
+        Ok(unsafe { read_region(self.start, self.size, &mut self.pos, buf) })
+    }
+}
+
+impl Write for RawMemWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.size - self.pos);
+
+        // SAFETY:
+        // `pos + len <= size`, so the destination range is in bounds by the contract of `new`
+        unsafe {
+            core::ptr::copy_nonoverlapping(buf.as_ptr(), self.start.add(self.pos), len);
+        }
+        self.pos += len;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for RawMemWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u128> {
+        self.pos = resolve_seek(self.pos, self.size, pos)?;
+        Ok(self.pos as u128)
+    }
+}
+
+#[cfg(all(test, feature = "std", not(loom)))]
+mod test {
+    use super::{resolve_seek, RawMemReader, RawMemWriter};
+    use crate::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+
+    fn invalid(res: io::Result<usize>) -> bool {
+        matches!(res, Err(e) if e.kind() == ErrorKind::InvalidInput)
+    }
+
+    #[test]
+    fn seek_from_start() {
+        assert_eq!(resolve_seek(3, 16, SeekFrom::Start(0)).unwrap(), 0);
+        assert_eq!(resolve_seek(3, 16, SeekFrom::Start(16)).unwrap(), 16);
+        assert!(invalid(resolve_seek(3, 16, SeekFrom::Start(17))));
+        assert_eq!(resolve_seek(3, 16, SeekFrom::StartFar(9)).unwrap(), 9);
+    }
+
+    #[test]
+    fn seek_from_end_negative() {
+        assert_eq!(resolve_seek(0, 16, SeekFrom::End(0)).unwrap(), 16);
+        assert_eq!(resolve_seek(0, 16, SeekFrom::End(-4)).unwrap(), 12);
+        assert_eq!(resolve_seek(0, 16, SeekFrom::End(-16)).unwrap(), 0);
+        assert_eq!(resolve_seek(0, 16, SeekFrom::EndFar(-5)).unwrap(), 11);
+        assert!(invalid(resolve_seek(0, 16, SeekFrom::End(1))));
+    }
+
+    #[test]
+    fn seek_from_current_negative() {
+        assert_eq!(resolve_seek(10, 16, SeekFrom::Current(0)).unwrap(), 10);
+        assert_eq!(resolve_seek(10, 16, SeekFrom::Current(-10)).unwrap(), 0);
+        assert_eq!(resolve_seek(10, 16, SeekFrom::Current(6)).unwrap(), 16);
+        assert_eq!(resolve_seek(10, 16, SeekFrom::CurrentFar(-3)).unwrap(), 7);
+        assert!(invalid(resolve_seek(10, 16, SeekFrom::Current(7))));
+    }
+
+    #[test]
+    fn seek_before_start() {
+        assert!(invalid(resolve_seek(10, 16, SeekFrom::Current(-11))));
+        assert!(invalid(resolve_seek(0, 16, SeekFrom::End(-17))));
+        assert!(invalid(resolve_seek(0, 16, SeekFrom::End(i64::MIN))));
+        assert!(invalid(resolve_seek(
+            10,
+            16,
+            SeekFrom::CurrentFar(i128::MIN)
+        )));
+        assert!(invalid(resolve_seek(0, 16, SeekFrom::EndFar(i128::MIN))));
+    }
+
+    #[test]
+    fn seek_past_usize_max() {
+        let max = usize::MAX;
+        assert!(invalid(resolve_seek(
+            0,
+            max,
+            SeekFrom::StartFar(max as u128 + 1)
+        )));
+        assert!(invalid(resolve_seek(0, max, SeekFrom::StartFar(u128::MAX))));
+        assert!(invalid(resolve_seek(max, max, SeekFrom::Current(1))));
+        assert!(invalid(resolve_seek(
+            max,
+            max,
+            SeekFrom::CurrentFar(i128::MAX)
+        )));
+        assert!(invalid(resolve_seek(0, max, SeekFrom::End(1))));
+        assert!(invalid(resolve_seek(0, max, SeekFrom::EndFar(i128::MAX))));
+        assert_eq!(resolve_seek(0, max, SeekFrom::End(0)).unwrap(), max);
+        assert_eq!(
+            resolve_seek(max, max, SeekFrom::CurrentFar(-1)).unwrap(),
+            max - 1
+        );
+    }
+
+    #[test]
+    fn failed_seek_keeps_position() {
+        let data = *b"0123456789";
+        // SAFETY: `data` outlives the reader and is not modified
+        let mut reader = unsafe { RawMemReader::new(data.as_ptr(), data.len()) };
+        assert_eq!(reader.seek(SeekFrom::End(-4)).unwrap(), 6);
+        assert!(reader.seek(SeekFrom::Current(-7)).is_err());
+        assert_eq!(reader.position(), 6);
+
+        let mut buf = [0; 8];
+        assert_eq!(reader.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"6789");
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn writer_truncates_at_end() {
+        let mut data = [0u8; 8];
+        // SAFETY: `data` outlives the writer and is only accessed through it
+        let mut writer = unsafe { RawMemWriter::new(data.as_mut_ptr(), data.len()) };
+        assert_eq!(writer.write(b"abcdef").unwrap(), 6);
+        assert_eq!(writer.write(b"ghij").unwrap(), 2);
+        assert_eq!(writer.write(b"k").unwrap(), 0);
+        assert_eq!(writer.position(), 8);
+
+        assert_eq!(writer.seek(SeekFrom::Current(-5)).unwrap(), 3);
+        let mut buf = [0; 8];
+        assert_eq!(writer.read(&mut buf).unwrap(), 5);
+        assert_eq!(&buf[..5], b"defgh");
+        assert!(writer.seek(SeekFrom::Start(9)).is_err());
+        assert_eq!(writer.position(), 8);
+        assert_eq!(&data, b"abcdefgh");
+    }
+}
@@ -1,5 +1,5 @@
 #![no_std]
-#![feature(allocator_api, const_maybe_uninit_zeroed)]
+#![feature(allocator_api, btreemap_alloc, const_maybe_uninit_zeroed)]
 
 extern crate alloc;
 
@@ -8,18 +8,22 @@ pub use alloc::format;
 pub mod cell;
 pub mod collection;
 pub mod hash;
+// `cargo test --doc` passes this crate to rustdoc as the `std` extern, which shadows the host's `std` that `hosted` builds on
+#[cfg(all(feature = "std", not(doctest)))]
+pub mod hosted;
 pub mod io;
 pub mod str;
 pub mod sync;
+pub mod task;
 
 #[cfg(target_arch = "x86_64")]
 #[macro_export]
 macro_rules! has_x86_feature {
-    ($feature:literal) => {
+    ($feature:literal) => {{
         extern "C" {
             fn __has_x86_feature(feature: $crate::str::StringView) -> ::core::primitive::bool;
         }
 
         unsafe { __has_x86_feature($crate::str::StringView::new($feature)) }
-    };
+    }};
 }
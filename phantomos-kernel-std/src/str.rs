@@ -174,10 +174,14 @@ pub use core::ptr::addr_of as __addr_of;
 /// must be called with a string literal or a constant expression of type `&'static str`
 /// ## Examples
 /// ```
-///# use xlang_abi::string::StringView;
-///# use xlang_abi::const_sv;
+///# #![no_std]
+///# extern crate std;
+///# use std::str::StringView;
+///# use std::const_sv;
+///# fn main() {
 /// const HELLO_WORLD: StringView = const_sv!("Hello World");
 /// assert_eq!(HELLO_WORLD,StringView::new("Hello World"));
+///# }
 /// ```
 ///
 #[macro_export]
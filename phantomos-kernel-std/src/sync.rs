@@ -1,13 +1,62 @@
-use core::{arch::asm, cell::UnsafeCell, mem::MaybeUninit, sync::atomic::AtomicUsize};
+use core::{
+    cell::{Cell, UnsafeCell},
+    fmt,
+    mem::MaybeUninit,
+    ops::Deref,
+};
+
+use self::prim::{spin_loop, AtomicUsize};
+
+mod lock;
+mod rwlock;
+mod seqlock;
+
+pub use self::lock::{
+    IrqSave, IrqSpinLock, IrqSpinLockGuard, Mutex, MutexGuard, RawMutex, RawSpinLock,
+    RawTicketLock, SpinLock, SpinLockGuard, TicketLock, TicketLockGuard,
+};
+pub use self::rwlock::{
+    IrqRwLock, RwLock, RwLockReadGuard, RwLockUpgradableGuard, RwLockWriteGuard,
+};
+pub use self::seqlock::{IrqSeqLock, SeqLock, SeqLockWriteGuard};
+
+/// The atomics and spin hint that the lock-free primitives of this crate are built on.
+///
+/// When built with `--cfg loom` (on the hosted build), these are loom's instrumented versions instead of `core`'s,
+/// so that [`RingBuffer`](crate::collection::RingBuffer), [`OnceCell`], [`AtomicFlag`](atomic::AtomicFlag) and the raw locks of [`Mutex`] can be model-checked.
+/// loom's atomics can't be created in constant expressions, so constructors of types built on them are only `const` without loom.
+/// The models are in the `loom_test` modules, and run with `RUSTFLAGS="--cfg loom" cargo test --release --features std`.
+///
+/// On the hosted build, the spin hint yields the host thread instead, see [`hosted::spin_loop`](crate::hosted::spin_loop).
+pub(crate) mod prim {
+    #[cfg(not(loom))]
+    pub(crate) use core::sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering};
+
+    #[cfg(not(any(loom, all(feature = "std", not(doctest)))))]
+    pub(crate) use core::hint::spin_loop;
+
+    #[cfg(all(not(loom), feature = "std", not(doctest)))]
+    pub(crate) use crate::hosted::spin_loop;
+
+    #[cfg(loom)]
+    pub(crate) use loom::{
+        hint::spin_loop,
+        sync::atomic::{fence, AtomicBool, AtomicUsize, Ordering},
+    };
+}
 
 pub mod atomic {
     pub use core::sync::atomic::*;
 
     use core::{
         cell::UnsafeCell,
+        fmt,
         mem::{ManuallyDrop, MaybeUninit},
+        ptr,
     };
 
+    use super::seqlock::{atomic_load, atomic_store, RawSeqLock};
+
     pub unsafe trait Atomic: Sized {
         type Base: HasAtomic;
 
@@ -308,67 +357,439 @@ pub mod atomic {
         }
     }
 
-    pub struct AtomicCell<T: HasAtomic> {
-        inner: UnsafeCell<AtomicStorage<T, T::Storage, T::Atomic>>,
+    /// Checks whether a `T` can be accessed through an `A`, which requires the same size and at least the same alignment
+    const fn can_transmute<T, A>() -> bool {
+        core::mem::size_of::<T>() == core::mem::size_of::<A>()
+            && core::mem::align_of::<T>() >= core::mem::align_of::<A>()
+    }
+
+    /// A 16-byte atomic built on `cmpxchg16b`, which must only be used if [`has_cx16`] returns `true`.
+    ///
+    /// `cmpxchg16b` is always sequentially consistent, so the orderings are ignored
+    #[cfg(target_arch = "x86_64")]
+    #[repr(C, align(16))]
+    struct AtomicU128Cx16(UnsafeCell<u128>);
+
+    #[cfg(target_arch = "x86_64")]
+    impl AtomicU128Cx16 {
+        fn compare_exchange(
+            &self,
+            current: u128,
+            new: u128,
+            _: Ordering,
+            _: Ordering,
+        ) -> Result<u128, u128> {
+            let (prev_lo, prev_hi): (u64, u64);
+            // SAFETY:
+            // `self` is 16-byte aligned, and the caller checked that `cmpxchg16b` is available.
+            // `rbx` is reserved by LLVM, so the low half of `new` is swapped into it around the instruction
+            unsafe {
+                core::arch::asm!(
+                    "xchg {new_lo}, rbx",
+                    "lock cmpxchg16b xmmword ptr [{ptr}]",
+                    "mov rbx, {new_lo}",
+                    ptr = in(reg) self.0.get(),
+                    new_lo = inout(reg) new as u64 => _,
+                    in("rcx") (new >> 64) as u64,
+                    inout("rax") current as u64 => prev_lo,
+                    inout("rdx") (current >> 64) as u64 => prev_hi,
+                    options(nostack),
+                );
+            }
+            let prev = ((prev_hi as u128) << 64) | (prev_lo as u128);
+            if prev == current {
+                Ok(prev)
+            } else {
+                Err(prev)
+            }
+        }
+
+        #[inline(always)]
+        fn compare_exchange_weak(
+            &self,
+            current: u128,
+            new: u128,
+            succ_ord: Ordering,
+            fail_ord: Ordering,
+        ) -> Result<u128, u128> {
+            self.compare_exchange(current, new, succ_ord, fail_ord)
+        }
+
+        fn load(&self, ord: Ordering) -> u128 {
+            // Writes back the value that is already there, if it happens to be zero
+            match self.compare_exchange(0, 0, ord, ord) {
+                Ok(val) | Err(val) => val,
+            }
+        }
+
+        fn swap(&self, val: u128, ord: Ordering) -> u128 {
+            let mut prev = 0;
+            loop {
+                match self.compare_exchange(prev, val, ord, ord) {
+                    Ok(prev) => break prev,
+                    Err(cur) => prev = cur,
+                }
+            }
+        }
+
+        #[inline(always)]
+        fn store(&self, val: u128, ord: Ordering) {
+            self.swap(val, ord);
+        }
+    }
+
+    /// Returns whether `cmpxchg16b` is available, caching the answer after the first call.
+    ///
+    /// This asks CPUID directly rather than `has_x86_feature!`, since init answers that from a `HashSet` built on first use,
+    /// and an [`AtomicCell`] touched while the set is being built would re-enter its initialization.
+    #[cfg(target_arch = "x86_64")]
+    fn has_cx16() -> bool {
+        const UNKNOWN: u8 = 0;
+        const ABSENT: u8 = 1;
+        const PRESENT: u8 = 2;
+
+        static CX16: AtomicU8 = AtomicU8::new(UNKNOWN);
+
+        match CX16.load(Ordering::Relaxed) {
+            UNKNOWN => {
+                let ecx: u32;
+                // SAFETY: CPUID is available on every x86_64 CPU. `rbx` is reserved by LLVM, so it is saved around the instruction
+                unsafe {
+                    core::arch::asm!(
+                        "mov {0:r}, rbx",
+                        "cpuid",
+                        "mov rbx, {0:r}",
+                        out(reg) _,
+                        inout("eax") 1u32 => _,
+                        inout("ecx") 0u32 => ecx,
+                        out("edx") _,
+                        options(nostack, preserves_flags),
+                    );
+                }
+                // Leaf 1 reports `cmpxchg16b` in bit 13 of ECX
+                let present = ecx & (1 << 13) != 0;
+                CX16.store(if present { PRESENT } else { ABSENT }, Ordering::Relaxed);
+                present
+            }
+            state => state == PRESENT,
+        }
+    }
+
+    /// The number of locks that protect [`AtomicCell`]s without a native atomic. It is prime, so that cells at any common stride spread over every lock
+    const STRIPES: usize = 67;
+
+    #[allow(clippy::declare_interior_mutable_const)]
+    const UNLOCKED_STRIPE: RawSeqLock = RawSeqLock::new();
+
+    static STRIPE_LOCKS: [RawSeqLock; STRIPES] = [UNLOCKED_STRIPE; STRIPES];
+
+    fn stripe_of<T>(ptr: *const T) -> &'static RawSeqLock {
+        &STRIPE_LOCKS[(ptr as usize) % STRIPES]
+    }
+
+    /// The write lock of the stripe protecting an [`AtomicCell`], which publishes the write when dropped.
+    ///
+    /// Interrupts are disabled while it is held, so that an interrupt handler on the same processor can't spin on the stripe
+    struct StripeGuard {
+        lock: &'static RawSeqLock,
+        seq: usize,
+        irqs_enabled: bool,
     }
 
-    impl<T: HasAtomic> AtomicCell<T> {
+    impl StripeGuard {
+        fn lock<T>(ptr: *const T) -> Self {
+            let irqs_enabled = super::lock::disable_interrupts();
+            let lock = stripe_of(ptr);
+            let seq = lock.write_lock();
+            Self {
+                lock,
+                seq,
+                irqs_enabled,
+            }
+        }
+
+        /// Releases the lock without invalidating concurrent optimistic reads, for when nothing was written
+        fn abort(self) {
+            let this = ManuallyDrop::new(self);
+            // SAFETY: We hold the lock, and nothing was written
+            unsafe { this.lock.write_abort(this.seq) }
+            if this.irqs_enabled {
+                // SAFETY: Interrupts were enabled when the lock was acquired
+                unsafe { super::lock::enable_interrupts() }
+            }
+        }
+    }
+
+    impl Drop for StripeGuard {
+        fn drop(&mut self) {
+            // SAFETY: We hold the lock
+            unsafe { self.lock.write_unlock(self.seq) }
+            if self.irqs_enabled {
+                // SAFETY: Interrupts were enabled when the lock was acquired
+                unsafe { super::lock::enable_interrupts() }
+            }
+        }
+    }
+
+    /// Runs `$native` with `$a` bound to the native atomic that can access `$this`, if there is one, and otherwise evaluates `$fallback`.
+    ///
+    /// Zero-sized values have nothing to access, so callers return before this
+    macro_rules! atomic {
+        ($this:ident, $a:ident => $native:expr, else $fallback:expr) => {
+            loop {
+                #[cfg(target_has_atomic = "8")]
+                atomic!(@check $this, AtomicU8, $a, $native);
+                #[cfg(target_has_atomic = "16")]
+                atomic!(@check $this, AtomicU16, $a, $native);
+                #[cfg(target_has_atomic = "32")]
+                atomic!(@check $this, AtomicU32, $a, $native);
+                #[cfg(target_has_atomic = "64")]
+                atomic!(@check $this, AtomicU64, $a, $native);
+                #[cfg(target_arch = "x86_64")]
+                if can_transmute::<T, AtomicU128Cx16>() && has_cx16() {
+                    atomic!(@check $this, AtomicU128Cx16, $a, $native);
+                }
+                break $fallback;
+            }
+        };
+        (@check $this:ident, $atomic:ty, $a:ident, $native:expr) => {
+            if can_transmute::<T, $atomic>() {
+                // SAFETY: `T` has the size of the atomic, and at least its alignment
+                let $a = unsafe { &*$this.value.get().cast::<$atomic>() };
+                break $native;
+            }
+        };
+    }
+
+    /// Types whose values are made of initialized bytes only, so they can be copied through integers, such as by [`AtomicCell`].
+    ///
+    /// # Safety
+    /// Every byte of every value of `Self` must be initialized. In particular, `Self` must not have padding, and must not contain unions or [`MaybeUninit`]
+    pub unsafe trait NoUninit: Copy {}
+
+    macro_rules! impl_no_uninit {
+        ($($ty:ty),* $(,)?) => {
+            $(
+                // SAFETY: Primitive types have no padding
+                unsafe impl NoUninit for $ty {}
+            )*
+        };
+    }
+
+    impl_no_uninit!(
+        (),
+        bool,
+        char,
+        u8,
+        u16,
+        u32,
+        u64,
+        u128,
+        usize,
+        i8,
+        i16,
+        i32,
+        i64,
+        i128,
+        isize,
+        f32,
+        f64,
+        core::num::NonZeroU8,
+        core::num::NonZeroU16,
+        core::num::NonZeroU32,
+        core::num::NonZeroU64,
+        core::num::NonZeroU128,
+        core::num::NonZeroUsize,
+    );
+
+    // SAFETY: Pointers are addresses, which are integers, and `T: ?Sized` only adds metadata that is also integers
+    unsafe impl<T: ?Sized> NoUninit for *const T {}
+    unsafe impl<T: ?Sized> NoUninit for *mut T {}
+    unsafe impl<T: ?Sized> NoUninit for ptr::NonNull<T> {}
+
+    // SAFETY: Arrays have no padding between their elements
+    unsafe impl<T: NoUninit, const N: usize> NoUninit for [T; N] {}
+
+    /// A memory location holding a `T` that can be accessed atomically.
+    ///
+    /// Cells use a native atomic if `T` has the same size as one and at least the same alignment, and 16-byte values aligned to 16 bytes use `cmpxchg16b` when the processor supports it.
+    /// Any other `T` is protected by one of a global table of sequence locks, selected by the address of the cell, so loads don't write to shared memory.
+    /// [`AtomicCell::is_lock_free`] reports which applies.
+    ///
+    /// Both paths copy the value through integers, and [`AtomicCell::compare_exchange`] compares it bitwise before falling back to [`Eq`].
+    /// Padding bytes are uninitialized, and reading them as integers is undefined behaviour, so `T` must be [`NoUninit`].
+    /// This rules out types such as `(u64, u8)`, or a `#[repr(align(4))]` struct holding a `u8`. Declaring their padding as explicit fields makes them usable
+    #[repr(transparent)]
+    pub struct AtomicCell<T> {
+        value: UnsafeCell<T>,
+    }
+
+    unsafe impl<T: Send> Sync for AtomicCell<T> {}
+
+    impl<T: NoUninit> AtomicCell<T> {
         #[inline]
         pub const fn new(x: T) -> Self {
             Self {
-                inner: UnsafeCell::new(AtomicStorage::new_zeroed_storage(x)),
+                value: UnsafeCell::new(x),
             }
         }
 
         #[inline]
         pub fn into_inner(self) -> T {
-            self.inner.into_inner().into_inner()
+            self.value.into_inner()
         }
 
         #[inline]
         pub fn get_mut(&mut self) -> &mut T {
-            self.inner.get_mut().get_mut()
+            self.value.get_mut()
         }
 
-        #[inline]
-        fn as_atomic(&self) -> &T::Atomic {
-            unsafe { &*(self.inner.get() as *mut T::Atomic) }
+        /// Checks whether operations on an `AtomicCell<T>` use a native atomic, rather than a lock
+        pub fn is_lock_free() -> bool {
+            #[cfg(target_arch = "x86_64")]
+            if can_transmute::<T, AtomicU128Cx16>() && has_cx16() {
+                return true;
+            }
+            core::mem::size_of::<T>() == 0
+                || (cfg!(target_has_atomic = "8") && can_transmute::<T, u8>())
+                || (cfg!(target_has_atomic = "16") && can_transmute::<T, u16>())
+                || (cfg!(target_has_atomic = "32") && can_transmute::<T, u32>())
+                || (cfg!(target_has_atomic = "64") && can_transmute::<T, u64>())
         }
 
         #[inline]
         pub fn load(&self, ord: Ordering) -> T {
-            unsafe { T::from_base(self.as_atomic().load(ord)) }
+            if core::mem::size_of::<T>() == 0 {
+                // SAFETY: Zero-sized values have nothing to read
+                return unsafe { ptr::read(self.value.get()) };
+            }
+            atomic! {
+                self, a => unsafe { core::mem::transmute_copy(&a.load(ord)) },
+                else self.load_locked()
+            }
+        }
+
+        fn load_locked(&self) -> T {
+            let lock = stripe_of(self.value.get());
+            if let Some(seq) = lock.read_begin() {
+                // SAFETY:
+                // Writers only write the value with `atomic_store` while they hold the stripe.
+                // The copy may be torn, so it's validated before being interpreted as a `T`
+                let val = unsafe { atomic_load(self.value.get()) };
+                if lock.read_validate(seq) {
+                    // SAFETY: No writer was active during the copy
+                    return unsafe { val.assume_init() };
+                }
+            }
+
+            // A writer was active, so take the lock rather than retrying, which could starve
+            let guard = StripeGuard::lock(self.value.get());
+            // SAFETY: We hold the lock, so nothing else writes the value
+            let val = unsafe { atomic_load(self.value.get()).assume_init() };
+            guard.abort();
+            val
         }
 
         #[inline]
         pub fn store(&self, val: T, ord: Ordering) {
-            self.as_atomic().store(val.into_base(), ord)
+            if core::mem::size_of::<T>() == 0 {
+                return;
+            }
+            atomic! {
+                self, a => a.store(unsafe { core::mem::transmute_copy(&val) }, ord),
+                else {
+                    let _guard = StripeGuard::lock(self.value.get());
+                    // SAFETY: We hold the lock
+                    unsafe { atomic_store(self.value.get(), val) }
+                }
+            }
         }
 
         #[inline]
         pub fn swap(&self, val: T, ord: Ordering) -> T {
-            unsafe { T::from_base(self.as_atomic().swap(val.into_base(), ord)) }
+            if core::mem::size_of::<T>() == 0 {
+                return val;
+            }
+            atomic! {
+                self, a => unsafe { core::mem::transmute_copy(&a.swap(core::mem::transmute_copy(&val), ord)) },
+                else {
+                    let _guard = StripeGuard::lock(self.value.get());
+                    // SAFETY: We hold the lock, so nothing else writes the value
+                    unsafe {
+                        let prev = atomic_load(self.value.get()).assume_init();
+                        atomic_store(self.value.get(), val);
+                        prev
+                    }
+                }
+            }
         }
+    }
 
+    impl<T: NoUninit + Eq> AtomicCell<T> {
+        /// Compares the bits of the value to `cmp`, replacing it with `new` if they're equal
         #[inline]
-        pub fn compare_exchange(
+        fn compare_exchange_bitwise(
             &self,
             cmp: T,
             new: T,
             succ_ord: Ordering,
             fail_ord: Ordering,
+            weak: bool,
         ) -> Result<T, T> {
-            match self.as_atomic().compare_exchange(
-                cmp.into_base(),
-                new.into_base(),
-                succ_ord,
-                fail_ord,
-            ) {
-                Ok(val) => Ok(unsafe { T::from_base(val) }),
-                Err(val) => Err(unsafe { T::from_base(val) }),
+            if core::mem::size_of::<T>() == 0 {
+                // Zero-sized values are always bitwise equal
+                return Ok(cmp);
+            }
+            atomic! {
+                self, a => {
+                    // SAFETY: `T` has the size of the atomic's base type, and is `NoUninit`
+                    let (cmp, new) = unsafe { (core::mem::transmute_copy(&cmp), core::mem::transmute_copy(&new)) };
+                    let res = if weak {
+                        a.compare_exchange_weak(cmp, new, succ_ord, fail_ord)
+                    } else {
+                        a.compare_exchange(cmp, new, succ_ord, fail_ord)
+                    };
+                    match res {
+                        Ok(val) => Ok(unsafe { core::mem::transmute_copy(&val) }),
+                        Err(val) => Err(unsafe { core::mem::transmute_copy(&val) }),
+                    }
+                },
+                else {
+                    let guard = StripeGuard::lock(self.value.get());
+                    // SAFETY: We hold the lock, so nothing else writes the value
+                    let cur = unsafe { atomic_load(self.value.get()).assume_init() };
+                    if cur == cmp {
+                        // SAFETY: We hold the lock
+                        unsafe { atomic_store(self.value.get(), new) }
+                        drop(guard);
+                        Ok(cur)
+                    } else {
+                        guard.abort();
+                        Err(cur)
+                    }
+                }
             }
         }
 
+        /// Replaces the value with `new` if it is equal to `cmp`, returning the previous value, which is `Ok` if the replacement happened
+        #[inline]
+        pub fn compare_exchange(
+            &self,
+            mut cmp: T,
+            new: T,
+            succ_ord: Ordering,
+            fail_ord: Ordering,
+        ) -> Result<T, T> {
+            loop {
+                match self.compare_exchange_bitwise(cmp, new, succ_ord, fail_ord, false) {
+                    // The value is equal to `cmp` without being bitwise equal, so retry with its exact bits
+                    Err(cur) if cur == cmp => cmp = cur,
+                    res => break res,
+                }
+            }
+        }
+
+        /// Like [`AtomicCell::compare_exchange`], but may fail spuriously, even if the value is equal to `cmp`
         #[inline]
         pub fn compare_exchange_weak(
             &self,
@@ -377,33 +798,50 @@ pub mod atomic {
             succ_ord: Ordering,
             fail_ord: Ordering,
         ) -> Result<T, T> {
-            match self.as_atomic().compare_exchange_weak(
-                cmp.into_base(),
-                new.into_base(),
-                succ_ord,
-                fail_ord,
-            ) {
-                Ok(val) => Ok(unsafe { T::from_base(val) }),
-                Err(val) => Err(unsafe { T::from_base(val) }),
+            self.compare_exchange_bitwise(cmp, new, succ_ord, fail_ord, true)
+        }
+
+        /// Repeatedly applies `f` to the value and tries to store the result, until it's stored or `f` returns `None`.
+        ///
+        /// Returns the value that `f` was last applied to, which is `Ok` if the result was stored. `f` may be called several times if other threads modify the value concurrently
+        pub fn fetch_update<F: FnMut(T) -> Option<T>>(
+            &self,
+            set_ord: Ordering,
+            fetch_ord: Ordering,
+            mut f: F,
+        ) -> Result<T, T> {
+            let mut prev = self.load(fetch_ord);
+            while let Some(next) = f(prev) {
+                match self.compare_exchange_weak(prev, next, set_ord, fetch_ord) {
+                    Ok(prev) => return Ok(prev),
+                    Err(cur) => prev = cur,
+                }
             }
+            Err(prev)
         }
     }
 
-    impl<T: HasAtomic + Default> Default for AtomicCell<T> {
+    impl<T: NoUninit + Default> Default for AtomicCell<T> {
         #[inline]
         fn default() -> Self {
             Self::new(T::default())
         }
     }
 
-    impl<T: HasAtomic> From<T> for AtomicCell<T> {
+    impl<T: NoUninit> From<T> for AtomicCell<T> {
         #[inline]
         fn from(val: T) -> Self {
             Self::new(val)
         }
     }
 
-    unsafe impl<T: HasAtomic> Sync for AtomicCell<T> {}
+    impl<T: NoUninit + fmt::Debug> fmt::Debug for AtomicCell<T> {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            f.debug_tuple("AtomicCell")
+                .field(&self.load(Ordering::SeqCst))
+                .finish()
+        }
+    }
 
     pub struct AtomicLeastCell<T: HasAtomicLeast> {
         inner: UnsafeCell<AtomicStorage<T, T::Storage, T::AtomicLeast>>,
@@ -539,8 +977,13 @@ pub mod atomic {
 
     impl_has_atomic_for_enum!(char, u32, target_has_atomic = "32");
 
+    #[cfg(not(loom))]
     pub struct AtomicFlag(AtomicLeastCell<bool>);
 
+    #[cfg(loom)]
+    pub struct AtomicFlag(super::prim::AtomicBool);
+
+    #[cfg(not(loom))]
     impl AtomicFlag {
         pub const fn new() -> Self {
             Self(AtomicLeastCell::new(false))
@@ -549,11 +992,38 @@ pub mod atomic {
         pub const fn new_with_val(val: bool) -> Self {
             Self(AtomicLeastCell::new(val))
         }
+    }
+
+    #[cfg(loom)]
+    impl AtomicFlag {
+        pub fn new() -> Self {
+            Self(super::prim::AtomicBool::new(false))
+        }
 
+        pub fn new_with_val(val: bool) -> Self {
+            Self(super::prim::AtomicBool::new(val))
+        }
+    }
+
+    impl Default for AtomicFlag {
+        fn default() -> Self {
+            Self::new()
+        }
+    }
+
+    impl AtomicFlag {
         pub fn test_and_set(&self, ord: Ordering) -> bool {
             self.0.swap(true, ord)
         }
 
+        pub fn test_and_clear(&self, ord: Ordering) -> bool {
+            self.0.swap(false, ord)
+        }
+
+        pub fn test(&self, ord: Ordering) -> bool {
+            self.0.load(ord)
+        }
+
         pub fn clear(&self, ord: Ordering) {
             self.0.store(false, ord);
         }
@@ -563,6 +1033,40 @@ pub mod atomic {
         }
     }
 }
+
+/// A queue of threads waiting for a condition to become false, such as a buffer being full or empty.
+///
+/// Implementations must not lose wakeups: a waiter has to be registered on the queue before it checks the condition,
+/// so that a [`WaitQueue::wake_one`] or [`WaitQueue::wake_all`] call made after the condition changes always reaches it.
+pub trait WaitQueue {
+    /// Blocks the caller while `cond` returns `true`.
+    ///
+    /// `cond` is checked before blocking and after each wakeup. Spurious wakeups are allowed.
+    fn wait_while(&self, cond: &mut dyn FnMut() -> bool);
+
+    /// Wakes at least one waiter, if any
+    fn wake_one(&self);
+
+    /// Wakes every waiter
+    fn wake_all(&self);
+}
+
+/// A [`WaitQueue`] that busy-waits instead of blocking, for use before the scheduler is running
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SpinWaitQueue;
+
+impl WaitQueue for SpinWaitQueue {
+    fn wait_while(&self, cond: &mut dyn FnMut() -> bool) {
+        while cond() {
+            spin_loop();
+        }
+    }
+
+    fn wake_one(&self) {}
+
+    fn wake_all(&self) {}
+}
+
 const ONCE_INIT: usize = 0x02;
 const ONCE_LOCKED: usize = 0x01;
 
@@ -579,6 +1083,7 @@ impl<T> Default for OnceCell<T> {
     }
 }
 
+#[cfg(not(loom))]
 impl<T> OnceCell<T> {
     pub const fn new() -> Self {
         Self {
@@ -586,22 +1091,47 @@ impl<T> OnceCell<T> {
             cell: UnsafeCell::new(MaybeUninit::uninit()),
         }
     }
+}
 
+#[cfg(loom)]
+impl<T> OnceCell<T> {
+    pub fn new() -> Self {
+        Self {
+            flag: AtomicUsize::new(0),
+            cell: UnsafeCell::new(MaybeUninit::uninit()),
+        }
+    }
+}
+
+impl<T> OnceCell<T> {
     fn begin_init(&self) -> bool {
-        if (self.flag.load(atomic::Ordering::Relaxed) & ONCE_INIT) == 0 {
-            while (self.flag.fetch_or(ONCE_LOCKED, atomic::Ordering::Acquire) & ONCE_LOCKED) != 0 {
-                unsafe {
-                    asm!("pause");
+        let mut flag = self.flag.load(atomic::Ordering::Relaxed);
+        if (flag & ONCE_INIT) == 0 {
+            // Only a successful compare-exchange writes the flag, so waiters don't keep writing it while another thread initializes
+            loop {
+                if (flag & ONCE_LOCKED) != 0 {
+                    spin_loop();
+                    flag = self.flag.load(atomic::Ordering::Relaxed);
+                    continue;
+                }
+                match self.flag.compare_exchange_weak(
+                    flag,
+                    flag | ONCE_LOCKED,
+                    atomic::Ordering::Acquire,
+                    atomic::Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    Err(cur) => flag = cur,
                 }
             }
-            if (self.flag.load(atomic::Ordering::Relaxed) & ONCE_INIT) == 0 {
+            if (flag & ONCE_INIT) == 0 {
                 true
             } else {
                 self.flag.store(ONCE_INIT, atomic::Ordering::Relaxed);
                 false
             }
         } else {
-            atomic::fence(atomic::Ordering::Acquire);
+            prim::fence(atomic::Ordering::Acquire);
             false
         }
     }
@@ -703,3 +1233,322 @@ impl<T> OnceCell<T> {
         Ok(unsafe { &*(self.cell.get().cast::<T>()) })
     }
 }
+
+/// A value that is initialized by calling `F` the first time it is accessed, from whichever thread gets there first.
+///
+/// This is a [`OnceCell`] that carries its initializer, so it can be used directly in a `static`, without repeating the initializer at every use site.
+/// Other threads that access the value during initialization wait for it to complete.
+pub struct Lazy<T, F = fn() -> T> {
+    cell: OnceCell<T>,
+    // Only taken by the thread that initializes `cell`, which excludes every other thread
+    init: Cell<Option<F>>,
+}
+
+unsafe impl<T: Send + Sync, F: Send> Sync for Lazy<T, F> {}
+
+#[cfg(not(loom))]
+impl<T, F> Lazy<T, F> {
+    /// Creates a new `Lazy` that is initialized by calling `init`
+    pub const fn new(init: F) -> Self {
+        Self {
+            cell: OnceCell::new(),
+            init: Cell::new(Some(init)),
+        }
+    }
+}
+
+#[cfg(loom)]
+impl<T, F> Lazy<T, F> {
+    /// Creates a new `Lazy` that is initialized by calling `init`
+    pub fn new(init: F) -> Self {
+        Self {
+            cell: OnceCell::new(),
+            init: Cell::new(Some(init)),
+        }
+    }
+}
+
+impl<T, F: FnOnce() -> T> Lazy<T, F> {
+    /// Forces initialization if it hasn't happened yet, and returns a reference to the value.
+    ///
+    /// This is equivalent to dereferencing `this`
+    pub fn force(this: &Self) -> &T {
+        this.cell.get_or_init(|| match this.init.take() {
+            Some(init) => init(),
+            None => panic!("Lazy instance has previously been poisoned"),
+        })
+    }
+
+    /// Returns a reference to the value if it has been initialized, without initializing it
+    pub fn get(this: &Self) -> Option<&T> {
+        this.cell.get()
+    }
+}
+
+impl<T, F: FnOnce() -> T> Deref for Lazy<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        Self::force(self)
+    }
+}
+
+impl<T: Default> Default for Lazy<T> {
+    fn default() -> Self {
+        Self::new(T::default)
+    }
+}
+
+impl<T: fmt::Debug, F> fmt::Debug for Lazy<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_tuple("Lazy");
+        match self.cell.get() {
+            Some(val) => s.field(val),
+            None => s.field(&format_args!("<uninit>")),
+        };
+        s.finish()
+    }
+}
+
+#[cfg(all(test, feature = "std", not(loom)))]
+mod test {
+    extern crate std as host;
+
+    use alloc::sync::Arc;
+
+    use super::atomic::{AtomicCell, NoUninit, Ordering};
+    use super::OnceCell;
+
+    #[test]
+    fn once_cell_initializes_once() {
+        let cell = Arc::new(OnceCell::new());
+        let threads = (0..4)
+            .map(|i| {
+                let cell = cell.clone();
+                host::thread::spawn(move || *cell.get_or_init(|| i))
+            })
+            .collect::<alloc::vec::Vec<_>>();
+        let first = *cell.get_or_init(|| 4);
+        for thread in threads {
+            assert_eq!(thread.join().unwrap(), first);
+        }
+        assert_eq!(cell.set(5), Err(5));
+    }
+
+    #[test]
+    fn atomic_cell_lock_free_and_locked() {
+        let small = AtomicCell::new(5u32);
+        assert!(AtomicCell::<u32>::is_lock_free());
+        assert_eq!(small.swap(6, Ordering::AcqRel), 5);
+        assert_eq!(
+            small.compare_exchange(6, 7, Ordering::AcqRel, Ordering::Acquire),
+            Ok(6)
+        );
+        assert_eq!(small.load(Ordering::Acquire), 7);
+
+        // Too large for any native atomic, so this takes the striped lock path
+        let large = Arc::new(AtomicCell::new([0u64; 4]));
+        assert!(!AtomicCell::<[u64; 4]>::is_lock_free());
+        let threads = (1..=4u64)
+            .map(|i| {
+                let large = large.clone();
+                host::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        large.store([i; 4], Ordering::Release);
+                        let [a, b, c, d] = large.load(Ordering::Acquire);
+                        assert!(a == b && b == c && c == d);
+                    }
+                })
+            })
+            .collect::<alloc::vec::Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn atomic_cell_explicit_padding() {
+        // `(u32, u16)` would have two bytes of padding, which are declared as a field so that the pair can be copied through a `u64`
+        #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+        #[repr(C, align(8))]
+        struct Pair {
+            a: u32,
+            b: u16,
+            _pad: u16,
+        }
+
+        // SAFETY: `Pair` has no implicit padding
+        unsafe impl NoUninit for Pair {}
+
+        let pair = |a, b| Pair { a, b, _pad: 0 };
+        let cell = AtomicCell::new(pair(1, 2));
+        assert!(AtomicCell::<Pair>::is_lock_free());
+        assert_eq!(
+            cell.compare_exchange(pair(1, 2), pair(3, 4), Ordering::AcqRel, Ordering::Acquire),
+            Ok(pair(1, 2))
+        );
+        assert_eq!(
+            cell.compare_exchange(pair(1, 2), pair(5, 6), Ordering::AcqRel, Ordering::Acquire),
+            Err(pair(3, 4))
+        );
+        assert_eq!(cell.load(Ordering::Acquire), pair(3, 4));
+    }
+
+    #[test]
+    fn atomic_cell_zero_sized() {
+        let unit = AtomicCell::new(());
+        assert!(AtomicCell::<()>::is_lock_free());
+        unit.store((), Ordering::Release);
+        assert_eq!(unit.swap((), Ordering::AcqRel), ());
+        assert_eq!(
+            unit.compare_exchange((), (), Ordering::AcqRel, Ordering::Acquire),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn atomic_cell_locked_unaligned() {
+        // Three bytes fit no native atomic, and are copied a byte at a time
+        let cell = Arc::new(AtomicCell::new([0u8; 3]));
+        assert!(!AtomicCell::<[u8; 3]>::is_lock_free());
+        let threads = (1..=4u8)
+            .map(|i| {
+                let cell = cell.clone();
+                host::thread::spawn(move || {
+                    for _ in 0..1000 {
+                        let [a, b, c] = cell.swap([i; 3], Ordering::AcqRel);
+                        assert!(a == b && b == c);
+                        let _ = cell.compare_exchange(
+                            [i; 3],
+                            [0; 3],
+                            Ordering::AcqRel,
+                            Ordering::Acquire,
+                        );
+                    }
+                })
+            })
+            .collect::<alloc::vec::Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let [a, b, c] = cell.load(Ordering::Acquire);
+        assert!(a == b && b == c);
+    }
+}
+
+#[cfg(all(loom, test))]
+mod loom_test {
+    use loom::cell::UnsafeCell;
+    use loom::model;
+    use loom::sync::Arc;
+    use loom::thread;
+
+    use super::atomic::AtomicFlag;
+    use super::prim::{spin_loop, Ordering};
+    use super::{Mutex, OnceCell, RawMutex, RawSpinLock, RawTicketLock};
+
+    #[test]
+    fn loom_once_cell_publishes_value() {
+        model(|| {
+            let cell = Arc::new(OnceCell::new());
+            let setter = {
+                let cell = cell.clone();
+                thread::spawn(move || cell.set(alloc::vec![7]).is_ok())
+            };
+            if let Some(val) = cell.get() {
+                assert_eq!(*val, [7]);
+            }
+            assert!(setter.join().unwrap());
+            assert_eq!(*cell.get().unwrap(), [7]);
+        });
+    }
+
+    #[test]
+    fn loom_once_cell_init_abort() {
+        model(|| {
+            let cell = Arc::new(OnceCell::new());
+            let aborter = {
+                let cell = cell.clone();
+                thread::spawn(move || cell.get_or_try_init(|| Err(())).copied())
+            };
+            assert_eq!(*cell.get_or_init(|| 2), 2);
+            // The failed initializer either ran first and left the cell uninitialized, or found it initialized
+            assert!(matches!(aborter.join().unwrap(), Err(()) | Ok(2)));
+            assert_eq!(cell.get(), Some(&2));
+        });
+    }
+
+    #[test]
+    fn loom_atomic_flag_publishes_data() {
+        model(|| {
+            let shared = Arc::new((AtomicFlag::new(), UnsafeCell::new(0)));
+            let writer = {
+                let shared = shared.clone();
+                thread::spawn(move || {
+                    shared.1.with_mut(|val| unsafe { *val = 1 });
+                    shared.0.set(Ordering::Release);
+                })
+            };
+            if shared.0.test(Ordering::Acquire) {
+                assert_eq!(shared.1.with(|val| unsafe { *val }), 1);
+            }
+            writer.join().unwrap();
+        });
+    }
+
+    #[test]
+    fn loom_atomic_flag_test_and_set_excludes() {
+        model(|| {
+            let shared = Arc::new((AtomicFlag::new(), UnsafeCell::new(0)));
+            let threads = (0..2)
+                .map(|_| {
+                    let shared = shared.clone();
+                    thread::spawn(move || {
+                        while shared.0.test_and_set(Ordering::Acquire) {
+                            spin_loop();
+                        }
+                        shared.1.with_mut(|val| unsafe { *val += 1 });
+                        assert!(shared.0.test_and_clear(Ordering::Release));
+                    })
+                })
+                .collect::<alloc::vec::Vec<_>>();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            assert_eq!(shared.1.with(|val| unsafe { *val }), 2);
+            assert!(!shared.0.test(Ordering::Relaxed));
+        });
+    }
+
+    /// Checks that `R` excludes two threads that increment a counter it protects
+    fn check_mutual_exclusion<R: RawMutex + Send + Sync + 'static>() {
+        model(|| {
+            // loom can only see races on its own `UnsafeCell`, so the counter is kept outside the mutex
+            let shared = Arc::new((Mutex::<(), R>::new(()), UnsafeCell::new(0)));
+            let threads = (0..2)
+                .map(|_| {
+                    let shared = shared.clone();
+                    thread::spawn(move || {
+                        let _guard = shared.0.lock();
+                        shared.1.with_mut(|val| unsafe { *val += 1 });
+                    })
+                })
+                .collect::<alloc::vec::Vec<_>>();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            assert!(!shared.0.is_locked());
+            assert_eq!(shared.1.with(|val| unsafe { *val }), 2);
+        });
+    }
+
+    #[test]
+    fn loom_spin_lock_excludes() {
+        check_mutual_exclusion::<RawSpinLock>();
+    }
+
+    #[test]
+    fn loom_ticket_lock_excludes() {
+        check_mutual_exclusion::<RawTicketLock>();
+    }
+}
@@ -0,0 +1,553 @@
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    marker::PhantomData,
+    ops::{Deref, DerefMut},
+};
+
+use super::prim::{spin_loop, AtomicBool, AtomicUsize, Ordering};
+
+/// A raw lock, which provides mutual exclusion without protecting any data itself.
+///
+/// [`Mutex`] pairs a raw lock with the data it protects, and releases it with an RAII guard.
+///
+/// # Safety
+/// Between a successful [`RawMutex::lock`] or [`RawMutex::try_lock`] and the matching [`RawMutex::unlock`], no other call may acquire the lock.
+/// Acquiring the lock must synchronize-with (be an `Acquire` operation on) the previous release of the lock, which must be a `Release` operation
+pub unsafe trait RawMutex {
+    /// An unlocked lock
+    #[cfg(not(loom))]
+    const INIT: Self;
+
+    /// Creates an unlocked lock. This replaces [`RawMutex::INIT`] under loom, whose atomics can't be created in constant expressions
+    #[cfg(loom)]
+    fn init() -> Self;
+
+    /// Acquires the lock, spinning until it's available
+    fn lock(&self);
+
+    /// Attempts to acquire the lock without spinning, returning whether it was acquired
+    fn try_lock(&self) -> bool;
+
+    /// Releases the lock
+    ///
+    /// # Safety
+    /// The lock must be held by the current context
+    unsafe fn unlock(&self);
+
+    /// Checks whether the lock is currently held by anyone.
+    ///
+    /// The result is immediately stale, so it's only useful for diagnostics
+    fn is_locked(&self) -> bool;
+}
+
+/// A test-and-test-and-set spinlock.
+///
+/// Waiters spin on a plain load, so they don't contend for the cache line until the lock is released.
+/// It is not fair: a waiter can be overtaken indefinitely. See [`RawTicketLock`] for a fair lock
+pub struct RawSpinLock {
+    locked: AtomicBool,
+}
+
+unsafe impl RawMutex for RawSpinLock {
+    #[cfg(not(loom))]
+    const INIT: Self = Self {
+        locked: AtomicBool::new(false),
+    };
+
+    #[cfg(loom)]
+    fn init() -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+        }
+    }
+
+    fn lock(&self) {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            while self.locked.load(Ordering::Relaxed) {
+                spin_loop();
+            }
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        self.locked
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_ok()
+    }
+
+    unsafe fn unlock(&self) {
+        self.locked.store(false, Ordering::Release);
+    }
+
+    fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+}
+
+/// A fair spinlock, which grants the lock to waiters in the order they arrived.
+///
+/// Each locker takes a ticket and waits until that ticket is served, so no waiter can starve.
+/// In exchange, a waiter that is preempted holds up every waiter behind it
+pub struct RawTicketLock {
+    next_ticket: AtomicUsize,
+    now_serving: AtomicUsize,
+}
+
+unsafe impl RawMutex for RawTicketLock {
+    #[cfg(not(loom))]
+    const INIT: Self = Self {
+        next_ticket: AtomicUsize::new(0),
+        now_serving: AtomicUsize::new(0),
+    };
+
+    #[cfg(loom)]
+    fn init() -> Self {
+        Self {
+            next_ticket: AtomicUsize::new(0),
+            now_serving: AtomicUsize::new(0),
+        }
+    }
+
+    fn lock(&self) {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            spin_loop();
+        }
+    }
+
+    fn try_lock(&self) -> bool {
+        let serving = self.now_serving.load(Ordering::Relaxed);
+        self.next_ticket
+            .compare_exchange(
+                serving,
+                serving.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_ok()
+    }
+
+    unsafe fn unlock(&self) {
+        // Only the holder writes `now_serving`, so this doesn't need to be a read-modify-write
+        let serving = self.now_serving.load(Ordering::Relaxed);
+        self.now_serving
+            .store(serving.wrapping_add(1), Ordering::Release);
+    }
+
+    fn is_locked(&self) -> bool {
+        self.next_ticket.load(Ordering::Relaxed) != self.now_serving.load(Ordering::Relaxed)
+    }
+}
+
+/// Disables interrupts on the current processor, returning whether they were enabled beforehand
+#[cfg(all(target_arch = "x86_64", not(feature = "std")))]
+pub(super) fn disable_interrupts() -> bool {
+    const RFLAGS_IF: u64 = 1 << 9;
+
+    let rflags: u64;
+    // SAFETY:
+    // Reading RFLAGS and clearing IF has no effect on the Rust abstract machine.
+    // The asm isn't `nomem`, so that it also acts as a compiler barrier
+    unsafe {
+        core::arch::asm!("pushfq", "pop {}", "cli", out(reg) rflags);
+    }
+    (rflags & RFLAGS_IF) != 0
+}
+
+/// Enables interrupts on the current processor
+#[cfg(all(target_arch = "x86_64", not(feature = "std")))]
+pub(super) unsafe fn enable_interrupts() {
+    unsafe {
+        core::arch::asm!("sti", options(nostack));
+    }
+}
+
+// The hosted build runs in user mode, where interrupts can't be masked, so it simulates the interrupt flag instead
+#[cfg(all(feature = "std", not(doctest)))]
+pub(super) use crate::hosted::{disable_interrupts, enable_interrupts};
+
+// Other architectures don't mask interrupts yet
+#[cfg(not(any(
+    all(target_arch = "x86_64", not(feature = "std")),
+    all(feature = "std", not(doctest))
+)))]
+pub(super) fn disable_interrupts() -> bool {
+    false
+}
+
+#[cfg(not(any(
+    all(target_arch = "x86_64", not(feature = "std")),
+    all(feature = "std", not(doctest))
+)))]
+pub(super) unsafe fn enable_interrupts() {}
+
+/// Wraps a raw lock so that interrupts are disabled on the current processor while it is held.
+///
+/// This is needed for any lock that an interrupt handler may take, since an interrupt that arrives while the lock is held on the same processor would otherwise deadlock.
+/// Interrupts are disabled before spinning for the lock, and are restored to their previous state (rather than unconditionally enabled) when it is released,
+///  so these locks can be nested as long as they are released in the reverse order they were acquired.
+///
+/// On the hosted build (the `std` feature), interrupts can't be masked, and this saves and restores a simulated interrupt flag of the current thread instead
+pub struct IrqSave<R> {
+    raw: R,
+    // Only accessed by the holder of `raw`
+    irqs_enabled: AtomicBool,
+}
+
+unsafe impl<R: RawMutex> RawMutex for IrqSave<R> {
+    #[cfg(not(loom))]
+    const INIT: Self = Self {
+        raw: R::INIT,
+        irqs_enabled: AtomicBool::new(false),
+    };
+
+    #[cfg(loom)]
+    fn init() -> Self {
+        Self {
+            raw: R::init(),
+            irqs_enabled: AtomicBool::new(false),
+        }
+    }
+
+    fn lock(&self) {
+        let irqs_enabled = disable_interrupts();
+        self.raw.lock();
+        self.irqs_enabled.store(irqs_enabled, Ordering::Relaxed);
+    }
+
+    fn try_lock(&self) -> bool {
+        let irqs_enabled = disable_interrupts();
+        if self.raw.try_lock() {
+            self.irqs_enabled.store(irqs_enabled, Ordering::Relaxed);
+            true
+        } else {
+            if irqs_enabled {
+                // SAFETY: Interrupts were enabled when we got here
+                unsafe { enable_interrupts() }
+            }
+            false
+        }
+    }
+
+    unsafe fn unlock(&self) {
+        let irqs_enabled = self.irqs_enabled.load(Ordering::Relaxed);
+        // SAFETY: Guaranteed by our caller
+        unsafe { self.raw.unlock() }
+        if irqs_enabled {
+            // SAFETY: Interrupts were enabled when the lock was acquired
+            unsafe { enable_interrupts() }
+        }
+    }
+
+    fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+}
+
+/// A mutual exclusion lock protecting a `T`, built on the raw lock `R`.
+///
+/// The data can only be accessed through the [`MutexGuard`] returned from [`Mutex::lock`] or [`Mutex::try_lock`], which releases the lock when dropped.
+/// Unlike the host's `Mutex`, there is no poisoning: if a panic unwinds while the lock is held, the lock is released even if the data was left half-updated.
+///
+/// Until the scheduler can park threads, every raw lock spins, and `Mutex<T>` is the same as [`SpinLock<T>`]
+pub struct Mutex<T: ?Sized, R = RawSpinLock> {
+    raw: R,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send, R: RawMutex + Send> Send for Mutex<T, R> {}
+unsafe impl<T: ?Sized + Send, R: RawMutex + Sync> Sync for Mutex<T, R> {}
+
+#[cfg(not(loom))]
+impl<T, R: RawMutex> Mutex<T, R> {
+    /// Creates a new unlocked mutex containing `val`
+    pub const fn new(val: T) -> Self {
+        Self {
+            raw: R::INIT,
+            data: UnsafeCell::new(val),
+        }
+    }
+}
+
+#[cfg(loom)]
+impl<T, R: RawMutex> Mutex<T, R> {
+    /// Creates a new unlocked mutex containing `val`
+    pub fn new(val: T) -> Self {
+        Self {
+            raw: R::init(),
+            data: UnsafeCell::new(val),
+        }
+    }
+}
+
+impl<T, R: RawMutex> Mutex<T, R> {
+    /// Consumes the mutex and returns the contained value
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized, R: RawMutex> Mutex<T, R> {
+    /// Acquires the lock, spinning until it's available
+    pub fn lock(&self) -> MutexGuard<'_, T, R> {
+        self.raw.lock();
+        MutexGuard {
+            mutex: self,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Attempts to acquire the lock without spinning
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T, R>> {
+        if self.raw.try_lock() {
+            Some(MutexGuard {
+                mutex: self,
+                _not_send: PhantomData,
+            })
+        } else {
+            None
+        }
+    }
+
+    /// Checks whether the lock is currently held by anyone.
+    ///
+    /// The result is immediately stale, so it's only useful for diagnostics
+    pub fn is_locked(&self) -> bool {
+        self.raw.is_locked()
+    }
+
+    /// Returns a mutable reference to the contained value.
+    ///
+    /// This doesn't lock, since the mutable borrow statically guarantees exclusive access
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// Releases the lock without a guard.
+    ///
+    /// This is intended for paths that never return to the holder, such as a panic handler that needs a lock the panicking code may have held
+    ///
+    /// # Safety
+    /// The lock must be held, and the guard that holds it must never be used or dropped afterwards
+    pub unsafe fn force_unlock(&self) {
+        // SAFETY: Guaranteed by our caller
+        unsafe { self.raw.unlock() }
+    }
+}
+
+impl<T: Default, R: RawMutex> Default for Mutex<T, R> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T, R: RawMutex> From<T> for Mutex<T, R> {
+    fn from(val: T) -> Self {
+        Self::new(val)
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, R: RawMutex> fmt::Debug for Mutex<T, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_struct("Mutex");
+        match self.try_lock() {
+            Some(guard) => s.field("data", &&*guard),
+            None => s.field("data", &format_args!("<locked>")),
+        };
+        s.finish_non_exhaustive()
+    }
+}
+
+/// An RAII guard for a locked [`Mutex`], which releases the lock when dropped.
+///
+/// The guard can't be sent to another thread, since the lock (in particular an [`IrqSave`] lock) has to be released by the context that acquired it
+pub struct MutexGuard<'a, T: ?Sized, R: RawMutex = RawSpinLock> {
+    mutex: &'a Mutex<T, R>,
+    _not_send: PhantomData<*mut ()>,
+}
+
+unsafe impl<T: ?Sized + Sync, R: RawMutex + Sync> Sync for MutexGuard<'_, T, R> {}
+
+impl<T: ?Sized, R: RawMutex> Deref for MutexGuard<'_, T, R> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: We hold the lock
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized, R: RawMutex> DerefMut for MutexGuard<'_, T, R> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: We hold the lock
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<T: ?Sized, R: RawMutex> Drop for MutexGuard<'_, T, R> {
+    fn drop(&mut self) {
+        // SAFETY: We hold the lock
+        unsafe { self.mutex.raw.unlock() }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, R: RawMutex> fmt::Debug for MutexGuard<'_, T, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        T::fmt(self, f)
+    }
+}
+
+impl<T: ?Sized + fmt::Display, R: RawMutex> fmt::Display for MutexGuard<'_, T, R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        T::fmt(self, f)
+    }
+}
+
+/// An unfair spinlock. See [`RawSpinLock`]
+pub type SpinLock<T> = Mutex<T, RawSpinLock>;
+
+/// The guard of a [`SpinLock`]
+pub type SpinLockGuard<'a, T> = MutexGuard<'a, T, RawSpinLock>;
+
+/// A fair spinlock. See [`RawTicketLock`]
+pub type TicketLock<T> = Mutex<T, RawTicketLock>;
+
+/// The guard of a [`TicketLock`]
+pub type TicketLockGuard<'a, T> = MutexGuard<'a, T, RawTicketLock>;
+
+/// A spinlock that disables interrupts while held. See [`IrqSave`]
+pub type IrqSpinLock<T> = Mutex<T, IrqSave<RawSpinLock>>;
+
+/// The guard of an [`IrqSpinLock`]
+pub type IrqSpinLockGuard<'a, T> = MutexGuard<'a, T, IrqSave<RawSpinLock>>;
+
+#[cfg(all(test, feature = "std", not(loom)))]
+mod test {
+    extern crate std as host;
+
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+
+    use super::{disable_interrupts, enable_interrupts, IrqSpinLock, SpinLock, TicketLock};
+    use crate::hosted::interrupts_enabled;
+
+    /// Increments a counter behind `lock` from several threads at once, and checks that no increment was lost
+    fn check_mutual_exclusion<L: Send + Sync + 'static>(
+        lock: L,
+        increment: fn(&L),
+        get: fn(&L) -> u64,
+    ) {
+        const THREADS: u64 = 4;
+        const ITERS: u64 = 10_000;
+
+        let lock = Arc::new(lock);
+        let threads = (0..THREADS)
+            .map(|_| {
+                let lock = lock.clone();
+                host::thread::spawn(move || {
+                    for _ in 0..ITERS {
+                        increment(&lock);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(get(&lock), THREADS * ITERS);
+    }
+
+    #[test]
+    fn spin_lock_excludes_threads() {
+        check_mutual_exclusion(SpinLock::new(0), |l| *l.lock() += 1, |l| *l.lock());
+    }
+
+    #[test]
+    fn ticket_lock_excludes_threads() {
+        check_mutual_exclusion(TicketLock::new(0), |l| *l.lock() += 1, |l| *l.lock());
+    }
+
+    #[test]
+    fn spin_lock_try_lock() {
+        let lock = SpinLock::new(1);
+        let guard = lock.lock();
+        assert!(lock.is_locked());
+        assert!(lock.try_lock().is_none());
+        drop(guard);
+        assert!(!lock.is_locked());
+        assert_eq!(*lock.try_lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn ticket_lock_try_lock() {
+        let lock = TicketLock::new(1);
+        let guard = lock.lock();
+        assert!(lock.is_locked());
+        assert!(lock.try_lock().is_none());
+        drop(guard);
+        assert!(!lock.is_locked());
+        // A failed `try_lock` must not take a ticket, or the next `lock` would never be served
+        *lock.lock() += 1;
+        assert_eq!(*lock.try_lock().unwrap(), 2);
+    }
+
+    #[test]
+    fn force_unlock_releases_forgotten_guard() {
+        let lock = TicketLock::new(());
+        core::mem::forget(lock.lock());
+        assert!(lock.is_locked());
+        // SAFETY: The lock is held, and its guard was forgotten
+        unsafe { lock.force_unlock() }
+        assert!(!lock.is_locked());
+        drop(lock.lock());
+    }
+
+    #[test]
+    fn irq_save_restores_interrupts_when_nested() {
+        let outer = IrqSpinLock::new(());
+        let inner = IrqSpinLock::new(());
+        assert!(interrupts_enabled());
+
+        let outer_guard = outer.lock();
+        assert!(!interrupts_enabled());
+        let inner_guard = inner.lock();
+        assert!(!interrupts_enabled());
+        // Releasing the inner lock restores the state from when it was acquired, which is still disabled
+        drop(inner_guard);
+        assert!(!interrupts_enabled());
+        drop(outer_guard);
+        assert!(interrupts_enabled());
+    }
+
+    #[test]
+    fn irq_save_leaves_interrupts_disabled() {
+        let lock = IrqSpinLock::new(());
+        assert!(disable_interrupts());
+        drop(lock.lock());
+        assert!(!interrupts_enabled());
+        drop(lock.try_lock().unwrap());
+        assert!(!interrupts_enabled());
+        // SAFETY: This only sets the simulated flag of this thread
+        unsafe { enable_interrupts() }
+    }
+
+    #[test]
+    fn irq_save_failed_try_lock_restores_interrupts() {
+        let lock = Arc::new(IrqSpinLock::new(()));
+        let guard = lock.lock();
+        let other = lock.clone();
+        host::thread::spawn(move || {
+            assert!(other.try_lock().is_none());
+            assert!(interrupts_enabled());
+        })
+        .join()
+        .unwrap();
+        drop(guard);
+        assert!(interrupts_enabled());
+    }
+}
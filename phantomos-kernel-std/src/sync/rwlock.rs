@@ -0,0 +1,564 @@
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    hint::spin_loop,
+    marker::PhantomData,
+    mem::ManuallyDrop,
+    ops::{Deref, DerefMut},
+};
+
+use super::{
+    atomic::{AtomicCell, Ordering},
+    lock::{disable_interrupts, enable_interrupts},
+};
+
+const WRITER: usize = 0x01;
+const UPGRADABLE: usize = 0x02;
+const WRITER_WAITING: usize = 0x04;
+const READER: usize = 0x08;
+
+/// Disables interrupts if `IRQ_SAVE` is set, returning whether they need to be enabled again when the guard is released
+fn irq_enter<const IRQ_SAVE: bool>() -> bool {
+    IRQ_SAVE && disable_interrupts()
+}
+
+unsafe fn irq_leave(irqs_enabled: bool) {
+    if irqs_enabled {
+        // SAFETY: Interrupts were enabled when the matching `irq_enter` was called
+        unsafe { enable_interrupts() }
+    }
+}
+
+/// A writer-preferring reader-writer spinlock protecting a `T`.
+///
+/// Any number of readers can hold the lock at once, or a single writer. Once a writer is waiting, new readers wait behind it,
+///  so that a steady stream of readers can't starve writers out. This also means a reader must not acquire the lock again while it holds it,
+///  since a writer arriving in between would deadlock both.
+///
+/// One reader at a time may instead take an upgradable read ([`RwLock::upgradable_read`]), which coexists with plain readers,
+///  but can be atomically upgraded to a write lock once they leave, without letting another writer in between.
+///
+/// If `IRQ_SAVE` is set (see [`IrqRwLock`]), interrupts are disabled on the current processor while any guard is held,
+///  and restored to their previous state when it is released, so that the lock can be shared with interrupt handlers
+pub struct RwLock<T: ?Sized, const IRQ_SAVE: bool = false> {
+    state: AtomicCell<usize>,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: ?Sized + Send, const IRQ_SAVE: bool> Send for RwLock<T, IRQ_SAVE> {}
+unsafe impl<T: ?Sized + Send + Sync, const IRQ_SAVE: bool> Sync for RwLock<T, IRQ_SAVE> {}
+
+/// A [`RwLock`] that disables interrupts while held
+pub type IrqRwLock<T> = RwLock<T, true>;
+
+impl<T, const IRQ_SAVE: bool> RwLock<T, IRQ_SAVE> {
+    /// Creates a new unlocked `RwLock` containing `val`
+    pub const fn new(val: T) -> Self {
+        Self {
+            state: AtomicCell::new(0),
+            data: UnsafeCell::new(val),
+        }
+    }
+
+    /// Consumes the lock and returns the contained value
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+}
+
+impl<T: ?Sized, const IRQ_SAVE: bool> RwLock<T, IRQ_SAVE> {
+    /// Applies `f` to the state until it returns `None` or the update succeeds, returning whether it succeeded
+    fn try_update(&self, ord: Ordering, mut f: impl FnMut(usize) -> Option<usize>) -> bool {
+        let mut state = self.state.load(Ordering::Relaxed);
+        while let Some(new) = f(state) {
+            match self
+                .state
+                .compare_exchange_weak(state, new, ord, Ordering::Relaxed)
+            {
+                Ok(_) => return true,
+                Err(cur) => state = cur,
+            }
+        }
+        false
+    }
+
+    /// Applies `f` to the state, spinning while it returns `None`
+    fn update(&self, ord: Ordering, mut f: impl FnMut(usize) -> Option<usize>) {
+        while !self.try_update(ord, &mut f) {
+            spin_loop();
+        }
+    }
+
+    /// Acquires the lock exclusively with `try_acquire`, spinning until it succeeds.
+    ///
+    /// While spinning, `WRITER_WAITING` is set so that readers that haven't yet acquired the lock are held off
+    fn update_exclusive(&self, try_acquire: impl Fn(usize) -> Option<usize>) {
+        self.update(Ordering::Acquire, |state| {
+            if let Some(new) = try_acquire(state) {
+                Some(new)
+            } else if state & WRITER_WAITING == 0 {
+                // Announce ourselves, then keep trying. The announcement doesn't acquire anything, so a stale read is harmless
+                self.state
+                    .compare_exchange_weak(
+                        state,
+                        state | WRITER_WAITING,
+                        Ordering::Relaxed,
+                        Ordering::Relaxed,
+                    )
+                    .ok();
+                None
+            } else {
+                None
+            }
+        });
+    }
+
+    fn try_acquire_read(state: usize) -> Option<usize> {
+        if state & (WRITER | WRITER_WAITING) == 0 {
+            Some(state + READER)
+        } else {
+            None
+        }
+    }
+
+    fn try_acquire_upgradable(state: usize) -> Option<usize> {
+        if state & (WRITER | WRITER_WAITING | UPGRADABLE) == 0 {
+            Some(state | UPGRADABLE)
+        } else {
+            None
+        }
+    }
+
+    fn try_acquire_write(state: usize) -> Option<usize> {
+        if state & !WRITER_WAITING == 0 {
+            Some(WRITER)
+        } else {
+            None
+        }
+    }
+
+    /// Acquires a shared read lock, spinning while a writer holds the lock or is waiting for it
+    pub fn read(&self) -> RwLockReadGuard<'_, T, IRQ_SAVE> {
+        let irqs_enabled = irq_enter::<IRQ_SAVE>();
+        self.update(Ordering::Acquire, Self::try_acquire_read);
+        RwLockReadGuard {
+            lock: self,
+            irqs_enabled,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Attempts to acquire a shared read lock without spinning
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T, IRQ_SAVE>> {
+        let irqs_enabled = irq_enter::<IRQ_SAVE>();
+        if self.try_update(Ordering::Acquire, Self::try_acquire_read) {
+            Some(RwLockReadGuard {
+                lock: self,
+                irqs_enabled,
+                _not_send: PhantomData,
+            })
+        } else {
+            // SAFETY: From `irq_enter` above
+            unsafe { irq_leave(irqs_enabled) }
+            None
+        }
+    }
+
+    /// Acquires an upgradable read lock, spinning while a writer or another upgradable reader holds the lock, or a writer is waiting for it
+    pub fn upgradable_read(&self) -> RwLockUpgradableGuard<'_, T, IRQ_SAVE> {
+        let irqs_enabled = irq_enter::<IRQ_SAVE>();
+        self.update(Ordering::Acquire, Self::try_acquire_upgradable);
+        RwLockUpgradableGuard {
+            lock: self,
+            irqs_enabled,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Attempts to acquire an upgradable read lock without spinning
+    pub fn try_upgradable_read(&self) -> Option<RwLockUpgradableGuard<'_, T, IRQ_SAVE>> {
+        let irqs_enabled = irq_enter::<IRQ_SAVE>();
+        if self.try_update(Ordering::Acquire, Self::try_acquire_upgradable) {
+            Some(RwLockUpgradableGuard {
+                lock: self,
+                irqs_enabled,
+                _not_send: PhantomData,
+            })
+        } else {
+            // SAFETY: From `irq_enter` above
+            unsafe { irq_leave(irqs_enabled) }
+            None
+        }
+    }
+
+    /// Acquires an exclusive write lock, spinning until every other guard is released.
+    ///
+    /// While spinning, readers that haven't yet acquired the lock are held off
+    pub fn write(&self) -> RwLockWriteGuard<'_, T, IRQ_SAVE> {
+        let irqs_enabled = irq_enter::<IRQ_SAVE>();
+        self.update_exclusive(Self::try_acquire_write);
+        RwLockWriteGuard {
+            lock: self,
+            irqs_enabled,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Attempts to acquire an exclusive write lock without spinning
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T, IRQ_SAVE>> {
+        let irqs_enabled = irq_enter::<IRQ_SAVE>();
+        if self.try_update(Ordering::Acquire, Self::try_acquire_write) {
+            Some(RwLockWriteGuard {
+                lock: self,
+                irqs_enabled,
+                _not_send: PhantomData,
+            })
+        } else {
+            // SAFETY: From `irq_enter` above
+            unsafe { irq_leave(irqs_enabled) }
+            None
+        }
+    }
+
+    /// Checks whether a writer currently holds the lock.
+    ///
+    /// The result is immediately stale, so it's only useful for diagnostics
+    pub fn is_locked_exclusive(&self) -> bool {
+        self.state.load(Ordering::Relaxed) & WRITER != 0
+    }
+
+    /// Returns the number of readers (including an upgradable reader) currently holding the lock.
+    ///
+    /// The result is immediately stale, so it's only useful for diagnostics
+    pub fn reader_count(&self) -> usize {
+        let state = self.state.load(Ordering::Relaxed);
+        state / READER + ((state & UPGRADABLE) != 0) as usize
+    }
+
+    /// Returns a mutable reference to the contained value.
+    ///
+    /// This doesn't lock, since the mutable borrow statically guarantees exclusive access
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default, const IRQ_SAVE: bool> Default for RwLock<T, IRQ_SAVE> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T, const IRQ_SAVE: bool> From<T> for RwLock<T, IRQ_SAVE> {
+    fn from(val: T) -> Self {
+        Self::new(val)
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, const IRQ_SAVE: bool> fmt::Debug for RwLock<T, IRQ_SAVE> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_struct("RwLock");
+        match self.try_read() {
+            Some(guard) => s.field("data", &&*guard),
+            None => s.field("data", &format_args!("<locked>")),
+        };
+        s.finish_non_exhaustive()
+    }
+}
+
+/// An RAII guard for a shared read lock on a [`RwLock`]
+pub struct RwLockReadGuard<'a, T: ?Sized, const IRQ_SAVE: bool = false> {
+    lock: &'a RwLock<T, IRQ_SAVE>,
+    irqs_enabled: bool,
+    _not_send: PhantomData<*mut ()>,
+}
+
+unsafe impl<T: ?Sized + Sync, const IRQ_SAVE: bool> Sync for RwLockReadGuard<'_, T, IRQ_SAVE> {}
+
+impl<T: ?Sized, const IRQ_SAVE: bool> Deref for RwLockReadGuard<'_, T, IRQ_SAVE> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: We hold a read lock
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized, const IRQ_SAVE: bool> Drop for RwLockReadGuard<'_, T, IRQ_SAVE> {
+    fn drop(&mut self) {
+        self.lock
+            .update(Ordering::Release, |state| Some(state - READER));
+        // SAFETY: From acquiring the lock
+        unsafe { irq_leave(self.irqs_enabled) }
+    }
+}
+
+/// An RAII guard for an upgradable read lock on a [`RwLock`].
+///
+/// It only gives shared access, but can be turned into a [`RwLockWriteGuard`] with [`RwLockUpgradableGuard::upgrade`]
+pub struct RwLockUpgradableGuard<'a, T: ?Sized, const IRQ_SAVE: bool = false> {
+    lock: &'a RwLock<T, IRQ_SAVE>,
+    irqs_enabled: bool,
+    _not_send: PhantomData<*mut ()>,
+}
+
+unsafe impl<T: ?Sized + Sync, const IRQ_SAVE: bool> Sync
+    for RwLockUpgradableGuard<'_, T, IRQ_SAVE>
+{
+}
+
+impl<'a, T: ?Sized, const IRQ_SAVE: bool> RwLockUpgradableGuard<'a, T, IRQ_SAVE> {
+    fn try_acquire_upgrade(state: usize) -> Option<usize> {
+        // Like `try_acquire_write`, this clears `WRITER_WAITING`, which may have been set by this upgrade. Any other waiting writer sets it again
+        if state & !(UPGRADABLE | WRITER_WAITING) == 0 {
+            Some(WRITER)
+        } else {
+            None
+        }
+    }
+
+    /// Upgrades to a write lock, spinning until the remaining readers leave.
+    ///
+    /// No writer can acquire the lock in between, so anything read through this guard is still current once the write guard is returned.
+    /// While spinning, readers that haven't yet acquired the lock are held off, as they are by [`RwLock::write`]
+    pub fn upgrade(self) -> RwLockWriteGuard<'a, T, IRQ_SAVE> {
+        let this = ManuallyDrop::new(self);
+        this.lock.update_exclusive(Self::try_acquire_upgrade);
+        RwLockWriteGuard {
+            lock: this.lock,
+            irqs_enabled: this.irqs_enabled,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Attempts to upgrade to a write lock without spinning, giving back the upgradable guard if there are still readers
+    pub fn try_upgrade(self) -> Result<RwLockWriteGuard<'a, T, IRQ_SAVE>, Self> {
+        if self
+            .lock
+            .try_update(Ordering::Acquire, Self::try_acquire_upgrade)
+        {
+            let this = ManuallyDrop::new(self);
+            Ok(RwLockWriteGuard {
+                lock: this.lock,
+                irqs_enabled: this.irqs_enabled,
+                _not_send: PhantomData,
+            })
+        } else {
+            Err(self)
+        }
+    }
+
+    /// Downgrades to a plain read lock, letting another upgradable reader in
+    pub fn downgrade(self) -> RwLockReadGuard<'a, T, IRQ_SAVE> {
+        let this = ManuallyDrop::new(self);
+        this.lock.update(Ordering::Relaxed, |state| {
+            Some((state & !UPGRADABLE) + READER)
+        });
+        RwLockReadGuard {
+            lock: this.lock,
+            irqs_enabled: this.irqs_enabled,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized, const IRQ_SAVE: bool> Deref for RwLockUpgradableGuard<'_, T, IRQ_SAVE> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: We hold an upgradable read lock, which excludes writers
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized, const IRQ_SAVE: bool> Drop for RwLockUpgradableGuard<'_, T, IRQ_SAVE> {
+    fn drop(&mut self) {
+        self.lock
+            .update(Ordering::Release, |state| Some(state & !UPGRADABLE));
+        // SAFETY: From acquiring the lock
+        unsafe { irq_leave(self.irqs_enabled) }
+    }
+}
+
+/// An RAII guard for an exclusive write lock on a [`RwLock`]
+pub struct RwLockWriteGuard<'a, T: ?Sized, const IRQ_SAVE: bool = false> {
+    lock: &'a RwLock<T, IRQ_SAVE>,
+    irqs_enabled: bool,
+    _not_send: PhantomData<*mut ()>,
+}
+
+unsafe impl<T: ?Sized + Sync, const IRQ_SAVE: bool> Sync for RwLockWriteGuard<'_, T, IRQ_SAVE> {}
+
+impl<'a, T: ?Sized, const IRQ_SAVE: bool> RwLockWriteGuard<'a, T, IRQ_SAVE> {
+    /// Downgrades to a read lock, letting other readers in without giving writers a chance to acquire the lock in between
+    pub fn downgrade(self) -> RwLockReadGuard<'a, T, IRQ_SAVE> {
+        let this = ManuallyDrop::new(self);
+        this.lock
+            .update(Ordering::Release, |state| Some((state & !WRITER) + READER));
+        RwLockReadGuard {
+            lock: this.lock,
+            irqs_enabled: this.irqs_enabled,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Downgrades to an upgradable read lock, letting other readers in without giving writers a chance to acquire the lock in between
+    pub fn downgrade_to_upgradable(self) -> RwLockUpgradableGuard<'a, T, IRQ_SAVE> {
+        let this = ManuallyDrop::new(self);
+        this.lock.update(Ordering::Release, |state| {
+            Some((state & !WRITER) | UPGRADABLE)
+        });
+        RwLockUpgradableGuard {
+            lock: this.lock,
+            irqs_enabled: this.irqs_enabled,
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized, const IRQ_SAVE: bool> Deref for RwLockWriteGuard<'_, T, IRQ_SAVE> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: We hold the write lock
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized, const IRQ_SAVE: bool> DerefMut for RwLockWriteGuard<'_, T, IRQ_SAVE> {
+    fn deref_mut(&mut self) -> &mut T {
+        // SAFETY: We hold the write lock
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized, const IRQ_SAVE: bool> Drop for RwLockWriteGuard<'_, T, IRQ_SAVE> {
+    fn drop(&mut self) {
+        // Another writer may have started waiting since we acquired the lock, so only our own bit is cleared
+        self.lock
+            .update(Ordering::Release, |state| Some(state & !WRITER));
+        // SAFETY: From acquiring the lock
+        unsafe { irq_leave(self.irqs_enabled) }
+    }
+}
+
+macro_rules! impl_guard_fmt {
+    ($($guard:ident),* $(,)?) => {
+        $(
+            impl<T: ?Sized + fmt::Debug, const IRQ_SAVE: bool> fmt::Debug for $guard<'_, T, IRQ_SAVE> {
+                fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                    T::fmt(self, f)
+                }
+            }
+
+            impl<T: ?Sized + fmt::Display, const IRQ_SAVE: bool> fmt::Display for $guard<'_, T, IRQ_SAVE> {
+                fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                    T::fmt(self, f)
+                }
+            }
+        )*
+    };
+}
+
+impl_guard_fmt!(RwLockReadGuard, RwLockUpgradableGuard, RwLockWriteGuard);
+
+#[cfg(all(test, feature = "std", not(loom)))]
+mod test {
+    extern crate std as host;
+
+    use alloc::sync::Arc;
+    use host::time::{Duration, Instant};
+
+    use super::RwLock;
+
+    #[test]
+    fn readers_share_and_writers_exclude() {
+        let lock = RwLock::<u32>::new(0);
+        let first = lock.read();
+        let second = lock.try_read().unwrap();
+        assert_eq!(lock.reader_count(), 2);
+        assert!(lock.try_write().is_none());
+        drop((first, second));
+
+        let mut writer = lock.write();
+        *writer = 1;
+        assert!(lock.is_locked_exclusive());
+        assert!(lock.try_read().is_none());
+        assert!(lock.try_upgradable_read().is_none());
+        drop(writer);
+        assert_eq!(*lock.read(), 1);
+    }
+
+    #[test]
+    fn one_upgradable_reader_at_a_time() {
+        let lock = RwLock::<u32>::new(0);
+        let upgradable = lock.upgradable_read();
+        assert!(lock.try_upgradable_read().is_none());
+        let reader = lock.read();
+        assert_eq!(lock.reader_count(), 2);
+        // The plain reader still holds the lock
+        let upgradable = upgradable.try_upgrade().unwrap_err();
+        drop(reader);
+        let mut writer = upgradable.try_upgrade().unwrap();
+        *writer = 1;
+        let reader = writer.downgrade();
+        assert_eq!(*reader, 1);
+        assert!(lock.try_upgradable_read().is_some());
+    }
+
+    #[test]
+    fn upgrade_holds_off_new_readers() {
+        let lock = Arc::new(RwLock::<u32>::new(0));
+        let (held, wait_held) = host::sync::mpsc::channel();
+        let reader = {
+            let lock = lock.clone();
+            host::thread::spawn(move || {
+                let guard = lock.read();
+                held.send(()).unwrap();
+                // Once the upgrade is waiting, new readers must wait behind it
+                let deadline = Instant::now() + Duration::from_secs(10);
+                let held_off = loop {
+                    if lock.try_read().is_none() {
+                        break true;
+                    } else if Instant::now() > deadline {
+                        break false;
+                    }
+                };
+                drop(guard);
+                held_off
+            })
+        };
+
+        wait_held.recv().unwrap();
+        let mut writer = lock.upgradable_read().upgrade();
+        *writer = 1;
+        drop(writer);
+        assert!(reader.join().unwrap());
+        // The upgrade's announcement doesn't outlive it
+        assert_eq!(*lock.try_read().unwrap(), 1);
+    }
+
+    #[test]
+    fn write_excludes_threads() {
+        let lock = Arc::new(RwLock::<u64>::new(0));
+        let threads = (0..4)
+            .map(|i| {
+                let lock = lock.clone();
+                host::thread::spawn(move || {
+                    for _ in 0..10_000 {
+                        if i % 2 == 0 {
+                            *lock.write() += 1;
+                        } else {
+                            *lock.upgradable_read().upgrade() += 1;
+                        }
+                        drop(lock.read());
+                    }
+                })
+            })
+            .collect::<alloc::vec::Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(*lock.read(), 40_000);
+    }
+}
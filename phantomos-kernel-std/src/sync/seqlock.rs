@@ -0,0 +1,353 @@
+use core::{
+    cell::UnsafeCell,
+    fmt,
+    hint::spin_loop,
+    marker::PhantomData,
+    mem::MaybeUninit,
+    ops::{Deref, DerefMut},
+    ptr,
+};
+
+use super::{
+    atomic::{fence, AtomicU8, AtomicUsize, Ordering},
+    lock::{disable_interrupts, enable_interrupts},
+};
+
+/// Copies the `T` at `src` with relaxed atomic loads, one word at a time if `T` is aligned to a word and one byte at a time otherwise.
+///
+/// Together with [`atomic_store`], this lets the optimistic reads of a [`RawSeqLock`] race with a writer without a data race.
+/// The copy may still be torn, so it's returned as `MaybeUninit`, and must be validated before it's interpreted as a `T`
+///
+/// # Safety
+/// `src` must be valid for reads and aligned, and may only be written concurrently by [`atomic_store`]
+pub(super) unsafe fn atomic_load<T>(src: *const T) -> MaybeUninit<T> {
+    let mut val = MaybeUninit::<T>::uninit();
+    if align_of::<T>() >= align_of::<AtomicUsize>() {
+        let (src, dst) = (src.cast::<AtomicUsize>(), val.as_mut_ptr().cast::<usize>());
+        for i in 0..size_of::<T>() / size_of::<usize>() {
+            // SAFETY: `T` is a whole number of words, since its size is a multiple of its alignment
+            unsafe { dst.add(i).write((*src.add(i)).load(Ordering::Relaxed)) }
+        }
+    } else {
+        let (src, dst) = (src.cast::<AtomicU8>(), val.as_mut_ptr().cast::<u8>());
+        for i in 0..size_of::<T>() {
+            // SAFETY: `i` is within the `T`
+            unsafe { dst.add(i).write((*src.add(i)).load(Ordering::Relaxed)) }
+        }
+    }
+    val
+}
+
+/// Writes `val` to `dst` with relaxed atomic stores, in the same units as [`atomic_load`]
+///
+/// # Safety
+/// `dst` must be valid for writes and aligned, and the write lock of the [`RawSeqLock`] protecting it must be held
+pub(super) unsafe fn atomic_store<T>(dst: *mut T, val: T) {
+    if align_of::<T>() >= align_of::<AtomicUsize>() {
+        let (src, dst) = (
+            ptr::addr_of!(val).cast::<usize>(),
+            dst.cast::<AtomicUsize>(),
+        );
+        for i in 0..size_of::<T>() / size_of::<usize>() {
+            // SAFETY: `T` is a whole number of words, since its size is a multiple of its alignment
+            unsafe { (*dst.add(i)).store(src.add(i).read(), Ordering::Relaxed) }
+        }
+    } else {
+        let (src, dst) = (ptr::addr_of!(val).cast::<u8>(), dst.cast::<AtomicU8>());
+        for i in 0..size_of::<T>() {
+            // SAFETY: `i` is within the `T`
+            unsafe { (*dst.add(i)).store(src.add(i).read(), Ordering::Relaxed) }
+        }
+    }
+}
+
+/// The sequence counter of a [`SeqLock`], without the data it protects.
+///
+/// It is also used to protect values of [`AtomicCell`](super::atomic::AtomicCell) that can't use a native atomic
+pub(super) struct RawSeqLock {
+    // Odd while a writer holds the lock
+    seq: AtomicUsize,
+}
+
+impl RawSeqLock {
+    pub(super) const fn new() -> Self {
+        Self {
+            seq: AtomicUsize::new(0),
+        }
+    }
+
+    /// Starts an optimistic read, returning the sequence number to validate it against, or `None` if a writer is active
+    pub(super) fn read_begin(&self) -> Option<usize> {
+        let seq = self.seq.load(Ordering::Acquire);
+        if seq & 1 == 0 {
+            Some(seq)
+        } else {
+            None
+        }
+    }
+
+    /// Checks that no writer was active since [`RawSeqLock::read_begin`] returned `seq`, so that the data read in between is consistent
+    pub(super) fn read_validate(&self, seq: usize) -> bool {
+        fence(Ordering::Acquire);
+        self.seq.load(Ordering::Relaxed) == seq
+    }
+
+    /// Acquires the write lock, spinning while another writer holds it, and returns the sequence number to release it with
+    pub(super) fn write_lock(&self) -> usize {
+        let mut seq = self.seq.load(Ordering::Relaxed);
+        loop {
+            if seq & 1 != 0 {
+                spin_loop();
+                seq = self.seq.load(Ordering::Relaxed);
+            } else {
+                match self.seq.compare_exchange_weak(
+                    seq,
+                    seq.wrapping_add(1),
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => break,
+                    Err(cur) => seq = cur,
+                }
+            }
+        }
+        // Keeps the writes to the data from becoming visible before the sequence number is odd
+        fence(Ordering::Release);
+        seq
+    }
+
+    /// Releases the write lock, invalidating concurrent optimistic reads
+    ///
+    /// # Safety
+    /// The write lock must be held, and `seq` must be the value returned when it was acquired
+    pub(super) unsafe fn write_unlock(&self, seq: usize) {
+        self.seq.store(seq.wrapping_add(2), Ordering::Release);
+    }
+
+    /// Releases the write lock without invalidating concurrent optimistic reads, for when nothing was written
+    ///
+    /// # Safety
+    /// The write lock must be held, and `seq` must be the value returned when it was acquired. Nothing may have been written while it was held
+    pub(super) unsafe fn write_abort(&self, seq: usize) {
+        self.seq.store(seq, Ordering::Release);
+    }
+}
+
+/// A sequence lock protecting a `T: Copy`, for small values that are read far more often than they're written.
+///
+/// Readers never write to the lock: they copy the value out, and retry if a writer was active during the copy,
+///  so readers don't contend with each other at all. Writers are serialized with each other, and never wait for readers.
+///
+/// A reader spins while a writer holds the lock, so a reader in an interrupt handler must not interrupt a writer on the same processor.
+/// If `IRQ_SAVE` is set (see [`IrqSeqLock`]), writers disable interrupts on the current processor while they hold the lock
+pub struct SeqLock<T: Copy, const IRQ_SAVE: bool = false> {
+    seq: RawSeqLock,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Copy + Send, const IRQ_SAVE: bool> Send for SeqLock<T, IRQ_SAVE> {}
+unsafe impl<T: Copy + Send, const IRQ_SAVE: bool> Sync for SeqLock<T, IRQ_SAVE> {}
+
+/// A [`SeqLock`] whose writers disable interrupts while they hold it
+pub type IrqSeqLock<T> = SeqLock<T, true>;
+
+impl<T: Copy, const IRQ_SAVE: bool> SeqLock<T, IRQ_SAVE> {
+    /// Creates a new `SeqLock` containing `val`
+    pub const fn new(val: T) -> Self {
+        Self {
+            seq: RawSeqLock::new(),
+            data: UnsafeCell::new(val),
+        }
+    }
+
+    /// Consumes the lock and returns the contained value
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+
+    /// Returns a mutable reference to the contained value.
+    ///
+    /// This doesn't lock, since the mutable borrow statically guarantees exclusive access
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// Attempts to read the value without spinning, returning `None` if a writer was active
+    pub fn try_read(&self) -> Option<T> {
+        let seq = self.seq.read_begin()?;
+
+        // SAFETY:
+        // Writers only write the value with `atomic_store`. The copy may be torn, but if the sequence number is unchanged afterwards, no writer was active
+        let val = unsafe { atomic_load(self.data.get()) };
+
+        if self.seq.read_validate(seq) {
+            // SAFETY: No writer was active during the copy, so it's a copy of a valid `T`
+            Some(unsafe { val.assume_init() })
+        } else {
+            None
+        }
+    }
+
+    /// Reads the value, spinning while a writer is active
+    pub fn read(&self) -> T {
+        loop {
+            if let Some(val) = self.try_read() {
+                return val;
+            }
+            spin_loop();
+        }
+    }
+
+    /// Acquires the write lock, spinning while another writer holds it.
+    ///
+    /// The guard gives access to a copy of the value, which is written back when it is dropped.
+    /// Readers retry until then, so it should be held briefly
+    pub fn write(&self) -> SeqLockWriteGuard<'_, T, IRQ_SAVE> {
+        let irqs_enabled = IRQ_SAVE && disable_interrupts();
+        let seq = self.seq.write_lock();
+        // SAFETY: We hold the write lock, so nothing else writes the value
+        let val = unsafe { atomic_load(self.data.get()).assume_init() };
+        SeqLockWriteGuard {
+            lock: self,
+            seq,
+            val,
+            irqs_enabled,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Replaces the value
+    pub fn store(&self, val: T) {
+        *self.write() = val;
+    }
+}
+
+impl<T: Copy + Default, const IRQ_SAVE: bool> Default for SeqLock<T, IRQ_SAVE> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+impl<T: Copy, const IRQ_SAVE: bool> From<T> for SeqLock<T, IRQ_SAVE> {
+    fn from(val: T) -> Self {
+        Self::new(val)
+    }
+}
+
+impl<T: Copy + fmt::Debug, const IRQ_SAVE: bool> fmt::Debug for SeqLock<T, IRQ_SAVE> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut s = f.debug_struct("SeqLock");
+        match self.try_read() {
+            Some(val) => s.field("data", &val),
+            None => s.field("data", &format_args!("<locked>")),
+        };
+        s.finish_non_exhaustive()
+    }
+}
+
+/// An RAII guard for the write lock of a [`SeqLock`], which publishes the new value when dropped
+pub struct SeqLockWriteGuard<'a, T: Copy, const IRQ_SAVE: bool = false> {
+    lock: &'a SeqLock<T, IRQ_SAVE>,
+    // Returned from `RawSeqLock::write_lock`
+    seq: usize,
+    // Readers copy the value while it's being written, so it's only written with `atomic_store`, when the guard is dropped
+    val: T,
+    irqs_enabled: bool,
+    _not_send: PhantomData<*mut ()>,
+}
+
+unsafe impl<T: Copy + Sync, const IRQ_SAVE: bool> Sync for SeqLockWriteGuard<'_, T, IRQ_SAVE> {}
+
+impl<T: Copy, const IRQ_SAVE: bool> Deref for SeqLockWriteGuard<'_, T, IRQ_SAVE> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.val
+    }
+}
+
+impl<T: Copy, const IRQ_SAVE: bool> DerefMut for SeqLockWriteGuard<'_, T, IRQ_SAVE> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.val
+    }
+}
+
+impl<T: Copy, const IRQ_SAVE: bool> Drop for SeqLockWriteGuard<'_, T, IRQ_SAVE> {
+    fn drop(&mut self) {
+        // SAFETY: We hold the write lock, which returned `self.seq`
+        unsafe {
+            atomic_store(self.lock.data.get(), self.val);
+            self.lock.seq.write_unlock(self.seq);
+        }
+        if self.irqs_enabled {
+            // SAFETY: Interrupts were enabled when the lock was acquired
+            unsafe { enable_interrupts() }
+        }
+    }
+}
+
+impl<T: Copy + fmt::Debug, const IRQ_SAVE: bool> fmt::Debug for SeqLockWriteGuard<'_, T, IRQ_SAVE> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        T::fmt(self, f)
+    }
+}
+
+#[cfg(all(test, feature = "std", not(loom)))]
+mod test {
+    extern crate std as host;
+
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use core::sync::atomic::{AtomicBool, Ordering};
+
+    use super::SeqLock;
+
+    #[test]
+    fn write_guard_publishes_on_drop() {
+        let lock = SeqLock::<[u32; 3]>::new([1, 2, 3]);
+        let mut guard = lock.write();
+        assert_eq!(*guard, [1, 2, 3]);
+        guard[1] = 5;
+        assert!(lock.try_read().is_none());
+        drop(guard);
+        assert_eq!(lock.read(), [1, 5, 3]);
+        lock.store([0; 3]);
+        assert_eq!(lock.try_read(), Some([0; 3]));
+    }
+
+    /// Has readers check that they never see a value that a writer is halfway through replacing
+    fn check_reads_are_consistent<T: Copy + Send + Sync + 'static>(
+        make: fn(u8) -> T,
+        consistent: fn(&T) -> bool,
+    ) {
+        let lock = Arc::new(SeqLock::<T>::new(make(0)));
+        let done = Arc::new(AtomicBool::new(false));
+        let readers = (0..3)
+            .map(|_| {
+                let (lock, done) = (lock.clone(), done.clone());
+                host::thread::spawn(move || {
+                    while !done.load(Ordering::Relaxed) {
+                        assert!(consistent(&lock.read()));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for i in 0..20_000u32 {
+            lock.store(make(i as u8));
+        }
+        done.store(true, Ordering::Relaxed);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn reads_are_consistent_by_word() {
+        check_reads_are_consistent(|i| [u64::from(i); 4], |v| v.iter().all(|&x| x == v[0]));
+    }
+
+    #[test]
+    fn reads_are_consistent_by_byte() {
+        check_reads_are_consistent(|i| [i; 13], |v| v.iter().all(|&x| x == v[0]));
+    }
+}
@@ -0,0 +1,444 @@
+//! Minimal support for running futures to completion without an async runtime
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::task::Wake;
+use alloc::vec::Vec;
+use core::future::Future;
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+
+use crate::io;
+
+/// A hook for blocking the thread that runs an executor until one of its futures is woken.
+///
+/// Implementations follow token semantics: [`Park::unpark`] makes a token available,
+/// and [`Park::park`] consumes it, returning immediately if one is already available.
+/// This ensures that a wakeup that races with a call to `park` is never lost.
+///
+/// In the kernel this is backed by the thread-blocking primitive of `ThreadHandle`, and in the hosted build by host threads.
+pub trait Park: Send + Sync + 'static {
+    /// The error returned when parking is cut short other than by [`Park::unpark`], such as when the thread is interrupted
+    type Error;
+
+    /// Blocks the current thread until a token is available, then consumes it.
+    ///
+    /// May return spuriously, without a token.
+    fn park(&self) -> Result<(), Self::Error>;
+
+    /// Makes a token available, waking the parked thread if any.
+    ///
+    /// This may be called from any thread, including from an interrupt handler, so it must not block or allocate.
+    fn unpark(&self);
+}
+
+struct TaskWaker<P> {
+    woken: AtomicBool,
+    parker: P,
+}
+
+impl<P: Park> Wake for TaskWaker<P> {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref()
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::Release);
+        self.parker.unpark();
+    }
+}
+
+/// Runs `fut` to completion on the current thread, parking it with `parker` whenever `fut` is pending.
+///
+/// Returns an error if parking fails, in which case `fut` is dropped before completion.
+pub fn block_on<F: Future, P: Park>(parker: P, fut: F) -> Result<F::Output, P::Error> {
+    let mut fut = core::pin::pin!(fut);
+    let waker = Arc::new(TaskWaker {
+        woken: AtomicBool::new(false),
+        parker,
+    });
+    let cx_waker = Waker::from(waker.clone());
+    let mut cx = Context::from_waker(&cx_waker);
+
+    loop {
+        if let Poll::Ready(val) = fut.as_mut().poll(&mut cx) {
+            break Ok(val);
+        }
+        while !waker.woken.swap(false, Ordering::Acquire) {
+            waker.parker.park()?;
+        }
+    }
+}
+
+struct Task<'a, P> {
+    fut: Pin<Box<dyn Future<Output = ()> + 'a>>,
+    waker: Arc<TaskWaker<P>>,
+}
+
+/// A single-threaded executor, which polls each of its tasks on the current CPU when they are woken.
+///
+/// Tasks are polled in the order they were spawned. There is no preemption, so a task that never returns
+/// [`Poll::Pending`] starves the others.
+pub struct Executor<'a, P: Park + Clone> {
+    parker: P,
+    tasks: Vec<Task<'a, P>>,
+}
+
+impl<'a, P: Park + Clone> Executor<'a, P> {
+    /// Creates an executor with no tasks, which parks using `parker` when no task can make progress
+    pub const fn new(parker: P) -> Self {
+        Self {
+            parker,
+            tasks: Vec::new(),
+        }
+    }
+
+    /// Adds a task to the executor. It is first polled by the next call to [`Executor::run`].
+    pub fn spawn<F: Future<Output = ()> + 'a>(&mut self, fut: F) {
+        self.tasks.push(Task {
+            fut: Box::pin(fut),
+            waker: Arc::new(TaskWaker {
+                woken: AtomicBool::new(true),
+                parker: self.parker.clone(),
+            }),
+        });
+    }
+
+    /// Returns the number of tasks which have not yet completed
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// Polls every task that has been woken, once, and returns whether any task was polled
+    pub fn run_once(&mut self) -> bool {
+        let mut polled = false;
+        self.tasks.retain_mut(|task| {
+            if !task.waker.woken.swap(false, Ordering::Acquire) {
+                return true;
+            }
+            polled = true;
+            let waker = Waker::from(task.waker.clone());
+            task.fut
+                .as_mut()
+                .poll(&mut Context::from_waker(&waker))
+                .is_pending()
+        });
+        polled
+    }
+
+    /// Runs tasks until all of them have completed, parking whenever none of them can make progress.
+    ///
+    /// If parking fails, the error is returned and the remaining tasks are kept, so `run` can be called again.
+    pub fn run(&mut self) -> Result<(), P::Error> {
+        while !self.tasks.is_empty() {
+            if !self.run_once() {
+                self.parker.park()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Adapts an asynchronous stream to the blocking [`io`] traits, by running each operation with [`block_on`]
+pub struct Blocking<T, P> {
+    inner: T,
+    parker: P,
+}
+
+impl<T, P> Blocking<T, P> {
+    pub const fn new(inner: T, parker: P) -> Self {
+        Self { inner, parker }
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: io::AsyncRead + Unpin, P: Park + Clone> io::Read for Blocking<T, P>
+where
+    io::Error: From<P::Error>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        block_on(
+            self.parker.clone(),
+            io::AsyncReadExt::read(&mut self.inner, buf),
+        )?
+    }
+}
+
+impl<T: io::AsyncWrite + Unpin, P: Park + Clone> io::Write for Blocking<T, P>
+where
+    io::Error: From<P::Error>,
+{
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        block_on(
+            self.parker.clone(),
+            io::AsyncWriteExt::write(&mut self.inner, buf),
+        )?
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        block_on(
+            self.parker.clone(),
+            io::AsyncWriteExt::flush(&mut self.inner),
+        )?
+    }
+}
+
+impl<T: io::AsyncSeek + Unpin, P: Park + Clone> io::Seek for Blocking<T, P>
+where
+    io::Error: From<P::Error>,
+{
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u128> {
+        block_on(
+            self.parker.clone(),
+            io::AsyncSeekExt::seek(&mut self.inner, pos),
+        )?
+    }
+}
+
+#[cfg(all(test, feature = "std", not(loom)))]
+mod test {
+    extern crate std as host;
+
+    use alloc::rc::Rc;
+    use alloc::sync::Arc;
+    use alloc::vec::Vec;
+    use core::cell::{Cell, RefCell};
+    use core::future::{pending, poll_fn};
+    use core::pin::Pin;
+    use core::sync::atomic::Ordering;
+    use core::task::{Context, Poll, Waker};
+
+    use super::{block_on, Blocking, Executor, Park};
+    use crate::hosted::HostParker;
+    use crate::io::{self, AsyncRead, Read};
+    use crate::sync::atomic::AtomicFlag;
+
+    /// A parker with the same token and interrupt flag semantics as the kernel's `ThreadParker`
+    #[derive(Clone)]
+    struct FlagParker(Arc<(AtomicFlag, AtomicFlag)>);
+
+    #[derive(Debug, PartialEq)]
+    struct Interrupted;
+
+    impl FlagParker {
+        fn new() -> Self {
+            Self(Arc::new((AtomicFlag::new(), AtomicFlag::new())))
+        }
+
+        fn interrupt(&self) {
+            self.0 .1.set(Ordering::Release);
+        }
+    }
+
+    impl Park for FlagParker {
+        type Error = Interrupted;
+
+        fn park(&self) -> Result<(), Interrupted> {
+            let (token, interrupted) = &*self.0;
+            if token.test_and_clear(Ordering::Acquire) {
+                return Ok(());
+            }
+            loop {
+                if interrupted.test_and_clear(Ordering::Acquire) {
+                    break Err(Interrupted);
+                }
+                if token.test_and_clear(Ordering::Acquire) {
+                    break Ok(());
+                }
+                core::hint::spin_loop();
+            }
+        }
+
+        fn unpark(&self) {
+            self.0 .0.set(Ordering::Release);
+        }
+    }
+
+    /// Returns `Pending` once, waking itself first
+    async fn yield_now() {
+        let mut yielded = false;
+        poll_fn(|cx| {
+            if core::mem::replace(&mut yielded, true) {
+                Poll::Ready(())
+            } else {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        })
+        .await
+    }
+
+    #[test]
+    fn block_on_ready() {
+        assert_eq!(block_on(HostParker::current(), async { 5 }), Ok(5));
+        assert_eq!(
+            block_on(HostParker::current(), async {
+                yield_now().await;
+                6
+            }),
+            Ok(6)
+        );
+    }
+
+    #[test]
+    fn block_on_woken_from_another_thread() {
+        let waker = Arc::new(host::sync::Mutex::new(None::<Waker>));
+        let done = Arc::new(AtomicFlag::new());
+
+        let fut = poll_fn(|cx| {
+            if done.test(Ordering::Acquire) {
+                return Poll::Ready(());
+            }
+            *waker.lock().unwrap() = Some(cx.waker().clone());
+            Poll::Pending
+        });
+
+        let waker2 = waker.clone();
+        let done2 = done.clone();
+        let thread = host::thread::spawn(move || loop {
+            if let Some(waker) = waker2.lock().unwrap().take() {
+                done2.set(Ordering::Release);
+                waker.wake();
+                break;
+            }
+            host::thread::yield_now();
+        });
+
+        assert_eq!(block_on(HostParker::current(), fut), Ok(()));
+        thread.join().unwrap();
+    }
+
+    #[test]
+    fn interrupt_stops_block_on() {
+        let parker = FlagParker::new();
+        parker.interrupt();
+        assert_eq!(block_on(parker.clone(), pending::<()>()), Err(Interrupted));
+
+        // The interruption is consumed, and a pending token still lets the future be polled again
+        parker.unpark();
+        assert_eq!(parker.park(), Ok(()));
+    }
+
+    #[test]
+    fn executor_polls_in_spawn_order() {
+        let log = RefCell::new(Vec::new());
+        let mut exec = Executor::new(HostParker::current());
+        for name in ['a', 'b'] {
+            let log = &log;
+            exec.spawn(async move {
+                for i in 0..3 {
+                    log.borrow_mut().push((name, i));
+                    yield_now().await;
+                }
+            });
+        }
+        assert_eq!(exec.len(), 2);
+        exec.run().unwrap();
+        assert!(exec.is_empty());
+        assert_eq!(
+            *log.borrow(),
+            [('a', 0), ('b', 0), ('a', 1), ('b', 1), ('a', 2), ('b', 2)]
+        );
+    }
+
+    #[test]
+    fn interrupted_executor_keeps_tasks() {
+        let parker = FlagParker::new();
+        let done = Rc::new(Cell::new(false));
+        let waker = Rc::new(RefCell::new(None::<Waker>));
+
+        let mut exec = Executor::new(parker.clone());
+        let (done2, waker2) = (done.clone(), waker.clone());
+        exec.spawn(poll_fn(move |cx| {
+            if done2.get() {
+                Poll::Ready(())
+            } else {
+                *waker2.borrow_mut() = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }));
+
+        parker.interrupt();
+        assert_eq!(exec.run(), Err(Interrupted));
+        assert_eq!(exec.len(), 1);
+
+        done.set(true);
+        waker.borrow_mut().take().unwrap().wake();
+        assert_eq!(exec.run(), Ok(()));
+        assert!(exec.is_empty());
+    }
+
+    /// Reads from a slice a few bytes at a time, returning `Pending` before each read
+    struct SlowReader<'a> {
+        data: &'a [u8],
+        ready: bool,
+    }
+
+    impl AsyncRead for SlowReader<'_> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context,
+            buf: &mut [u8],
+        ) -> Poll<io::Result<usize>> {
+            if !core::mem::replace(&mut self.ready, false) {
+                self.ready = true;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let len = buf.len().min(self.data.len()).min(3);
+            buf[..len].copy_from_slice(&self.data[..len]);
+            self.data = &self.data[len..];
+            Poll::Ready(Ok(len))
+        }
+    }
+
+    #[test]
+    fn blocking_adapts_async_read() {
+        let reader = SlowReader {
+            data: b"hello, world",
+            ready: false,
+        };
+        let mut blocking = Blocking::new(reader, HostParker::current());
+        let mut buf = [0; 12];
+        blocking.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello, world");
+        assert_eq!(blocking.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn future_is_dropped_on_interrupt() {
+        struct Dropped<'a>(&'a Cell<bool>);
+        impl Drop for Dropped<'_> {
+            fn drop(&mut self) {
+                self.0.set(true);
+            }
+        }
+
+        let dropped = Cell::new(false);
+        let parker = FlagParker::new();
+        parker.interrupt();
+        let guard = Dropped(&dropped);
+        let fut = async move {
+            let _guard = guard;
+            pending::<()>().await
+        };
+        assert_eq!(block_on(parker, fut), Err(Interrupted));
+        assert!(dropped.get());
+    }
+}
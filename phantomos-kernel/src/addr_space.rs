@@ -1,10 +1,83 @@
 use core::hash::{Hash, Hasher};
 
-use std::sync::atomic::{AtomicPtr, HasAtomic, HasAtomicLeast};
+use bytemuck::Pod;
+use core::ptr::NonNull;
+use std::collection::intrusive::IntrusivePointer;
+use std::collection::IntervalKey;
+use std::io;
+use std::sync::atomic::{AtomicPtr, HasAtomic, HasAtomicLeast, NoUninit};
 
+use crate::usercopy;
+
+/// A pointer into the user half of the address space, as passed to the kernel by user mode.
+///
+/// User mode may pass any address, so a `UserPtr` is never dereferenced directly. Its accessors check that the memory is in the user half,
+///  and report a fault as [`io::ErrorKind::BadAddress`] rather than panicking
 #[repr(transparent)]
 pub struct UserPtr<T: ?Sized>(*mut T);
 
+impl<T: ?Sized> UserPtr<T> {
+    pub fn from_user_addr(uaddr: *mut T) -> Self {
+        Self(uaddr)
+    }
+
+    pub fn into_user_addr(self) -> *mut T {
+        self.0
+    }
+}
+
+impl<T: Pod> UserPtr<T> {
+    /// Copies the value at this address out of user memory
+    ///
+    /// # Errors
+    /// Returns [`io::ErrorKind::BadAddress`] if the value isn't entirely in the user half of the address space, or can't be read
+    pub fn read(self) -> io::Result<T> {
+        let mut val = T::zeroed();
+        self.read_slice(core::slice::from_mut(&mut val))?;
+        Ok(val)
+    }
+
+    /// Copies `val` into user memory at this address
+    ///
+    /// # Errors
+    /// Returns [`io::ErrorKind::BadAddress`] if the value isn't entirely in the user half of the address space, or can't be written
+    pub fn write(self, val: T) -> io::Result<()> {
+        self.write_slice(core::slice::from_ref(&val))
+    }
+
+    /// Fills `buf` with the consecutive values starting at this address in user memory
+    ///
+    /// # Errors
+    /// Returns [`io::ErrorKind::BadAddress`] if the values aren't entirely in the user half of the address space, or can't be read
+    pub fn read_slice(self, buf: &mut [T]) -> io::Result<()> {
+        usercopy::copy_from_user(bytemuck::cast_slice_mut(buf), self.0.addr())
+    }
+
+    /// Copies `buf` into user memory as consecutive values starting at this address
+    ///
+    /// # Errors
+    /// Returns [`io::ErrorKind::BadAddress`] if the values aren't entirely in the user half of the address space, or can't be written.
+    /// Some of the values may have been written already
+    pub fn write_slice(self, buf: &[T]) -> io::Result<()> {
+        usercopy::copy_to_user(self.0.addr(), bytemuck::cast_slice(buf))
+    }
+}
+
+impl UserPtr<u8> {
+    /// Copies the NUL-terminated string at this address out of user memory into `buf`, and returns it without the NUL.
+    ///
+    /// At most `buf.len()` bytes are read, including the NUL
+    ///
+    /// # Errors
+    /// Returns [`io::ErrorKind::BadAddress`] if the string runs past the user half of the address space, or can't be read,
+    ///  [`io::ErrorKind::InvalidInput`] if it doesn't fit in `buf`, and [`io::ErrorKind::InvalidData`] if it isn't UTF-8
+    pub fn read_cstr(self, buf: &mut [u8]) -> io::Result<&str> {
+        let len = usercopy::copy_cstr_from_user(buf, self.0.addr())?;
+        core::str::from_utf8(&buf[..len])
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "string is not valid UTF-8"))
+    }
+}
+
 #[repr(transparent)]
 pub struct HandlePtr<T: ?Sized>(*mut T);
 
@@ -18,6 +91,26 @@ impl<T: ?Sized> HandlePtr<T> {
     }
 }
 
+// SAFETY:
+// A `HandlePtr` is only an address, which is unsafe to dereference. The objects it refers to are shared between processors anyway
+unsafe impl<T: ?Sized + Send + Sync> Send for HandlePtr<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for HandlePtr<T> {}
+
+// SAFETY:
+// Kernel objects referred to by a `HandlePtr` stay at the same address until their handle is destroyed,
+// which does not happen while they are queued
+unsafe impl<T> IntrusivePointer for HandlePtr<T> {
+    type Target = T;
+
+    fn into_raw(this: Self) -> NonNull<T> {
+        NonNull::new(this.0).expect("null handle pointer")
+    }
+
+    unsafe fn from_raw(ptr: NonNull<T>) -> Self {
+        Self(ptr.as_ptr())
+    }
+}
+
 #[repr(transparent)]
 pub struct IOPtr<T: ?Sized>(*mut T);
 
@@ -99,15 +192,62 @@ macro_rules! impl_traits_for_addr_space {
 
 impl_traits_for_addr_space!(UserPtr, HandlePtr, IOPtr);
 
-#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(transparent)]
 pub struct PhysAddr(*mut ());
 
+// SAFETY:
+// A physical address is only a number, which is unsafe to access memory through
+unsafe impl Send for PhysAddr {}
+unsafe impl Sync for PhysAddr {}
+
+impl PhysAddr {
+    pub const fn null() -> Self {
+        Self(core::ptr::null_mut())
+    }
+
+    pub fn is_null(self) -> bool {
+        self.0.is_null()
+    }
+
+    /// Returns the address as an integer
+    pub fn addr(self) -> usize {
+        self.0.addr()
+    }
+
+    /// Returns a pointer through which the kernel can access the memory at this address
+    pub fn into_kernel_addr(self) -> *mut () {
+        // TODO: Go through the physical memory map once the kernel has its own page tables. For now, physical memory is identity mapped
+        self.0
+    }
+
+    /// Returns the physical address of the memory that the kernel accesses through `kaddr`
+    pub fn from_kernel_addr(kaddr: *mut ()) -> Self {
+        // TODO: Translate through the physical memory map, as in `into_kernel_addr`
+        Self(kaddr)
+    }
+}
+
+impl IntervalKey for PhysAddr {
+    fn checked_add(self, len: usize) -> Option<Self> {
+        let addr = self.0.addr().checked_add(len)?;
+        Some(Self(self.0.with_addr(addr)))
+    }
+
+    fn align_up(self, align: usize) -> Option<Self> {
+        let addr = self.0.addr().align_up(align)?;
+        Some(Self(self.0.with_addr(addr)))
+    }
+}
+
 impl core::fmt::Pointer for PhysAddr {
     fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
         self.0.fmt(f)
     }
 }
+// SAFETY: `PhysAddr` is a transparent wrapper around a pointer
+unsafe impl NoUninit for PhysAddr {}
+
 unsafe impl HasAtomic for PhysAddr {
     type Atomic = AtomicPtr<()>;
     type Storage = [u8; core::mem::size_of::<*mut ()>()];
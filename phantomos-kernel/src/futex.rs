@@ -0,0 +1,330 @@
+//! Futex-style blocking on a 32-bit word, keyed by the word's physical address.
+//!
+//! Keying by physical address means that every mapping of the word, in any address space, refers to the same waiters.
+//! This is the building block for user-mode mutexes and kernel sleeping locks: a thread checks a word and blocks with [`wait`] while it has an unwanted value,
+//!  and whoever changes the word calls [`wake`] afterwards.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use std::sync::IrqSpinLock;
+
+use crate::{
+    addr_space::{HandlePtr, PhysAddr},
+    state,
+    thread::{BlockError, ThreadHandle, ThreadQueue, ThreadStatus},
+};
+
+const BUCKET_COUNT: usize = 64;
+
+/// The threads waiting on any of the addresses that hash to one bucket, in the order they started waiting
+struct Bucket(IrqSpinLock<ThreadQueue>);
+
+// SAFETY:
+// The queued threads are only accessed through their atomic fields, and only while holding the lock
+unsafe impl Sync for Bucket {}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_BUCKET: Bucket = Bucket(IrqSpinLock::new(ThreadQueue::new()));
+
+static BUCKETS: [Bucket; BUCKET_COUNT] = [EMPTY_BUCKET; BUCKET_COUNT];
+
+fn bucket(addr: PhysAddr) -> &'static IrqSpinLock<ThreadQueue> {
+    // Fibonacci hashing. Words are 4-byte aligned, so the low bits are dropped first
+    let hash = ((addr.addr() >> 2) as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    &BUCKETS[(hash >> (64 - BUCKET_COUNT.trailing_zeros())) as usize].0
+}
+
+fn read_tsc() -> u64 {
+    // SAFETY:
+    // `rdtsc` has no side effects
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Blocks the current thread while the word at `addr` contains `expected`, until another thread calls [`wake`] on `addr`.
+///
+/// The word is checked while holding the lock that [`wake`] takes, so a wakeup is never lost between the check and blocking.
+/// Returns `Ok(())` immediately if the word doesn't contain `expected`. Callers must recheck the word after returning either way,
+///  since it may have changed again since the wakeup.
+///
+/// `timeout` is measured in timestamp counter ticks. While blocked, the thread is [`ThreadStatus::Blocked`] on `addr`.
+///
+/// # Errors
+/// Returns [`BlockError::Interrupted`] if the thread is [interrupted](ThreadHandle::interrupt) before it is woken,
+///  and [`BlockError::Timeout`] if `timeout` elapses first. A wakeup that races with either still wins, and returns `Ok(())`.
+///
+/// # Safety
+/// `addr` must be the address of a 4-byte aligned word that the kernel can access
+pub unsafe fn wait(addr: PhysAddr, expected: u32, timeout: Option<u64>) -> Result<(), BlockError> {
+    // SAFETY: Guaranteed by our caller
+    unsafe {
+        wait_as(
+            state::get_kernel_state().uthread.get(),
+            addr,
+            expected,
+            timeout,
+        )
+    }
+}
+
+/// [`wait`] on behalf of the thread at `thread_ptr`, which is the current thread outside of tests
+///
+/// # Safety
+/// As for [`wait`]. `thread_ptr` must refer to a live thread that is running on the current CPU, and isn't already waiting
+unsafe fn wait_as(
+    thread_ptr: HandlePtr<ThreadHandle>,
+    addr: PhysAddr,
+    expected: u32,
+    timeout: Option<u64>,
+) -> Result<(), BlockError> {
+    // SAFETY:
+    // Thread handles are never freed while a reference to them is reachable
+    // TODO: Hold a counted reference to the handle instead
+    let thread = unsafe { &*thread_ptr.into_kernel_addr() };
+    let deadline = timeout.map(|ticks| read_tsc().saturating_add(ticks));
+    let bucket = bucket(addr);
+
+    {
+        let mut queue = bucket.lock();
+        // SAFETY: Guaranteed by our caller
+        let word = unsafe { &*addr.into_kernel_addr().cast::<AtomicU32>() };
+        if word.load(Ordering::Relaxed) != expected {
+            return Ok(());
+        }
+        if thread.interrupted_flag.test_and_clear(Ordering::Acquire) {
+            return Err(BlockError::Interrupted);
+        }
+        thread.waiting_address.store(addr, Ordering::Relaxed);
+        thread
+            .status
+            .store(ThreadStatus::Blocked, Ordering::Release);
+        queue.push_back(thread_ptr);
+    }
+
+    let res = loop {
+        if thread.waiting_address.load(Ordering::Acquire).is_null() {
+            break Ok(());
+        }
+
+        let err = if thread.interrupted_flag.test(Ordering::Acquire) {
+            BlockError::Interrupted
+        } else if deadline.is_some_and(|deadline| read_tsc() >= deadline) {
+            BlockError::Timeout
+        } else {
+            // TODO: Yield to the scheduler instead of spinning
+            core::hint::spin_loop();
+            continue;
+        };
+
+        let mut queue = bucket.lock();
+        // `wake` dequeues under the lock, so the thread is either still queued or has already been woken
+        if thread.waiting_address.load(Ordering::Relaxed).is_null() {
+            break Ok(());
+        }
+        // SAFETY:
+        // The thread is still queued in this bucket, since only `wake` dequeues it otherwise
+        unsafe {
+            queue.cursor_mut_from_value(thread).remove_current();
+        }
+        thread
+            .waiting_address
+            .store(PhysAddr::null(), Ordering::Relaxed);
+        if err == BlockError::Interrupted {
+            thread.interrupted_flag.clear(Ordering::Relaxed);
+        }
+        break Err(err);
+    };
+
+    thread
+        .status
+        .store(ThreadStatus::Running, Ordering::Release);
+    res
+}
+
+/// Wakes up to `count` threads blocked in [`wait`] on `addr`, in the order they started waiting, and returns how many were woken
+pub fn wake(addr: PhysAddr, count: usize) -> usize {
+    let mut queue = bucket(addr).lock();
+    let mut cursor = queue.cursor_front_mut();
+    let mut woken = 0;
+
+    while woken < count {
+        let waiting_address = match cursor.get() {
+            Some(thread) => thread.waiting_address.load(Ordering::Relaxed),
+            None => break,
+        };
+        if waiting_address != addr {
+            cursor.move_next();
+            continue;
+        }
+
+        let thread_ptr: HandlePtr<ThreadHandle> = cursor.remove_current().unwrap();
+        // SAFETY:
+        // Thread handles are never freed while a reference to them is reachable
+        let thread = unsafe { &*thread_ptr.into_kernel_addr() };
+        thread
+            .waiting_address
+            .store(PhysAddr::null(), Ordering::Release);
+        // TODO: Reschedule the thread
+        woken += 1;
+    }
+
+    woken
+}
+
+#[cfg(test)]
+mod test {
+    extern crate std as host;
+
+    use core::sync::atomic::{AtomicU32, Ordering};
+    use host::{boxed::Box, thread, vec::Vec};
+
+    use super::{bucket, wait_as, wake, Bucket, BUCKETS, BUCKET_COUNT};
+    use crate::{
+        addr_space::{HandlePtr, PhysAddr},
+        handle::HandleRef,
+        thread::{BlockError, ThreadHandle, ThreadStatus},
+    };
+
+    /// Returns a word that lives for the rest of the test run, so waiters on different words never share an address
+    fn word(val: u32) -> (&'static AtomicU32, PhysAddr) {
+        let word = Box::leak(Box::new(AtomicU32::new(val)));
+        let addr = PhysAddr::from_kernel_addr((word as *const AtomicU32).cast_mut().cast());
+        (word, addr)
+    }
+
+    fn new_thread() -> HandleRef<ThreadHandle> {
+        HandleRef::new(ThreadHandle::new(HandlePtr::from_kernel_addr(
+            core::ptr::null_mut(),
+        )))
+    }
+
+    /// Starts a host thread that waits on `addr` as `thread`, and returns once it is queued
+    fn spawn_waiter(
+        thread: &HandleRef<ThreadHandle>,
+        addr: PhysAddr,
+        expected: u32,
+    ) -> thread::JoinHandle<Result<(), BlockError>> {
+        let waiter = thread.clone();
+        let handle = thread::spawn(move || unsafe {
+            wait_as(HandleRef::as_ptr(&waiter), addr, expected, None)
+        });
+        // The status is set under the bucket lock, so the thread is queued by the time `wake` can take the lock
+        while thread.status.load(Ordering::Acquire) != ThreadStatus::Blocked {
+            thread::yield_now();
+        }
+        handle
+    }
+
+    #[test]
+    fn bucket_ignores_low_bits() {
+        let addr = PhysAddr::from_kernel_addr(0x1000 as *mut ());
+        for offset in 1..4 {
+            let other = PhysAddr::from_kernel_addr((0x1000 + offset) as *mut ());
+            assert!(core::ptr::eq(bucket(addr), bucket(other)));
+        }
+    }
+
+    #[test]
+    fn bucket_spreads_consecutive_words() {
+        let mut used = [false; BUCKET_COUNT];
+        for i in 0..BUCKET_COUNT {
+            let addr = PhysAddr::from_kernel_addr((0x10_0000 + 4 * i) as *mut ());
+            let idx = BUCKETS
+                .iter()
+                .position(|Bucket(queue)| core::ptr::eq(queue, bucket(addr)))
+                .unwrap();
+            used[idx] = true;
+        }
+        let used = used.iter().filter(|&&used| used).count();
+        assert!(used >= BUCKET_COUNT * 3 / 4, "only {} buckets used", used);
+    }
+
+    #[test]
+    fn wait_returns_on_mismatch() {
+        let (_, addr) = word(1);
+        let thread = new_thread();
+        assert_eq!(
+            unsafe { wait_as(HandleRef::as_ptr(&thread), addr, 0, None) },
+            Ok(())
+        );
+        assert_eq!(
+            thread.status.load(Ordering::Relaxed),
+            ThreadStatus::Unstarted
+        );
+        assert_eq!(wake(addr, usize::MAX), 0);
+    }
+
+    #[test]
+    fn wake_counts_and_orders() {
+        let (word, addr) = word(0);
+        let threads: Vec<_> = (0..3).map(|_| new_thread()).collect();
+        let waiters: Vec<_> = threads
+            .iter()
+            .map(|thread| spawn_waiter(thread, addr, 0))
+            .collect();
+
+        word.store(1, Ordering::Relaxed);
+        assert_eq!(wake(addr, 2), 2);
+        // The first two threads to wait are the ones woken
+        assert!(threads[0].waiting_address.load(Ordering::Relaxed).is_null());
+        assert!(threads[1].waiting_address.load(Ordering::Relaxed).is_null());
+        assert_eq!(threads[2].waiting_address.load(Ordering::Relaxed), addr);
+
+        assert_eq!(wake(addr, 5), 1);
+        assert_eq!(wake(addr, 5), 0);
+        for (thread, waiter) in threads.iter().zip(waiters) {
+            assert_eq!(waiter.join().unwrap(), Ok(()));
+            assert_eq!(thread.status.load(Ordering::Relaxed), ThreadStatus::Running);
+        }
+    }
+
+    #[test]
+    fn wake_skips_other_addresses() {
+        // There are more words than buckets, so two of them share a bucket
+        let words: Vec<_> = (0..=BUCKET_COUNT).map(|_| word(0).1).collect();
+        let (first, second) = (0..words.len())
+            .flat_map(|i| (i + 1..words.len()).map(move |j| (i, j)))
+            .find(|&(i, j)| core::ptr::eq(bucket(words[i]), bucket(words[j])))
+            .map(|(i, j)| (words[i], words[j]))
+            .unwrap();
+
+        let threads = [new_thread(), new_thread()];
+        let first_waiter = spawn_waiter(&threads[0], first, 0);
+        let second_waiter = spawn_waiter(&threads[1], second, 0);
+
+        assert_eq!(wake(second, usize::MAX), 1);
+        assert_eq!(second_waiter.join().unwrap(), Ok(()));
+        assert_eq!(threads[0].waiting_address.load(Ordering::Relaxed), first);
+
+        assert_eq!(wake(first, usize::MAX), 1);
+        assert_eq!(first_waiter.join().unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn wait_times_out() {
+        let (_, addr) = word(0);
+        let thread = new_thread();
+        let res = unsafe { wait_as(HandleRef::as_ptr(&thread), addr, 0, Some(10_000)) };
+        assert_eq!(res, Err(BlockError::Timeout));
+        assert_eq!(thread.status.load(Ordering::Relaxed), ThreadStatus::Running);
+        assert!(thread.waiting_address.load(Ordering::Relaxed).is_null());
+        // The thread took itself off the queue
+        assert_eq!(wake(addr, usize::MAX), 0);
+    }
+
+    #[test]
+    fn wait_interrupted() {
+        let (_, addr) = word(0);
+        let thread = new_thread();
+
+        // A pending interruption is consumed without blocking
+        thread.interrupt();
+        let res = unsafe { wait_as(HandleRef::as_ptr(&thread), addr, 0, None) };
+        assert_eq!(res, Err(BlockError::Interrupted));
+        assert!(!thread.interrupted_flag.test(Ordering::Relaxed));
+
+        let waiter = spawn_waiter(&thread, addr, 0);
+        thread.interrupt();
+        assert_eq!(waiter.join().unwrap(), Err(BlockError::Interrupted));
+        assert!(!thread.interrupted_flag.test(Ordering::Relaxed));
+        assert_eq!(wake(addr, usize::MAX), 0);
+    }
+}
@@ -1,21 +1,468 @@
+//! Reference counting for kernel objects.
+//!
+//! Every object that can be referred to by a handle starts with a [`Handle`] header, which holds its type and reference counts.
+//! [`HandleRef`] is a counted reference to such an object, like an `Arc` whose counts live in the object itself,
+//!  so the same object can be referred to by a [`HandlePtr`] in intrusive queues while it is kept alive by `HandleRef`s.
+//! [`AnyHandleRef`] refers to an object of any type, and is [downcast](AnyHandleRef::downcast) by checking [`Handle::ty`].
+
+use alloc::{
+    alloc::{Allocator, Global},
+    boxed::Box,
+};
+use core::{
+    alloc::Layout,
+    fmt,
+    marker::PhantomData,
+    mem::ManuallyDrop,
+    ops::Deref,
+    ptr::{self, NonNull},
+    sync::atomic::{fence, Ordering},
+};
 use std::sync::atomic::AtomicCell;
 
+use crate::addr_space::HandlePtr;
+
 #[repr(usize)]
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
 pub enum HandleType {
     Unused = 0,
     ThreadHandle = 1,
     ProcessHandle = 2,
     IOHandle = 3,
+    SecurityDescriptor = 4,
 }
 
+/// A kernel object type that can be referred to by handles.
+///
+/// The object is destroyed by dropping it when its last [`HandleRef`] is released, so its `Drop` impl is its destructor.
+/// Objects are shared between processors, and may be destroyed on any of them, so they must be `Send` and `Sync`.
+///
 /// # Safety
-/// TODO
-pub unsafe trait HandleKind {
+/// `Self` must be `#[repr(C)]`, and its first field must be a [`Handle`]. No other type may use the same `TYPE`
+pub unsafe trait HandleKind: Sized + Send + Sync {
     const TYPE: HandleType;
 }
 
+/// How to destroy and free an object, given only its header
+struct HandleVTable {
+    drop_in_place: unsafe fn(NonNull<Handle>),
+    layout: Layout,
+}
+
+struct VTableOf<T>(PhantomData<T>);
+
+impl<T: HandleKind> VTableOf<T> {
+    const VTABLE: HandleVTable = HandleVTable {
+        drop_in_place: drop_object::<T>,
+        layout: Layout::new::<T>(),
+    };
+}
+
+/// # Safety
+/// `handle` must be the header of a live `T`, which must not be used afterwards
+unsafe fn drop_object<T: HandleKind>(handle: NonNull<Handle>) {
+    // SAFETY: Guaranteed by our caller. The header is the first field of `T`, so they share an address
+    unsafe { ptr::drop_in_place(handle.cast::<T>().as_ptr()) }
+}
+
+/// The header at the start of every kernel object that is referred to by handles
 #[repr(C)]
 pub struct Handle {
     pub ty: HandleType,
+    /// The number of [`HandleRef`]s to the object. The object is destroyed when it reaches zero
     pub rc: AtomicCell<usize>,
+    /// The number of [`WeakHandleRef`]s to the object, plus one shared by all of the `HandleRef`s. The memory is freed when it reaches zero
+    weak: AtomicCell<usize>,
+    vtable: &'static HandleVTable,
+}
+
+impl Handle {
+    /// Returns the header for a new `T`.
+    ///
+    /// [`HandleRef::new`] resets the header of the object it's given, so this is only needed to construct a `T` in the first place
+    pub const fn new<T: HandleKind>() -> Self {
+        Self {
+            ty: T::TYPE,
+            rc: AtomicCell::new(1),
+            weak: AtomicCell::new(1),
+            vtable: &VTableOf::<T>::VTABLE,
+        }
+    }
+
+    fn acquire(&self) {
+        self.rc
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |rc| rc.checked_add(1))
+            .expect("handle reference count overflowed");
+    }
+
+    fn acquire_weak(&self) {
+        self.weak
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |weak| {
+                weak.checked_add(1)
+            })
+            .expect("weak handle reference count overflowed");
+    }
+
+    /// Acquires a reference unless the object has already been destroyed
+    fn try_acquire(&self) -> bool {
+        self.rc
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |rc| {
+                (rc != 0).then(|| {
+                    rc.checked_add(1)
+                        .expect("handle reference count overflowed")
+                })
+            })
+            .is_ok()
+    }
+
+    /// Releases a reference, destroying the object if it was the last one
+    ///
+    /// # Safety
+    /// The caller must own a reference to the object at `handle`, which must have been allocated by `alloc`
+    unsafe fn release<A: Allocator>(handle: NonNull<Handle>, alloc: &A) {
+        // SAFETY: The caller's reference keeps the header alive
+        let header = unsafe { handle.as_ref() };
+        if header
+            .rc
+            .fetch_update(Ordering::Release, Ordering::Relaxed, |rc| Some(rc - 1))
+            != Ok(1)
+        {
+            return;
+        }
+        // Synchronizes with the releases of every other reference, so their uses of the object happen before it's destroyed
+        fence(Ordering::Acquire);
+
+        // SAFETY: This was the last reference, so nothing can use the object anymore
+        unsafe { (header.vtable.drop_in_place)(handle) }
+        // SAFETY: The references collectively owned a weak reference. The header has no destructor, so it's still usable
+        unsafe { Self::release_weak(handle, alloc) }
+    }
+
+    /// Releases a weak reference, freeing the object's memory if it was the last one
+    ///
+    /// # Safety
+    /// The caller must own a weak reference to the object at `handle`, which must have been allocated by `alloc`
+    unsafe fn release_weak<A: Allocator>(handle: NonNull<Handle>, alloc: &A) {
+        // SAFETY: The caller's weak reference keeps the header alive
+        let header = unsafe { handle.as_ref() };
+        if header
+            .weak
+            .fetch_update(Ordering::Release, Ordering::Relaxed, |weak| Some(weak - 1))
+            != Ok(1)
+        {
+            return;
+        }
+        fence(Ordering::Acquire);
+
+        let layout = header.vtable.layout;
+        // SAFETY: Nothing refers to the object anymore, and it was allocated by `alloc` with the layout of its type
+        unsafe { alloc.deallocate(handle.cast(), layout) }
+    }
+}
+
+impl fmt::Debug for Handle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Handle")
+            .field("ty", &self.ty)
+            .field("rc", &self.rc.load(Ordering::Relaxed))
+            .field("weak", &self.weak.load(Ordering::Relaxed))
+            .finish_non_exhaustive()
+    }
+}
+
+/// A counted reference to a kernel object of type `T`, allocated with `A`.
+///
+/// Cloning a `HandleRef` increments [`Handle::rc`] of the object, and dropping it decrements the count,
+///  destroying the object when the last reference is dropped
+pub struct HandleRef<T: HandleKind, A: Allocator = Global> {
+    ptr: NonNull<T>,
+    alloc: A,
+    _marker: PhantomData<T>,
+}
+
+// SAFETY: Like `Arc`, a `HandleRef` shares the object between threads, and may drop it on any of them, which `HandleKind` allows
+unsafe impl<T: HandleKind, A: Allocator + Send> Send for HandleRef<T, A> {}
+unsafe impl<T: HandleKind, A: Allocator + Sync> Sync for HandleRef<T, A> {}
+
+impl<T: HandleKind> HandleRef<T> {
+    /// Moves `value` into a new object, and returns the only reference to it
+    pub fn new(value: T) -> Self {
+        Self::new_in(value, Global)
+    }
+
+    /// Consumes the reference without releasing it, returning a pointer to the object.
+    ///
+    /// The reference is leaked unless it is reconstructed with [`HandleRef::from_raw`]
+    pub fn into_raw(this: Self) -> HandlePtr<T> {
+        let this = ManuallyDrop::new(this);
+        HandlePtr::from_kernel_addr(this.ptr.as_ptr())
+    }
+
+    /// Reconstructs a reference consumed by [`HandleRef::into_raw`]
+    ///
+    /// # Safety
+    /// `ptr` must have been returned by [`HandleRef::into_raw`], and each such pointer may only be reconstructed once
+    pub unsafe fn from_raw(ptr: HandlePtr<T>) -> Self {
+        Self {
+            ptr: NonNull::new(ptr.into_kernel_addr()).expect("null handle pointer"),
+            alloc: Global,
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: HandleKind, A: Allocator> HandleRef<T, A> {
+    /// Moves `value` into a new object allocated with `alloc`, and returns the only reference to it
+    pub fn new_in(value: T, alloc: A) -> Self {
+        let (ptr, alloc) = Box::into_raw_with_allocator(Box::new_in(value, alloc));
+        // SAFETY:
+        // `T: HandleKind`, so the header is its first field. It's reset in case `value` was constructed with a header for another type
+        unsafe { ptr.cast::<Handle>().write(Handle::new::<T>()) }
+        Self {
+            // SAFETY: `Box` never returns a null pointer
+            ptr: unsafe { NonNull::new_unchecked(ptr) },
+            alloc,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Returns the header of the object
+    pub fn handle(this: &Self) -> &Handle {
+        // SAFETY: `T: HandleKind`, so the header is its first field
+        unsafe { this.ptr.cast::<Handle>().as_ref() }
+    }
+
+    /// Returns a pointer to the object, without affecting its reference count
+    pub fn as_ptr(this: &Self) -> HandlePtr<T> {
+        HandlePtr::from_kernel_addr(this.ptr.as_ptr())
+    }
+
+    /// Checks whether two references refer to the same object
+    pub fn ptr_eq(this: &Self, other: &Self) -> bool {
+        this.ptr == other.ptr
+    }
+
+    /// Returns the number of [`HandleRef`]s to the object, which may have changed by the time it is used
+    pub fn strong_count(this: &Self) -> usize {
+        Self::handle(this).rc.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of [`WeakHandleRef`]s to the object, which may have changed by the time it is used
+    pub fn weak_count(this: &Self) -> usize {
+        Self::handle(this).weak.load(Ordering::Relaxed) - 1
+    }
+
+    /// Returns a weak reference to the object
+    pub fn downgrade(this: &Self) -> WeakHandleRef<T, A>
+    where
+        A: Clone,
+    {
+        Self::handle(this).acquire_weak();
+        WeakHandleRef {
+            ptr: this.ptr,
+            alloc: this.alloc.clone(),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Erases the type of the reference, which can be recovered with [`AnyHandleRef::downcast`]
+    pub fn into_any(this: Self) -> AnyHandleRef<A> {
+        let this = ManuallyDrop::new(this);
+        AnyHandleRef {
+            handle: this.ptr.cast(),
+            // SAFETY: `this` is never used or dropped again
+            alloc: unsafe { ptr::read(&this.alloc) },
+        }
+    }
+}
+
+impl<T: HandleKind, A: Allocator + Clone> Clone for HandleRef<T, A> {
+    fn clone(&self) -> Self {
+        Self::handle(self).acquire();
+        Self {
+            ptr: self.ptr,
+            alloc: self.alloc.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: HandleKind, A: Allocator> Deref for HandleRef<T, A> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        // SAFETY: Our reference keeps the object alive
+        unsafe { self.ptr.as_ref() }
+    }
+}
+
+impl<T: HandleKind, A: Allocator> Drop for HandleRef<T, A> {
+    fn drop(&mut self) {
+        // SAFETY: We own a reference, to an object allocated by `self.alloc`
+        unsafe { Handle::release(self.ptr.cast(), &self.alloc) }
+    }
+}
+
+impl<T: HandleKind + fmt::Debug, A: Allocator> fmt::Debug for HandleRef<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        T::fmt(self, f)
+    }
+}
+
+impl<T: HandleKind, A: Allocator> fmt::Pointer for HandleRef<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Pointer::fmt(&self.ptr, f)
+    }
+}
+
+/// A weak reference to a kernel object of type `T`, which keeps its memory allocated but doesn't keep it alive.
+///
+/// It can be [upgraded](WeakHandleRef::upgrade) to a [`HandleRef`] while the object hasn't been destroyed
+pub struct WeakHandleRef<T: HandleKind, A: Allocator = Global> {
+    ptr: NonNull<T>,
+    alloc: A,
+    _marker: PhantomData<T>,
+}
+
+// SAFETY: Like `Weak`, a `WeakHandleRef` can be upgraded to a `HandleRef` on any thread
+unsafe impl<T: HandleKind, A: Allocator + Send> Send for WeakHandleRef<T, A> {}
+unsafe impl<T: HandleKind, A: Allocator + Sync> Sync for WeakHandleRef<T, A> {}
+
+impl<T: HandleKind, A: Allocator> WeakHandleRef<T, A> {
+    fn handle(&self) -> &Handle {
+        // SAFETY: Our weak reference keeps the header allocated, and it has no destructor
+        unsafe { self.ptr.cast::<Handle>().as_ref() }
+    }
+
+    /// Returns a counted reference to the object, or `None` if it has already been destroyed
+    pub fn upgrade(&self) -> Option<HandleRef<T, A>>
+    where
+        A: Clone,
+    {
+        self.handle().try_acquire().then(|| HandleRef {
+            ptr: self.ptr,
+            alloc: self.alloc.clone(),
+            _marker: PhantomData,
+        })
+    }
+
+    /// Returns the number of [`HandleRef`]s to the object, which is zero once it has been destroyed
+    pub fn strong_count(&self) -> usize {
+        self.handle().rc.load(Ordering::Relaxed)
+    }
+
+    /// Checks whether two weak references refer to the same object
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        self.ptr == other.ptr
+    }
+}
+
+impl<T: HandleKind, A: Allocator + Clone> Clone for WeakHandleRef<T, A> {
+    fn clone(&self) -> Self {
+        self.handle().acquire_weak();
+        Self {
+            ptr: self.ptr,
+            alloc: self.alloc.clone(),
+            _marker: PhantomData,
+        }
+    }
+}
+
+impl<T: HandleKind, A: Allocator> Drop for WeakHandleRef<T, A> {
+    fn drop(&mut self) {
+        // SAFETY: We own a weak reference, to an object allocated by `self.alloc`
+        unsafe { Handle::release_weak(self.ptr.cast(), &self.alloc) }
+    }
+}
+
+impl<T: HandleKind, A: Allocator> fmt::Debug for WeakHandleRef<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("(WeakHandleRef)")
+    }
+}
+
+/// A counted reference to a kernel object of any type, allocated with `A`
+pub struct AnyHandleRef<A: Allocator = Global> {
+    handle: NonNull<Handle>,
+    alloc: A,
+}
+
+// SAFETY: Every `HandleKind` is `Send` and `Sync`
+unsafe impl<A: Allocator + Send> Send for AnyHandleRef<A> {}
+unsafe impl<A: Allocator + Sync> Sync for AnyHandleRef<A> {}
+
+impl<A: Allocator> AnyHandleRef<A> {
+    /// Returns the header of the object
+    pub fn handle(&self) -> &Handle {
+        // SAFETY: Our reference keeps the object alive
+        unsafe { self.handle.as_ref() }
+    }
+
+    /// Returns the type of the object
+    pub fn ty(&self) -> HandleType {
+        self.handle().ty
+    }
+
+    /// Checks whether the object is a `T`
+    pub fn is<T: HandleKind>(&self) -> bool {
+        self.ty() == T::TYPE
+    }
+
+    /// Returns a reference to the object if it is a `T`
+    pub fn downcast_ref<T: HandleKind>(&self) -> Option<&T> {
+        // SAFETY: `T::TYPE` is unique to `T`, so the object is a `T`
+        self.is::<T>()
+            .then(|| unsafe { self.handle.cast::<T>().as_ref() })
+    }
+
+    /// Converts the reference into a [`HandleRef<T>`] if the object is a `T`, and otherwise returns it unchanged
+    pub fn downcast<T: HandleKind>(self) -> Result<HandleRef<T, A>, Self> {
+        if !self.is::<T>() {
+            return Err(self);
+        }
+        let this = ManuallyDrop::new(self);
+        Ok(HandleRef {
+            ptr: this.handle.cast(),
+            // SAFETY: `this` is never used or dropped again
+            alloc: unsafe { ptr::read(&this.alloc) },
+            _marker: PhantomData,
+        })
+    }
+
+    /// Checks whether two references refer to the same object
+    pub fn ptr_eq(&self, other: &Self) -> bool {
+        self.handle == other.handle
+    }
+}
+
+impl<T: HandleKind, A: Allocator> From<HandleRef<T, A>> for AnyHandleRef<A> {
+    fn from(handle: HandleRef<T, A>) -> Self {
+        HandleRef::into_any(handle)
+    }
+}
+
+impl<A: Allocator + Clone> Clone for AnyHandleRef<A> {
+    fn clone(&self) -> Self {
+        self.handle().acquire();
+        Self {
+            handle: self.handle,
+            alloc: self.alloc.clone(),
+        }
+    }
+}
+
+impl<A: Allocator> Drop for AnyHandleRef<A> {
+    fn drop(&mut self) {
+        // SAFETY: We own a reference, to an object allocated by `self.alloc`
+        unsafe { Handle::release(self.handle, &self.alloc) }
+    }
+}
+
+impl<A: Allocator> fmt::Debug for AnyHandleRef<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AnyHandleRef")
+            .field("ty", &self.ty())
+            .field("ptr", &self.handle)
+            .finish()
+    }
 }
@@ -0,0 +1,516 @@
+//! Per-process tables of handles, which are how user mode refers to kernel objects.
+//!
+//! A handle is a number, local to one process, that refers to a kernel object together with the [rights](HandleRights) it grants over it.
+//! Rights can only ever be reduced: a handle can be [duplicated](HandleTable::duplicate) with a subset of its rights, but never gain new ones,
+//!  so a process can hand out restricted access to its objects.
+
+use alloc::alloc::{Allocator, Global};
+use core::{
+    fmt,
+    hash::BuildHasherDefault,
+    mem,
+    ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign},
+};
+use std::{
+    collection::HashMap,
+    hash::XLangHasher,
+    io::{self, ErrorKind},
+    sync::SpinLock,
+};
+
+use crate::handle::{AnyHandleRef, HandleKind, HandleRef};
+
+/// The operations that a handle permits on its object
+#[derive(Copy, Clone, Hash, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct HandleRights(u32);
+
+impl HandleRights {
+    /// No rights, which still allows the handle to be closed
+    pub const NONE: Self = Self(0);
+    /// The handle can be duplicated, with the same or fewer rights
+    pub const DUPLICATE: Self = Self(1 << 0);
+    /// The handle can be removed from the table, to be transferred to another process
+    pub const TRANSFER: Self = Self(1 << 1);
+    /// The state of the object can be read
+    pub const READ: Self = Self(1 << 2);
+    /// The state of the object can be modified
+    pub const WRITE: Self = Self(1 << 3);
+    /// The object can be waited on
+    pub const WAIT: Self = Self(1 << 4);
+    /// The object can be signalled, such as by interrupting a thread
+    pub const SIGNAL: Self = Self(1 << 5);
+    /// The security descriptor and other properties of the object can be changed
+    pub const MANAGE: Self = Self(1 << 6);
+    /// Every right
+    pub const ALL: Self = Self((1 << 7) - 1);
+
+    const NAMES: [(Self, &'static str); 7] = [
+        (Self::DUPLICATE, "DUPLICATE"),
+        (Self::TRANSFER, "TRANSFER"),
+        (Self::READ, "READ"),
+        (Self::WRITE, "WRITE"),
+        (Self::WAIT, "WAIT"),
+        (Self::SIGNAL, "SIGNAL"),
+        (Self::MANAGE, "MANAGE"),
+    ];
+
+    /// Returns the rights encoded by `bits`, or `None` if any bit doesn't correspond to a right
+    pub const fn from_bits(bits: u32) -> Option<Self> {
+        if bits & !Self::ALL.0 == 0 {
+            Some(Self(bits))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the bits encoding the rights
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Checks whether every right in `other` is also in `self`
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Checks whether there are no rights at all, as in [`HandleRights::NONE`]
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitOr for HandleRights {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for HandleRights {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+impl BitAnd for HandleRights {
+    type Output = Self;
+
+    fn bitand(self, rhs: Self) -> Self {
+        Self(self.0 & rhs.0)
+    }
+}
+
+impl BitAndAssign for HandleRights {
+    fn bitand_assign(&mut self, rhs: Self) {
+        self.0 &= rhs.0;
+    }
+}
+
+impl fmt::Debug for HandleRights {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("NONE");
+        }
+        let mut sep = "";
+        for (right, name) in Self::NAMES {
+            if self.contains(right) {
+                f.write_str(sep)?;
+                f.write_str(name)?;
+                sep = " | ";
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The number of a handle in a [`HandleTable`], as seen by user mode.
+///
+/// Zero is never the number of a handle, so it can be used to mean "no handle"
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(transparent)]
+pub struct HandleId(u32);
+
+impl HandleId {
+    /// The number that never refers to a handle
+    pub const INVALID: Self = Self(0);
+
+    pub const fn from_raw(raw: u32) -> Self {
+        Self(raw)
+    }
+
+    pub const fn into_raw(self) -> u32 {
+        self.0
+    }
+}
+
+/// The most handles that one table can hold
+pub const MAX_HANDLES: usize = 1 << 16;
+
+struct HandleEntry<A: Allocator> {
+    object: AnyHandleRef<A>,
+    rights: HandleRights,
+}
+
+struct TableInner<A: Allocator> {
+    entries: HashMap<HandleId, HandleEntry<A>, BuildHasherDefault<XLangHasher>, A>,
+    next_id: u32,
+}
+
+impl<A: Allocator> TableInner<A> {
+    fn get(&self, id: HandleId, required: HandleRights) -> io::Result<&HandleEntry<A>> {
+        let entry = self
+            .entries
+            .get(&id)
+            .ok_or(io::Error::from_kind(ErrorKind::InvalidHandle))?;
+        if entry.rights.contains(required) {
+            Ok(entry)
+        } else {
+            Err(io::Error::from_kind(ErrorKind::PermissionDenied))
+        }
+    }
+
+    /// Picks the number of a new handle, and makes room for it so that inserting it can't fail
+    fn reserve(&mut self) -> io::Result<HandleId> {
+        if self.entries.len() >= MAX_HANDLES {
+            return Err(io::Error::new(
+                ErrorKind::OutOfMemory,
+                "too many open handles",
+            ));
+        }
+        self.entries.try_reserve(1)?;
+        // Numbers are handed out in increasing order, so that a closed number isn't reused until the others have been,
+        //  which makes use-after-close bugs in user mode more likely to fail than to refer to an unrelated object.
+        // The table is never full here, so this finds a free number eventually
+        loop {
+            let id = HandleId(self.next_id);
+            self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+            if id != HandleId::INVALID && !self.entries.contains_key(&id) {
+                break Ok(id);
+            }
+        }
+    }
+}
+
+/// A table mapping handle numbers to kernel objects, owned by one process.
+///
+/// Lookups are checked against both the type of the object and the rights of the handle. Objects are released outside of the table's lock,
+///  so their destructors may use the table
+pub struct HandleTable<A: Allocator + Clone = Global> {
+    inner: SpinLock<TableInner<A>>,
+    alloc: A,
+}
+
+impl HandleTable {
+    /// Returns a new, empty, table
+    pub fn new() -> Self {
+        Self::new_in(Global)
+    }
+}
+
+impl Default for HandleTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<A: Allocator + Clone> HandleTable<A> {
+    /// Returns a new, empty, table, which allocates its storage with `alloc`
+    pub fn new_in(alloc: A) -> Self {
+        Self {
+            inner: SpinLock::new(TableInner {
+                entries: HashMap::new_in(alloc.clone()),
+                next_id: 1,
+            }),
+            alloc,
+        }
+    }
+
+    /// Returns the number of open handles
+    pub fn len(&self) -> usize {
+        self.inner.lock().entries.len()
+    }
+
+    /// Checks whether the table has no open handles
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Opens a handle to `object` with `rights`, and returns its number
+    ///
+    /// # Errors
+    /// Returns [`ErrorKind::OutOfMemory`] if the table can't grow, or already holds [`MAX_HANDLES`] handles
+    pub fn insert<T: HandleKind>(
+        &self,
+        object: HandleRef<T, A>,
+        rights: HandleRights,
+    ) -> io::Result<HandleId> {
+        self.insert_any(object.into(), rights)
+    }
+
+    /// Opens a handle to an object of any type with `rights`, and returns its number
+    ///
+    /// # Errors
+    /// Returns [`ErrorKind::OutOfMemory`] if the table can't grow, or already holds [`MAX_HANDLES`] handles
+    pub fn insert_any(
+        &self,
+        object: AnyHandleRef<A>,
+        rights: HandleRights,
+    ) -> io::Result<HandleId> {
+        // On error, the lock is released before `object`
+        let mut inner = self.inner.lock();
+        let id = inner.reserve()?;
+        inner.entries.insert(id, HandleEntry { object, rights });
+        Ok(id)
+    }
+
+    /// Returns a reference to the object of handle `id`, which must be a `T`, if the handle has every right in `required`
+    ///
+    /// # Errors
+    /// Returns [`ErrorKind::InvalidHandle`] if `id` isn't open, [`ErrorKind::WrongHandleType`] if the object isn't a `T`,
+    ///  and [`ErrorKind::PermissionDenied`] if the handle lacks some of the `required` rights
+    pub fn get<T: HandleKind>(
+        &self,
+        id: HandleId,
+        required: HandleRights,
+    ) -> io::Result<HandleRef<T, A>> {
+        let object = self.get_any(id, required)?;
+        object
+            .downcast()
+            .map_err(|_| io::Error::from_kind(ErrorKind::WrongHandleType))
+    }
+
+    /// Returns a reference to the object of handle `id`, whatever its type, if the handle has every right in `required`
+    ///
+    /// # Errors
+    /// Returns [`ErrorKind::InvalidHandle`] if `id` isn't open, and [`ErrorKind::PermissionDenied`] if the handle lacks some of the `required` rights
+    pub fn get_any(&self, id: HandleId, required: HandleRights) -> io::Result<AnyHandleRef<A>> {
+        Ok(self.inner.lock().get(id, required)?.object.clone())
+    }
+
+    /// Returns the rights of handle `id`
+    ///
+    /// # Errors
+    /// Returns [`ErrorKind::InvalidHandle`] if `id` isn't open
+    pub fn rights(&self, id: HandleId) -> io::Result<HandleRights> {
+        Ok(self.inner.lock().get(id, HandleRights::NONE)?.rights)
+    }
+
+    /// Opens a new handle to the object of handle `id`, with `rights`, and returns its number.
+    ///
+    /// The handle must have [`HandleRights::DUPLICATE`], and `rights` must be a subset of its rights
+    ///
+    /// # Errors
+    /// Returns [`ErrorKind::InvalidHandle`] if `id` isn't open, [`ErrorKind::PermissionDenied`] if the handle can't be duplicated or `rights` exceeds its rights,
+    ///  and [`ErrorKind::OutOfMemory`] if the new handle can't be opened
+    pub fn duplicate(&self, id: HandleId, rights: HandleRights) -> io::Result<HandleId> {
+        let mut inner = self.inner.lock();
+        inner.get(id, HandleRights::DUPLICATE | rights)?;
+        let new_id = inner.reserve()?;
+        let object = inner.entries.get(&id).unwrap().object.clone();
+        inner.entries.insert(new_id, HandleEntry { object, rights });
+        Ok(new_id)
+    }
+
+    /// Closes handle `id`, releasing its reference to the object
+    ///
+    /// # Errors
+    /// Returns [`ErrorKind::InvalidHandle`] if `id` isn't open
+    pub fn close(&self, id: HandleId) -> io::Result<()> {
+        // The lock is released at the end of the statement, before the object is
+        let entry = self.inner.lock().entries.remove(&id);
+        entry
+            .map(drop)
+            .ok_or(io::Error::from_kind(ErrorKind::InvalidHandle))
+    }
+
+    /// Closes handle `id` and returns its object and rights, so that it can be inserted into the table of another process.
+    ///
+    /// The handle must have [`HandleRights::TRANSFER`]
+    ///
+    /// # Errors
+    /// Returns [`ErrorKind::InvalidHandle`] if `id` isn't open, and [`ErrorKind::PermissionDenied`] if the handle can't be transferred
+    pub fn take(&self, id: HandleId) -> io::Result<(AnyHandleRef<A>, HandleRights)> {
+        let mut inner = self.inner.lock();
+        inner.get(id, HandleRights::TRANSFER)?;
+        let (_, entry) = inner.entries.remove(&id).unwrap();
+        Ok((entry.object, entry.rights))
+    }
+
+    /// Closes every handle, such as when the process exits
+    pub fn clear(&self) {
+        let entries = mem::replace(
+            &mut self.inner.lock().entries,
+            HashMap::new_in(self.alloc.clone()),
+        );
+        drop(entries);
+    }
+}
+
+impl<A: Allocator + Clone> fmt::Debug for HandleTable<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let inner = self.inner.lock();
+        f.debug_map()
+            .entries(
+                inner
+                    .entries
+                    .iter()
+                    .map(|(id, entry)| (id.0, (entry.object.ty(), entry.rights))),
+            )
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use core::ptr;
+    use std::io::ErrorKind;
+
+    use super::{HandleId, HandleRights, HandleTable};
+    use crate::{
+        addr_space::HandlePtr, handle::HandleRef, process::ProcessHandle, thread::ThreadHandle,
+    };
+
+    fn new_thread() -> HandleRef<ThreadHandle> {
+        HandleRef::new(ThreadHandle::new(HandlePtr::from_kernel_addr(
+            ptr::null_mut(),
+        )))
+    }
+
+    #[test]
+    fn insert_get_close() {
+        let table = HandleTable::new();
+        let thread = new_thread();
+        let id = table.insert(thread.clone(), HandleRights::READ).unwrap();
+        assert_ne!(id, HandleId::INVALID);
+        assert_eq!(table.len(), 1);
+        assert_eq!(HandleRef::strong_count(&thread), 2);
+
+        let got = table.get::<ThreadHandle>(id, HandleRights::READ).unwrap();
+        assert!(HandleRef::ptr_eq(&got, &thread));
+        drop(got);
+
+        table.close(id).unwrap();
+        assert!(table.is_empty());
+        assert_eq!(HandleRef::strong_count(&thread), 1);
+        assert_eq!(
+            table.get_any(id, HandleRights::NONE).unwrap_err().kind(),
+            ErrorKind::InvalidHandle
+        );
+        assert_eq!(
+            table.close(id).unwrap_err().kind(),
+            ErrorKind::InvalidHandle
+        );
+    }
+
+    #[test]
+    fn get_checks_type_and_rights() {
+        let table = HandleTable::new();
+        let id = table.insert(new_thread(), HandleRights::READ).unwrap();
+
+        assert_eq!(
+            table
+                .get::<ProcessHandle>(id, HandleRights::READ)
+                .map(drop)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::WrongHandleType
+        );
+        assert_eq!(
+            table
+                .get::<ThreadHandle>(id, HandleRights::READ | HandleRights::WRITE)
+                .map(drop)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::PermissionDenied
+        );
+        assert!(table
+            .get_any(id, HandleRights::READ)
+            .unwrap()
+            .is::<ThreadHandle>());
+    }
+
+    #[test]
+    fn duplicate_reduces_rights() {
+        let table = HandleTable::new();
+        let thread = new_thread();
+        let rights = HandleRights::DUPLICATE | HandleRights::READ | HandleRights::WRITE;
+        let id = table.insert(thread.clone(), rights).unwrap();
+
+        let dup = table.duplicate(id, HandleRights::READ).unwrap();
+        assert_ne!(dup, id);
+        assert_eq!(table.rights(dup).unwrap(), HandleRights::READ);
+        assert_eq!(table.rights(id).unwrap(), rights);
+        assert_eq!(HandleRef::strong_count(&thread), 3);
+
+        // Rights can't be gained, and the reduced handle can't be duplicated at all
+        assert_eq!(
+            table
+                .duplicate(id, HandleRights::MANAGE)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::PermissionDenied
+        );
+        assert_eq!(
+            table.duplicate(dup, HandleRights::READ).unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+        assert_eq!(table.len(), 2);
+    }
+
+    #[test]
+    fn take_requires_transfer() {
+        let table = HandleTable::new();
+        let thread = new_thread();
+        let fixed = table.insert(thread.clone(), HandleRights::READ).unwrap();
+        let movable = table
+            .insert(thread.clone(), HandleRights::TRANSFER | HandleRights::READ)
+            .unwrap();
+
+        assert_eq!(
+            table.take(fixed).unwrap_err().kind(),
+            ErrorKind::PermissionDenied
+        );
+        assert_eq!(table.rights(fixed).unwrap(), HandleRights::READ);
+
+        let (object, rights) = table.take(movable).unwrap();
+        assert_eq!(rights, HandleRights::TRANSFER | HandleRights::READ);
+        assert!(HandleRef::ptr_eq(
+            &object.downcast::<ThreadHandle>().unwrap(),
+            &thread
+        ));
+        assert_eq!(
+            table.rights(movable).unwrap_err().kind(),
+            ErrorKind::InvalidHandle
+        );
+        assert_eq!(table.len(), 1);
+    }
+
+    #[test]
+    fn reserve_wraps_around() {
+        let table = HandleTable::new();
+        let first = table.insert(new_thread(), HandleRights::NONE).unwrap();
+        assert_eq!(first, HandleId::from_raw(1));
+
+        table.inner.lock().next_id = u32::MAX;
+        let last = table.insert(new_thread(), HandleRights::NONE).unwrap();
+        assert_eq!(last, HandleId::from_raw(u32::MAX));
+        // Zero is never handed out, and 1 is still open
+        let wrapped = table.insert(new_thread(), HandleRights::NONE).unwrap();
+        assert_eq!(wrapped, HandleId::from_raw(2));
+    }
+
+    #[test]
+    fn clear_releases_objects() {
+        let table = HandleTable::new();
+        let thread = new_thread();
+        for _ in 0..3 {
+            table.insert(thread.clone(), HandleRights::ALL).unwrap();
+        }
+        assert_eq!(HandleRef::strong_count(&thread), 4);
+        table.clear();
+        assert!(table.is_empty());
+        assert_eq!(HandleRef::strong_count(&thread), 1);
+    }
+}
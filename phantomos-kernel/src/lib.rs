@@ -1,12 +1,19 @@
-#![feature(default_alloc_error_handler)]
+#![feature(allocator_api, default_alloc_error_handler)]
 #![no_std]
 
+extern crate alloc;
+
 pub mod addr_space;
 pub mod allocator;
+pub mod futex;
 pub mod handle;
+pub mod handle_table;
+pub mod percpu;
+pub mod process;
 pub mod security;
 pub mod state;
 pub mod thread;
+pub mod usercopy;
 
 #[panic_handler]
 fn handle_panic(info: &core::panic::PanicInfo) -> ! {
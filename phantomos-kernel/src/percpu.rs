@@ -0,0 +1,90 @@
+//! Storage with a separate value for each CPU, like thread-local storage for CPUs.
+//!
+//! Per-CPU values are declared with [`per_cpu!`](crate::per_cpu), and accessed through [`PerCpu::get`], which selects the slot of the current CPU
+//!  from its [`KernelState`](crate::state::KernelState), and disables preemption while the value is borrowed so that the thread can't migrate to another CPU.
+
+use core::ops::Deref;
+
+use crate::state::{self, PreemptGuard};
+
+/// The maximum number of CPUs the kernel supports
+pub const MAX_CPUS: usize = 64;
+
+/// A value with a separate instance for each CPU.
+///
+/// A slot is only ever accessed by the CPU it belongs to, so `T` doesn't need to be [`Sync`], and can use [`Cell`](core::cell::Cell) and similar for mutation.
+/// However, an interrupt handler on the same CPU may access the slot while it is borrowed, so mutation must be left in a consistent state at every point
+pub struct PerCpu<T> {
+    slots: [T; MAX_CPUS],
+}
+
+// SAFETY:
+// Each slot is only accessed from its own CPU, with preemption disabled, so values are never accessed concurrently from different threads
+unsafe impl<T: Send> Sync for PerCpu<T> {}
+
+impl<T> PerCpu<T> {
+    /// Creates per-CPU storage from the initial value of each slot. See [`per_cpu!`](crate::per_cpu) for a simpler way to declare it
+    pub const fn new(slots: [T; MAX_CPUS]) -> Self {
+        Self { slots }
+    }
+
+    /// Borrows the value of the current CPU, disabling preemption until the returned reference is dropped
+    pub fn get(&self) -> PerCpuRef<'_, T> {
+        let preempt = PreemptGuard::new();
+        let cpu = state::get_kernel_state().cpu_id;
+        PerCpuRef {
+            value: &self.slots[cpu],
+            _preempt: preempt,
+        }
+    }
+
+    /// Calls `f` with the value of the current CPU, with preemption disabled
+    pub fn with<R>(&self, f: impl FnOnce(&T) -> R) -> R {
+        f(&self.get())
+    }
+
+    /// Returns a mutable reference to the value of every CPU.
+    ///
+    /// This doesn't disable preemption, since the mutable borrow statically guarantees exclusive access
+    pub fn get_all_mut(&mut self) -> &mut [T; MAX_CPUS] {
+        &mut self.slots
+    }
+}
+
+/// A borrow of the current CPU's value in a [`PerCpu`], which keeps preemption disabled until dropped
+pub struct PerCpuRef<'a, T> {
+    value: &'a T,
+    _preempt: PreemptGuard,
+}
+
+impl<T> Deref for PerCpuRef<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+/// Declares statics with a separate value for each CPU, each initialized from the same constant expression.
+///
+/// ```ignore
+/// per_cpu! {
+///     static TICKS: Cell<u64> = Cell::new(0);
+/// }
+///
+/// let ticks = TICKS.get();
+/// ticks.set(ticks.get() + 1);
+/// ```
+#[macro_export]
+macro_rules! per_cpu {
+    ($($(#[$meta:meta])* $vis:vis static $name:ident: $ty:ty = $init:expr;)*) => {
+        $(
+            $(#[$meta])*
+            $vis static $name: $crate::percpu::PerCpu<$ty> = {
+                #[allow(clippy::declare_interior_mutable_const)]
+                const INIT: $ty = $init;
+                $crate::percpu::PerCpu::new([INIT; $crate::percpu::MAX_CPUS])
+            };
+        )*
+    };
+}
@@ -0,0 +1,36 @@
+use crate::{
+    handle::{Handle, HandleKind, HandleType},
+    handle_table::HandleTable,
+};
+
+#[repr(C)]
+pub struct ProcessHandle {
+    handle: Handle,
+    handles: HandleTable,
+}
+
+// SAFETY: `ProcessHandle` is `#[repr(C)]`, and starts with its header
+unsafe impl HandleKind for ProcessHandle {
+    const TYPE: HandleType = HandleType::ProcessHandle;
+}
+
+impl ProcessHandle {
+    /// Returns a new process, with no open handles
+    pub fn new() -> Self {
+        Self {
+            handle: Handle::new::<Self>(),
+            handles: HandleTable::new(),
+        }
+    }
+
+    /// Returns the table of the handles that the process has open
+    pub fn handles(&self) -> &HandleTable {
+        &self.handles
+    }
+}
+
+impl Default for ProcessHandle {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,6 +1,11 @@
-use crate::handle::Handle;
+use crate::handle::{Handle, HandleKind, HandleType};
 
 #[repr(C)]
 pub struct SecurityDescriptor {
     header: Handle,
 }
+
+// SAFETY: `SecurityDescriptor` is `#[repr(C)]`, and starts with its header
+unsafe impl HandleKind for SecurityDescriptor {
+    const TYPE: HandleType = HandleType::SecurityDescriptor;
+}
@@ -1,29 +1,126 @@
 #[cfg(target_arch = "x86_64")]
 pub mod system {
     use super::KernelState;
+
+    /// The MSR that holds the base address of the `gs` segment
+    const IA32_GS_BASE: u32 = 0xC000_0101;
+
     #[repr(C)]
     pub struct SystemKernelState {
+        /// Points back to the [`KernelState`] this is part of, so that it can be read from `gs:0`
+        this: *const KernelState,
         ss: u64,
         rsp: u64,
     }
 
+    impl SystemKernelState {
+        pub(super) const fn new() -> Self {
+            Self {
+                this: core::ptr::null(),
+                ss: 0,
+                rsp: 0,
+            }
+        }
+    }
+
     pub fn get_kernel_state() -> &'static KernelState {
         let mut ret: *const KernelState;
         unsafe {
-            core::arch::asm!("lea {}, gs:0", out(reg) ret);
+            core::arch::asm!("mov {}, gs:[0]", out(reg) ret, options(nostack, readonly, preserves_flags));
         }
         unsafe { &*ret }
     }
+
+    /// Points the `gs` segment of the current CPU at `state`
+    ///
+    /// # Safety
+    /// Must be called in kernel mode
+    pub(super) unsafe fn set_kernel_state(state: &'static mut KernelState) {
+        let this: *const KernelState = state;
+        state.sys.this = this;
+        let addr = this as u64;
+        unsafe {
+            core::arch::asm!("wrmsr", in("ecx") IA32_GS_BASE, in("eax") addr as u32, in("edx") (addr >> 32) as u32, options(nostack, preserves_flags));
+        }
+    }
 }
 
 #[repr(C)]
 pub struct KernelState {
     pub sys: system::SystemKernelState,
     pub uthread: Cell<HandlePtr<ThreadHandle>>,
+    /// The index of this CPU, which selects its slot in [`PerCpu`](crate::percpu::PerCpu) storage
+    pub cpu_id: usize,
+    /// The number of live [`PreemptGuard`]s on this CPU. The current thread may only be preempted while this is zero
+    pub preempt_count: Cell<usize>,
+}
+
+impl KernelState {
+    /// Returns the initial state of CPU `cpu_id`, which isn't running a thread yet and has preemption enabled
+    pub fn new(cpu_id: usize) -> Self {
+        assert!(cpu_id < MAX_CPUS, "CPU {} is out of range", cpu_id);
+        Self {
+            sys: system::SystemKernelState::new(),
+            uthread: Cell::new(HandlePtr::from_kernel_addr(core::ptr::null_mut())),
+            cpu_id,
+            preempt_count: Cell::new(0),
+        }
+    }
+}
+
+/// Brings up the state of the current CPU as CPU `cpu_id`, which [`get_kernel_state`] returns from then on.
+///
+/// Each CPU calls this once while it's being brought up, before it uses [`PerCpu`](crate::percpu::PerCpu) storage or a [`PreemptGuard`]
+///
+/// # Safety
+/// Must be called in kernel mode, at most once on each CPU, and `cpu_id` must be unique to the current CPU
+pub unsafe fn init_cpu(cpu_id: usize) {
+    let state = Box::leak(Box::new(KernelState::new(cpu_id)));
+    // SAFETY: Guaranteed by our caller
+    unsafe { system::set_kernel_state(state) }
+}
+
+/// Disables preemption of the current thread until dropped.
+///
+/// Guards nest, and preemption is enabled again once every guard on the CPU has been dropped.
+/// Interrupts are still delivered, so this doesn't protect against interrupt handlers on the same CPU
+pub struct PreemptGuard {
+    _not_send: PhantomData<*mut ()>,
+}
+
+impl PreemptGuard {
+    pub fn new() -> Self {
+        let state = get_kernel_state();
+        state.preempt_count.set(state.preempt_count.get() + 1);
+        compiler_fence(Ordering::Acquire);
+        Self {
+            _not_send: PhantomData,
+        }
+    }
+}
+
+impl Default for PreemptGuard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for PreemptGuard {
+    fn drop(&mut self) {
+        compiler_fence(Ordering::Release);
+        let state = get_kernel_state();
+        state.preempt_count.set(state.preempt_count.get() - 1);
+        // TODO: Reschedule if preemption was requested while it was disabled
+    }
 }
 
-use core::cell::Cell;
+use alloc::boxed::Box;
+use core::{
+    cell::Cell,
+    marker::PhantomData,
+    sync::atomic::{compiler_fence, Ordering},
+};
 
 pub use system::get_kernel_state;
 
-use crate::{addr_space::HandlePtr, thread::ThreadHandle};
+use crate::{addr_space::HandlePtr, percpu::MAX_CPUS, thread::ThreadHandle};
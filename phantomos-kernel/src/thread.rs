@@ -1,13 +1,18 @@
 use core::sync::atomic::Ordering;
 use std::{
     cell::RacyCell,
-    collection::RingBuffer,
+    collection::{
+        intrusive::{LinkedList, ListLink},
+        RingBuffer,
+    },
+    io,
     sync::atomic::{AtomicCell, AtomicFlag, AtomicLeastCell},
+    task::Park,
 };
 
 use crate::{
     addr_space::{HandlePtr, PhysAddr},
-    handle::Handle,
+    handle::{Handle, HandleKind, HandleType},
     security::SecurityDescriptor,
     state,
 };
@@ -32,22 +37,71 @@ pub enum BlockError {
     Timeout,
 }
 
+impl From<BlockError> for io::Error {
+    fn from(e: BlockError) -> Self {
+        match e {
+            BlockError::Interrupted => io::Error::from(io::ErrorKind::Interrupted),
+            BlockError::Timeout => io::Error::from(io::ErrorKind::TimedOut),
+        }
+    }
+}
+
 #[repr(C)]
 pub struct ThreadHandle {
     handle: Handle,
-    status: AtomicLeastCell<ThreadStatus>,
-    interrupted_flag: AtomicFlag,
+    pub(crate) status: AtomicLeastCell<ThreadStatus>,
+    /// Set by [`ThreadHandle::interrupt`], and cleared by the blocking call that returns [`BlockError::Interrupted`] because of it
+    pub(crate) interrupted_flag: AtomicFlag,
+    /// The park token of [`ThreadParker`], set by `unpark` and cleared by the `park` that consumes it
     token_flag: AtomicFlag,
     pending_signals: RingBuffer<u32>,
-    waiting_address: AtomicCell<PhysAddr>,
+    /// The address the thread is blocked on in [`futex::wait`](crate::futex::wait), which is reset to null when it is woken
+    pub(crate) waiting_address: AtomicCell<PhysAddr>,
     exit_status: AtomicLeastCell<u32>,
     priority: AtomicLeastCell<u32>,
     security_ctx: RacyCell<HandlePtr<SecurityDescriptor>>,
+    /// Links the thread into a run queue or a wait queue. A thread is never runnable and waiting at once, so one link serves both
+    queue_link: ListLink,
 }
 
+// SAFETY: `ThreadHandle` is `#[repr(C)]`, and starts with its header
+unsafe impl HandleKind for ThreadHandle {
+    const TYPE: HandleType = HandleType::ThreadHandle;
+}
+
+std::intrusive_adapter!(
+    /// Stores threads in a [`ThreadQueue`] through their queue link
+    pub ThreadQueueAdapter = HandlePtr<ThreadHandle>: ThreadHandle { queue_link: ListLink }
+);
+
+/// A queue of threads, such as a run queue or a wait queue, which never allocates
+pub type ThreadQueue = LinkedList<ThreadQueueAdapter>;
+
+/// The number of signals that can be pending on a thread at once
+const PENDING_SIGNALS: usize = 32;
+
 impl ThreadHandle {
+    /// Returns a new thread, which hasn't started and runs in `security_ctx`
+    pub fn new(security_ctx: HandlePtr<SecurityDescriptor>) -> Self {
+        Self {
+            handle: Handle::new::<Self>(),
+            status: AtomicLeastCell::new(ThreadStatus::Unstarted),
+            interrupted_flag: AtomicFlag::new(),
+            token_flag: AtomicFlag::new(),
+            pending_signals: RingBuffer::with_capacity(PENDING_SIGNALS),
+            waiting_address: AtomicCell::new(PhysAddr::null()),
+            exit_status: AtomicLeastCell::new(0),
+            priority: AtomicLeastCell::new(0),
+            security_ctx: RacyCell::new(security_ctx),
+            queue_link: ListLink::new(),
+        }
+    }
+
+    /// Interrupts the thread, so that it stops blocking with [`BlockError::Interrupted`].
+    ///
+    /// If the thread isn't blocked, the interruption stays pending until it next blocks.
     pub fn interrupt(&self) {
-        self.interrupted_flag.clear(Ordering::Release);
+        self.interrupted_flag.set(Ordering::Release);
         // TODO: Reschedule self
     }
 
@@ -57,3 +111,67 @@ impl ThreadHandle {
         core::ptr::eq(state.uthread.get().into_kernel_addr(), self)
     }
 }
+
+/// Blocks a kernel thread on behalf of a [`std::task`] executor, using the thread's park token.
+///
+/// While parked, the thread is [`ThreadStatus::Blocked`] with a null waiting address, as it is not waiting on any address.
+#[derive(Copy, Clone, Debug)]
+pub struct ThreadParker(HandlePtr<ThreadHandle>);
+
+// SAFETY:
+// `ThreadParker` only accesses the atomic fields of the thread
+unsafe impl Send for ThreadParker {}
+unsafe impl Sync for ThreadParker {}
+
+impl ThreadParker {
+    /// Returns a parker for the thread running on the current CPU
+    pub fn current() -> Self {
+        Self(state::get_kernel_state().uthread.get())
+    }
+
+    fn thread(&self) -> &ThreadHandle {
+        // SAFETY:
+        // Thread handles are never freed while a reference to them is reachable
+        // TODO: Hold a counted reference to the handle instead
+        unsafe { &*self.0.into_kernel_addr() }
+    }
+}
+
+impl Park for ThreadParker {
+    type Error = BlockError;
+
+    fn park(&self) -> Result<(), BlockError> {
+        let thread = self.thread();
+        debug_assert!(thread.is_current_thread());
+
+        if thread.token_flag.test_and_clear(Ordering::Acquire) {
+            return Ok(());
+        }
+
+        thread
+            .waiting_address
+            .store(PhysAddr::null(), Ordering::Relaxed);
+        thread
+            .status
+            .store(ThreadStatus::Blocked, Ordering::Release);
+        let res = loop {
+            if thread.interrupted_flag.test_and_clear(Ordering::Acquire) {
+                break Err(BlockError::Interrupted);
+            }
+            if thread.token_flag.test_and_clear(Ordering::Acquire) {
+                break Ok(());
+            }
+            // TODO: Yield to the scheduler instead of spinning
+            core::hint::spin_loop();
+        };
+        thread
+            .status
+            .store(ThreadStatus::Running, Ordering::Release);
+        res
+    }
+
+    fn unpark(&self) {
+        self.thread().token_flag.set(Ordering::Release);
+        // TODO: Reschedule the thread if it is blocked
+    }
+}
@@ -0,0 +1,202 @@
+//! Copying between kernel memory and user memory, recovering from page faults.
+//!
+//! User mode can pass any address to the kernel, so an access to user memory may fault even after the address is checked, such as when the page is unmapped concurrently.
+//! User memory is therefore only accessed by the assembly routines in this module. Every instruction in them that touches user memory is listed in the exception table,
+//!  together with a fixup address. When a page fault at one of those instructions can't be resolved, the page fault handler resumes at the fixup, found with [`fixup_exception`],
+//!  and the routine reports the fault instead of the kernel panicking.
+
+use core::{arch::global_asm, num::NonZeroUsize, ptr};
+use std::io::{self, ErrorKind};
+
+/// The end of the user half of the address space. User memory lies entirely below it
+pub const USER_END: usize = 0x0000_8000_0000_0000;
+
+/// An entry of the exception table.
+///
+/// Both addresses are stored as offsets from the field that holds them, so the table needs no relocations
+#[repr(C)]
+struct ExceptionTableEntry {
+    insn: i32,
+    fixup: i32,
+}
+
+impl ExceptionTableEntry {
+    fn insn(&self) -> usize {
+        (ptr::addr_of!(self.insn) as usize).wrapping_add_signed(self.insn as isize)
+    }
+
+    fn fixup(&self) -> usize {
+        (ptr::addr_of!(self.fixup) as usize).wrapping_add_signed(self.fixup as isize)
+    }
+}
+
+extern "C" {
+    // Defined by the linker around the `ex_table` section
+    static __start_ex_table: [ExceptionTableEntry; 0];
+    static __stop_ex_table: [ExceptionTableEntry; 0];
+
+    // Exported by `phantomos-init`, whose page fault handler calls `hook` for faults in kernel mode
+    fn __register_fixup_exception(hook: extern "C" fn(usize) -> Option<NonZeroUsize>);
+
+    /// Copies `len` bytes from `src` to `dst`, and returns the number of bytes that weren't copied because of a fault
+    fn __copy_user(dst: *mut u8, src: *const u8, len: usize) -> usize;
+
+    /// Copies bytes from `src` to `dst` up to and including the first NUL, but at most `len` bytes.
+    /// Returns the number of bytes before the NUL, `len` if there was no NUL, or -1 if there was a fault
+    fn __copy_user_cstr(dst: *mut u8, src: *const u8, len: usize) -> isize;
+}
+
+// TODO: Wrap the accesses to user memory in `stac`/`clac` once SMAP is enabled
+global_asm!(
+    r#"
+.pushsection .text.__copy_user,"ax",@progbits
+.global __copy_user
+.hidden __copy_user
+.type __copy_user,@function
+__copy_user:
+    mov rcx, rdx
+.Lcopy_user_movs:
+    rep movsb
+.Lcopy_user_done:
+    // `rep movsb` counts `rcx` down as it goes, so it's the number of bytes left, even after a fault
+    mov rax, rcx
+    ret
+.size __copy_user, . - __copy_user
+.popsection
+
+.pushsection .text.__copy_user_cstr,"ax",@progbits
+.global __copy_user_cstr
+.hidden __copy_user_cstr
+.type __copy_user_cstr,@function
+__copy_user_cstr:
+    xor eax, eax
+.Lcopy_user_cstr_loop:
+    cmp rax, rdx
+    je .Lcopy_user_cstr_done
+.Lcopy_user_cstr_load:
+    movzx ecx, byte ptr [rsi + rax]
+    mov byte ptr [rdi + rax], cl
+    test cl, cl
+    jz .Lcopy_user_cstr_done
+    inc rax
+    jmp .Lcopy_user_cstr_loop
+.Lcopy_user_cstr_done:
+    ret
+.Lcopy_user_cstr_fault:
+    mov rax, -1
+    ret
+.size __copy_user_cstr, . - __copy_user_cstr
+.popsection
+
+// Retained even if nothing refers to it, since only the page fault handler looks it up
+.pushsection ex_table,"aR"
+.balign 4
+.long .Lcopy_user_movs - .
+.long .Lcopy_user_done - .
+.long .Lcopy_user_cstr_load - .
+.long .Lcopy_user_cstr_fault - .
+.popsection
+"#
+);
+
+fn exception_table() -> &'static [ExceptionTableEntry] {
+    // SAFETY:
+    // The linker defines the symbols at the start and end of the `ex_table` section, which only contains entries
+    unsafe {
+        let start = ptr::addr_of!(__start_ex_table).cast::<ExceptionTableEntry>();
+        let end = ptr::addr_of!(__stop_ex_table).cast::<ExceptionTableEntry>();
+        core::slice::from_raw_parts(start, end.offset_from(start) as usize)
+    }
+}
+
+/// Returns the address to resume at after a page fault at `ip`, if `ip` is an instruction that accesses user memory.
+///
+/// The page fault handler calls this for faults in kernel mode that it can't resolve, and resumes at the returned address instead of panicking
+pub fn fixup_exception(ip: usize) -> Option<usize> {
+    exception_table()
+        .iter()
+        .find(|entry| entry.insn() == ip)
+        .map(ExceptionTableEntry::fixup)
+}
+
+/// [`fixup_exception`] for the page fault handler in `phantomos-init`
+extern "C" fn fixup_exception_hook(ip: usize) -> Option<NonZeroUsize> {
+    fixup_exception(ip).and_then(NonZeroUsize::new)
+}
+
+/// Registers the exception table with the page fault handler in `phantomos-init`.
+///
+/// Init is loaded before the kernel, so it can't import the table, and the kernel hands it over at runtime instead.
+/// This must be called while the kernel starts up, before it accesses user memory, since until then every page fault panics
+pub fn register_fixup_hook() {
+    // SAFETY: The hook only reads the exception table, which is valid for as long as the kernel is loaded
+    unsafe { __register_fixup_exception(fixup_exception_hook) }
+}
+
+fn bad_address() -> io::Error {
+    io::Error::from_kind(ErrorKind::BadAddress)
+}
+
+/// Checks that the `len` bytes at `addr` are in the user half of the address space
+fn check_user_range(addr: usize, len: usize) -> io::Result<()> {
+    match addr.checked_add(len) {
+        Some(end) if end <= USER_END => Ok(()),
+        _ => Err(bad_address()),
+    }
+}
+
+/// Copies `dst.len()` bytes of user memory at address `src` into `dst`
+///
+/// # Errors
+/// Returns [`ErrorKind::BadAddress`] if the bytes aren't entirely in the user half of the address space, or any of them can't be read
+pub fn copy_from_user(dst: &mut [u8], src: usize) -> io::Result<()> {
+    if dst.is_empty() {
+        return Ok(());
+    }
+    check_user_range(src, dst.len())?;
+    // SAFETY:
+    // `dst` is valid kernel memory, and `src` is in the user half, where faults are recovered from
+    match unsafe { __copy_user(dst.as_mut_ptr(), src as *const u8, dst.len()) } {
+        0 => Ok(()),
+        _ => Err(bad_address()),
+    }
+}
+
+/// Copies `src` into user memory at address `dst`
+///
+/// # Errors
+/// Returns [`ErrorKind::BadAddress`] if the bytes aren't entirely in the user half of the address space, or any of them can't be written.
+/// The bytes before the one that couldn't be written may have been written already
+pub fn copy_to_user(dst: usize, src: &[u8]) -> io::Result<()> {
+    if src.is_empty() {
+        return Ok(());
+    }
+    check_user_range(dst, src.len())?;
+    // SAFETY:
+    // `src` is valid kernel memory, and `dst` is in the user half, where faults are recovered from
+    match unsafe { __copy_user(dst as *mut u8, src.as_ptr(), src.len()) } {
+        0 => Ok(()),
+        _ => Err(bad_address()),
+    }
+}
+
+/// Copies a NUL-terminated string from user memory at address `src` into `buf`, reading at most `buf.len()` bytes, and returns its length without the NUL
+///
+/// # Errors
+/// Returns [`ErrorKind::BadAddress`] if the string runs past the user half of the address space, or any of it can't be read,
+///  and [`ErrorKind::InvalidInput`] if there is no NUL in the first `buf.len()` bytes
+pub fn copy_cstr_from_user(buf: &mut [u8], src: usize) -> io::Result<usize> {
+    if src >= USER_END {
+        return Err(bad_address());
+    }
+    // The string may end before `buf` does, so only the part that's read has to be user memory
+    let len = buf.len().min(USER_END - src);
+    // SAFETY:
+    // `buf` is valid kernel memory, and the `len` bytes at `src` are in the user half, where faults are recovered from
+    match unsafe { __copy_user_cstr(buf.as_mut_ptr(), src as *const u8, len) } {
+        -1 => Err(bad_address()),
+        n if (n as usize) < len => Ok(n as usize),
+        _ if len < buf.len() => Err(bad_address()),
+        _ => Err(io::Error::new(ErrorKind::InvalidInput, "string too long")),
+    }
+}
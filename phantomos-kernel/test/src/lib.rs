@@ -0,0 +1,30 @@
+//! The kernel modules that can be built on the host, so that they can be tested with `cargo test`.
+//!
+//! They're built from the kernel's sources, with the hosted kernel standard library standing in for `std` as it does in the kernel.
+
+#![feature(allocator_api)]
+#![no_std]
+
+extern crate alloc;
+extern crate kstd as std;
+
+#[path = "../../src/addr_space.rs"]
+pub mod addr_space;
+#[path = "../../src/futex.rs"]
+pub mod futex;
+#[path = "../../src/handle.rs"]
+pub mod handle;
+#[path = "../../src/handle_table.rs"]
+pub mod handle_table;
+#[path = "../../src/percpu.rs"]
+pub mod percpu;
+#[path = "../../src/process.rs"]
+pub mod process;
+#[path = "../../src/security.rs"]
+pub mod security;
+#[path = "../../src/state.rs"]
+pub mod state;
+#[path = "../../src/thread.rs"]
+pub mod thread;
+#[path = "../../src/usercopy.rs"]
+pub mod usercopy;
@@ -0,0 +1,176 @@
+//! Reference counting of kernel objects, checked by counting how often an object is dropped and its memory freed.
+
+#![feature(allocator_api)]
+
+use std::alloc::{AllocError, Allocator, Global, Layout};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use kernel_test::addr_space::HandlePtr;
+use kernel_test::handle::{AnyHandleRef, Handle, HandleKind, HandleRef, HandleType};
+use kernel_test::thread::ThreadHandle;
+
+/// An object that counts how many times it has been dropped
+#[repr(C)]
+struct Counted {
+    handle: Handle,
+    drops: Arc<AtomicUsize>,
+}
+
+// SAFETY: `Counted` is `#[repr(C)]`, and starts with its header. Nothing else in the tests uses `IOHandle`
+unsafe impl HandleKind for Counted {
+    const TYPE: HandleType = HandleType::IOHandle;
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        self.drops.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// An allocator that counts how many of its allocations are live
+#[derive(Clone, Default)]
+struct CountingAlloc(Arc<AtomicUsize>);
+
+// SAFETY: Allocations are made and freed by `Global`, which upholds the contract
+unsafe impl Allocator for CountingAlloc {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[u8]>, AllocError> {
+        let ptr = Global.allocate(layout)?;
+        self.0.fetch_add(1, Ordering::Relaxed);
+        Ok(ptr)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+        // SAFETY: `ptr` was allocated by `Global` in `allocate`, with `layout`
+        unsafe { Global.deallocate(ptr, layout) }
+    }
+}
+
+fn counted() -> (
+    HandleRef<Counted, CountingAlloc>,
+    Arc<AtomicUsize>,
+    Arc<AtomicUsize>,
+) {
+    let drops = Arc::new(AtomicUsize::new(0));
+    let alloc = CountingAlloc::default();
+    let live = alloc.0.clone();
+    let object = HandleRef::new_in(
+        Counted {
+            handle: Handle::new::<Counted>(),
+            drops: drops.clone(),
+        },
+        alloc,
+    );
+    (object, drops, live)
+}
+
+#[test]
+fn strong_and_weak_counts() {
+    let (object, drops, live) = counted();
+    assert_eq!(HandleRef::strong_count(&object), 1);
+    assert_eq!(HandleRef::weak_count(&object), 0);
+    assert_eq!(live.load(Ordering::Relaxed), 1);
+
+    let clone = object.clone();
+    assert!(HandleRef::ptr_eq(&object, &clone));
+    assert_eq!(HandleRef::strong_count(&object), 2);
+
+    let weak = HandleRef::downgrade(&object);
+    let weak_clone = weak.clone();
+    assert!(weak.ptr_eq(&weak_clone));
+    assert_eq!(HandleRef::weak_count(&object), 2);
+    assert_eq!(weak.strong_count(), 2);
+
+    drop(clone);
+    drop(weak_clone);
+    assert_eq!(HandleRef::strong_count(&object), 1);
+    assert_eq!(HandleRef::weak_count(&object), 1);
+    assert_eq!(drops.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn upgrade_fails_once_destroyed() {
+    let (object, drops, live) = counted();
+    let weak = HandleRef::downgrade(&object);
+
+    let upgraded = weak.upgrade().unwrap();
+    assert_eq!(HandleRef::strong_count(&object), 2);
+    drop(upgraded);
+
+    drop(object);
+    assert_eq!(drops.load(Ordering::Relaxed), 1);
+    assert_eq!(weak.strong_count(), 0);
+    assert!(weak.upgrade().is_none());
+    // The count stays at zero, rather than being resurrected by the failed upgrade
+    assert_eq!(weak.strong_count(), 0);
+    assert_eq!(live.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn last_release_frees_memory() {
+    let (object, drops, live) = counted();
+    let weak = HandleRef::downgrade(&object);
+    let any = HandleRef::into_any(object.clone());
+
+    drop(object);
+    assert_eq!(drops.load(Ordering::Relaxed), 0);
+    drop(any);
+    // The object is destroyed with its last strong reference, but the memory stays until the last weak one is gone
+    assert_eq!(drops.load(Ordering::Relaxed), 1);
+    assert_eq!(live.load(Ordering::Relaxed), 1);
+    drop(weak);
+    assert_eq!(drops.load(Ordering::Relaxed), 1);
+    assert_eq!(live.load(Ordering::Relaxed), 0);
+
+    // Without weak references, both happen at once
+    let (object, drops, live) = counted();
+    drop(object);
+    assert_eq!(drops.load(Ordering::Relaxed), 1);
+    assert_eq!(live.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn raw_round_trip() {
+    let drops = Arc::new(AtomicUsize::new(0));
+    let object = HandleRef::new(Counted {
+        handle: Handle::new::<Counted>(),
+        drops: drops.clone(),
+    });
+    let ptr = HandleRef::into_raw(object);
+    assert_eq!(drops.load(Ordering::Relaxed), 0);
+    let object = unsafe { HandleRef::from_raw(ptr) };
+    assert_eq!(HandleRef::strong_count(&object), 1);
+    drop(object);
+    assert_eq!(drops.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn downcast_checks_type() {
+    let (object, drops, _) = counted();
+    let any: AnyHandleRef<CountingAlloc> = object.into();
+    assert_eq!(any.ty(), HandleType::IOHandle);
+    assert!(any.is::<Counted>());
+    assert!(!any.is::<ThreadHandle>());
+    assert!(any.downcast_ref::<ThreadHandle>().is_none());
+    assert!(any.downcast_ref::<Counted>().is_some());
+
+    // A failed downcast hands the reference back without releasing it
+    let any = match any.downcast::<ThreadHandle>() {
+        Ok(_) => panic!("downcast to the wrong type"),
+        Err(any) => any,
+    };
+    assert_eq!(any.handle().rc.load(Ordering::Relaxed), 1);
+    let object = any.downcast::<Counted>().unwrap();
+    assert_eq!(HandleRef::strong_count(&object), 1);
+    drop(object);
+    assert_eq!(drops.load(Ordering::Relaxed), 1);
+
+    let thread = HandleRef::new(ThreadHandle::new(HandlePtr::from_kernel_addr(
+        std::ptr::null_mut(),
+    )));
+    let any = HandleRef::into_any(thread);
+    assert_eq!(any.ty(), HandleType::ThreadHandle);
+    assert!(any.downcast::<Counted>().is_err());
+}
@@ -0,0 +1,128 @@
+//! Faults on bad user pointers, recovered from through the exception table.
+//!
+//! A `SIGSEGV` handler stands in for the kernel's page fault handler: it resumes at the fixup that `fixup_exception` returns for the faulting
+//!  instruction, and aborts if there's none.
+
+use std::ptr;
+use std::sync::Once;
+
+use kernel_test::addr_space::UserPtr;
+use kernel_test::usercopy::{self, USER_END};
+use kstd::io::ErrorKind;
+
+const PAGE_SIZE: usize = 4096;
+
+extern "C" fn handle_segv(_: libc::c_int, _: *mut libc::siginfo_t, ctx: *mut libc::c_void) {
+    // SAFETY: the kernel passes a `ucontext_t` to handlers installed with `SA_SIGINFO`
+    let ctx = unsafe { &mut *ctx.cast::<libc::ucontext_t>() };
+    let rip = &mut ctx.uc_mcontext.gregs[libc::REG_RIP as usize];
+    match usercopy::fixup_exception(*rip as usize) {
+        Some(fixup) => *rip = fixup as i64,
+        None => unsafe { libc::abort() },
+    }
+}
+
+fn install_handler() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| unsafe {
+        let mut action: libc::sigaction = core::mem::zeroed();
+        action.sa_sigaction = handle_segv as *const () as usize;
+        action.sa_flags = libc::SA_SIGINFO;
+        assert_eq!(libc::sigaction(libc::SIGSEGV, &action, ptr::null_mut()), 0);
+    });
+}
+
+/// Maps a readable and writable page followed by an inaccessible one, and returns the start of the first
+fn guarded_page() -> *mut u8 {
+    install_handler();
+    unsafe {
+        let page = libc::mmap(
+            ptr::null_mut(),
+            2 * PAGE_SIZE,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+            -1,
+            0,
+        );
+        assert_ne!(page, libc::MAP_FAILED);
+        let page = page.cast::<u8>();
+        assert_eq!(
+            libc::mprotect(page.add(PAGE_SIZE).cast(), PAGE_SIZE, libc::PROT_NONE),
+            0
+        );
+        page
+    }
+}
+
+#[test]
+fn read_write() {
+    let page = guarded_page();
+    let val = UserPtr::from_user_addr(page.cast::<u64>());
+    val.write(0xdead_beef).unwrap();
+    assert_eq!(val.read().unwrap(), 0xdead_beef);
+
+    let arr = UserPtr::from_user_addr(page.cast::<u32>());
+    arr.write_slice(&[1, 2, 3]).unwrap();
+    let mut buf = [0u32; 3];
+    arr.read_slice(&mut buf).unwrap();
+    assert_eq!(buf, [1, 2, 3]);
+}
+
+#[test]
+fn fault() {
+    let page = guarded_page();
+    // Straddles the end of the accessible page
+    let val = UserPtr::from_user_addr(unsafe { page.add(PAGE_SIZE - 4) }.cast::<u64>());
+    assert_eq!(val.read().unwrap_err().kind(), ErrorKind::BadAddress);
+    assert_eq!(val.write(1).unwrap_err().kind(), ErrorKind::BadAddress);
+
+    // The accessible part is still copied before the fault
+    assert_eq!(
+        usercopy::copy_to_user(page as usize + PAGE_SIZE - 4, &[7; 8])
+            .unwrap_err()
+            .kind(),
+        ErrorKind::BadAddress
+    );
+    let mut buf = [0u8; 4];
+    unsafe { ptr::copy_nonoverlapping(page.add(PAGE_SIZE - 4), buf.as_mut_ptr(), 4) };
+    assert_eq!(buf, [7; 4]);
+}
+
+#[test]
+fn kernel_half() {
+    let val = UserPtr::from_user_addr(USER_END as *mut u64);
+    assert_eq!(val.read().unwrap_err().kind(), ErrorKind::BadAddress);
+    let val = UserPtr::from_user_addr((USER_END - 4) as *mut u64);
+    assert_eq!(val.read().unwrap_err().kind(), ErrorKind::BadAddress);
+}
+
+#[test]
+fn read_cstr() {
+    let page = guarded_page();
+    // The page is zeroed, so the string is NUL-terminated
+    unsafe { ptr::copy_nonoverlapping(b"hello".as_ptr(), page, 5) };
+    let s = UserPtr::from_user_addr(page);
+    assert_eq!(s.read_cstr(&mut [0; 16]).unwrap(), "hello");
+    assert_eq!(s.read_cstr(&mut [0; 6]).unwrap(), "hello");
+    assert_eq!(
+        s.read_cstr(&mut [0; 5]).unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+
+    unsafe { *page = 0xff };
+    assert_eq!(
+        s.read_cstr(&mut [0; 16]).unwrap_err().kind(),
+        ErrorKind::InvalidData
+    );
+
+    // Runs into the inaccessible page before the NUL
+    let end = unsafe { page.add(PAGE_SIZE - 3) };
+    unsafe { ptr::copy_nonoverlapping(b"abc".as_ptr(), end, 3) };
+    assert_eq!(
+        UserPtr::from_user_addr(end)
+            .read_cstr(&mut [0; 16])
+            .unwrap_err()
+            .kind(),
+        ErrorKind::BadAddress
+    );
+}
@@ -0,0 +1,25 @@
+# Boots PhantomOS in QEMU with a kernel page fault triggered right after the IDT is set up,
+# and checks that the page fault handler panics with the faulting address rather than jumping into the void.
+
+FAULT_ADDR=0x400000000000
+LOG=smoke-test.log
+
+function error {
+    echo "error: $1" >&2
+    exit 1
+}
+
+pushd $(dirname $0) > /dev/null
+
+CARGO_FLAGS="--features phantomos-init/page-fault-smoke-test" ./build-iso.sh || error "build failed"
+
+rm -f $LOG
+# The panic handler spins forever, so QEMU is stopped by the timeout
+timeout 60 qemu-system-x86_64 -cdrom phantomos.iso -display none -serial file:$LOG -no-reboot > /dev/null 2>&1
+
+grep -q "Triggering a kernel page fault... returned" $LOG && error "the page fault handler returned"
+grep -q "Page Fault at .* accessing $FAULT_ADDR" $LOG || error "no page fault panic in $LOG"
+
+echo "status: page fault smoke test passed"
+
+popd > /dev/null